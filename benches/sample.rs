@@ -1,9 +1,10 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use nix::sched::{sched_setaffinity, CpuSet};
 use nix::unistd::Pid;
-use nuts_rs::math::{axpy, axpy_out, vector_dot};
-use nuts_rs::test_logps::{Maker,NormalLogp};
+use nuts_rs::math::{axpy, axpy_out, fill_normal, vector_dot};
+use nuts_rs::test_logps::{Maker, NormalLogp};
 use nuts_rs::{new_sampler, sample_parallel, Chain, JitterInitFunc, SamplerArgs};
+use rand::{Rng, SeedableRng};
 use rayon::ThreadPoolBuilder;
 
 fn make_sampler(dim: usize, mu: f64) -> impl Chain {
@@ -80,6 +81,19 @@ fn criterion_benchmark(c: &mut Criterion) {
         */
     }
 
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let mut momentum = vec![0.; 100_000];
+    c.bench_function("fill_normal 100000", |b| {
+        b.iter(|| fill_normal(&mut rng, black_box(&mut momentum)));
+    });
+    c.bench_function("standard_normal 100000", |b| {
+        b.iter(|| {
+            black_box(&mut momentum)
+                .iter_mut()
+                .for_each(|val| *val = rng.sample(rand_distr::StandardNormal))
+        });
+    });
+
     let mut out = vec![0.; 10];
     c.bench_function("sample_1000_10", |b| {
         b.iter(|| sample_one(black_box(3.), black_box(&mut out)))
@@ -101,7 +115,7 @@ fn criterion_benchmark(c: &mut Criterion) {
                 let seed = black_box(42);
                 let n_try_init = 10;
                 let (handle, channel) = sample_parallel(
-                    Maker{logp:func},
+                    Maker { logp: func },
                     &mut init_point_func,
                     settings,
                     n_chains,
@@ -0,0 +1,25 @@
+use std::{path::Path, process::Command};
+
+fn main() {
+    // Only use the hash of this repository, not of a repository that
+    // happens to contain a vendored copy of the crate.
+    let git_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(".git");
+    let hash = if git_dir.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+        Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|hash| hash.trim().to_string())
+    } else {
+        println!("cargo:rerun-if-changed=build.rs");
+        None
+    };
+    println!(
+        "cargo:rustc-env=NUTS_RS_GIT_HASH={}",
+        hash.unwrap_or_else(|| "unknown".to_string())
+    );
+}
@@ -0,0 +1,151 @@
+//! Sample the non-centered eight schools model with several chains in
+//! parallel and check convergence with R-hat and the effective sample size.
+//!
+//! The model is
+//!
+//! ```text
+//! mu ~ N(0, 5)
+//! tau ~ HalfCauchy(5)
+//! theta_tilde_j ~ N(0, 1)
+//! y_j ~ N(mu + tau * theta_tilde_j, sigma_j)
+//! ```
+//!
+//! and is sampled on the unconstrained space `(mu, log(tau), theta_tilde)`.
+//!
+//! Run with `cargo run --release --example eight_schools`.
+
+use nuts_rs::{
+    ess, r_hat, sample_parallel, CpuLogpFunc, CpuLogpFuncMaker, JitterInitFunc, LogpError,
+    SamplerArgs,
+};
+use thiserror::Error;
+
+pub const Y: [f64; 8] = [28., 8., -3., 7., -1., 1., 18., 12.];
+pub const SIGMA: [f64; 8] = [15., 10., 16., 11., 9., 11., 10., 18.];
+
+#[derive(Debug, Error)]
+pub enum EightSchoolsError {}
+
+impl LogpError for EightSchoolsError {
+    fn is_recoverable(&self) -> bool {
+        false
+    }
+}
+
+/// The posterior density of the non-centered eight schools model
+#[derive(Clone, Debug, Default)]
+pub struct EightSchools {}
+
+impl CpuLogpFunc for EightSchools {
+    type Err = EightSchoolsError;
+
+    fn dim(&self) -> usize {
+        2 + Y.len()
+    }
+
+    fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+        let mu = position[0];
+        let log_tau = position[1];
+        let tau = log_tau.exp();
+        let theta_tilde = &position[2..];
+
+        // Priors, including the jacobian of tau = exp(log_tau)
+        let mut logp = -mu * mu / (2. * 25.) - (tau * tau / 25.).ln_1p() + log_tau;
+        let mut grad_mu = -mu / 25.;
+        let mut grad_tau = -2. * tau / 25. / (1. + tau * tau / 25.);
+
+        for (j, (&tt, grad_tt)) in theta_tilde.iter().zip(grad[2..].iter_mut()).enumerate() {
+            let theta = mu + tau * tt;
+            let resid = (Y[j] - theta) / (SIGMA[j] * SIGMA[j]);
+            logp += -tt * tt / 2. - (Y[j] - theta) * resid / 2.;
+            grad_mu += resid;
+            grad_tau += resid * tt;
+            *grad_tt = -tt + resid * tau;
+        }
+
+        grad[0] = grad_mu;
+        grad[1] = grad_tau * tau + 1.;
+        Ok(logp)
+    }
+}
+
+impl CpuLogpFuncMaker for EightSchools {
+    type Func = EightSchools;
+
+    fn make_logp_func(&self) -> Result<Self::Func, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.clone())
+    }
+
+    fn dim(&self) -> usize {
+        CpuLogpFunc::dim(self)
+    }
+}
+
+/// Posterior summaries of `mu` and `log(tau)`
+#[derive(Debug)]
+pub struct Summary {
+    pub mu_mean: f64,
+    pub tau_mean: f64,
+    pub r_hat: [f64; 2],
+    pub ess: [f64; 2],
+    pub num_divergences: u64,
+}
+
+/// Sample `num_chains` chains with `num_draws` draws each after tuning.
+pub fn run(num_chains: u64, num_draws: u64, seed: u64) -> Summary {
+    let settings = SamplerArgs {
+        num_tune: 1000,
+        ..Default::default()
+    };
+    let num_tune = settings.num_tune;
+    let (handle, draws) = sample_parallel(
+        EightSchools::default(),
+        &mut JitterInitFunc::new(),
+        settings,
+        num_chains,
+        num_draws,
+        seed,
+        10,
+    )
+    .expect("Could not start sampling");
+
+    // The draws of mu and log(tau) for each chain, without tuning draws
+    let mut traces = vec![[vec![], vec![]]; num_chains as usize];
+    let mut num_divergences = 0;
+    for (draw, stats) in draws {
+        if stats.draw() < num_tune {
+            continue;
+        }
+        num_divergences += stats.divergence_info().is_some() as u64;
+        let trace = &mut traces[stats.chain() as usize];
+        trace[0].push(draw[0]);
+        trace[1].push(draw[1]);
+    }
+    for result in handle.join().expect("Sampler thread panicked") {
+        result.expect("Sampling failed");
+    }
+
+    let param =
+        |index: usize| -> Vec<&[f64]> { traces.iter().map(|trace| &trace[index][..]).collect() };
+    let mean = |values: &mut dyn Iterator<Item = f64>| {
+        let (sum, count) = values.fold((0., 0), |(sum, count), val| (sum + val, count + 1));
+        sum / count as f64
+    };
+    Summary {
+        mu_mean: mean(&mut param(0).into_iter().flatten().copied()),
+        tau_mean: mean(&mut param(1).into_iter().flatten().map(|val| val.exp())),
+        r_hat: [r_hat(&param(0)).unwrap(), r_hat(&param(1)).unwrap()],
+        ess: [ess(&param(0)).unwrap(), ess(&param(1)).unwrap()],
+        num_divergences,
+    }
+}
+
+#[allow(dead_code)]
+fn main() {
+    let summary = run(4, 1000, 42);
+    println!("E[mu]  = {:.2}", summary.mu_mean);
+    println!("E[tau] = {:.2}", summary.tau_mean);
+    println!("R-hat (mu, log tau) = {:.3?}", summary.r_hat);
+    println!("ESS (mu, log tau)   = {:.0?}", summary.ess);
+    println!("divergences         = {}", summary.num_divergences);
+}
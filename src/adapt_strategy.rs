@@ -1,17 +1,26 @@
-use std::{fmt::Debug, iter, marker::PhantomData};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    iter,
+    marker::PhantomData,
+    sync::{Arc, Condvar, Mutex},
+};
 
 use itertools::izip;
 
 use crate::{
     cpu_potential::{CpuLogpFunc, EuclideanPotential},
+    diagnostics::ess,
     mass_matrix::{
-        DiagAdaptExpSettings, DiagMassMatrix, DrawGradCollector, ExpWeightedVariance, MassMatrix,
+        DenseAdaptSettings, DenseMassMatrix, DenseShrinkage, DiagAdaptExpSettings, DiagMassMatrix,
+        DiagMassMatrixEstimator, DrawGradCollector, ExpWeightedVariance, MassMatrix,
     },
+    math::{portable_exp, portable_powf, portable_tanh},
     nuts::{
         AdaptStrategy, AsSampleStatVec, Collector, Hamiltonian, NutsOptions, SampleStatItem,
-        SampleStatValue,
+        SampleStatValue, StatField,
     },
-    stepsize::{AcceptanceRateCollector, DualAverage, DualAverageOptions},
+    stepsize::{AcceptanceRateCollector, DepthAcceptance, DualAverage, DualAverageOptions},
 };
 
 const LOWER_LIMIT: f64 = 1e-10f64;
@@ -20,17 +29,34 @@ const UPPER_LIMIT: f64 = 1e10f64;
 pub(crate) struct DualAverageStrategy<F, M> {
     step_size_adapt: DualAverage,
     options: DualAverageSettings,
-    num_tune: u64,
     num_early: u64,
+    num_exploration: u64,
+    num_adapted: u64,
+    num_tune: u64,
+    /// The draw at which the step size is frozen. This starts before
+    /// `num_tune` if draws are reserved for window extensions.
+    tune_end: u64,
+    num_extensions: u64,
+    /// The mean acceptance statistics of the last `accept_window` draws
+    window: VecDeque<f64>,
+    /// The pool of step size estimates and the number of draws between
+    /// exchanges
+    pool: Option<(PoolMember<StepSizeEstimate>, u64)>,
+    /// Use the portable functions in [`crate::math`], see
+    /// [`NutsOptions::strict_reproducibility`]
+    portable: bool,
     _phantom1: PhantomData<F>,
     _phantom2: PhantomData<M>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DualAverageStats {
     step_size_bar: f64,
     mean_tree_accept: f64,
     n_steps: u64,
+    tuning: bool,
+    window_extensions: u64,
+    depth_accept: Option<Box<[DepthAcceptance]>>,
 }
 
 impl AsSampleStatVec for DualAverageStats {
@@ -41,6 +67,32 @@ impl AsSampleStatVec for DualAverageStats {
             SampleStatValue::F64(self.mean_tree_accept),
         ));
         vec.push(("n_steps", SampleStatValue::U64(self.n_steps)));
+        vec.push(("tuning", SampleStatValue::Bool(self.tuning)));
+        vec.push((
+            "window_extensions",
+            SampleStatValue::U64(self.window_extensions),
+        ));
+        let by_depth = |func: fn(&DepthAcceptance) -> f64| -> Option<Box<[f64]>> {
+            self.depth_accept
+                .as_ref()
+                .map(|vals| vals.iter().map(func).collect())
+        };
+        vec.push(("depth_accept_min", by_depth(|val| val.min).into()));
+        vec.push(("depth_accept_max", by_depth(|val| val.max).into()));
+        vec.push(("depth_accept_mean", by_depth(DepthAcceptance::mean).into()));
+    }
+
+    fn add_to_schema(&self, schema: &mut Vec<StatField>) {
+        let mut vec = vec![];
+        self.add_to_vec(&mut vec);
+        schema.extend(vec.iter().map(|(name, value)| {
+            let mut field = StatField::from_value(name, value);
+            // There is one value for each doubling of the trajectory
+            if name.starts_with("depth_accept_") {
+                field.length = None;
+            }
+            field
+        }));
     }
 }
 
@@ -50,6 +102,39 @@ pub struct DualAverageSettings {
     pub target_accept: f64,
     pub final_window_ratio: f64,
     pub params: DualAverageOptions,
+    /// Export the minimum, maximum and mean acceptance statistic of the
+    /// leapfrog steps of each doubling of the trajectory, as the sample stats
+    /// `depth_accept_min`, `depth_accept_max` and `depth_accept_mean`. This
+    /// includes the last doubling even if it was rejected because of a U-turn
+    /// or a divergence. A drop towards the end of long trajectories indicates
+    /// a step size that is too large for the regions far from the starting
+    /// point.
+    pub store_depth_accept: bool,
+    /// The number of draws at the start of tuning that use large step sizes
+    /// to move away from a poor initial point quickly.
+    ///
+    /// During those draws the step size is `initial_step` times
+    /// `exploration_step_scale` times a factor that varies from draw to draw
+    /// between `exp(-exploration_jitter)` and `exp(exploration_jitter)`. The
+    /// acceptance statistics of those draws are ignored, and dual averaging
+    /// starts after them. They count towards the number of tuning draws.
+    pub num_exploration: u64,
+    pub exploration_step_scale: f64,
+    pub exploration_jitter: f64,
+    /// Check the mean acceptance statistics of the last `accept_window`
+    /// tuning draws before the step size is frozen. If the standard error of
+    /// their mean is larger than `max_accept_se`, the mean is too noisy to
+    /// trust, and step size adaptation continues for another `accept_window`
+    /// draws, at most `max_window_extensions` times. Zero disables the check.
+    ///
+    /// The extensions never go past `num_tune`. Instead, the last
+    /// `accept_window * max_window_extensions` tuning draws, but at most
+    /// half of them, are reserved for them, and the step size is frozen
+    /// before those draws unless the window is extended. The sample stat
+    /// `window_extensions` counts the extensions so far.
+    pub accept_window: u64,
+    pub max_accept_se: f64,
+    pub max_window_extensions: u64,
 }
 
 impl Default for DualAverageSettings {
@@ -59,6 +144,183 @@ impl Default for DualAverageSettings {
             target_accept: 0.8,
             final_window_ratio: 0.4,
             params: DualAverageOptions::default(),
+            store_depth_accept: false,
+            num_exploration: 0,
+            exploration_step_scale: 4f64,
+            exploration_jitter: 1f64,
+            accept_window: 0,
+            max_accept_se: 0.02,
+            max_window_extensions: 4,
+        }
+    }
+}
+
+impl<F, M> DualAverageStrategy<F, M> {
+    /// The step size for an exploration draw
+    fn exploration_step_size(&self, draw: u64) -> f64 {
+        // The fractional parts of multiples of the golden ratio are spread
+        // evenly over [0, 1), so the step sizes vary without random numbers.
+        const GOLDEN: f64 = 0.618_033_988_749_895;
+        let noise = 2f64 * (draw as f64 * GOLDEN).fract() - 1f64;
+        let jitter = self.options.exploration_jitter * noise;
+        let jitter = if self.portable {
+            portable_exp(jitter)
+        } else {
+            jitter.exp()
+        };
+        self.options.params.initial_step * self.options.exploration_step_scale * jitter
+    }
+
+    /// Record the acceptance statistic of a tuning draw, and extend tuning
+    /// by another window if this is the last tuning draw and the window is
+    /// too noisy.
+    fn check_window(&mut self, draw: u64, accept_stat: f64) {
+        let len = self.options.accept_window as usize;
+        if len == 0 {
+            return;
+        }
+        if self.window.len() == len {
+            self.window.pop_front();
+        }
+        self.window.push_back(accept_stat);
+        if (draw + 1 != self.tune_end)
+            | (self.window.len() < len.max(2))
+            | (self.num_extensions >= self.options.max_window_extensions)
+            | (self.tune_end + self.options.accept_window > self.num_tune)
+        {
+            return;
+        }
+        let n = self.window.len() as f64;
+        let mean = self.window.iter().sum::<f64>() / n;
+        let var = self
+            .window
+            .iter()
+            .map(|val| (val - mean) * (val - mean))
+            .sum::<f64>()
+            / (n - 1f64);
+        if (var / n).sqrt() > self.options.max_accept_se {
+            self.tune_end += self.options.accept_window;
+            self.num_extensions += 1;
+        }
+    }
+
+    pub(crate) fn with_options(options: DualAverageSettings, num_tune: u64) -> Self {
+        let reserved = options
+            .accept_window
+            .saturating_mul(options.max_window_extensions)
+            .min(num_tune / 2);
+        Self {
+            num_early: ((num_tune as f64) * options.final_window_ratio).ceil() as u64,
+            num_exploration: options.num_exploration.min(num_tune),
+            num_adapted: 0,
+            num_tune,
+            tune_end: num_tune - reserved,
+            num_extensions: 0,
+            window: VecDeque::with_capacity(options.accept_window as usize),
+            pool: None,
+            portable: false,
+            options,
+            step_size_adapt: DualAverage::new(options.params),
+            _phantom1: PhantomData,
+            _phantom2: PhantomData,
+        }
+    }
+
+    /// Pool the dual averaging state with other chains every `every` draws
+    /// until the step size is frozen.
+    #[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+    pub(crate) fn set_pool(&mut self, pool: PoolMember<StepSizeEstimate>, every: u64) {
+        self.pool = Some((pool, every.max(1)));
+    }
+
+    /// Use the portable functions in [`crate::math`] instead of the platform
+    /// libm, if the chain runs with `strict_reproducibility`.
+    pub(crate) fn set_portable(&mut self, portable: bool) {
+        self.portable = portable;
+        self.step_size_adapt.set_portable(portable);
+    }
+
+    /// The step size of the first draw
+    pub(crate) fn initial_step_size(&self) -> f64 {
+        if self.num_exploration > 0 {
+            self.exploration_step_size(0)
+        } else {
+            self.options.params.initial_step
+        }
+    }
+
+    /// Update the dual averaging with the acceptance statistics of `draw`
+    /// and return the step size of the next draw.
+    pub(crate) fn next_step_size(
+        &mut self,
+        draw: u64,
+        collector: &AcceptanceRateCollector<crate::cpu_state::State>,
+    ) -> f64 {
+        self.num_adapted += 1;
+        if draw >= self.tune_end {
+            // Leave the pool, so that other chains do not wait for us
+            self.pool = None;
+        }
+        if draw + 1 < self.num_exploration {
+            return self.exploration_step_size(draw + 1);
+        }
+        if draw < self.num_exploration {
+            return self.step_size_adapt.current_step_size();
+        }
+        let target = if draw >= self.num_early {
+            self.options.target_accept
+        } else {
+            let start = self.options.early_target_accept;
+            let end = self.options.target_accept;
+            let time = (draw as f64) / (self.num_early as f64);
+            let tanh = if self.portable {
+                portable_tanh(6f64 * (time - 0.6))
+            } else {
+                (6f64 * (time - 0.6)).tanh()
+            };
+            start + (end - start) * (1f64 + tanh) / 2f64
+        };
+        if draw < self.tune_end {
+            let accept_stat = collector.mean.current();
+            self.step_size_adapt.advance(accept_stat, target);
+            self.check_window(draw, accept_stat);
+            if let Some((pool, every)) = self.pool.as_ref() {
+                if (draw > 0) & draw.is_multiple_of(*every) {
+                    let estimate = StepSizeEstimate {
+                        count: self.step_size_adapt.count(),
+                        state: self.step_size_adapt.pool_state(),
+                    };
+                    if let Some(pooled) = pool.exchange(estimate) {
+                        self.step_size_adapt.set_pool_state(pooled.state);
+                    }
+                }
+            }
+            self.step_size_adapt.current_step_size()
+        } else {
+            self.step_size_adapt.current_step_size_adapted()
+        }
+    }
+
+    pub(crate) fn new_acceptance_collector(
+        &self,
+    ) -> AcceptanceRateCollector<crate::cpu_state::State> {
+        AcceptanceRateCollector::new(self.options.store_depth_accept)
+    }
+
+    pub(crate) fn stats(
+        &self,
+        collector: &AcceptanceRateCollector<crate::cpu_state::State>,
+    ) -> DualAverageStats {
+        DualAverageStats {
+            step_size_bar: self.step_size_adapt.current_step_size_adapted(),
+            mean_tree_accept: collector.mean.current(),
+            n_steps: collector.mean.count(),
+            tuning: self.num_adapted < self.num_tune,
+            window_extensions: self.num_extensions,
+            depth_accept: collector
+                .by_depth
+                .as_ref()
+                .map(|vals| vals.as_slice().into()),
         }
     }
 }
@@ -70,23 +332,17 @@ impl<F: CpuLogpFunc, M: MassMatrix> AdaptStrategy for DualAverageStrategy<F, M>
     type Options = DualAverageSettings;
 
     fn new(options: Self::Options, num_tune: u64, _dim: usize) -> Self {
-        Self {
-            num_tune,
-            num_early: ((num_tune as f64) * options.final_window_ratio).ceil() as u64,
-            options,
-            step_size_adapt: DualAverage::new(options.params),
-            _phantom1: PhantomData::default(),
-            _phantom2: PhantomData::default(),
-        }
+        Self::with_options(options, num_tune)
     }
 
     fn init(
         &mut self,
-        _options: &mut NutsOptions,
+        options: &mut NutsOptions,
         potential: &mut Self::Potential,
         _state: &<Self::Potential as Hamiltonian>::State,
     ) {
-        potential.step_size = self.options.params.initial_step;
+        self.set_portable(options.strict_reproducibility);
+        potential.step_size = self.initial_step_size();
     }
 
     fn adapt(
@@ -96,25 +352,11 @@ impl<F: CpuLogpFunc, M: MassMatrix> AdaptStrategy for DualAverageStrategy<F, M>
         draw: u64,
         collector: &Self::Collector,
     ) {
-        let target = if draw >= self.num_early {
-            self.options.target_accept
-        } else {
-            let start = self.options.early_target_accept;
-            let end = self.options.target_accept;
-            let time = (draw as f64) / (self.num_early as f64);
-            start + (end - start) * (1f64 + (6f64 * (time - 0.6)).tanh()) / 2f64
-        };
-        if draw < self.num_tune {
-            self.step_size_adapt
-                .advance(collector.mean.current(), target);
-            potential.step_size = self.step_size_adapt.current_step_size()
-        } else {
-            potential.step_size = self.step_size_adapt.current_step_size_adapted()
-        }
+        potential.step_size = self.next_step_size(draw, collector);
     }
 
     fn new_collector(&self) -> Self::Collector {
-        AcceptanceRateCollector::new()
+        self.new_acceptance_collector()
     }
 
     fn current_stats(
@@ -123,28 +365,275 @@ impl<F: CpuLogpFunc, M: MassMatrix> AdaptStrategy for DualAverageStrategy<F, M>
         _potential: &Self::Potential,
         collector: &Self::Collector,
     ) -> Self::Stats {
-        DualAverageStats {
-            step_size_bar: self.step_size_adapt.current_step_size_adapted(),
-            mean_tree_accept: collector.mean.current(),
-            n_steps: collector.mean.count(),
+        self.stats(collector)
+    }
+}
+
+/// An estimate that several chains pool during tuning, see [`AdaptPool`]
+pub(crate) trait PoolEstimate: Clone + Send + Debug {
+    /// Combine the estimates of all chains of a round, or return `None` if
+    /// none of them contains any draws.
+    fn merge(estimates: &[Self]) -> Option<Self>;
+}
+
+/// Estimates that several chains pool during tuning
+///
+/// All chains that are still tuning meet at the same draws, and leave with
+/// the merged estimate of all chains. Chains that stop or fail leave the
+/// pool, so that the others do not wait for them.
+#[derive(Debug)]
+pub(crate) struct AdaptPool<T> {
+    state: Mutex<AdaptPoolState<T>>,
+    round_done: Condvar,
+}
+
+#[derive(Debug)]
+struct AdaptPoolState<T> {
+    /// The number of chains that take part in the next round
+    active: usize,
+    round: u64,
+    /// The estimates of the chains that arrived in the current round
+    estimates: Vec<T>,
+    /// The merged estimate of the last round, if any estimate was not empty
+    result: Option<T>,
+}
+
+impl<T: PoolEstimate> AdaptPoolState<T> {
+    fn finish_round(&mut self) {
+        self.result = T::merge(&self.estimates);
+        self.estimates.clear();
+        self.round += 1;
+    }
+}
+
+#[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+impl<T: PoolEstimate> AdaptPool<T> {
+    /// A pool for `num_chains` chains. Each chain must own exactly one
+    /// [`PoolMember`] of the pool.
+    pub(crate) fn new(num_chains: usize) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(AdaptPoolState {
+                active: num_chains,
+                round: 0,
+                estimates: Vec::with_capacity(num_chains),
+                result: None,
+            }),
+            round_done: Condvar::new(),
+        })
+    }
+
+    pub(crate) fn member(self: &Arc<Self>) -> PoolMember<T> {
+        PoolMember(self.clone())
+    }
+}
+
+/// The membership of one chain in an [`AdaptPool`]. The chain leaves the
+/// pool when this is dropped.
+#[derive(Debug)]
+pub(crate) struct PoolMember<T: PoolEstimate>(Arc<AdaptPool<T>>);
+
+impl<T: PoolEstimate> PoolMember<T> {
+    /// Add the estimate of this chain to the current round, wait for the
+    /// other chains, and return the merged estimate.
+    fn exchange(&self, estimate: T) -> Option<T> {
+        let mut state = self.0.state.lock().expect("Poisoned adaptation pool");
+        state.estimates.push(estimate);
+        let round = state.round;
+        if state.estimates.len() == state.active {
+            state.finish_round();
+            self.0.round_done.notify_all();
+        } else {
+            while state.round == round {
+                state = self
+                    .0
+                    .round_done
+                    .wait(state)
+                    .expect("Poisoned adaptation pool");
+            }
         }
+        state.result.clone()
+    }
+}
+
+impl<T: PoolEstimate> Drop for PoolMember<T> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.0.state.lock() else {
+            return;
+        };
+        state.active -= 1;
+        if !state.estimates.is_empty() & (state.estimates.len() == state.active) {
+            state.finish_round();
+            self.0.round_done.notify_all();
+        }
+    }
+}
+
+/// The diagonal draw and gradient variance estimates of a chain
+#[derive(Debug, Clone)]
+pub(crate) struct VarianceEstimate {
+    count: u64,
+    draw_mean: Box<[f64]>,
+    draw_var: Box<[f64]>,
+    grad_mean: Box<[f64]>,
+    grad_var: Box<[f64]>,
+}
+
+impl VarianceEstimate {
+    fn new(draw: &ExpWeightedVariance, grad: &ExpWeightedVariance) -> Self {
+        Self {
+            count: draw.count(),
+            draw_mean: draw.mean().into(),
+            draw_var: draw.current().into(),
+            grad_mean: grad.mean().into(),
+            grad_var: grad.current().into(),
+        }
+    }
+}
+
+/// The mean and variance of the union of groups with `counts` draws, from
+/// the means and variances of each group.
+fn pool_moments<'a>(
+    counts: &[u64],
+    means: impl Iterator<Item = &'a [f64]> + Clone,
+    vars: impl Iterator<Item = &'a [f64]>,
+    dim: usize,
+) -> (Box<[f64]>, Box<[f64]>) {
+    let total = counts.iter().sum::<u64>() as f64;
+    let mut mean = vec![0f64; dim].into_boxed_slice();
+    for (&count, vals) in counts.iter().zip(means.clone()) {
+        let weight = count as f64 / total;
+        izip!(mean.iter_mut(), vals).for_each(|(out, val)| *out += weight * val);
+    }
+    // The within-group variances plus the variance of the group means
+    let mut var = vec![0f64; dim].into_boxed_slice();
+    for ((&count, group_mean), group_var) in counts.iter().zip(means).zip(vars) {
+        let weight = count as f64 / total;
+        izip!(var.iter_mut(), group_mean, group_var, mean.iter()).for_each(
+            |(out, group_mean, group_var, mean)| {
+                let diff = group_mean - mean;
+                *out += weight * (group_var + diff * diff);
+            },
+        );
+    }
+    (mean, var)
+}
+
+impl PoolEstimate for VarianceEstimate {
+    fn merge(estimates: &[Self]) -> Option<Self> {
+        let counts: Vec<u64> = estimates.iter().map(|est| est.count).collect();
+        let count = counts.iter().sum::<u64>();
+        if count == 0 {
+            return None;
+        }
+        let dim = estimates[0].draw_var.len();
+        let (draw_mean, draw_var) = pool_moments(
+            &counts,
+            estimates.iter().map(|est| &est.draw_mean[..]),
+            estimates.iter().map(|est| &est.draw_var[..]),
+            dim,
+        );
+        let (grad_mean, grad_var) = pool_moments(
+            &counts,
+            estimates.iter().map(|est| &est.grad_mean[..]),
+            estimates.iter().map(|est| &est.grad_var[..]),
+            dim,
+        );
+        Some(Self {
+            count,
+            draw_mean,
+            draw_var,
+            grad_mean,
+            grad_var,
+        })
+    }
+}
+
+/// The dual averaging state of a chain, see [`DualAverage::pool_state`]
+#[derive(Debug, Clone)]
+pub(crate) struct StepSizeEstimate {
+    count: u64,
+    state: [f64; 3],
+}
+
+impl PoolEstimate for StepSizeEstimate {
+    fn merge(estimates: &[Self]) -> Option<Self> {
+        let count = estimates.iter().map(|est| est.count).sum::<u64>();
+        if count == 0 {
+            return None;
+        }
+        let mut state = [0f64; 3];
+        for est in estimates {
+            let weight = est.count as f64 / count as f64;
+            izip!(state.iter_mut(), est.state).for_each(|(out, val)| *out += weight * val);
+        }
+        Some(Self { count, state })
     }
 }
 
 pub(crate) struct ExpWindowDiagAdapt<F> {
     dim: usize,
     num_tune: u64,
+    num_tune_total: u64,
+    num_adapted: u64,
     exp_variance_draw: ExpWeightedVariance,
     exp_variance_grad: ExpWeightedVariance,
     exp_variance_draw_bg: ExpWeightedVariance,
     exp_variance_grad_bg: ExpWeightedVariance,
     settings: DiagAdaptExpSettings,
+    pool: Option<PoolMember<VarianceEstimate>>,
+    /// Whether the gradient at a tuning draw was ever nonzero in a
+    /// dimension, if `detect_zero_gradients` is set.
+    grad_nonzero: Option<Box<[bool]>>,
     _phantom: PhantomData<F>,
 }
 
+#[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+impl<F> ExpWindowDiagAdapt<F> {
+    /// Pool the variance estimates with other chains at each window switch
+    /// until adaptation ends.
+    pub(crate) fn set_pool(&mut self, pool: PoolMember<VarianceEstimate>) {
+        self.pool = Some(pool);
+    }
+}
+
+impl<F> ExpWindowDiagAdapt<F> {
+    fn register_gradient(&mut self, grad: &[f64]) {
+        let Some(grad_nonzero) = self.grad_nonzero.as_mut() else {
+            return;
+        };
+        let scale = grad.iter().fold(0f64, |max, val| max.max(val.abs()));
+        let threshold = f64::EPSILON * scale;
+        grad_nonzero
+            .iter_mut()
+            .zip(grad)
+            .for_each(|(nonzero, val)| *nonzero |= val.abs() > threshold);
+    }
+
+    /// The dimensions where the gradient was zero at all tuning draws so far.
+    fn zero_gradient_dims(&self) -> Option<Box<[f64]>> {
+        let dims: Box<[f64]> = self
+            .grad_nonzero
+            .as_ref()?
+            .iter()
+            .enumerate()
+            .filter(|(_, &nonzero)| !nonzero)
+            .map(|(i, _)| i as f64)
+            .collect();
+        Some(dims).filter(|dims| !dims.is_empty())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ExpWindowDiagAdaptStats {
     mass_matrix_inv: Option<Box<[f64]>>,
+    /// Minimum, maximum and mean of the inverse mass matrix diagonal
+    /// during tuning.
+    mass_matrix_inv_summary: Option<(f64, f64, f64)>,
+    /// The dimensions whose gradient was zero at all draws during tuning, see
+    /// `detect_zero_gradients`. This is only reported on the last tuning
+    /// draw, and only if there are such dimensions, as they usually point to
+    /// parameters that do not enter the logp function.
+    zero_gradient_dims: Option<Box<[f64]>>,
 }
 
 impl AsSampleStatVec for ExpWindowDiagAdaptStats {
@@ -153,6 +642,23 @@ impl AsSampleStatVec for ExpWindowDiagAdaptStats {
             "mass_matrix_inv",
             SampleStatValue::OptionArray(self.mass_matrix_inv.clone()),
         ));
+        let summary = self.mass_matrix_inv_summary;
+        vec.push((
+            "mass_matrix_inv_min",
+            SampleStatValue::OptionF64(summary.map(|(min, _, _)| min)),
+        ));
+        vec.push((
+            "mass_matrix_inv_max",
+            SampleStatValue::OptionF64(summary.map(|(_, max, _)| max)),
+        ));
+        vec.push((
+            "mass_matrix_inv_mean",
+            SampleStatValue::OptionF64(summary.map(|(_, _, mean)| mean)),
+        ));
+        vec.push((
+            "zero_gradient_dims",
+            SampleStatValue::OptionArray(self.zero_gradient_dims.clone()),
+        ));
     }
 }
 
@@ -163,15 +669,24 @@ impl<F: CpuLogpFunc> AdaptStrategy for ExpWindowDiagAdapt<F> {
     type Options = DiagAdaptExpSettings;
 
     fn new(options: Self::Options, num_tune: u64, dim: usize) -> Self {
+        // The empirical fisher information uses the uncentered second moment
+        let center_grad = options.estimator != DiagMassMatrixEstimator::EmpiricalFisher;
+        let decay = options.early_variance_decay;
         Self {
             dim,
             num_tune: num_tune.saturating_sub(options.final_window),
-            exp_variance_draw: ExpWeightedVariance::new(dim, options.early_variance_decay, true),
-            exp_variance_grad: ExpWeightedVariance::new(dim, options.early_variance_decay, true),
-            exp_variance_draw_bg: ExpWeightedVariance::new(dim, options.early_variance_decay, true),
-            exp_variance_grad_bg: ExpWeightedVariance::new(dim, options.early_variance_decay, true),
+            num_tune_total: num_tune,
+            num_adapted: 0,
+            exp_variance_draw: ExpWeightedVariance::new(dim, decay, true),
+            exp_variance_grad: ExpWeightedVariance::new(dim, decay, center_grad),
+            exp_variance_draw_bg: ExpWeightedVariance::new(dim, decay, true),
+            exp_variance_grad_bg: ExpWeightedVariance::new(dim, decay, center_grad),
             settings: options,
-            _phantom: PhantomData::default(),
+            pool: None,
+            grad_nonzero: options
+                .detect_zero_gradients
+                .then(|| vec![false; dim].into()),
+            _phantom: PhantomData,
         }
     }
 
@@ -188,47 +703,54 @@ impl<F: CpuLogpFunc> AdaptStrategy for ExpWindowDiagAdapt<F> {
                 let diag = if !self.settings.grad_init {
                     1f64
                 } else {
-                    assert!(val != 0f64, "Gradient at initial position is zero");
-                    val * val
+                    val.mul_add(val, self.settings.grad_init_regularization)
                 };
-                assert!(diag.is_finite());
+                assert!(diag.is_finite() & (diag > 0f64), "Invalid initial gradient");
                 diag
             }));
         self.exp_variance_grad.set_mean(iter::repeat(0f64));
+        self.register_gradient(&state.grad);
 
-        potential.mass_matrix.update_diag(
-            izip!(
-                self.exp_variance_draw.current(),
-                self.exp_variance_grad.current(),
-            )
-            .map(|(draw, grad)| {
-                let val = (draw / grad).sqrt().clamp(LOWER_LIMIT, UPPER_LIMIT);
-                assert!(val.is_finite());
-                val
-            }),
-        );
+        self.update_mass_matrix(potential);
     }
 
     fn adapt(
         &mut self,
-        _options: &mut NutsOptions,
+        options: &mut NutsOptions,
         potential: &mut Self::Potential,
         draw: u64,
         collector: &Self::Collector,
     ) {
+        self.num_adapted += 1;
+        if draw < self.num_tune_total {
+            self.register_gradient(&collector.grad);
+        }
+        if draw >= self.num_tune {
+            // Leave the pool, so that other chains do not wait for us
+            self.pool = None;
+        }
+        if draw >= self.num_tune_total {
+            if self.settings.continuous_adaptation {
+                let portable = options.strict_reproducibility;
+                self.adapt_continuous(potential, draw, collector, portable);
+            }
+            return;
+        }
         if draw >= self.num_tune {
             return;
         }
 
-        if (draw % self.settings.window_switch_freq == 0) & (self.exp_variance_draw_bg.count() > 5)
+        if draw.is_multiple_of(self.settings.window_switch_freq)
+            & (self.exp_variance_draw_bg.count() > 5)
         {
             self.exp_variance_draw = std::mem::replace(
                 &mut self.exp_variance_draw_bg,
                 ExpWeightedVariance::new(self.dim, self.settings.variance_decay, true),
             );
+            let center_grad = self.exp_variance_grad_bg.use_mean;
             self.exp_variance_grad = std::mem::replace(
                 &mut self.exp_variance_grad_bg,
-                ExpWeightedVariance::new(self.dim, self.settings.variance_decay, true),
+                ExpWeightedVariance::new(self.dim, self.settings.variance_decay, center_grad),
             );
 
             self.exp_variance_draw_bg
@@ -246,20 +768,27 @@ impl<F: CpuLogpFunc> AdaptStrategy for ExpWindowDiagAdapt<F> {
                 .add_sample(collector.grad.iter().copied());
         }
 
+        // All chains switch windows at the same draws, but a chain might not
+        // have enough draws for a switch, so pool at every switch draw.
+        if (draw > 0) & draw.is_multiple_of(self.settings.window_switch_freq) {
+            if let Some(pool) = self.pool.as_ref() {
+                let estimate =
+                    VarianceEstimate::new(&self.exp_variance_draw, &self.exp_variance_grad);
+                // Each chain keeps its own mean, the pooled variance already
+                // includes the spread of the means of the chains.
+                if let Some(pooled) = pool.exchange(estimate) {
+                    self.exp_variance_draw
+                        .set_variance(pooled.draw_var.iter().copied());
+                    self.exp_variance_grad
+                        .set_variance(pooled.grad_var.iter().copied());
+                }
+            }
+        }
+
         if self.exp_variance_draw.count() > 2 {
             assert!(self.exp_variance_draw.count() == self.exp_variance_grad.count());
             if (self.settings.grad_init) | (draw > self.settings.window_switch_freq) {
-                potential.mass_matrix.update_diag(
-                    izip!(
-                        self.exp_variance_draw.current(),
-                        self.exp_variance_grad.current(),
-                    )
-                    .map(|(draw, grad)| {
-                        let val = (draw / grad).sqrt().clamp(LOWER_LIMIT, UPPER_LIMIT);
-                        assert!(val.is_finite());
-                        val
-                    }),
-                );
+                self.update_mass_matrix(potential);
             }
         }
     }
@@ -279,8 +808,358 @@ impl<F: CpuLogpFunc> AdaptStrategy for ExpWindowDiagAdapt<F> {
         } else {
             None
         };
+        let summary = if self.num_adapted < self.num_tune_total {
+            let variance = &potential.mass_matrix.variance;
+            let min = variance.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = variance.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let mean = variance.iter().sum::<f64>() / variance.len() as f64;
+            Some((min, max, mean))
+        } else {
+            None
+        };
+        let zero_gradient_dims = if self.num_adapted + 1 == self.num_tune_total {
+            self.zero_gradient_dims()
+        } else {
+            None
+        };
         ExpWindowDiagAdaptStats {
             mass_matrix_inv: diag,
+            mass_matrix_inv_summary: summary,
+            zero_gradient_dims,
+        }
+    }
+}
+
+impl<F: CpuLogpFunc> ExpWindowDiagAdapt<F> {
+    fn update_mass_matrix(&self, potential: &mut EuclideanPotential<F, DiagMassMatrix>) {
+        let estimator = self.settings.estimator;
+        potential.mass_matrix.update_diag(
+            izip!(
+                self.exp_variance_draw.current(),
+                self.exp_variance_grad.current(),
+            )
+            .map(|(draw, grad)| {
+                let val = match estimator {
+                    DiagMassMatrixEstimator::DrawGradVariance => (draw / grad).sqrt(),
+                    DiagMassMatrixEstimator::DrawVariance => *draw,
+                    DiagMassMatrixEstimator::EmpiricalFisher => grad.recip(),
+                };
+                let val = val.clamp(LOWER_LIMIT, UPPER_LIMIT);
+                assert!(val.is_finite());
+                val
+            }),
+        );
+    }
+
+    /// Robbins-Monro updates of the mass matrix after tuning, with a step size
+    /// that vanishes as the number of draws grows.
+    fn adapt_continuous(
+        &mut self,
+        potential: &mut EuclideanPotential<F, DiagMassMatrix>,
+        draw: u64,
+        collector: &DrawGradCollector,
+        portable: bool,
+    ) {
+        if !collector.is_good {
+            return;
+        }
+        let n = (draw - self.num_tune_total + 1) as f64;
+        let exponent = -self.settings.continuous_decay_exponent;
+        let decay = if portable {
+            portable_powf(n, exponent)
+        } else {
+            n.powf(exponent)
+        };
+        let alpha = self.settings.variance_decay * decay;
+        self.exp_variance_draw.alpha = alpha;
+        self.exp_variance_grad.alpha = alpha;
+        self.exp_variance_draw
+            .add_sample(collector.draw.iter().copied());
+        self.exp_variance_grad
+            .add_sample(collector.grad.iter().copied());
+        self.update_mass_matrix(potential);
+    }
+}
+
+pub(crate) struct DenseWindowAdapt<F> {
+    dim: usize,
+    settings: DenseAdaptSettings,
+    /// The draw at which adaptation stops
+    adapt_end: u64,
+    window_size: u64,
+    /// The draw that ends the current window
+    window_end: u64,
+    count: u64,
+    mean: Box<[f64]>,
+    /// The sum of outer products of deviations from the mean, row-major
+    m2: Box<[f64]>,
+    /// The draws of the current window, only if windows can be extended
+    draws: Vec<Box<[f64]>>,
+    num_updates: u64,
+    num_extensions: u64,
+    /// The shrinkage weight of the last update
+    shrinkage: f64,
+    _phantom: PhantomData<F>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DenseWindowAdaptStats {
+    mass_matrix_inv: Option<Box<[f64]>>,
+    mass_matrix_updates: u64,
+    mass_matrix_window_extensions: u64,
+    mass_matrix_shrinkage: f64,
+}
+
+impl AsSampleStatVec for DenseWindowAdaptStats {
+    fn add_to_vec(&self, vec: &mut Vec<SampleStatItem>) {
+        vec.push((
+            "mass_matrix_inv",
+            SampleStatValue::OptionArray(self.mass_matrix_inv.clone()),
+        ));
+        vec.push((
+            "mass_matrix_updates",
+            SampleStatValue::U64(self.mass_matrix_updates),
+        ));
+        vec.push((
+            "mass_matrix_window_extensions",
+            SampleStatValue::U64(self.mass_matrix_window_extensions),
+        ));
+        vec.push((
+            "mass_matrix_shrinkage",
+            SampleStatValue::F64(self.mass_matrix_shrinkage),
+        ));
+    }
+}
+
+impl<F> DenseWindowAdapt<F> {
+    /// The end of a window of `size` draws that starts at `start`. If the
+    /// next window would not fit before the end of adaptation, this window
+    /// is extended to the end instead.
+    fn window_end(&self, start: u64, size: u64) -> u64 {
+        let end = start + size;
+        if end + 2 * size > self.adapt_end {
+            self.adapt_end
+        } else {
+            end
+        }
+    }
+
+    fn reset_window(&mut self) {
+        self.count = 0;
+        self.mean.fill(0f64);
+        self.m2.fill(0f64);
+        self.draws.clear();
+    }
+
+    fn add_sample(&mut self, draw: &[f64]) {
+        if self.settings.max_window_extensions > 0 {
+            self.draws.push(draw.into());
+        }
+        self.count += 1;
+        let n = self.count as f64;
+        let delta: Vec<f64> = draw
+            .iter()
+            .zip(self.mean.iter())
+            .map(|(x, mean)| x - mean)
+            .collect();
+        self.mean
+            .iter_mut()
+            .zip(delta.iter())
+            .for_each(|(mean, delta)| *mean += delta / n);
+        for (row, &delta_i) in self.m2.chunks_exact_mut(self.dim).zip(delta.iter()) {
+            izip!(row, draw, self.mean.iter())
+                .for_each(|(m2, x, mean)| *m2 += delta_i * (x - mean));
+        }
+    }
+
+    /// The largest scaled Wasserstein distance between the first and second
+    /// half of the current window over all coordinates, see
+    /// [`DenseAdaptSettings`].
+    fn window_distance(&self) -> f64 {
+        let half = self.draws.len() / 2;
+        if half < 4 {
+            return 0f64;
+        }
+        let first = &self.draws[..half];
+        let second = &self.draws[self.draws.len() - half..];
+        let values = |draws: &[Box<[f64]>], i: usize| -> Vec<f64> {
+            draws.iter().map(|draw| draw[i]).collect()
+        };
+        (0..self.dim)
+            .map(|i| {
+                let (mut first, mut second) = (values(first, i), values(second, i));
+                let n = half as f64;
+                let mean = second.iter().sum::<f64>() / n;
+                let var = second.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1f64);
+                if !(var.is_finite() & (var > 0f64)) {
+                    return 0f64;
+                }
+                // Correlated draws vary less between the halves than `n`
+                // independent draws would suggest.
+                // The window has at least four draws per half
+                let ess = ess(&[&second]).unwrap_or(n).clamp(1f64, n);
+                first.sort_by(f64::total_cmp);
+                second.sort_by(f64::total_cmp);
+                let distance = first
+                    .iter()
+                    .zip(second.iter())
+                    .map(|(a, b)| (a - b).abs())
+                    .sum::<f64>()
+                    / n;
+                distance / var.sqrt() * (ess / 2f64).sqrt()
+            })
+            .fold(0f64, f64::max)
+    }
+
+    /// Drop the first half of the current window and extend it if the
+    /// halves are too different. `draw` is the last draw of the window.
+    fn extend_window(&mut self, draw: u64) -> bool {
+        let half = self.draws.len() / 2;
+        let end = draw + 1 + half as u64;
+        if (self.num_extensions >= self.settings.max_window_extensions)
+            | (half < 4)
+            | (end > self.adapt_end)
+            || (self.window_distance() <= self.settings.max_window_distance)
+        {
+            return false;
+        }
+        let kept = self.draws.split_off(self.draws.len() - half);
+        self.reset_window();
+        kept.iter().for_each(|draw| self.add_sample(draw));
+        self.window_end = end;
+        self.num_extensions += 1;
+        true
+    }
+
+    /// The Ledoit-Wolf weight for shrinking the sample covariance `cov`
+    /// towards its diagonal.
+    fn ledoit_wolf_weight(&self, cov: &[f64]) -> f64 {
+        let n = self.count as f64;
+        let mut var_sum = 0f64;
+        let mut sq_sum = 0f64;
+        for i in 0..self.dim {
+            for j in (0..self.dim).filter(|&j| j != i) {
+                let cov_ij = cov[i * self.dim + j];
+                let var_ij = cov[i * self.dim + i] * cov[j * self.dim + j];
+                var_sum += (cov_ij * cov_ij + var_ij) / (n - 1f64);
+                sq_sum += cov_ij * cov_ij;
+            }
+        }
+        if sq_sum > 0f64 {
+            (var_sum / sq_sum).min(1f64)
+        } else {
+            1f64
+        }
+    }
+
+    /// The covariance of the current window, shrunk towards its diagonal
+    /// with the weight that is also returned, and then towards `1e-3` times
+    /// the identity like in Stan.
+    fn regularized_covariance(&self) -> (Vec<f64>, f64) {
+        let n = self.count as f64;
+        let mut cov: Vec<f64> = self.m2.iter().map(|m2| m2 / (n - 1f64)).collect();
+        let diag_weight = match self.settings.shrinkage {
+            DenseShrinkage::Fixed(weight) => weight,
+            DenseShrinkage::LedoitWolf => self.ledoit_wolf_weight(&cov),
+        };
+        let weight = n / (n + 5f64);
+        let shrinkage = 1e-3 * 5f64 / (n + 5f64);
+        for (idx, val) in cov.iter_mut().enumerate() {
+            let is_diag = idx % (self.dim + 1) == 0;
+            if !is_diag {
+                *val *= 1f64 - diag_weight;
+            }
+            *val *= weight;
+            if is_diag {
+                *val += shrinkage;
+            }
+        }
+        (cov, diag_weight)
+    }
+}
+
+impl<F: CpuLogpFunc> AdaptStrategy for DenseWindowAdapt<F> {
+    type Potential = EuclideanPotential<F, DenseMassMatrix>;
+    type Collector = DrawGradCollector;
+    type Stats = DenseWindowAdaptStats;
+    type Options = DenseAdaptSettings;
+
+    fn new(options: Self::Options, num_tune: u64, dim: usize) -> Self {
+        let mut strategy = Self {
+            dim,
+            settings: options,
+            adapt_end: num_tune.saturating_sub(options.final_window),
+            window_size: options.base_window.max(1),
+            window_end: 0,
+            count: 0,
+            mean: vec![0f64; dim].into(),
+            m2: vec![0f64; dim * dim].into(),
+            draws: vec![],
+            num_updates: 0,
+            num_extensions: 0,
+            shrinkage: 0f64,
+            _phantom: PhantomData,
+        };
+        strategy.window_end = strategy.window_end(options.early_window, strategy.window_size);
+        strategy
+    }
+
+    fn init(
+        &mut self,
+        _options: &mut NutsOptions,
+        potential: &mut Self::Potential,
+        _state: &<Self::Potential as Hamiltonian>::State,
+    ) {
+        self.reset_window();
+        potential.mass_matrix.set_variance(&vec![1f64; self.dim]);
+    }
+
+    fn adapt(
+        &mut self,
+        _options: &mut NutsOptions,
+        potential: &mut Self::Potential,
+        draw: u64,
+        collector: &Self::Collector,
+    ) {
+        if (draw < self.settings.early_window) | (draw >= self.adapt_end) {
+            return;
+        }
+        if collector.is_good {
+            self.add_sample(&collector.draw);
+        }
+        if (draw + 1 < self.window_end) || self.extend_window(draw) {
+            return;
+        }
+        if self.count > 1 {
+            let (cov, shrinkage) = self.regularized_covariance();
+            if potential.mass_matrix.update(&cov) {
+                self.num_updates += 1;
+                self.shrinkage = shrinkage;
+            }
+        }
+        self.reset_window();
+        self.window_size *= 2;
+        self.window_end = self.window_end(self.window_end, self.window_size);
+    }
+
+    fn new_collector(&self) -> Self::Collector {
+        DrawGradCollector::new(self.dim)
+    }
+
+    fn current_stats(
+        &self,
+        _options: &NutsOptions,
+        potential: &Self::Potential,
+        _collector: &Self::Collector,
+    ) -> Self::Stats {
+        DenseWindowAdaptStats {
+            mass_matrix_inv: self
+                .settings
+                .store_mass_matrix
+                .then(|| potential.mass_matrix.inv_mass().into()),
+            mass_matrix_updates: self.num_updates,
+            mass_matrix_window_extensions: self.num_extensions,
+            mass_matrix_shrinkage: self.shrinkage,
         }
     }
 }
@@ -310,6 +1189,11 @@ impl<D1: AsSampleStatVec, D2: AsSampleStatVec> AsSampleStatVec for CombinedStats
         self.stats1.add_to_vec(vec);
         self.stats2.add_to_vec(vec);
     }
+
+    fn add_to_schema(&self, schema: &mut Vec<StatField>) {
+        self.stats1.add_to_schema(schema);
+        self.stats2.add_to_schema(schema);
+    }
 }
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -406,15 +1290,25 @@ where
             .register_leapfrog(start, end, divergence_info);
     }
 
-    fn register_draw(&mut self, state: &Self::State, info: &crate::nuts::SampleInfo) {
-        self.collector1.register_draw(state, info);
-        self.collector2.register_draw(state, info);
+    fn register_draw(
+        &mut self,
+        state: &Self::State,
+        info: &crate::nuts::SampleInfo,
+        potential_stats: &dyn AsSampleStatVec,
+    ) {
+        self.collector1.register_draw(state, info, potential_stats);
+        self.collector2.register_draw(state, info, potential_stats);
     }
 
     fn register_init(&mut self, state: &Self::State, options: &crate::nuts::NutsOptions) {
         self.collector1.register_init(state, options);
         self.collector2.register_init(state, options);
     }
+
+    fn register_doubling(&mut self, depth: u64) {
+        self.collector1.register_doubling(depth);
+        self.collector2.register_doubling(depth);
+    }
 }
 
 #[cfg(test)]
@@ -467,7 +1361,7 @@ pub mod test_logps {
 mod test {
     use super::test_logps::NormalLogp;
     use super::*;
-    use crate::nuts::{AdaptStrategy, Chain, NutsChain, NutsOptions};
+    use crate::nuts::{AdaptStrategy, Chain, NutsChain, NutsOptions, RngStreams, SampleStats};
 
     #[test]
     fn instanciate_adaptive_sampler() {
@@ -488,18 +1382,584 @@ mod test {
         let options = NutsOptions {
             maxdepth: 10u64,
             store_gradient: true,
+            check_invariants: true,
+            num_trajectories: 1,
+            step_size_jitter: 0f64,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
+            termination: None,
+            merge_audit: None,
         };
 
-        let rng = {
-            use rand::SeedableRng;
-            rand::rngs::StdRng::seed_from_u64(42)
-        };
+        let rng = RngStreams::<rand::rngs::StdRng>::seed_from_u64(42);
         let chain = 0u64;
 
-        let mut sampler = NutsChain::new(potential, strategy, options, rng, chain);
+        let mut sampler = NutsChain::new(potential, strategy, options, rng, chain, 42);
         sampler.set_position(&vec![1.5f64; ndim]).unwrap();
         for _ in 0..200 {
             sampler.draw().unwrap();
         }
     }
+
+    #[test]
+    fn continuous_adaptation() {
+        let ndim = 10;
+        let func = NormalLogp::new(ndim, 3.);
+        let num_tune = 100;
+        let settings = DiagAdaptExpSettings {
+            continuous_adaptation: true,
+            store_mass_matrix: true,
+            ..Default::default()
+        };
+        let step_size_adapt =
+            DualAverageStrategy::new(DualAverageSettings::default(), num_tune, func.dim());
+        let mass_matrix_adapt = ExpWindowDiagAdapt::new(settings, num_tune, func.dim());
+        let strategy = CombinedStrategy::new(step_size_adapt, mass_matrix_adapt);
+
+        let potential = EuclideanPotential::new(func, DiagMassMatrix::new(ndim), 1000f64, 0.1);
+        let options = NutsOptions {
+            maxdepth: 10u64,
+            store_gradient: false,
+            check_invariants: false,
+            num_trajectories: 1,
+            step_size_jitter: 0f64,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
+            termination: None,
+            merge_audit: None,
+        };
+        let rng = RngStreams::<rand::rngs::StdRng>::seed_from_u64(42);
+
+        let mass_matrix = |stats: &dyn SampleStats| match stats
+            .to_vec()
+            .into_iter()
+            .find(|(key, _)| *key == "mass_matrix_inv")
+        {
+            Some((_, SampleStatValue::OptionArray(Some(val)))) => val,
+            _ => panic!("Mass matrix not stored"),
+        };
+
+        let mut sampler = NutsChain::new(potential, strategy, options, rng, 0, 42);
+        sampler.set_position(&vec![1.5f64; ndim]).unwrap();
+        let mut after_tune = None;
+        for _ in 0..num_tune + 2 {
+            let (_, stats) = sampler.draw().unwrap();
+            after_tune = Some(mass_matrix(&stats));
+        }
+        let after_tune = after_tune.unwrap();
+        let mut after_sampling = None;
+        for _ in 0..100 {
+            let (_, stats) = sampler.draw().unwrap();
+            after_sampling = Some(mass_matrix(&stats));
+        }
+        let after_sampling = after_sampling.unwrap();
+        assert!(after_sampling.iter().all(|val| val.is_finite()));
+        assert!(after_tune
+            .iter()
+            .zip(after_sampling.iter())
+            .any(|(a, b)| a != b));
+    }
+
+    #[test]
+    fn exploration_phase() {
+        let ndim = 10;
+        let num_tune = 100;
+        let settings = DualAverageSettings {
+            num_exploration: 10,
+            ..Default::default()
+        };
+        let strategy = CombinedStrategy::new(
+            DualAverageStrategy::new(settings, num_tune, ndim),
+            ExpWindowDiagAdapt::new(DiagAdaptExpSettings::default(), num_tune, ndim),
+        );
+        let potential = EuclideanPotential::new(
+            NormalLogp::new(ndim, 3.),
+            DiagMassMatrix::new(ndim),
+            1000f64,
+            0.1,
+        );
+        let options = NutsOptions {
+            maxdepth: 10u64,
+            store_gradient: false,
+            check_invariants: false,
+            num_trajectories: 1,
+            step_size_jitter: 0f64,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
+            termination: None,
+            merge_audit: None,
+        };
+        let rng = RngStreams::<rand::rngs::StdRng>::seed_from_u64(42);
+        let mut sampler = NutsChain::new(potential, strategy, options, rng, 0, 42);
+        sampler.set_position(&[-20f64; 10]).unwrap();
+
+        let step_size = |stats: &dyn SampleStats| match stats
+            .to_vec()
+            .into_iter()
+            .find(|(key, _)| *key == "step_size")
+        {
+            Some((_, SampleStatValue::F64(val))) => val,
+            _ => panic!("Missing step size"),
+        };
+        let initial_step = settings.params.initial_step;
+        let mut step_sizes = vec![];
+        for _ in 0..settings.num_exploration {
+            let (_, stats) = sampler.draw().unwrap();
+            step_sizes.push(step_size(&stats));
+        }
+        let scale = initial_step * settings.exploration_step_scale;
+        assert!(step_sizes
+            .iter()
+            .all(|&val| (scale / 1f64.exp() <= val) & (val <= scale * 1f64.exp())));
+        assert!(step_sizes.iter().any(|&val| val != step_sizes[0]));
+
+        // Dual averaging starts at the initial step size after exploration
+        let (_, stats) = sampler.draw().unwrap();
+        assert!((step_size(&stats) - initial_step).abs() < 1e-12);
+        for _ in 0..200 {
+            let (draw, _) = sampler.draw().unwrap();
+            assert!(draw.iter().all(|val| val.is_finite()));
+        }
+    }
+
+    #[test]
+    fn warmup_stats() {
+        let logp = NormalLogp::new(10, 3.);
+        let num_tune = 50;
+        let strategy = CombinedStrategy::new(
+            DualAverageStrategy::new(DualAverageSettings::default(), num_tune, 10),
+            ExpWindowDiagAdapt::new(DiagAdaptExpSettings::default(), num_tune, 10),
+        );
+        let potential = EuclideanPotential::new(logp, DiagMassMatrix::new(10), 1000f64, 0.1);
+        let options = NutsOptions {
+            maxdepth: 10u64,
+            store_gradient: false,
+            check_invariants: false,
+            num_trajectories: 1,
+            step_size_jitter: 0f64,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
+            termination: None,
+            merge_audit: None,
+        };
+        let rng = RngStreams::<rand::rngs::StdRng>::seed_from_u64(42);
+        let mut sampler = NutsChain::new(potential, strategy, options, rng, 0, 42);
+        sampler.set_position(&[1.5f64; 10]).unwrap();
+
+        for draw in 0..num_tune + 10 {
+            let (_, stats) = sampler.draw().unwrap();
+            let stats = stats.to_vec();
+            let get = |name: &str| {
+                stats
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, val)| val.clone())
+                    .unwrap()
+            };
+            let tuning = draw < num_tune;
+            assert!(matches!(get("tuning"), SampleStatValue::Bool(val) if val == tuning));
+            match (get("mass_matrix_inv_min"), get("mass_matrix_inv_max")) {
+                (SampleStatValue::OptionF64(Some(min)), SampleStatValue::OptionF64(Some(max))) => {
+                    assert!(tuning);
+                    assert!((0f64 < min) & (min <= max));
+                }
+                (SampleStatValue::OptionF64(None), SampleStatValue::OptionF64(None)) => {
+                    assert!(!tuning)
+                }
+                _ => panic!("Unexpected mass matrix summary"),
+            }
+            assert!(matches!(
+                get("depth_accept_mean"),
+                SampleStatValue::OptionArray(None)
+            ));
+        }
+    }
+
+    #[test]
+    fn gradient_initialization() {
+        use crate::test_logps::NormalLogpError;
+
+        /// Independent normals with standard deviations 0.01, 1 and 100
+        struct Scaled {}
+
+        impl CpuLogpFunc for Scaled {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                3
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                let mut logp = 0f64;
+                for ((x, g), sigma) in position.iter().zip(grad.iter_mut()).zip([0.01, 1., 100.]) {
+                    logp -= x * x / (2. * sigma * sigma);
+                    *g = -x / (sigma * sigma);
+                }
+                Ok(logp)
+            }
+        }
+
+        let initial_metric = |grad_init, position: &[f64]| {
+            let settings = crate::SamplerArgs {
+                mass_matrix_adapt: DiagAdaptExpSettings {
+                    grad_init,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut sampler = crate::new_sampler(Scaled {}, settings, 0, 42);
+            sampler.set_position(position).unwrap();
+            sampler.metric().to_vec()
+        };
+
+        assert_eq!(initial_metric(false, &[1., 1., 1.]), vec![1.; 3]);
+
+        // The gradient is [-1e4, -1, -1e-4]
+        let metric = initial_metric(true, &[1., 1., 1.]);
+        assert!((metric[0] * 1e4 - 1.).abs() < 1e-6, "{:?}", metric);
+        assert!((metric[1] - 0.5f64.sqrt()).abs() < 1e-12, "{:?}", metric);
+        assert!((metric[2] - 1.).abs() < 1e-6, "{:?}", metric);
+
+        // A zero gradient falls back to the unit metric
+        assert_eq!(initial_metric(true, &[0., 0., 0.]), vec![1.; 3]);
+    }
+
+    #[test]
+    fn zero_gradient_dims() {
+        use crate::test_logps::NormalLogpError;
+
+        /// A normal distribution with standard deviation `1 / scale`, where
+        /// the last dimension is missing from the logp
+        struct MissingParam {
+            scale: f64,
+        }
+
+        impl CpuLogpFunc for MissingParam {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                4
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                let scale_sq = self.scale * self.scale;
+                grad.iter_mut()
+                    .zip(position)
+                    .for_each(|(g, x)| *g = -x * scale_sq);
+                grad[3] = 0.;
+                Ok(-position[..3].iter().map(|x| x * x).sum::<f64>() * scale_sq / 2.)
+            }
+        }
+
+        let num_tune = 50;
+        let mut settings = crate::SamplerArgs {
+            num_tune,
+            ..Default::default()
+        };
+        // The threshold is relative to the gradient, so a tiny gradient of
+        // a model with a large scale is not mistaken for a zero gradient.
+        for scale in [1., 1e-20] {
+            let mut sampler = crate::new_sampler(MissingParam { scale }, settings, 0, 42);
+            sampler.set_position(&[0.5 / scale; 4]).unwrap();
+            for draw in 0..num_tune + 10 {
+                let (_, stats) = sampler.draw().unwrap();
+                let dims = stats
+                    .to_vec()
+                    .into_iter()
+                    .find(|(key, _)| *key == "zero_gradient_dims")
+                    .map(|(_, val)| val);
+                match dims {
+                    Some(SampleStatValue::OptionArray(Some(dims))) => {
+                        assert_eq!(draw, num_tune - 1);
+                        assert_eq!(&dims[..], &[3f64]);
+                    }
+                    Some(SampleStatValue::OptionArray(None)) => assert!(draw != num_tune - 1),
+                    _ => panic!("Missing zero_gradient_dims stat"),
+                }
+            }
+        }
+
+        let no_report = |stats: Vec<SampleStatItem>| {
+            stats.iter().any(|(key, val)| {
+                (*key == "zero_gradient_dims") & matches!(val, SampleStatValue::OptionArray(None))
+            })
+        };
+
+        // Nothing is reported for a model that uses all parameters
+        let mut sampler = crate::new_sampler(NormalLogp::new(4, 3.), settings, 0, 42);
+        sampler.set_position(&[0.5f64; 4]).unwrap();
+        for _ in 0..num_tune {
+            assert!(no_report(sampler.draw().unwrap().1.to_vec()));
+        }
+
+        // or if the detection is turned off
+        settings.mass_matrix_adapt.detect_zero_gradients = false;
+        let mut sampler = crate::new_sampler(MissingParam { scale: 1. }, settings, 0, 43);
+        sampler.set_position(&[0.5f64; 4]).unwrap();
+        for _ in 0..num_tune {
+            assert!(no_report(sampler.draw().unwrap().1.to_vec()));
+        }
+    }
+
+    #[test]
+    fn accept_window_extension() {
+        let run = |max_accept_se: f64| {
+            let settings = crate::SamplerArgs {
+                num_tune: 50,
+                step_size_adapt: DualAverageSettings {
+                    accept_window: 10,
+                    max_accept_se,
+                    max_window_extensions: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut sampler = crate::new_sampler(NormalLogp::new(10, 3.), settings, 0, 42);
+            sampler.set_position(&[1.5f64; 10]).unwrap();
+            (0..100)
+                .map(|_| {
+                    let (_, stats) = sampler.draw().unwrap();
+                    let stats = stats.to_vec();
+                    let get = |name: &str| {
+                        stats
+                            .iter()
+                            .find(|(key, _)| *key == name)
+                            .map(|(_, val)| val.clone())
+                            .unwrap()
+                    };
+                    match (get("tuning"), get("window_extensions")) {
+                        (SampleStatValue::Bool(tuning), SampleStatValue::U64(extensions)) => {
+                            (tuning, extensions)
+                        }
+                        _ => panic!("Unexpected stat types"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Every window is too noisy for this threshold. The last 20 tuning
+        // draws are reserved for the two extensions.
+        let stats = run(0f64);
+        assert_eq!(stats.iter().filter(|(tuning, _)| *tuning).count(), 50);
+        assert!(stats[..50].iter().all(|(tuning, _)| *tuning));
+        assert_eq!(stats[29].1, 0);
+        assert_eq!(stats[30].1, 1);
+        assert_eq!(stats[40].1, 2);
+        assert_eq!(stats[99].1, 2);
+
+        let stats = run(f64::INFINITY);
+        assert_eq!(stats.iter().filter(|(tuning, _)| *tuning).count(), 50);
+        assert!(stats.iter().all(|(_, extensions)| *extensions == 0));
+    }
+
+    #[test]
+    fn depth_accept_stats() {
+        let settings = crate::SamplerArgs {
+            num_tune: 100,
+            step_size_adapt: DualAverageSettings {
+                store_depth_accept: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut sampler = crate::new_sampler(NormalLogp::new(10, 3.), settings, 0, 42);
+        sampler.set_position(&[1.5f64; 10]).unwrap();
+
+        for _ in 0..150 {
+            let (_, stats) = sampler.draw().unwrap();
+            let depth = stats.depth() as usize;
+            let stats = stats.to_vec();
+            let get = |name: &str| match stats.iter().find(|(key, _)| *key == name) {
+                Some((_, SampleStatValue::OptionArray(Some(vals)))) => vals.clone(),
+                _ => panic!("Missing stat {}", name),
+            };
+            let (min, max, mean) = (
+                get("depth_accept_min"),
+                get("depth_accept_max"),
+                get("depth_accept_mean"),
+            );
+            // The last doubling is included even if it was rejected
+            assert!((mean.len() == depth) | (mean.len() == depth + 1));
+            for i in 0..mean.len() {
+                assert!((0f64 <= min[i]) & (min[i] <= mean[i]));
+                assert!((mean[i] <= max[i]) & (max[i] <= 1f64));
+            }
+        }
+    }
+
+    #[test]
+    fn adapt_pool() {
+        let estimate = |count: u64, mean: f64, var: f64| VarianceEstimate {
+            count,
+            draw_mean: vec![mean; 2].into(),
+            draw_var: vec![var; 2].into(),
+            grad_mean: vec![0.; 2].into(),
+            grad_var: vec![1.; 2].into(),
+        };
+        let pool = AdaptPool::new(3);
+        let left = pool.member();
+        let handles: Vec<_> = (0..2)
+            .map(|chain| {
+                let member = pool.member();
+                std::thread::spawn(move || {
+                    let val = (chain + 1) as f64;
+                    let first = member.exchange(estimate(chain + 1, 3. * chain as f64, val));
+                    // Estimates without draws do not count
+                    let count = if chain == 0 { 0 } else { 2 };
+                    let second = member.exchange(estimate(count, 0., val));
+                    (first, second)
+                })
+            })
+            .collect();
+        // A chain that leaves before the first round is not waited for
+        drop(left);
+        let results: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        for (first, second) in results {
+            // The means are 0 and 3 with weights 1 and 2, so the pooled mean
+            // is 2 and the variance (1 * (1 + 4) + 2 * (2 + 1)) / 3.
+            let first = first.unwrap();
+            assert_eq!(first.count, 3);
+            assert!((first.draw_mean[0] - 2.).abs() < 1e-12);
+            assert!((first.draw_var[0] - 11. / 3.).abs() < 1e-12);
+            assert_eq!(&first.grad_var[..], &[1., 1.]);
+            let second = second.unwrap();
+            assert_eq!(&second.draw_var[..], &[2., 2.]);
+        }
+
+        let merged = StepSizeEstimate::merge(&[
+            StepSizeEstimate {
+                count: 1,
+                state: [0., -1., -2.],
+            },
+            StepSizeEstimate {
+                count: 3,
+                state: [0.5, 1., 2.],
+            },
+        ])
+        .unwrap();
+        assert_eq!(merged.state, [0.375, 0.5, 1.]);
+    }
+
+    #[test]
+    fn dense_shrinkage() {
+        use rand::{Rng, SeedableRng};
+        use rand_distr::StandardNormal;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let settings = |shrinkage| DenseAdaptSettings {
+            shrinkage,
+            ..Default::default()
+        };
+        let sample = |adapt: &mut DenseWindowAdapt<NormalLogp>, rng: &mut _, n, corr: f64| {
+            adapt.reset_window();
+            for _ in 0..n {
+                let common: f64 = Rng::sample(rng, StandardNormal);
+                let draw: Vec<f64> = (0..4)
+                    .map(|_| {
+                        let own: f64 = Rng::sample(rng, StandardNormal);
+                        corr * common + (1. - corr * corr).sqrt() * own
+                    })
+                    .collect();
+                adapt.add_sample(&draw);
+            }
+            adapt.regularized_covariance()
+        };
+
+        let mut adapt = DenseWindowAdapt::new(settings(DenseShrinkage::LedoitWolf), 1000, 4);
+        let (_, independent) = sample(&mut adapt, &mut rng, 10, 0.);
+        let (_, correlated) = sample(&mut adapt, &mut rng, 200, 0.9);
+        assert!(independent > 0.5, "{}", independent);
+        assert!(correlated < 0.05, "{}", correlated);
+
+        let mut adapt = DenseWindowAdapt::new(settings(DenseShrinkage::Fixed(1.)), 1000, 4);
+        let (cov, weight) = sample(&mut adapt, &mut rng, 3, 0.9);
+        assert_eq!(weight, 1.);
+        for (idx, val) in cov.iter().enumerate() {
+            assert_eq!(idx % 5 == 0, *val != 0., "{:?}", cov);
+        }
+        assert!(DenseMassMatrix::new(4).update(&cov));
+    }
+
+    #[test]
+    fn dense_window_extension() {
+        use rand::{Rng, SeedableRng};
+        use rand_distr::StandardNormal;
+
+        let settings = DenseAdaptSettings {
+            max_window_extensions: 2,
+            ..Default::default()
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut adapt = DenseWindowAdapt::<NormalLogp>::new(settings, 1000, 2);
+        for _ in 0..100 {
+            let draw: [f64; 2] = [rng.sample(StandardNormal), rng.sample(StandardNormal)];
+            adapt.add_sample(&draw);
+        }
+        let stationary = adapt.window_distance();
+        assert!(stationary < settings.max_window_distance, "{}", stationary);
+
+        // Stationary, but strongly autocorrelated draws like those of a
+        // chain with a poor mass matrix
+        adapt.reset_window();
+        let rho: f64 = 0.95;
+        let mut state = [0f64; 2];
+        for _ in 0..200 {
+            for val in state.iter_mut() {
+                let noise: f64 = rng.sample(StandardNormal);
+                *val = rho * *val + (1. - rho * rho).sqrt() * noise;
+            }
+            adapt.add_sample(&state);
+        }
+        let correlated = adapt.window_distance();
+        assert!(correlated < settings.max_window_distance, "{}", correlated);
+        assert!(!adapt.extend_window(199));
+
+        // The second coordinate drifts from 5 to 0
+        adapt.reset_window();
+        for i in 0..100 {
+            let noise: f64 = rng.sample(StandardNormal);
+            adapt.add_sample(&[noise, 5. - i as f64 / 20. + noise]);
+        }
+        let drifting = adapt.window_distance();
+        assert!(drifting > settings.max_window_distance, "{}", drifting);
+        assert!(adapt.extend_window(99));
+        assert_eq!(adapt.count, 50);
+        assert_eq!(adapt.window_end, 150);
+
+        // A far away initial point drifts into the first window
+        let settings = crate::SamplerArgs {
+            num_tune: 500,
+            dense_mass_matrix_adapt: DenseAdaptSettings {
+                early_window: 0,
+                max_window_extensions: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut sampler =
+            crate::new_dense_sampler(NormalLogp::new(3, 0.), settings, 0, 42).unwrap();
+        sampler.set_position(&[300., -300., 300.]).unwrap();
+        let extensions = (0..500)
+            .map(|_| {
+                let (_, stats) = sampler.draw().unwrap();
+                match stats
+                    .to_vec()
+                    .into_iter()
+                    .find(|(name, _)| *name == "mass_matrix_window_extensions")
+                {
+                    Some((_, SampleStatValue::U64(val))) => val,
+                    _ => panic!("Missing window extensions"),
+                }
+            })
+            .last()
+            .unwrap();
+        assert!(extensions > 0);
+    }
 }
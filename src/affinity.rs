@@ -0,0 +1,85 @@
+//! Pinning of chain threads to cores.
+//!
+//! Memory is usually allocated on the NUMA node of the core that first
+//! writes to it, so a chain that is created after its thread was pinned
+//! keeps its state pool and logp buffers local to that node.
+
+/// Restores the previous affinity of the current thread when dropped.
+pub(crate) struct PinGuard {
+    #[cfg(all(feature = "affinity", target_os = "linux"))]
+    previous: libc::cpu_set_t,
+}
+
+impl PinGuard {
+    /// Keep the current thread pinned until it exits.
+    pub(crate) fn keep(self) {
+        let _ = std::mem::ManuallyDrop::new(self);
+    }
+}
+
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &self.previous);
+        }
+    }
+}
+
+/// Pin the current thread to one of the cores it is allowed to run on.
+///
+/// Consecutive indices are assigned to consecutive cores. Returns `None` if
+/// pinning is not supported or failed.
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+pub(crate) fn pin_current_thread(index: usize) -> Option<PinGuard> {
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    unsafe {
+        let mut previous: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, size, &mut previous) != 0 {
+            return None;
+        }
+        let cpus: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &previous))
+            .collect();
+        if cpus.is_empty() {
+            return None;
+        }
+        let mut target: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpus[index % cpus.len()], &mut target);
+        if libc::sched_setaffinity(0, size, &target) != 0 {
+            return None;
+        }
+        Some(PinGuard { previous })
+    }
+}
+
+#[cfg(not(all(feature = "affinity", target_os = "linux")))]
+pub(crate) fn pin_current_thread(_index: usize) -> Option<PinGuard> {
+    None
+}
+
+#[cfg(all(test, feature = "affinity", target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn allowed_cpus() -> usize {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set),
+                0
+            );
+            libc::CPU_COUNT(&set) as usize
+        }
+    }
+
+    #[test]
+    fn pin_and_restore() {
+        let before = allowed_cpus();
+        {
+            let _guard = pin_current_thread(3).unwrap();
+            assert_eq!(allowed_cpus(), 1);
+        }
+        assert_eq!(allowed_cpus(), before);
+    }
+}
@@ -0,0 +1,262 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    cpu_potential::CpuLogpFunc,
+    cpu_sampler::{sample_sequentially, SamplerArgs},
+    diagnostics::ess,
+    nuts::{NutsError, SampleStats},
+};
+
+/// Cost and efficiency measurements from a short pilot run
+#[derive(Debug, Clone, Copy)]
+pub struct PilotRun {
+    /// Wall time per gradient evaluation
+    pub time_per_gradient: Duration,
+    /// The mean number of gradient evaluations per draw after tuning
+    pub gradients_per_draw: f64,
+    /// The mean number of gradient evaluations per draw during tuning
+    pub gradients_per_tune_draw: f64,
+    /// The smallest effective sample size over all parameters, divided by the
+    /// number of draws after tuning.
+    pub ess_per_draw: f64,
+}
+
+impl PilotRun {
+    /// Run a single chain with `settings.num_tune` tuning steps and `draws`
+    /// draws and measure its cost and efficiency.
+    ///
+    /// The effective sample size needs at least four draws, so fewer
+    /// `draws` are an error.
+    pub fn measure<F: CpuLogpFunc>(
+        logp: F,
+        settings: SamplerArgs,
+        start: &[f64],
+        draws: u64,
+        seed: u64,
+    ) -> Result<PilotRun, NutsError> {
+        if draws < 4 {
+            return Err(NutsError::InvalidSettings(format!(
+                "A pilot run needs at least four draws, but got {}",
+                draws
+            )));
+        }
+        let dim = logp.dim();
+        let num_tune = settings.num_tune;
+        let start_time = Instant::now();
+        let chain = sample_sequentially(logp, settings, start, num_tune + draws, 0, seed)?;
+
+        let mut tune_gradients = 0u64;
+        let mut gradients = 0u64;
+        let mut trace = vec![vec![]; dim];
+        for (i, draw) in chain.enumerate() {
+            let (position, stats) = draw?;
+            if (i as u64) < num_tune {
+                tune_gradients += stats.n_leapfrog();
+            } else {
+                gradients += stats.n_leapfrog();
+                trace
+                    .iter_mut()
+                    .zip(position.iter())
+                    .for_each(|(param, &val)| param.push(val));
+            }
+        }
+        let elapsed = start_time.elapsed();
+
+        let min_ess = trace
+            .iter()
+            .filter_map(|param| ess(&[param]).ok())
+            .filter(|val| !val.is_nan())
+            .fold(f64::INFINITY, f64::min);
+        let total_gradients = (tune_gradients + gradients).max(1);
+        Ok(PilotRun {
+            time_per_gradient: elapsed.div_f64(total_gradients as f64),
+            gradients_per_draw: gradients as f64 / draws as f64,
+            gradients_per_tune_draw: tune_gradients as f64 / num_tune.max(1) as f64,
+            ess_per_draw: (min_ess / draws as f64).min(1f64),
+        })
+    }
+}
+
+/// Resources and goals for a sampling run
+#[derive(Debug, Clone, Copy)]
+pub struct RunBudget {
+    /// The effective sample size that all parameters should reach
+    pub target_ess: f64,
+    /// The number of cores that can be used for chains
+    pub num_cores: usize,
+    /// The maximum wall time for the run
+    pub time_budget: Duration,
+    /// The minimum number of chains, so that convergence can be checked
+    pub min_chains: u64,
+}
+
+impl Default for RunBudget {
+    fn default() -> Self {
+        Self {
+            target_ess: 1000f64,
+            num_cores: std::thread::available_parallelism()
+                .map(|val| val.get())
+                .unwrap_or(1),
+            time_budget: Duration::from_secs(3600),
+            min_chains: 4,
+        }
+    }
+}
+
+/// A recommended number of chains and draws, see [`recommend_run`].
+#[derive(Debug, Clone, Copy)]
+pub struct RunRecommendation {
+    pub num_chains: u64,
+    /// The number of draws per chain after tuning
+    pub num_draws: u64,
+    pub num_tune: u64,
+    /// The number of chains that run at the same time
+    pub chain_threads: usize,
+    /// The predicted wall time of the run, at most the time budget
+    pub expected_time: Duration,
+    /// Whether the draws reach the target effective sample size, or had to
+    /// be limited to fit into the time budget
+    pub reaches_target: bool,
+}
+
+impl RunRecommendation {
+    /// Set the number of tuning steps and chain threads in the sampler
+    /// settings. `num_chains` and `num_draws` are passed to
+    /// [`sample_parallel`](crate::sample_parallel) directly.
+    pub fn apply(&self, settings: &mut SamplerArgs) {
+        settings.num_tune = self.num_tune;
+        settings.parallelism.chain_threads = Some(self.chain_threads);
+    }
+}
+
+/// Choose the number of chains and draws for a target effective sample size.
+///
+/// One chain is started per core (but at least `budget.min_chains`), and the
+/// number of draws is chosen such that the pooled effective sample size
+/// reaches the target according to the pilot run. If that would take longer
+/// than `budget.time_budget`, the number of draws is reduced until the run
+/// fits, see [`RunRecommendation::reaches_target`]. Returns an error if the
+/// target is not positive, or if the budget does not fit the tuning steps
+/// and 100 draws per chain.
+pub fn recommend_run(
+    pilot: &PilotRun,
+    budget: &RunBudget,
+    num_tune: u64,
+) -> Result<RunRecommendation, NutsError> {
+    const MIN_DRAWS: u64 = 100;
+
+    if !(budget.target_ess.is_finite() & (budget.target_ess > 0f64)) {
+        return Err(NutsError::InvalidSettings(format!(
+            "The target effective sample size must be positive, but is {}",
+            budget.target_ess
+        )));
+    }
+    let num_cores = budget.num_cores.max(1) as u64;
+    let num_chains = num_cores.max(budget.min_chains).max(1);
+    let ess_per_draw = pilot.ess_per_draw.max(1e-6);
+    let target_draws = (budget.target_ess / (ess_per_draw * num_chains as f64))
+        .ceil()
+        .max(MIN_DRAWS as f64);
+
+    // The chains run in rounds of at most one chain per core
+    let rounds = num_chains.div_ceil(num_cores) as f64;
+    let tune_time = pilot.time_per_gradient.as_secs_f64()
+        * rounds
+        * num_tune as f64
+        * pilot.gradients_per_tune_draw;
+    let draw_time = pilot.time_per_gradient.as_secs_f64() * rounds * pilot.gradients_per_draw;
+    let max_draws = if draw_time > 0f64 {
+        ((budget.time_budget.as_secs_f64() - tune_time) / draw_time).floor()
+    } else {
+        f64::INFINITY
+    };
+    if max_draws.is_nan() | (max_draws < MIN_DRAWS as f64) {
+        return Err(NutsError::InvalidSettings(format!(
+            "The time budget of {:?} is too short for {} tuning steps and {} draws per chain",
+            budget.time_budget, num_tune, MIN_DRAWS
+        )));
+    }
+    let num_draws = target_draws.min(max_draws) as u64;
+
+    Ok(RunRecommendation {
+        num_chains,
+        num_draws,
+        num_tune,
+        chain_threads: num_chains.min(num_cores) as usize,
+        expected_time: Duration::from_secs_f64(tune_time + num_draws as f64 * draw_time),
+        reaches_target: num_draws as f64 >= target_draws,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_sampler::test_logps::NormalLogp;
+
+    #[test]
+    fn recommend_from_pilot() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let pilot =
+            PilotRun::measure(NormalLogp::new(5, 0.), settings, &[0.5; 5], 200, 42).unwrap();
+        assert!(pilot.gradients_per_draw >= 1f64);
+        assert!(pilot.ess_per_draw > 0f64);
+
+        let budget = RunBudget {
+            target_ess: 4000f64,
+            num_cores: 4,
+            ..Default::default()
+        };
+        let recommendation = recommend_run(&pilot, &budget, 500).unwrap();
+        assert_eq!(recommendation.num_chains, 4);
+        assert!(recommendation.reaches_target);
+        let expected_ess =
+            (recommendation.num_chains * recommendation.num_draws) as f64 * pilot.ess_per_draw;
+        assert!(expected_ess >= 4000f64);
+
+        let mut settings = SamplerArgs::default();
+        recommendation.apply(&mut settings);
+        assert_eq!(settings.num_tune, 500);
+        assert_eq!(settings.parallelism.chain_threads, Some(4));
+
+        assert!(matches!(
+            PilotRun::measure(NormalLogp::new(5, 0.), settings, &[0.5; 5], 0, 42),
+            Err(NutsError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn recommend_within_budget() {
+        let pilot = PilotRun {
+            time_per_gradient: Duration::from_millis(1),
+            gradients_per_draw: 10.,
+            gradients_per_tune_draw: 10.,
+            ess_per_draw: 0.5,
+        };
+        let budget = |secs| RunBudget {
+            target_ess: 4000f64,
+            num_cores: 4,
+            time_budget: Duration::from_secs_f64(secs),
+            min_chains: 4,
+        };
+
+        // 500 tuning steps and 2000 draws take 25 seconds per chain
+        let recommendation = recommend_run(&pilot, &budget(30.), 500).unwrap();
+        assert_eq!(recommendation.num_draws, 2000);
+        assert!(recommendation.reaches_target);
+        assert!((recommendation.expected_time.as_secs_f64() - 25.).abs() < 1e-9);
+
+        let recommendation = recommend_run(&pilot, &budget(15.), 500).unwrap();
+        assert_eq!(recommendation.num_draws, 1000);
+        assert!(!recommendation.reaches_target);
+        assert!(recommendation.expected_time <= Duration::from_secs(15));
+
+        // Not even 100 draws fit after tuning
+        assert!(recommend_run(&pilot, &budget(5.5), 500).is_err());
+        let mut invalid = budget(30.);
+        invalid.target_ess = f64::NAN;
+        assert!(recommend_run(&pilot, &invalid, 500).is_err());
+    }
+}
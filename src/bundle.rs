@@ -0,0 +1,327 @@
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::{
+    cpu_potential::CpuLogpFunc,
+    cpu_sampler::{new_sampler, new_step_size_sampler, SamplerArgs},
+    metadata::RunMetadata,
+    nuts::{check_dim, Chain, NutsError},
+};
+
+const HEADER: &str = "nuts-rs-tuned-bundle 2";
+
+/// Errors when a [`TunedBundle`] is created, loaded or used
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Position has {got} entries, but the chain has dimension {expected}")]
+    PositionDimension { expected: usize, got: usize },
+    #[error("Could not read or write the bundle: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid bundle in line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error("Bundle was made with nuts-rs {found}, but this is version {expected}")]
+    VersionMismatch { found: String, expected: String },
+    #[error("Bundle was made for model hash {found}, but the model hash is {expected}")]
+    ModelMismatch { found: u64, expected: u64 },
+    #[error("Could not initialize the sampler: {0}")]
+    Sampler(#[from] NutsError),
+}
+
+/// The result of tuning a chain, which can be stored and reused to start
+/// sampling without warmup
+///
+/// Production services can tune a model offline, [`save`](Self::save) the
+/// bundle, and [`load`](Self::load) it to start sampling right away with
+/// [`new_sampler`](Self::new_sampler). The bundle records the
+/// [`RunMetadata`] of the tuning run, and bundles from a different crate
+/// version or for a different model hash are rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunedBundle {
+    pub metadata: RunMetadata,
+    pub step_size: f64,
+    /// The diagonal of the inverse mass matrix
+    pub metric: Box<[f64]>,
+    /// A point in the typical set, where sampling starts
+    pub position: Box<[f64]>,
+}
+
+impl TunedBundle {
+    /// Store the tuned step size and metric of `chain`, and a `position`
+    /// in the typical set, usually the last draw.
+    ///
+    /// The final step size is only set after the first draw after tuning.
+    pub fn from_chain<C: Chain>(
+        chain: &C,
+        position: &[f64],
+        metadata: RunMetadata,
+    ) -> Result<Self, BundleError> {
+        if chain.dim() != position.len() {
+            return Err(BundleError::PositionDimension {
+                expected: chain.dim(),
+                got: position.len(),
+            });
+        }
+        Ok(TunedBundle {
+            metadata,
+            step_size: chain.step_size(),
+            metric: chain.metric().into(),
+            position: position.into(),
+        })
+    }
+
+    /// Write the bundle in a line based text format. Floats are written
+    /// with enough digits to be read back exactly.
+    pub fn save<W: Write>(&self, mut writer: W) -> Result<(), BundleError> {
+        let join = |values: &[f64]| {
+            values
+                .iter()
+                .map(|val| val.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        writeln!(writer, "{}", HEADER)?;
+        self.metadata.write_lines(&mut writer)?;
+        writeln!(writer, "step_size {}", self.step_size)?;
+        writeln!(writer, "metric {}", join(&self.metric))?;
+        writeln!(writer, "position {}", join(&self.position))?;
+        Ok(())
+    }
+
+    /// Read a bundle that was written with [`save`](Self::save).
+    pub fn load<R: BufRead>(reader: R) -> Result<Self, BundleError> {
+        let mut lines = reader.lines().enumerate();
+        let mut next = |key: &str| -> Result<(usize, String), BundleError> {
+            let (idx, line) = lines.next().ok_or_else(|| BundleError::Parse {
+                line: 0,
+                message: format!("Missing {}", key),
+            })?;
+            let line = line?;
+            let value = line
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix(' ').or(rest.is_empty().then_some("")))
+                .ok_or_else(|| BundleError::Parse {
+                    line: idx + 1,
+                    message: format!("Expected {}", key),
+                })?;
+            Ok((idx + 1, value.to_string()))
+        };
+        fn parse<T: FromStr>(line: usize, value: &str) -> Result<T, BundleError> {
+            value.parse().map_err(|_| BundleError::Parse {
+                line,
+                message: format!("Invalid value {}", value),
+            })
+        }
+        let parse_array = |(line, value): (usize, String)| {
+            value
+                .split_whitespace()
+                .map(|val| parse(line, val))
+                .collect::<Result<Box<[f64]>, _>>()
+        };
+
+        next(HEADER)?;
+        let (_, crate_version) = next("crate_version")?;
+        let (_, git_hash) = next("git_hash")?;
+        let (line, options_hash) = next("options_hash")?;
+        let options_hash = parse(line, &options_hash)?;
+        let (_, model_id) = next("model_id")?;
+        let (line, model_hash) = next("model_hash")?;
+        let model_hash = parse(line, &model_hash)?;
+        let metadata = RunMetadata {
+            crate_version,
+            git_hash,
+            options_hash,
+            model_id,
+            model_hash,
+        };
+        let (line, step_size) = next("step_size")?;
+        let step_size = parse(line, &step_size)?;
+        let metric = parse_array(next("metric")?)?;
+        let (line, position) = next("position")?;
+        let position = parse_array((line, position))?;
+        if metric.len() != position.len() {
+            return Err(BundleError::Parse {
+                line,
+                message: "Metric and position have different lengths".to_string(),
+            });
+        }
+        Ok(TunedBundle {
+            metadata,
+            step_size,
+            metric,
+            position,
+        })
+    }
+
+    /// Create a sampler that starts at the stored position with the stored
+    /// step size and metric, and does not tune.
+    ///
+    /// `settings.num_tune` and the initial step size are overwritten. This
+    /// fails if the bundle was made for a different model hash or crate
+    /// version, or if the model has a different dimension.
+    pub fn new_sampler<F: CpuLogpFunc>(
+        &self,
+        logp: F,
+        mut settings: SamplerArgs,
+        model_hash: u64,
+        chain: u64,
+        seed: u64,
+    ) -> Result<impl Chain, BundleError> {
+        self.check_version()?;
+        if self.metadata.model_hash != model_hash {
+            return Err(BundleError::ModelMismatch {
+                found: self.metadata.model_hash,
+                expected: model_hash,
+            });
+        }
+        check_dim(logp.dim(), self.position.len())?;
+
+        settings.num_tune = 0;
+        settings.step_size_adapt.params.initial_step = self.step_size;
+        let mut sampler = new_sampler(logp, settings, chain, seed);
+        sampler.set_position(&self.position)?;
+        // Mass matrix adaptation sets a unit metric in set_position
+        sampler.set_metric(&self.metric)?;
+        Ok(sampler)
+    }
+
+    /// Create a sampler that starts at the stored position with the stored
+    /// metric, and only tunes the step size for `settings.num_tune` draws.
+    ///
+    /// This is meant for refitting the same model to slightly different
+    /// data, where a full warmup is wasteful but the stored step size might
+    /// not fit anymore, so the model hash is not checked. A few hundred
+    /// tuning draws are usually enough. The stored step size is the initial
+    /// step size. This fails if the bundle was made with a different crate
+    /// version, or if the model has a different dimension.
+    pub fn retune_sampler<F: CpuLogpFunc>(
+        &self,
+        logp: F,
+        mut settings: SamplerArgs,
+        chain: u64,
+        seed: u64,
+    ) -> Result<impl Chain, BundleError> {
+        self.check_version()?;
+        check_dim(logp.dim(), self.position.len())?;
+
+        settings.step_size_adapt.params.initial_step = self.step_size;
+        let mut sampler = new_step_size_sampler(logp, settings, chain, seed);
+        sampler.set_position(&self.position)?;
+        sampler.set_metric(&self.metric)?;
+        Ok(sampler)
+    }
+
+    fn check_version(&self) -> Result<(), BundleError> {
+        let version = env!("CARGO_PKG_VERSION");
+        if self.metadata.crate_version != version {
+            return Err(BundleError::VersionMismatch {
+                found: self.metadata.crate_version.clone(),
+                expected: version.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_logps::NormalLogp, SampleStatValue, SampleStats};
+
+    #[test]
+    fn save_and_load() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(4, 2.), settings, 0, 42);
+        sampler.set_position(&[0.; 4]).unwrap();
+        let mut position = Box::default();
+        for _ in 0..settings.num_tune + 1 {
+            position = sampler.draw().unwrap().0;
+        }
+        let metadata = RunMetadata::new(&settings, "shifted normal", 17);
+        let err = TunedBundle::from_chain(&sampler, &position[..3], metadata.clone());
+        assert!(matches!(
+            err,
+            Err(BundleError::PositionDimension {
+                expected: 4,
+                got: 3
+            })
+        ));
+        let bundle = TunedBundle::from_chain(&sampler, &position, metadata).unwrap();
+        // The mass matrix of a standard normal is close to the identity
+        assert!(bundle.metric.iter().all(|&val| (0.3..3.).contains(&val)));
+
+        let mut buffer = vec![];
+        bundle.save(&mut buffer).unwrap();
+        let loaded = TunedBundle::load(&buffer[..]).unwrap();
+        assert_eq!(loaded, bundle);
+        assert_eq!(loaded.metadata.model_id, "shifted normal");
+
+        let mut sampler = loaded
+            .new_sampler(NormalLogp::new(4, 2.), settings, 17, 1, 43)
+            .unwrap();
+        assert_eq!(sampler.metric(), &bundle.metric[..]);
+        let mut mean = 0f64;
+        for _ in 0..500 {
+            let (draw, stats) = sampler.draw().unwrap();
+            assert!(stats.divergence_info().is_none());
+            mean += draw[0] / 500.;
+        }
+        assert!((sampler.step_size() / bundle.step_size - 1.).abs() < 1e-12);
+        assert!((mean - 2.).abs() < 0.3, "mean {}", mean);
+
+        let err = loaded.new_sampler(NormalLogp::new(4, 2.), settings, 18, 1, 43);
+        assert!(matches!(err, Err(BundleError::ModelMismatch { .. })));
+        let err = loaded.new_sampler(NormalLogp::new(3, 2.), settings, 17, 1, 43);
+        assert!(matches!(err, Err(BundleError::Sampler(_))));
+
+        let text = String::from_utf8(buffer)
+            .unwrap()
+            .replace("step_size", "step");
+        let err = TunedBundle::load(text.as_bytes());
+        assert!(matches!(err, Err(BundleError::Parse { line: 7, .. })));
+    }
+
+    #[test]
+    fn retune() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(4, 2.), settings, 0, 42);
+        sampler.set_position(&[0.; 4]).unwrap();
+        let mut position = Box::default();
+        for _ in 0..settings.num_tune + 1 {
+            position = sampler.draw().unwrap().0;
+        }
+        let metadata = RunMetadata::new(&settings, "shifted normal", 17);
+        let bundle = TunedBundle::from_chain(&sampler, &position, metadata).unwrap();
+
+        // Different data, and a much shorter warmup
+        let settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        let mut sampler = bundle
+            .retune_sampler(NormalLogp::new(4, 2.5), settings, 1, 43)
+            .unwrap();
+        let mut mean = 0f64;
+        for draw in 0..550 {
+            let (draw_pos, stats) = sampler.draw().unwrap();
+            let tuning = stats.to_vec().into_iter().find(|(key, _)| *key == "tuning");
+            assert!(matches!(tuning, Some((_, SampleStatValue::Bool(val))) if val == (draw < 50)));
+            if draw >= 50 {
+                mean += draw_pos[0] / 500.;
+            }
+        }
+        assert_eq!(sampler.metric(), &bundle.metric[..]);
+        assert!((sampler.step_size() / bundle.step_size - 1.).abs() > 1e-12);
+        assert!((mean - 2.5).abs() < 0.3, "mean {}", mean);
+
+        let err = bundle.retune_sampler(NormalLogp::new(3, 2.), settings, 1, 43);
+        assert!(matches!(err, Err(BundleError::Sampler(_))));
+    }
+}
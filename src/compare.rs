@@ -0,0 +1,273 @@
+use std::time::Duration;
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    diagnostics::{ess, r_hat},
+    nuts::{SampleStatValue, SampleStats},
+};
+
+/// Errors when the diagnostics of runs are computed or compared
+#[derive(Error, Debug)]
+pub enum CompareError {
+    #[error("Need at least one chain")]
+    NoChains,
+    #[error("Chain {chain} has {got} draws, but all chains need the same number of at least four draws ({expected})")]
+    ChainLength {
+        chain: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error(
+        "Draw {draw} of chain {chain} has length {got}, but the first draw has length {expected}"
+    )]
+    DrawLength {
+        chain: usize,
+        draw: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error("The runs have {a} and {b} parameters")]
+    ParameterCount { a: usize, b: usize },
+    #[error("Need at least {expected} draws, but got {got}")]
+    TooFewDraws { expected: usize, got: usize },
+    #[error("The metric has length {got}, but the draws have length {expected}")]
+    MetricLength { expected: usize, got: usize },
+}
+
+/// Diagnostics of a multi-chain run, see [`compare_runs`]
+#[derive(Debug, Clone)]
+pub struct RunDiagnostics {
+    /// The effective sample size of each parameter
+    pub ess: Vec<f64>,
+    /// The split R-hat of each parameter
+    pub r_hat: Vec<f64>,
+    /// The mean acceptance statistic of the draws
+    pub mean_accept: f64,
+    /// The number of draws from a diverging trajectory
+    pub num_divergences: u64,
+    /// The total number of leapfrog steps
+    pub n_leapfrog: u64,
+    /// The wall time of the run
+    pub elapsed: Duration,
+}
+
+impl RunDiagnostics {
+    /// Compute the diagnostics from the draws and stats of each chain after
+    /// tuning.
+    ///
+    /// All chains must have the same number of at least four draws, and all
+    /// draws the same length.
+    pub fn from_draws<S: SampleStats>(
+        chains: &[Vec<(Box<[f64]>, S)>],
+        elapsed: Duration,
+    ) -> Result<Self, CompareError> {
+        let first = chains.first().ok_or(CompareError::NoChains)?;
+        let expected = first.len().max(4);
+        let dim = first.first().map(|(draw, _)| draw.len()).unwrap_or(0);
+        for (chain, draws) in chains.iter().enumerate() {
+            if draws.len() != expected {
+                return Err(CompareError::ChainLength {
+                    chain,
+                    expected,
+                    got: draws.len(),
+                });
+            }
+            if let Some((draw, (vals, _))) = draws
+                .iter()
+                .enumerate()
+                .find(|(_, (vals, _))| vals.len() != dim)
+            {
+                return Err(CompareError::DrawLength {
+                    chain,
+                    draw,
+                    expected: dim,
+                    got: vals.len(),
+                });
+            }
+        }
+        let traces = (0..dim)
+            .map(|param| {
+                chains
+                    .iter()
+                    .map(|chain| chain.iter().map(|(draw, _)| draw[param]).collect_vec())
+                    .collect_vec()
+            })
+            .collect_vec();
+        let map_params = |func: fn(&[&[f64]]) -> Result<f64, CompareError>| {
+            traces
+                .iter()
+                .map(|param| func(&param.iter().map(|trace| &trace[..]).collect_vec()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let stats = chains.iter().flatten().map(|(_, stats)| stats);
+        let mut accept_sum = 0f64;
+        let mut num_draws = 0u64;
+        let mut num_divergences = 0;
+        let mut n_leapfrog = 0;
+        for stats in stats {
+            let accept = stats
+                .to_vec()
+                .into_iter()
+                .find(|(key, _)| *key == "mean_tree_accept");
+            if let Some((_, SampleStatValue::F64(accept))) = accept {
+                accept_sum += accept;
+            }
+            num_draws += 1;
+            num_divergences += stats.divergence_info().is_some() as u64;
+            n_leapfrog += stats.n_leapfrog();
+        }
+
+        Ok(RunDiagnostics {
+            ess: map_params(ess)?,
+            r_hat: map_params(r_hat)?,
+            mean_accept: accept_sum / num_draws as f64,
+            num_divergences,
+            n_leapfrog,
+            elapsed,
+        })
+    }
+}
+
+/// The differences between the diagnostics of two runs, see [`compare_runs`]
+#[derive(Debug, Clone)]
+pub struct RunComparison {
+    /// The ratio of the effective sample sizes `b / a` of each parameter
+    pub ess_ratio: Vec<f64>,
+    /// The change of R-hat `b - a` of each parameter
+    pub r_hat_diff: Vec<f64>,
+    /// The change of the mean acceptance statistic `b - a`
+    pub mean_accept_diff: f64,
+    /// The change of the number of divergences `b - a`
+    pub divergences_diff: i64,
+    /// The ratio of the number of leapfrog steps `b / a`
+    pub n_leapfrog_ratio: f64,
+    /// The ratio of the wall times `b / a`
+    pub elapsed_ratio: f64,
+}
+
+impl RunComparison {
+    /// The smallest ratio of effective sample sizes over all parameters.
+    pub fn min_ess_ratio(&self) -> f64 {
+        self.ess_ratio.iter().copied().fold(f64::INFINITY, f64::min)
+    }
+
+    /// The largest increase of R-hat over all parameters.
+    pub fn max_r_hat_increase(&self) -> f64 {
+        self.r_hat_diff
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+/// Compare the diagnostics of two runs of the same model, for example before
+/// and after a change of the model or of the sampler.
+///
+/// Ratios and differences are relative to `a`, so a ratio of effective
+/// sample sizes larger than one means that `b` mixes better. Both runs must
+/// have the same number of parameters.
+pub fn compare_runs(a: &RunDiagnostics, b: &RunDiagnostics) -> Result<RunComparison, CompareError> {
+    if (a.ess.len() != b.ess.len()) | (a.r_hat.len() != b.r_hat.len()) {
+        return Err(CompareError::ParameterCount {
+            a: a.ess.len(),
+            b: b.ess.len(),
+        });
+    }
+    Ok(RunComparison {
+        ess_ratio: a.ess.iter().zip(&b.ess).map(|(a, b)| b / a).collect(),
+        r_hat_diff: a.r_hat.iter().zip(&b.r_hat).map(|(a, b)| b - a).collect(),
+        mean_accept_diff: b.mean_accept - a.mean_accept,
+        divergences_diff: b.num_divergences as i64 - a.num_divergences as i64,
+        n_leapfrog_ratio: b.n_leapfrog as f64 / a.n_leapfrog as f64,
+        elapsed_ratio: b.elapsed.as_secs_f64() / a.elapsed.as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{sample_sequentially, test_logps::NormalLogp, SamplerArgs};
+
+    fn run(settings: SamplerArgs) -> RunDiagnostics {
+        let start = Instant::now();
+        let chains = (0..2)
+            .map(|chain| {
+                let seed = 42 + chain;
+                sample_sequentially(NormalLogp::new(3, 1.), settings, &[0.; 3], 600, chain, seed)
+                    .unwrap()
+                    .skip(settings.num_tune as usize)
+                    .map(|draw| draw.unwrap())
+                    .collect_vec()
+            })
+            .collect_vec();
+        RunDiagnostics::from_draws(&chains, start.elapsed()).unwrap()
+    }
+
+    #[test]
+    fn compare_settings() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let a = run(settings);
+        assert_eq!(a.ess.len(), 3);
+        assert!((0.5..1.).contains(&a.mean_accept));
+
+        let same = compare_runs(&a, &a).unwrap();
+        assert!(same.ess_ratio.iter().all(|&val| val == 1.));
+        assert!(same.r_hat_diff.iter().all(|&val| val == 0.));
+        assert_eq!(same.divergences_diff, 0);
+        assert_eq!(same.n_leapfrog_ratio, 1.);
+
+        // A higher target acceptance rate needs more leapfrog steps
+        let mut settings = settings;
+        settings.step_size_adapt.target_accept = 0.95;
+        let b = run(settings);
+        let diff = compare_runs(&a, &b).unwrap();
+        assert!(diff.mean_accept_diff > 0., "{:?}", diff);
+        assert!(diff.n_leapfrog_ratio > 1., "{:?}", diff);
+        assert!(diff.min_ess_ratio() > 0.);
+        assert!(diff.max_r_hat_increase() < 0.05);
+    }
+
+    #[test]
+    fn invalid_runs() {
+        let settings = SamplerArgs {
+            num_tune: 0,
+            ..Default::default()
+        };
+        let draws = |num_draws| {
+            sample_sequentially(NormalLogp::new(3, 1.), settings, &[0.; 3], num_draws, 0, 42)
+                .unwrap()
+                .map(|draw| draw.unwrap())
+                .collect_vec()
+        };
+        let elapsed = Duration::from_secs(1);
+        assert!(matches!(
+            RunDiagnostics::from_draws(&[draws(4)][..0], elapsed),
+            Err(CompareError::NoChains)
+        ));
+        assert!(matches!(
+            RunDiagnostics::from_draws(&[draws(3), draws(3)], elapsed),
+            Err(CompareError::ChainLength { chain: 0, .. })
+        ));
+        assert!(matches!(
+            RunDiagnostics::from_draws(&[draws(10), draws(11)], elapsed),
+            Err(CompareError::ChainLength { chain: 1, .. })
+        ));
+
+        let a = RunDiagnostics::from_draws(&[draws(10)], elapsed).unwrap();
+        let mut b = a.clone();
+        b.ess.pop();
+        b.r_hat.pop();
+        assert!(matches!(
+            compare_runs(&a, &b),
+            Err(CompareError::ParameterCount { a: 3, b: 2 })
+        ));
+    }
+}
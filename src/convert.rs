@@ -0,0 +1,227 @@
+#[cfg(any(feature = "ndarray", feature = "nalgebra"))]
+use std::time::Duration;
+
+#[cfg(feature = "ndarray")]
+use ndarray::{Array3, ArrayView3, Axis};
+use thiserror::Error;
+
+use crate::stopping::RunState;
+
+/// Draws that can not be arranged as an array of shape `(chain, draw, dim)`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DrawShapeError {
+    #[error("Chain {chain} has {len} draws, but chain 0 has {expected}")]
+    ChainLength {
+        chain: usize,
+        len: usize,
+        expected: usize,
+    },
+    #[error("Draw {draw} of chain {chain} has dimension {len} instead of {expected}")]
+    Dimension {
+        chain: usize,
+        draw: usize,
+        len: usize,
+        expected: usize,
+    },
+}
+
+#[cfg(feature = "ndarray")]
+/// Arrange the draws of several chains in an array of shape
+/// `(chain, draw, dim)`.
+///
+/// All chains must have the same number of draws of the same dimension.
+/// The draws can be boxed like those of [`sample_sequentially`] or shared
+/// like those of [`RunState`].
+///
+/// [`sample_sequentially`]: crate::sample_sequentially
+pub fn draws_to_array<D: AsRef<[f64]>>(chains: &[Vec<D>]) -> Result<Array3<f64>, DrawShapeError> {
+    let num_draws = chains.first().map(|chain| chain.len()).unwrap_or(0);
+    let dim = chains
+        .first()
+        .and_then(|chain| chain.first())
+        .map(|draw| draw.as_ref().len())
+        .unwrap_or(0);
+    for (chain, draws) in chains.iter().enumerate() {
+        if draws.len() != num_draws {
+            return Err(DrawShapeError::ChainLength {
+                chain,
+                len: draws.len(),
+                expected: num_draws,
+            });
+        }
+        let wrong_dim = draws
+            .iter()
+            .enumerate()
+            .find(|(_, val)| val.as_ref().len() != dim);
+        if let Some((draw, val)) = wrong_dim {
+            return Err(DrawShapeError::Dimension {
+                chain,
+                draw,
+                len: val.as_ref().len(),
+                expected: dim,
+            });
+        }
+    }
+    Ok(Array3::from_shape_fn(
+        (chains.len(), num_draws, dim),
+        |(chain, draw, param)| chains[chain][draw].as_ref()[param],
+    ))
+}
+
+impl RunState {
+    /// The first [`num_complete_draws`](Self::num_complete_draws) of each
+    /// chain as an array of shape `(chain, draw, dim)`.
+    #[cfg(feature = "ndarray")]
+    pub fn to_array(&self) -> Array3<f64> {
+        let n = self.num_complete_draws();
+        Array3::from_shape_fn((self.draws.len(), n, self.dim), |(chain, draw, param)| {
+            self.draws[chain][draw][param]
+        })
+    }
+
+    /// The first [`num_complete_draws`](Self::num_complete_draws) of each
+    /// chain as a matrix with one row per draw.
+    #[cfg(feature = "nalgebra")]
+    pub fn to_matrices(&self) -> Vec<nalgebra::DMatrix<f64>> {
+        let n = self.num_complete_draws();
+        self.draws
+            .iter()
+            .map(|chain| nalgebra::DMatrix::from_fn(n, self.dim, |draw, param| chain[draw][param]))
+            .collect()
+    }
+}
+
+#[cfg(feature = "ndarray")]
+impl From<&RunState> for Array3<f64> {
+    fn from(state: &RunState) -> Self {
+        state.to_array()
+    }
+}
+
+/// The elapsed time of the resulting run is zero.
+#[cfg(feature = "ndarray")]
+impl From<ArrayView3<'_, f64>> for RunState {
+    fn from(array: ArrayView3<'_, f64>) -> Self {
+        let draws = array
+            .axis_iter(Axis(0))
+            .map(|chain| {
+                chain
+                    .axis_iter(Axis(0))
+                    .map(|draw| draw.iter().copied().collect())
+                    .collect()
+            })
+            .collect();
+        RunState {
+            draws,
+            dim: array.shape()[2],
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(feature = "nalgebra")]
+impl From<&RunState> for Vec<nalgebra::DMatrix<f64>> {
+    fn from(state: &RunState) -> Self {
+        state.to_matrices()
+    }
+}
+
+/// Use one matrix per chain with one row per draw. The elapsed time of the
+/// resulting run is zero.
+#[cfg(feature = "nalgebra")]
+impl TryFrom<&[nalgebra::DMatrix<f64>]> for RunState {
+    type Error = DrawShapeError;
+
+    fn try_from(matrices: &[nalgebra::DMatrix<f64>]) -> Result<Self, Self::Error> {
+        let dim = matrices.first().map(|matrix| matrix.ncols()).unwrap_or(0);
+        let draws = matrices
+            .iter()
+            .enumerate()
+            .map(|(chain, matrix)| {
+                if matrix.ncols() != dim {
+                    return Err(DrawShapeError::Dimension {
+                        chain,
+                        draw: 0,
+                        len: matrix.ncols(),
+                        expected: dim,
+                    });
+                }
+                Ok(matrix
+                    .row_iter()
+                    .map(|row| row.iter().copied().collect())
+                    .collect())
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(RunState {
+            draws,
+            dim,
+            elapsed: Duration::ZERO,
+        })
+    }
+}
+
+#[cfg(all(test, any(feature = "ndarray", feature = "nalgebra")))]
+mod tests {
+    use super::*;
+
+    fn run_state() -> RunState {
+        let draws = (0..2)
+            .map(|chain| {
+                (0..4 + chain)
+                    .map(|draw| vec![chain as f64, draw as f64, 10. * draw as f64].into())
+                    .collect()
+            })
+            .collect();
+        RunState {
+            draws,
+            dim: 3,
+            elapsed: Duration::from_secs(1),
+        }
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn ndarray_conversion() {
+        let state = run_state();
+        let array = Array3::from(&state);
+        assert_eq!(array.shape(), &[2, 4, 3]);
+        assert_eq!(array[[1, 2, 0]], 1.);
+        assert_eq!(array[[1, 2, 2]], 20.);
+
+        let back = RunState::from(array.view());
+        assert_eq!(back.dim, 3);
+        assert_eq!(back.draws[1][3], state.draws[1][3]);
+        assert_eq!(back.num_complete_draws(), 4);
+
+        let err = draws_to_array(&state.draws).unwrap_err();
+        assert_eq!(
+            err,
+            DrawShapeError::ChainLength {
+                chain: 1,
+                len: 5,
+                expected: 4
+            }
+        );
+        assert_eq!(draws_to_array(&back.draws).unwrap(), array);
+    }
+
+    #[cfg(feature = "nalgebra")]
+    #[test]
+    fn nalgebra_conversion() {
+        let state = run_state();
+        let matrices: Vec<nalgebra::DMatrix<f64>> = (&state).into();
+        assert_eq!(matrices.len(), 2);
+        assert_eq!(matrices[1].shape(), (4, 3));
+        assert_eq!(matrices[1][(2, 2)], 20.);
+
+        let back = RunState::try_from(&matrices[..]).unwrap();
+        let complete: Vec<Vec<_>> = state
+            .draws
+            .iter()
+            .map(|chain| chain[..4].to_vec())
+            .collect();
+        assert_eq!(back.draws, complete);
+        let bad = [matrices[0].clone(), nalgebra::DMatrix::zeros(4, 2)];
+        assert!(RunState::try_from(&bad[..]).is_err());
+    }
+}
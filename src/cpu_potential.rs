@@ -1,9 +1,15 @@
+use std::any::Any;
 use std::fmt::Debug;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use thiserror::Error;
 
 use crate::cpu_state::{InnerState, State, StatePool};
-use crate::mass_matrix::MassMatrix;
+use crate::mass_matrix::{DiagMassMatrix, MassMatrix, NullCollector};
 use crate::nuts::{
-    AsSampleStatVec, Collector, Direction, DivergenceInfo, Hamiltonian, LogpError, NutsError,
+    check_dim, check_inverse_temperature, check_metric, AsSampleStatVec, Collector, Direction,
+    DivergenceInfo, Hamiltonian, HamiltonianSnapshot, LogpError, NutsError, SampleStatValue,
+    StatField, StepSizeFn,
 };
 
 /// Compute the unnormalized log probability density of the posterior
@@ -14,6 +20,14 @@ use crate::nuts::{
 /// Errors during that computation can be recoverable or non-recoverable.
 /// If a non-recoverable error occurs during sampling, the sampler will
 /// stop and return an error.
+///
+/// The logp function may return `-inf` for positions outside of the support
+/// of the posterior, for example to implement hard constraints. The gradient
+/// is ignored in that case. During sampling such a point is treated like a
+/// recoverable error: the trajectory ends with a divergence and the point is
+/// never accepted. The number of those boundary hits is reported in the
+/// `boundary_hits` sample stat. The initial position must be inside the
+/// support.
 pub trait CpuLogpFunc {
     type Err: Debug + Send + LogpError + 'static;
 
@@ -21,12 +35,196 @@ pub trait CpuLogpFunc {
     fn dim(&self) -> usize;
 }
 
+/// A panic in the logp function, see [`SamplerArgs::catch_logp_panics`].
+///
+/// [`SamplerArgs::catch_logp_panics`]: crate::SamplerArgs::catch_logp_panics
+#[derive(Debug, Error)]
+#[error("Logp function panicked: {message}")]
+pub struct LogpPanic {
+    /// The panic message, if the payload was a string
+    pub message: String,
+}
+
+impl LogpPanic {
+    /// Extract the message from the payload of a caught panic.
+    pub(crate) fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "<non-string panic payload>".to_string(),
+            },
+        };
+        LogpPanic { message }
+    }
+}
+
+/// An error while the logp function is evaluated
+enum EvalError<E> {
+    Logp(E),
+    Panic(LogpPanic),
+}
+
+impl<E: std::error::Error + Send + 'static> From<EvalError<E>> for NutsError {
+    fn from(err: EvalError<E>) -> Self {
+        match err {
+            EvalError::Logp(err) => NutsError::LogpFailure(Box::new(err)),
+            EvalError::Panic(panic) => NutsError::LogpFailure(Box::new(panic)),
+        }
+    }
+}
+
+/// The bins of the energy error histogram, see
+/// [`SamplerArgs::energy_error_histogram`].
+///
+/// The first bin counts leapfrog steps with an absolute energy error below
+/// `lower`, followed by `num_bins` logarithmically spaced bins between
+/// `lower` and `upper`. The last bin counts errors of at least `upper`,
+/// including infinite and NaN errors, so there are `num_bins + 2` counts.
+///
+/// [`SamplerArgs::energy_error_histogram`]: crate::SamplerArgs::energy_error_histogram
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnergyErrorBins {
+    lower: f64,
+    upper: f64,
+    num_bins: usize,
+}
+
+impl Default for EnergyErrorBins {
+    fn default() -> Self {
+        Self {
+            lower: 1e-3,
+            upper: 1e3,
+            num_bins: 12,
+        }
+    }
+}
+
+impl EnergyErrorBins {
+    /// Returns an error unless `0 < lower < upper < inf` and `num_bins` is
+    /// positive.
+    pub fn new(lower: f64, upper: f64, num_bins: usize) -> Result<Self, NutsError> {
+        if !((0f64 < lower) & (lower < upper) & upper.is_finite()) | (num_bins == 0) {
+            return Err(NutsError::InvalidSettings(format!(
+                "Energy error histogram needs 0 < lower < upper < inf and at least one bin, \
+                 but has lower {}, upper {} and {} bins",
+                lower, upper, num_bins
+            )));
+        }
+        Ok(Self {
+            lower,
+            upper,
+            num_bins,
+        })
+    }
+
+    pub fn lower(&self) -> f64 {
+        self.lower
+    }
+
+    pub fn upper(&self) -> f64 {
+        self.upper
+    }
+
+    pub fn num_bins(&self) -> usize {
+        self.num_bins
+    }
+
+    /// The number of counts in the histogram.
+    pub fn num_counts(&self) -> usize {
+        self.num_bins + 2
+    }
+
+    /// The index of the bin of an energy error.
+    pub fn bin(&self, energy_error: f64) -> usize {
+        let error = energy_error.abs();
+        if error < self.lower {
+            return 0;
+        }
+        if (error >= self.upper) | error.is_nan() {
+            return self.num_bins + 1;
+        }
+        let frac = (error / self.lower).ln() / (self.upper / self.lower).ln();
+        1 + ((frac * self.num_bins as f64) as usize).min(self.num_bins - 1)
+    }
+}
+
+/// Energy error counts of all leapfrog steps of a chain
+#[derive(Debug)]
+struct EnergyErrorHistogram {
+    bins: EnergyErrorBins,
+    counts: Box<[u64]>,
+}
+
+impl EnergyErrorHistogram {
+    fn new(bins: EnergyErrorBins) -> Self {
+        EnergyErrorHistogram {
+            bins,
+            counts: vec![0; bins.num_counts()].into(),
+        }
+    }
+
+    fn add(&mut self, energy_error: f64) {
+        self.counts[self.bins.bin(energy_error)] += 1;
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct DivergenceInfoImpl<E: Send + std::error::Error> {
     logp_function_error: Option<E>,
     start: Option<InnerState>,
     end: Option<InnerState>,
+    start_idx: i64,
+    end_idx: Option<i64>,
     energy_error: Option<f64>,
+    outside_support: bool,
+}
+
+impl<E: Debug + Send + std::error::Error> DivergenceInfoImpl<E> {
+    /// Describe a divergence in a leapfrog step from `start` to `end`, with
+    /// copies of both states if `store_states` is set.
+    pub(crate) fn new(
+        logp_function_error: Option<E>,
+        start: &State,
+        end: Option<&State>,
+        energy_error: Option<f64>,
+        outside_support: bool,
+        store_states: bool,
+    ) -> Self {
+        DivergenceInfoImpl {
+            logp_function_error,
+            start: store_states.then(|| start.clone_inner()),
+            end: end.filter(|_| store_states).map(|end| end.clone_inner()),
+            start_idx: start.idx_in_trajectory,
+            end_idx: end.map(|end| end.idx_in_trajectory),
+            energy_error,
+            outside_support,
+        }
+    }
+
+    /// The stats of diverging draws, see [`Hamiltonian::divergence_stat_schema`].
+    pub(crate) fn stat_schema(dim: usize, store_states: bool) -> Vec<StatField> {
+        let example: DivergenceInfoImpl<E> = DivergenceInfoImpl {
+            logp_function_error: None,
+            start: None,
+            end: None,
+            start_idx: 0,
+            end_idx: None,
+            energy_error: None,
+            outside_support: false,
+        };
+        let mut schema = vec![];
+        example.add_to_schema(&mut schema);
+        schema.iter_mut().for_each(|field| {
+            field.diverging_only = true;
+            if store_states
+                & ((field.name == "divergence_start") | (field.name == "divergence_end"))
+            {
+                field.length = Some(dim);
+            }
+        });
+        schema
+    }
 }
 
 impl<E: Debug + Send + std::error::Error> AsSampleStatVec for DivergenceInfoImpl<E> {
@@ -44,10 +242,11 @@ impl<E: Debug + Send + std::error::Error> AsSampleStatVec for DivergenceInfoImpl
             self.end.as_ref().map(|v| v.q.clone()).into(),
         ));
         vec.push(("divergence_energy_error", self.energy_error.into()));
+        vec.push(("divergence_outside_support", self.outside_support.into()));
     }
 }
 
-impl<E: Debug + Send + std::error::Error> DivergenceInfo for DivergenceInfoImpl<E> {
+impl<E: Debug + Send + std::error::Error + 'static> DivergenceInfo for DivergenceInfoImpl<E> {
     fn start_location(&self) -> Option<&[f64]> {
         Some(&self.start.as_ref()?.q)
     }
@@ -61,17 +260,21 @@ impl<E: Debug + Send + std::error::Error> DivergenceInfo for DivergenceInfoImpl<
     }
 
     fn end_idx_in_trajectory(&self) -> Option<i64> {
-        Some(self.end.as_ref()?.idx_in_trajectory)
+        self.end_idx
     }
 
     fn start_idx_in_trajectory(&self) -> Option<i64> {
-        Some(self.end.as_ref()?.idx_in_trajectory)
+        Some(self.start_idx)
     }
 
-    fn logp_function_error(&self) -> Option<&dyn std::error::Error> {
+    fn logp_function_error(&self) -> Option<&(dyn std::error::Error + 'static)> {
         self.logp_function_error
             .as_ref()
-            .map(|x| x as &dyn std::error::Error)
+            .map(|x| x as &(dyn std::error::Error + 'static))
+    }
+
+    fn outside_support(&self) -> bool {
+        self.outside_support
     }
 }
 
@@ -80,27 +283,184 @@ pub(crate) struct EuclideanPotential<F: CpuLogpFunc, M: MassMatrix> {
     pub(crate) mass_matrix: M,
     max_energy_error: f64,
     pub(crate) step_size: f64,
+    inverse_temperature: f64,
+    boundary_hits: u64,
+    /// How often a leapfrog step that failed with a recoverable logp error
+    /// is retried with smaller substeps, see [`SamplerArgs::max_step_retries`].
+    ///
+    /// [`SamplerArgs::max_step_retries`]: crate::SamplerArgs::max_step_retries
+    pub(crate) max_step_retries: u64,
+    step_retries: u64,
+    /// Whether to estimate the curvature along trajectories, see
+    /// [`SamplerArgs::curvature_diagnostics`].
+    ///
+    /// [`SamplerArgs::curvature_diagnostics`]: crate::SamplerArgs::curvature_diagnostics
+    pub(crate) curvature_diagnostics: bool,
+    /// Whether divergences keep copies of the states at their start and end,
+    /// see [`SamplerArgs::store_divergence_states`].
+    ///
+    /// [`SamplerArgs::store_divergence_states`]: crate::SamplerArgs::store_divergence_states
+    pub(crate) store_divergence_states: bool,
+    /// Whether panics in the logp function are turned into errors, see
+    /// [`SamplerArgs::catch_logp_panics`].
+    ///
+    /// [`SamplerArgs::catch_logp_panics`]: crate::SamplerArgs::catch_logp_panics
+    pub(crate) catch_logp_panics: bool,
+    /// The largest curvature seen in the trajectories of the current draw
+    max_curvature: Option<f64>,
+    /// The change of position in a leapfrog step, for the curvature estimate
+    curvature_dq: Box<[f64]>,
+    /// A position dependent multiplier for the step size
+    step_size_fn: Option<StepSizeFn>,
+    last_step_size: f64,
+    energy_error_histogram: Option<EnergyErrorHistogram>,
 }
 
 impl<F: CpuLogpFunc, M: MassMatrix> EuclideanPotential<F, M> {
     pub(crate) fn new(logp: F, mass_matrix: M, max_energy_error: f64, step_size: f64) -> Self {
+        let dim = logp.dim();
         EuclideanPotential {
             logp,
             mass_matrix,
             max_energy_error,
             step_size,
+            last_step_size: step_size,
+            inverse_temperature: 1f64,
+            boundary_hits: 0,
+            max_step_retries: 0,
+            step_retries: 0,
+            curvature_diagnostics: false,
+            max_curvature: None,
+            curvature_dq: vec![0f64; dim].into(),
+            store_divergence_states: true,
+            catch_logp_panics: false,
+            step_size_fn: None,
+            energy_error_histogram: None,
+        }
+    }
+
+    /// Count the energy errors of all leapfrog steps in a histogram, see
+    /// [`SamplerArgs::energy_error_histogram`].
+    ///
+    /// [`SamplerArgs::energy_error_histogram`]: crate::SamplerArgs::energy_error_histogram
+    pub(crate) fn set_energy_error_bins(&mut self, bins: Option<EnergyErrorBins>) {
+        self.energy_error_histogram = bins.map(EnergyErrorHistogram::new);
+    }
+
+    /// Describe a divergence in a leapfrog step from `start` to `end`.
+    fn divergence_info(
+        &self,
+        logp_function_error: Option<F::Err>,
+        start: &State,
+        end: Option<&State>,
+        energy_error: Option<f64>,
+        outside_support: bool,
+    ) -> DivergenceInfoImpl<F::Err> {
+        DivergenceInfoImpl::new(
+            logp_function_error,
+            start,
+            end,
+            energy_error,
+            outside_support,
+            self.store_divergence_states,
+        )
+    }
+
+    /// Estimate the curvature of the potential along the step from `start`
+    /// to `end`, in the geometry of the mass matrix.
+    ///
+    /// This is the secant estimate `dq^T H dq / dq^T M dq` of a Rayleigh
+    /// quotient of the preconditioned hessian `H`. The leapfrog integrator
+    /// is unstable for a quadratic potential if the step size is larger
+    /// than `2 / sqrt(curvature)`.
+    fn step_curvature(&mut self, start: &State, end: &State) -> Option<f64> {
+        self.curvature_dq
+            .iter_mut()
+            .zip(end.q.iter().zip(start.q.iter()))
+            .for_each(|(dq, (a, b))| *dq = a - b);
+        let norm = self.mass_matrix.mass_norm_sq(&self.curvature_dq);
+        if norm == 0f64 {
+            return None;
+        }
+        // The gradient is the gradient of the logp, not of the potential
+        let change: f64 = self
+            .curvature_dq
+            .iter()
+            .zip(end.grad.iter().zip(start.grad.iter()))
+            .map(|(dq, (a, b))| -dq * (a - b))
+            .sum();
+        let curvature = change / norm;
+        curvature.is_finite().then_some(curvature)
+    }
+
+    /// Integrate a full leapfrog step of size `epsilon` from `start`.
+    fn integrate(
+        &mut self,
+        pool: &mut StatePool,
+        start: &State,
+        epsilon: f64,
+    ) -> Result<State, EvalError<F::Err>> {
+        let mut out = pool.new_state();
+
+        start.first_momentum_halfstep(&mut out, epsilon);
+        self.update_velocity(&mut out);
+
+        start.position_step(&mut out, epsilon);
+        self.update_potential_gradient(&mut out)?;
+
+        out.second_momentum_halfstep(epsilon);
+
+        self.update_velocity(&mut out);
+        self.update_kinetic_energy(&mut out);
+        Ok(out)
+    }
+
+    /// Integrate a step of size `epsilon` from `start` with `num_substeps`
+    /// leapfrog steps. This stops early if a substep leaves the support.
+    fn integrate_substeps(
+        &mut self,
+        pool: &mut StatePool,
+        start: &State,
+        epsilon: f64,
+        num_substeps: u64,
+    ) -> Result<State, EvalError<F::Err>> {
+        let mut state = self.integrate(pool, start, epsilon / num_substeps as f64)?;
+        for _ in 1..num_substeps {
+            if state.potential_energy == f64::INFINITY {
+                break;
+            }
+            state = self.integrate(pool, &state, epsilon / num_substeps as f64)?;
         }
+        Ok(state)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct PotentialStats {
     step_size: f64,
+    inverse_temperature: f64,
+    boundary_hits: u64,
+    step_retries: u64,
+    max_curvature: Option<f64>,
+    energy_error_histogram: Option<Box<[f64]>>,
 }
 
 impl AsSampleStatVec for PotentialStats {
     fn add_to_vec(&self, vec: &mut Vec<crate::nuts::SampleStatItem>) {
         vec.push(("step_size", self.step_size.into()));
+        vec.push(("inverse_temperature", self.inverse_temperature.into()));
+        vec.push(("boundary_hits", self.boundary_hits.into()));
+        vec.push(("step_retries", self.step_retries.into()));
+        vec.push(("max_curvature", self.max_curvature.into()));
+        let stable_step_size = self
+            .max_curvature
+            .filter(|&curvature| curvature > 0f64)
+            .map(|curvature| 2f64 / curvature.sqrt());
+        vec.push(("stable_step_size", stable_step_size.into()));
+        vec.push((
+            "energy_error_histogram",
+            SampleStatValue::OptionArray(self.energy_error_histogram.clone()),
+        ));
     }
 }
 
@@ -118,53 +478,72 @@ impl<F: CpuLogpFunc, M: MassMatrix> Hamiltonian for EuclideanPotential<F, M> {
         initial_energy: f64,
         collector: &mut C,
     ) -> Result<Result<Self::State, Self::DivergenceInfo>, NutsError> {
-        let mut out = pool.new_state();
-
         let sign = match dir {
             Direction::Forward => 1,
             Direction::Backward => -1,
         };
 
-        let epsilon = (sign as f64) * self.step_size;
-
-        start.first_momentum_halfstep(&mut out, epsilon);
-        self.update_velocity(&mut out);
+        let mut epsilon = (sign as f64) * self.step_size;
+        if let Some(func) = self.step_size_fn.as_mut() {
+            let scale = func(&start.q);
+            if !(scale.is_finite() & (scale > 0f64)) {
+                return Err(NutsError::InvalidStepSizeMultiplier(scale));
+            }
+            epsilon *= scale;
+        }
+        self.last_step_size = epsilon.abs();
 
-        start.position_step(&mut out, epsilon);
-        if let Err(logp_error) = self.update_potential_gradient(&mut out) {
-            if !logp_error.is_recoverable() {
-                return Err(NutsError::LogpFailure(Box::new(logp_error)));
+        let mut result = self.integrate(pool, start, epsilon);
+        let mut retries = 0;
+        while let Err(EvalError::Logp(logp_error)) = &result {
+            if !logp_error.is_recoverable() | (retries >= self.max_step_retries) {
+                break;
             }
-            let div_info = DivergenceInfoImpl {
-                logp_function_error: Some(logp_error),
-                start: Some(start.clone_inner()),
-                end: None,
-                energy_error: None,
-            };
+            retries += 1;
+            self.step_retries += 1;
+            result = self.integrate_substeps(pool, start, epsilon, 1 << retries.min(32));
+        }
+        let mut out = match result {
+            Ok(out) => out,
+            Err(EvalError::Panic(panic)) => return Err(NutsError::LogpFailure(Box::new(panic))),
+            Err(EvalError::Logp(logp_error)) => {
+                if !logp_error.is_recoverable() {
+                    return Err(NutsError::LogpFailure(Box::new(logp_error)));
+                }
+                let div_info = self.divergence_info(Some(logp_error), start, None, None, false);
+                collector.register_leapfrog(start, start, Some(&div_info));
+                return Ok(Err(div_info));
+            }
+        };
+        if out.potential_energy == f64::INFINITY {
+            self.boundary_hits += 1;
+            if let Some(histogram) = self.energy_error_histogram.as_mut() {
+                histogram.add(f64::INFINITY);
+            }
+            let div_info = self.divergence_info(None, start, Some(&out), None, true);
             collector.register_leapfrog(start, &out, Some(&div_info));
             return Ok(Err(div_info));
         }
 
-        out.second_momentum_halfstep(epsilon);
-
-        self.update_velocity(&mut out);
-        self.update_kinetic_energy(&mut out);
-
         *out.index_in_trajectory_mut() = start.index_in_trajectory() + sign;
 
-        start.set_psum(&mut out, dir);
+        if self.curvature_diagnostics {
+            if let Some(curvature) = self.step_curvature(start, &out) {
+                let max = self.max_curvature.get_or_insert(curvature);
+                *max = max.max(curvature);
+            }
+        }
 
         let energy_error = {
             use crate::nuts::State;
             out.energy() - initial_energy
         };
+        if let Some(histogram) = self.energy_error_histogram.as_mut() {
+            histogram.add(energy_error);
+        }
         if (energy_error > self.max_energy_error) | !energy_error.is_finite() {
-            let divergence_info = DivergenceInfoImpl {
-                logp_function_error: None,
-                start: Some(start.clone_inner()),
-                end: Some(out.clone_inner()),
-                energy_error: Some(energy_error),
-            };
+            let divergence_info =
+                self.divergence_info(None, start, Some(&out), Some(energy_error), false);
             collector.register_leapfrog(start, &out, Some(&divergence_info));
             return Ok(Err(divergence_info));
         }
@@ -175,30 +554,118 @@ impl<F: CpuLogpFunc, M: MassMatrix> Hamiltonian for EuclideanPotential<F, M> {
     }
 
     fn init_state(&mut self, pool: &mut StatePool, init: &[f64]) -> Result<Self::State, NutsError> {
+        check_dim(self.dim(), init.len())?;
         let mut state = pool.new_state();
         {
             let inner = state.try_mut_inner().expect("State already in use");
             inner.q.copy_from_slice(init);
-            inner.p_sum.fill(0.);
         }
-        self.update_potential_gradient(&mut state)
-            .map_err(|e| NutsError::LogpFailure(Box::new(e)))?;
+        self.update_potential_gradient(&mut state)?;
+        if state.potential_energy == f64::INFINITY {
+            return Err(NutsError::InitOutsideSupport);
+        }
         Ok(state)
     }
 
-    fn randomize_momentum<R: rand::Rng + ?Sized>(&self, state: &mut Self::State, rng: &mut R) {
+    fn randomize_momentum<R: rand::Rng + ?Sized>(
+        &self,
+        state: &mut Self::State,
+        rng: &mut R,
+        portable: bool,
+    ) {
         let inner = state.try_mut_inner().unwrap();
-        self.mass_matrix.randomize_momentum(inner, rng);
+        self.mass_matrix.randomize_momentum(inner, rng, portable);
         self.mass_matrix.update_velocity(inner);
         self.mass_matrix.update_kinetic_energy(inner);
     }
 
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, portable: bool, out: &mut [f64]) {
+        self.mass_matrix.sample_momentum(rng, portable, out);
+    }
+
+    fn logp_momentum(&self, momentum: &[f64]) -> f64 {
+        self.mass_matrix.logp_momentum(momentum)
+    }
+
+    fn set_momentum(&self, state: &mut Self::State, momentum: &[f64]) {
+        let inner = state.try_mut_inner().unwrap();
+        inner.p.copy_from_slice(momentum);
+        self.mass_matrix.update_velocity(inner);
+        self.mass_matrix.update_kinetic_energy(inner);
+    }
+
+    fn evaluate(
+        &mut self,
+        pool: &mut StatePool,
+        position: &[f64],
+        momentum: &[f64],
+    ) -> Result<HamiltonianSnapshot, NutsError> {
+        check_dim(self.dim(), momentum.len())?;
+        let mut state = self.init_state(pool, position)?;
+        self.set_momentum(&mut state, momentum);
+        Ok(HamiltonianSnapshot {
+            potential_energy: state.potential_energy,
+            kinetic_energy: state.kinetic_energy,
+            gradient: state.grad.clone(),
+            velocity: state.v.clone(),
+        })
+    }
+
     fn current_stats(&self) -> Self::Stats {
         PotentialStats {
             step_size: self.step_size,
+            inverse_temperature: self.inverse_temperature,
+            boundary_hits: self.boundary_hits,
+            step_retries: self.step_retries,
+            max_curvature: self.max_curvature,
+            energy_error_histogram: self
+                .energy_error_histogram
+                .as_ref()
+                .map(|histogram| histogram.counts.iter().map(|&count| count as f64).collect()),
         }
     }
 
+    fn register_draw_start(&mut self) {
+        self.max_curvature = None;
+    }
+
+    fn divergence_stat_schema(&self) -> Vec<StatField> {
+        DivergenceInfoImpl::<F::Err>::stat_schema(self.dim(), self.store_divergence_states)
+    }
+
+    fn set_metric(&mut self, variance: &[f64]) -> Result<(), NutsError> {
+        check_dim(self.dim(), variance.len())?;
+        check_metric(variance)?;
+        self.mass_matrix.set_variance(variance);
+        Ok(())
+    }
+
+    fn metric(&self) -> &[f64] {
+        self.mass_matrix.variance()
+    }
+
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<(), NutsError> {
+        check_inverse_temperature(inverse_temperature)?;
+        self.inverse_temperature = inverse_temperature;
+        Ok(())
+    }
+
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn set_step_size(&mut self, step_size: f64) {
+        self.step_size = step_size;
+    }
+
+    fn last_step_size(&self) -> f64 {
+        self.last_step_size
+    }
+
+    fn set_step_size_fn(&mut self, func: Option<StepSizeFn>) {
+        self.step_size_fn = func;
+    }
+
     fn new_empty_state(&mut self, pool: &mut StatePool) -> Self::State {
         pool.new_state()
     }
@@ -213,14 +680,34 @@ impl<F: CpuLogpFunc, M: MassMatrix> Hamiltonian for EuclideanPotential<F, M> {
 }
 
 impl<F: CpuLogpFunc, M: MassMatrix> EuclideanPotential<F, M> {
-    fn update_potential_gradient(&mut self, state: &mut State) -> Result<(), F::Err> {
+    fn update_potential_gradient(&mut self, state: &mut State) -> Result<(), EvalError<F::Err>> {
         let logp = {
             let inner = state.try_mut_inner().unwrap();
-            self.logp.logp(&inner.q, &mut inner.grad)
-        }?;
+            if self.catch_logp_panics {
+                // The chain stops after a panic, so we never observe a
+                // logp function that was left in an inconsistent state.
+                let logp = &mut self.logp;
+                catch_unwind(AssertUnwindSafe(|| logp.logp(&inner.q, &mut inner.grad)))
+                    .map_err(|payload| EvalError::Panic(LogpPanic::new(payload)))?
+            } else {
+                self.logp.logp(&inner.q, &mut inner.grad)
+            }
+        }
+        .map_err(EvalError::Logp)?;
 
         let inner = state.try_mut_inner().unwrap();
-        inner.potential_energy = -logp;
+        if logp == f64::NEG_INFINITY {
+            // Outside of the support. The gradient is meaningless, and
+            // scaling by an inverse temperature of zero would produce nan.
+            inner.grad.fill(0f64);
+            inner.potential_energy = f64::INFINITY;
+        } else if self.inverse_temperature != 1f64 {
+            let beta = self.inverse_temperature;
+            inner.grad.iter_mut().for_each(|grad| *grad *= beta);
+            inner.potential_energy = -beta * logp;
+        } else {
+            inner.potential_energy = -logp;
+        }
         Ok(())
     }
 
@@ -234,3 +721,169 @@ impl<F: CpuLogpFunc, M: MassMatrix> EuclideanPotential<F, M> {
             .update_kinetic_energy(state.try_mut_inner().expect("State already in us"))
     }
 }
+
+/// A point on a leapfrog path, see [`leapfrog_n`].
+#[derive(Debug, Clone)]
+pub struct LeapfrogPoint {
+    pub position: Box<[f64]>,
+    pub momentum: Box<[f64]>,
+    pub logp: f64,
+    /// The value of the hamiltonian
+    pub energy: f64,
+}
+
+/// Integrate `n` leapfrog steps from `position` and `momentum` and return
+/// the path including the initial point.
+///
+/// `inv_mass_diag` is the diagonal of the inverse mass matrix, the identity
+/// is used if it is `None`, and its entries must be positive and finite. A
+/// negative `step_size` integrates backward in time. The path ends early if the logp function returns a recoverable
+/// error or `-inf`, or if the energy is not finite.
+pub fn leapfrog_n<F: CpuLogpFunc>(
+    logp: F,
+    position: &[f64],
+    momentum: &[f64],
+    inv_mass_diag: Option<&[f64]>,
+    step_size: f64,
+    n: usize,
+) -> Result<Vec<LeapfrogPoint>, NutsError> {
+    use crate::nuts::State as _;
+
+    let dim = logp.dim();
+    check_dim(dim, momentum.len())?;
+    let mut mass_matrix = DiagMassMatrix::new(dim);
+    match inv_mass_diag {
+        Some(diag) => {
+            check_dim(dim, diag.len())?;
+            check_metric(diag)?;
+            mass_matrix.update_diag(diag.iter().copied());
+        }
+        None => mass_matrix.update_diag(std::iter::repeat_n(1f64, dim)),
+    }
+    let mut potential = EuclideanPotential::new(logp, mass_matrix, f64::INFINITY, step_size);
+    let mut pool = potential.new_pool(n + 1);
+    let mut state = potential.init_state(&mut pool, position)?;
+    potential.set_momentum(&mut state, momentum);
+    let initial_energy = state.energy();
+
+    let point = |state: &State| LeapfrogPoint {
+        position: state.q.clone(),
+        momentum: state.p.clone(),
+        logp: -state.potential_energy,
+        energy: state.energy(),
+    };
+    let mut path = Vec::with_capacity(n + 1);
+    path.push(point(&state));
+    for _ in 0..n {
+        state = match potential.leapfrog(
+            &mut pool,
+            &state,
+            Direction::Forward,
+            initial_energy,
+            &mut NullCollector {},
+        )? {
+            Ok(state) => state,
+            Err(_) => break,
+        };
+        path.push(point(&state));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_logps::NormalLogp;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn hamiltonian_finite_differences() {
+        let mut mass_matrix = DiagMassMatrix::new(3);
+        mass_matrix.update_diag([0.5, 2., 3.].into_iter());
+        let mut potential =
+            EuclideanPotential::new(NormalLogp::new(3, 1.), mass_matrix, 1000., 0.1);
+        let mut pool = potential.new_pool(4);
+        let position = [0.3, -1., 2.];
+        let momentum = [1., 0.2, -0.7];
+        let point = potential.evaluate(&mut pool, &position, &momentum).unwrap();
+
+        let h = 1e-5;
+        for i in 0..3 {
+            let mut shifted = |delta: f64, shift_position: bool| {
+                let (mut q, mut p) = (position, momentum);
+                if shift_position {
+                    q[i] += delta;
+                } else {
+                    p[i] += delta;
+                }
+                potential.evaluate(&mut pool, &q, &p).unwrap()
+            };
+            let (up, down) = (shifted(h, false), shifted(-h, false));
+            let velocity = (up.kinetic_energy - down.kinetic_energy) / (2. * h);
+            assert_abs_diff_eq!(point.velocity[i], velocity, epsilon = 1e-6);
+
+            let (up, down) = (shifted(h, true), shifted(-h, true));
+            let gradient = -(up.potential_energy - down.potential_energy) / (2. * h);
+            assert_abs_diff_eq!(point.gradient[i], gradient, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn leapfrog_path() {
+        let path = leapfrog_n(
+            NormalLogp::new(2, 0.),
+            &[1., 0.],
+            &[0., 1.],
+            None,
+            0.01,
+            314,
+        )
+        .unwrap();
+        assert_eq!(path.len(), 315);
+        // A half period of the harmonic oscillator
+        let last = path.last().unwrap();
+        assert_abs_diff_eq!(last.position[0], -1., epsilon = 1e-3);
+        assert_abs_diff_eq!(last.position[1], 0., epsilon = 1e-2);
+        for point in path.iter() {
+            assert_abs_diff_eq!(point.energy, path[0].energy, epsilon = 1e-4);
+        }
+
+        let back = leapfrog_n(
+            NormalLogp::new(2, 0.),
+            &last.position,
+            &last.momentum,
+            None,
+            -0.01,
+            314,
+        )
+        .unwrap();
+        assert_abs_diff_eq!(back.last().unwrap().position[0], 1., epsilon = 1e-8);
+
+        let heavy = leapfrog_n(
+            NormalLogp::new(2, 0.),
+            &[1., 0.],
+            &[0., 1.],
+            Some(&[0.25, 0.25]),
+            0.01,
+            314,
+        )
+        .unwrap();
+        // With inverse mass 1/4 the oscillator is half as fast
+        assert_abs_diff_eq!(heavy.last().unwrap().position[0], 0., epsilon = 1e-2);
+
+        for value in [0., -1., f64::NAN] {
+            let err = leapfrog_n(
+                NormalLogp::new(2, 0.),
+                &[1., 0.],
+                &[0., 1.],
+                Some(&[1., value]),
+                0.01,
+                10,
+            );
+            assert!(matches!(
+                err,
+                Err(NutsError::InvalidMetric { index: 1, .. })
+            ));
+        }
+    }
+}
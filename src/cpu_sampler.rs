@@ -1,20 +1,42 @@
-use rand::{prelude::StdRng, Rng, SeedableRng};
-use rayon::prelude::*;
-use std::thread::JoinHandle;
+use rand::Rng;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
+#[cfg(feature = "parallel")]
+use {
+    crate::{
+        adapt_strategy::AdaptPool, affinity::pin_current_thread, nuts::StatsSnapshot, RunState,
+    },
+    crossbeam::channel::Sender,
+    rand::{prelude::StdRng, SeedableRng},
+    rayon::prelude::*,
+    std::{
+        collections::BTreeSet,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Condvar, Mutex, PoisonError,
+        },
+        thread::JoinHandle,
+        time::Instant,
+    },
+};
 
 use crate::{
     adapt_strategy::{
-        CombinedStrategy, DualAverageSettings, DualAverageStrategy, ExpWindowDiagAdapt,
+        CombinedStrategy, DenseWindowAdapt, DualAverageSettings, DualAverageStrategy,
+        ExpWindowDiagAdapt, PoolMember, StepSizeEstimate, VarianceEstimate,
+    },
+    cpu_potential::{EnergyErrorBins, EuclideanPotential},
+    mass_matrix::{
+        DenseAdaptSettings, DenseMassMatrix, DenseMetric, DiagAdaptExpSettings, DiagMassMatrix,
+        MassMatrix,
     },
-    cpu_potential::EuclideanPotential,
-    mass_matrix::{DiagAdaptExpSettings, DiagMassMatrix},
-    nuts::{Chain, NutsChain, NutsError, NutsOptions, SampleStats},
+    nuts::{check_dim, Chain, NutsChain, NutsError, NutsOptions, RngStreams, SampleStats},
+    riemannian::{CpuLogpHessianFunc, SoftAbsPotential, SoftAbsSettings, SoftAbsStepSizeAdapt},
     CpuLogpFunc,
 };
 
 /// Settings for the NUTS sampler
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct SamplerArgs {
     /// The number of tuning steps, where we fit the step size and mass matrix.
     pub num_tune: u64,
@@ -23,13 +45,105 @@ pub struct SamplerArgs {
     pub maxdepth: u64,
     /// Store the gradient in the SampleStats
     pub store_gradient: bool,
+    /// Check invariants of the trajectory tree after each doubling and fail
+    /// the draw with [`NutsError::InvariantViolation`] if one is violated,
+    /// see [`Chain::set_check_invariants`].
+    pub check_invariants: bool,
+    /// The number of trajectories with jittered step sizes that are built
+    /// after each momentum refresh. One of them is chosen at random, and
+    /// trajectory expectations are averaged over all of them.
+    pub num_trajectories: u64,
+    /// The relative step size jitter between those trajectories, in `[0, 1)`.
+    /// With several trajectories, [`Chain::draw`] fails with
+    /// [`NutsError::InvalidSettings`] for other values.
+    pub step_size_jitter: f64,
+    /// Draw momenta and choose points in the trajectory using only basic
+    /// floating point arithmetic, which gives identical chains on all
+    /// 64-bit platforms (e.g. x86_64 and aarch64) for a logp function that
+    /// is itself reproducible. This is a bit slower than the default, which
+    /// uses the platform libm.
+    pub strict_reproducibility: bool,
+    /// Compute the kinetic energy and the scalar products of the U-turn
+    /// criterion in a fixed order with compensated summation, see
+    /// [`compensated_dot`].
+    ///
+    /// By default these sums are vectorized, so their rounding depends on
+    /// the `simd_support` feature, and they lose accuracy in high
+    /// dimensions. With this, they are bitwise reproducible across builds
+    /// and platforms, at the cost of a few times slower reductions. Use it
+    /// together with `strict_reproducibility` for identical chains.
+    ///
+    /// [`compensated_dot`]: crate::math::compensated_dot
+    pub reproducible_sums: bool,
+    /// Choose the draw within each subtree of a trajectory with the
+    /// Gumbel-max trick: every point gets its log weight plus an independent
+    /// standard Gumbel value, and the point with the largest value wins.
+    /// This makes each choice an explicit comparison of log weights that can
+    /// be checked against the multinomial NUTS specification. The draws
+    /// follow the same distribution, but differ from the default.
+    pub gumbel_selection: bool,
     /// If the energy error is larger than this threshold we treat the leapfrog
     /// step as a divergence.
     pub max_energy_error: f64,
+    /// Retry a leapfrog step that failed with a recoverable logp error up to
+    /// this many times before the trajectory ends with a divergence. Retry
+    /// `k` integrates the same step with `2^k` substeps. The number of retries
+    /// is reported in the `step_retries` sample stat.
+    ///
+    /// This helps with logp functions that fail in narrow regions, for
+    /// example ODE solvers that only converge for moderate parameter changes.
+    /// Because the substeps depend on where the logp function fails, the
+    /// integrator is no longer exactly reversible, which can introduce a
+    /// small bias. The default of zero disables retries.
+    pub max_step_retries: u64,
+    /// Estimate the curvature of the posterior along each trajectory.
+    ///
+    /// If this is enabled, the sample stats `max_curvature` and
+    /// `stable_step_size` report the largest curvature seen in the trajectory
+    /// of each draw, measured in the geometry of the mass matrix, and the
+    /// largest step size for which the leapfrog integrator would be stable
+    /// at that curvature. Draws whose `step_size` is close to or above their
+    /// `stable_step_size` point to regions where the posterior is too stiff
+    /// for the adapted step size, which is a common cause of divergences
+    /// that persist after tuning. This costs an extra pass over the position
+    /// and gradient per leapfrog step.
+    pub curvature_diagnostics: bool,
+    /// Keep copies of the states at the start and end of the diverging
+    /// leapfrog step, which are reported as `divergence_start` and
+    /// `divergence_end`. Without them, divergences only store scalars like
+    /// the energy error and the indices in the trajectory, which avoids
+    /// allocations if there are many divergences in high dimensions.
+    pub store_divergence_states: bool,
+    /// Catch panics in the logp function and stop the chain with a
+    /// [`NutsError::LogpFailure`] that contains a [`LogpPanic`] with the
+    /// panic message. Without this, a panic unwinds through the sampler,
+    /// and in [`sample_parallel`] it takes down the sampler thread of all
+    /// chains. The default panic hook still prints the message.
+    ///
+    /// [`LogpPanic`]: crate::LogpPanic
+    pub catch_logp_panics: bool,
+    /// Count the energy errors of all leapfrog steps of a chain in a
+    /// histogram with these bins, which is reported cumulatively in the
+    /// `energy_error_histogram` sample stat.
+    ///
+    /// While `divergence_energy_error` only shows the worst step, the
+    /// histogram tells a step size that is marginal everywhere, with many
+    /// errors just below `max_energy_error`, apart from rare regions with
+    /// catastrophic errors and otherwise small errors. Steps that leave the
+    /// support count as infinite errors, steps where the logp function
+    /// failed are not counted.
+    pub energy_error_histogram: Option<EnergyErrorBins>,
     /// Settings for step size adaptation.
     pub step_size_adapt: DualAverageSettings,
     /// Settings for mass matrix adaptation.
     pub mass_matrix_adapt: DiagAdaptExpSettings,
+    /// Settings for dense mass matrix adaptation in [`new_dense_sampler`].
+    pub dense_mass_matrix_adapt: DenseAdaptSettings,
+    /// Settings for the Riemannian metric of [`new_softabs_sampler`].
+    pub softabs: SoftAbsSettings,
+    /// How threads are split between chains and logp evaluations in
+    /// [`sample_parallel`].
+    pub parallelism: ParallelismSettings,
 }
 
 impl Default for SamplerArgs {
@@ -38,19 +152,174 @@ impl Default for SamplerArgs {
             num_tune: 1000,
             maxdepth: 10,
             max_energy_error: 1000f64,
+            max_step_retries: 0,
+            curvature_diagnostics: false,
+            store_divergence_states: true,
+            catch_logp_panics: false,
+            energy_error_histogram: None,
             store_gradient: false,
+            check_invariants: false,
+            num_trajectories: 1,
+            step_size_jitter: 0.2,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
             step_size_adapt: DualAverageSettings::default(),
             mass_matrix_adapt: DiagAdaptExpSettings::default(),
+            dense_mass_matrix_adapt: DenseAdaptSettings::default(),
+            softabs: SoftAbsSettings::default(),
+            parallelism: ParallelismSettings::default(),
+        }
+    }
+}
+
+/// Settings for the two levels of parallelism in [`sample_parallel`]
+///
+/// Chains are sampled in parallel, and each logp function can use rayon
+/// internally. To avoid oversubscription, `chain_threads * logp_threads`
+/// should not exceed the number of cores.
+#[derive(Debug, Clone, Copy)]
+pub struct ParallelismSettings {
+    /// The number of chains that are sampled at the same time. If `None`,
+    /// chains are sampled in the global rayon thread pool.
+    pub chain_threads: Option<usize>,
+    /// The number of threads in a separate rayon thread pool for each chain.
+    /// Rayon calls in the logp function run in this pool. If this is 1, they
+    /// run in the same pool as the chains. Sampling fails with
+    /// [`NutsError::InvalidSettings`] if this is 0.
+    pub logp_threads: usize,
+    /// Pin the thread of each chain to a separate core before the chain is
+    /// created, so that its memory is allocated on the local NUMA node. If
+    /// `logp_threads` is larger than one, each thread of the logp pool of a
+    /// chain is pinned instead, and the chain runs in one of them. This
+    /// only has an effect with the `affinity` feature on Linux.
+    pub pin_threads: bool,
+    /// Schedule individual draws instead of whole chains.
+    ///
+    /// Every chain runs in its own thread, but at most `chain_threads` of
+    /// them draw at the same time, and the next draw always goes to a
+    /// waiting chain with the fewest draws. Chains with more expensive draws
+    /// then get more time, and all chains finish at about the same time
+    /// instead of leaving cores idle at the end of the run. If
+    /// `chain_threads` is `None`, the number of cores divided by
+    /// `logp_threads` is used.
+    pub schedule_draws: bool,
+    /// The number of draws that can wait in the channel returned by
+    /// [`sample_parallel`] before the chains block.
+    ///
+    /// A consumer that is slower than the chains, for example because it
+    /// writes draws to a remote database, throttles sampling instead of
+    /// letting draws pile up in memory. With `0` every draw is handed over
+    /// directly.
+    pub draw_buffer: usize,
+    /// Keep a copy of the draws after tuning in the [`StatsMonitor`] of
+    /// [`sample_parallel_monitored`], so that other threads can compute
+    /// diagnostics with [`StatsMonitor::run_state`] while sampling.
+    pub monitor_draws: bool,
+    /// Pool the mass matrix and step size estimates of all chains during
+    /// tuning.
+    ///
+    /// At every `window_switch_freq` draws of mass matrix adaptation the
+    /// chains wait for each other and continue with the merged variance
+    /// estimates, so that each chain adapts with the draws of all chains.
+    /// The variances are pooled with the spread of the chain means,
+    /// as if all draws came from one chain. The dual averaging of the step
+    /// size is pooled at the same draws, until the step size is frozen. This
+    /// helps most with expensive logp functions, where few tuning draws are
+    /// affordable. Because the chains wait for each other, each chain
+    /// runs in its own thread, and `chain_threads` and `schedule_draws` are
+    /// ignored.
+    pub pool_adaptation: bool,
+}
+
+impl Default for ParallelismSettings {
+    fn default() -> Self {
+        Self {
+            chain_threads: None,
+            logp_threads: 1,
+            pin_threads: false,
+            schedule_draws: false,
+            draw_buffer: 128,
+            monitor_draws: false,
+            pool_adaptation: false,
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+/// Hands out permits for single draws to chains that run in separate threads
+/// (see [`ParallelismSettings::schedule_draws`]).
+struct DrawScheduler {
+    state: Mutex<DrawSchedulerState>,
+    ready: Condvar,
+}
+
+#[cfg(feature = "parallel")]
+struct DrawSchedulerState {
+    free: usize,
+    /// The number of finished draws and the index of each waiting chain
+    waiting: BTreeSet<(u64, usize)>,
+}
+
+#[cfg(feature = "parallel")]
+impl DrawScheduler {
+    fn new(num_permits: usize) -> Self {
+        Self {
+            state: Mutex::new(DrawSchedulerState {
+                free: num_permits.max(1),
+                waiting: BTreeSet::new(),
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Block until `chain` may do its next draw, and return a permit that
+    /// is released when it is dropped.
+    fn acquire(&self, chain: usize, num_draws: u64) -> DrawPermit<'_> {
+        let key = (num_draws, chain);
+        let mut state = self.state.lock().expect("Poisoned scheduler lock");
+        state.waiting.insert(key);
+        while (state.free == 0) | (state.waiting.first() != Some(&key)) {
+            state = self.ready.wait(state).expect("Poisoned scheduler lock");
         }
+        state.waiting.remove(&key);
+        state.free -= 1;
+        // Another chain might be next in line for a remaining permit
+        self.ready.notify_all();
+        DrawPermit { scheduler: self }
+    }
+}
+
+#[cfg(feature = "parallel")]
+struct DrawPermit<'a> {
+    scheduler: &'a DrawScheduler,
+}
+
+#[cfg(feature = "parallel")]
+impl Drop for DrawPermit<'_> {
+    fn drop(&mut self) {
+        // Release the permit even if the lock was poisoned by a panic,
+        // so that the other chains can finish.
+        let mut state = match self.scheduler.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.free += 1;
+        self.scheduler.ready.notify_all();
     }
 }
 
 /// Propose new initial points for a sampler
 ///
 /// This trait can be implemented by users to control how the different
-/// chains should be initialized when using [`sample_parallel`].
+/// chains should be initialized when using [`sample_parallel`]. An error
+/// stops [`sample_parallel`] before any chain starts.
 pub trait InitPointFunc {
-    fn new_init_point<R: Rng + ?Sized>(&mut self, rng: &mut R, out: &mut [f64]);
+    fn new_init_point<R: Rng + ?Sized>(
+        &mut self,
+        rng: &mut R,
+        out: &mut [f64],
+    ) -> Result<(), NutsError>;
 }
 
 #[non_exhaustive]
@@ -74,9 +343,57 @@ pub enum ParallelSamplingError {
         #[from]
         source: Box<dyn std::error::Error + Send + Sync>,
     },
+    #[cfg(feature = "parallel")]
+    #[error("Could not create a thread pool")]
+    ThreadPoolCreation {
+        #[from]
+        source: rayon::ThreadPoolBuildError,
+    },
 }
 
-pub type ParallelChainResult = Result<(), ParallelSamplingError>;
+/// The failure of a single chain in [`sample_parallel`]
+///
+/// The other chains keep running, and the draws that the chain made before
+/// it failed were sent as usual.
+#[derive(Error, Debug)]
+#[error("Chain {chain} failed: {source}")]
+pub struct ChainError {
+    pub chain: u64,
+    /// The record of the chain up to the failure, or `None` if it panicked
+    pub metadata: Option<Box<ChainMetadata>>,
+    pub source: ParallelSamplingError,
+}
+
+pub type ParallelChainResult = Result<ChainMetadata, ChainError>;
+
+/// Why a chain in [`sample_parallel`] stopped drawing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainTermination {
+    /// All requested draws were made.
+    Finished,
+    /// A stop was requested through [`StatsMonitor::request_stop`].
+    Stopped,
+}
+
+/// A record of how a chain in [`sample_parallel`] was run, for
+/// reproducibility records and debugging
+#[derive(Debug, Clone)]
+pub struct ChainMetadata {
+    pub chain: u64,
+    /// The seed of the sampler of this chain, see [`new_sampler`]
+    pub seed: u64,
+    pub init_point: Box<[f64]>,
+    pub num_tune: u64,
+    /// The number of draws that were made, including tuning draws
+    pub num_draws: u64,
+    pub termination: ChainTermination,
+    /// When the chain started sampling
+    pub start_time: SystemTime,
+    /// The time spent on tuning draws
+    pub tuning_time: Duration,
+    /// The time spent on draws after tuning
+    pub sampling_time: Duration,
+}
 
 pub trait CpuLogpFuncMaker: Send + Sync {
     type Func: CpuLogpFunc;
@@ -85,7 +402,222 @@ pub trait CpuLogpFuncMaker: Send + Sync {
     fn dim(&self) -> usize;
 }
 
+#[cfg(feature = "parallel")]
+/// The progress of one chain, reported by [`StatsMonitor::heartbeat`]
+#[derive(Debug, Clone, Copy)]
+pub struct ChainHeartbeat {
+    pub chain: u64,
+    /// The number of draws so far, including tuning
+    pub num_draws: u64,
+    /// The number of draws per second since the previous heartbeat
+    pub draws_per_second: f64,
+    /// The time since the chain finished its last draw, or since sampling
+    /// started if it has no draws yet. If this keeps growing for a chain
+    /// whose draws usually take much less time, the chain is probably hung.
+    pub since_last_draw: Duration,
+    /// Whether the chain finished, failed or was stopped
+    pub finished: bool,
+}
+
+#[cfg(feature = "parallel")]
+#[derive(Debug, Clone, Copy, Default)]
+struct ChainActivity {
+    snapshot: StatsSnapshot,
+    last_draw: Option<Instant>,
+    finished: bool,
+}
+
+#[cfg(feature = "parallel")]
+/// Access to the accumulated statistics, and optionally the draws, of all
+/// chains while [`sample_parallel_monitored`] is running.
+///
+/// Cloning the monitor is cheap, and it can be polled from any thread.
+#[derive(Debug, Clone)]
+pub struct StatsMonitor {
+    chains: Arc<[Mutex<ChainActivity>]>,
+    /// The number of finished chains
+    num_finished: Arc<(Mutex<usize>, Condvar)>,
+    stop: Arc<AtomicBool>,
+    draws: Option<Arc<[Mutex<Vec<Arc<[f64]>>>]>>,
+    dim: usize,
+    start: Instant,
+}
+
+#[cfg(feature = "parallel")]
+impl StatsMonitor {
+    fn new(n_chains: u64, dim: usize, store_draws: bool) -> Self {
+        Self {
+            chains: (0..n_chains)
+                .map(|_| Mutex::new(ChainActivity::default()))
+                .collect(),
+            num_finished: Arc::new((Mutex::new(0), Condvar::new())),
+            stop: Arc::new(AtomicBool::new(false)),
+            draws: store_draws.then(|| (0..n_chains).map(|_| Mutex::new(vec![])).collect()),
+            dim,
+            start: Instant::now(),
+        }
+    }
+
+    /// Ask all chains to stop after their current draw.
+    ///
+    /// Chains that stop early finish successfully with fewer draws.
+    pub fn request_stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn update(&self, chain: usize, snapshot: StatsSnapshot) {
+        let mut activity = self.chains[chain].lock().expect("Poisoned stats lock");
+        activity.snapshot = snapshot;
+        activity.last_draw = Some(Instant::now());
+    }
+
+    fn finish(&self, chain: usize) {
+        // This also runs while a panicking chain unwinds, where a second
+        // panic would abort, so poisoned locks are used anyway.
+        self.chains[chain]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .finished = true;
+        let (num_finished, condvar) = &*self.num_finished;
+        *num_finished.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        condvar.notify_all();
+    }
+
+    /// Wait until all chains are finished or `timeout` has passed, and
+    /// return whether all chains are finished.
+    fn wait_finished(&self, timeout: Duration) -> bool {
+        let (num_finished, condvar) = &*self.num_finished;
+        let guard = num_finished.lock().expect("Poisoned stats lock");
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |num| *num < self.chains.len())
+            .expect("Poisoned stats lock");
+        *guard == self.chains.len()
+    }
+
+    /// Call `func` with the progress of all chains every `interval` in a
+    /// new thread, and once more when all chains are finished.
+    ///
+    /// Chains report progress only between draws, so this runs in its own
+    /// thread to notice chains that hang, for example in a logp function
+    /// that does not return. The thread ends after the last call.
+    pub fn heartbeat<F>(&self, interval: Duration, mut func: F) -> JoinHandle<()>
+    where
+        F: FnMut(&[ChainHeartbeat]) + Send + 'static,
+    {
+        let monitor = self.clone();
+        std::thread::spawn(move || {
+            let mut previous: Vec<u64> = vec![0; monitor.chains.len()];
+            let mut previous_time = Instant::now();
+            loop {
+                let all_finished = monitor.wait_finished(interval);
+                let now = Instant::now();
+                let seconds = now.duration_since(previous_time).as_secs_f64();
+                previous_time = now;
+                let beats: Vec<_> = monitor
+                    .chains
+                    .iter()
+                    .zip(previous.iter_mut())
+                    .enumerate()
+                    .map(|(chain, (activity, previous))| {
+                        let activity = *activity.lock().expect("Poisoned stats lock");
+                        let num_draws = activity.snapshot.num_draws;
+                        let new_draws = num_draws - std::mem::replace(previous, num_draws);
+                        ChainHeartbeat {
+                            chain: chain as u64,
+                            num_draws,
+                            draws_per_second: new_draws as f64 / seconds,
+                            since_last_draw: now
+                                .duration_since(activity.last_draw.unwrap_or(monitor.start)),
+                            finished: activity.finished,
+                        }
+                    })
+                    .collect();
+                func(&beats);
+                if all_finished {
+                    return;
+                }
+            }
+        })
+    }
+
+    fn stores_draws(&self) -> bool {
+        self.draws.is_some()
+    }
+
+    fn push_draw(&self, chain: usize, draw: Arc<[f64]>) {
+        if let Some(draws) = self.draws.as_ref() {
+            draws[chain].lock().expect("Poisoned draws lock").push(draw);
+        }
+    }
+
+    /// A read-only view of the draws after tuning of all chains so far, or
+    /// `None` if [`ParallelismSettings::monitor_draws`] is not set.
+    ///
+    /// The view shares the draws with the monitor, so this only copies one
+    /// pointer per draw. Each chain is read at once, so it never contains
+    /// half a draw, but chains can be ahead of each other. Diagnostics of
+    /// [`RunState`] only use the draws that all chains have finished.
+    pub fn run_state(&self) -> Option<Arc<RunState>> {
+        let draws = self.draws.as_ref()?;
+        Some(Arc::new(RunState {
+            draws: draws
+                .iter()
+                .map(|chain| chain.lock().expect("Poisoned draws lock").clone())
+                .collect(),
+            dim: self.dim,
+            elapsed: self.start.elapsed(),
+        }))
+    }
+
+    /// Whether all chains finished, failed or were stopped.
+    pub fn is_finished(&self) -> bool {
+        let (num_finished, _) = &*self.num_finished;
+        *num_finished.lock().expect("Poisoned stats lock") == self.chains.len()
+    }
+
+    /// The statistics of each chain after its most recent draw.
+    pub fn snapshot_stats(&self) -> Arc<[StatsSnapshot]> {
+        self.chains
+            .iter()
+            .map(|chain| chain.lock().expect("Poisoned stats lock").snapshot)
+            .collect()
+    }
+
+    /// The statistics summed over all chains.
+    pub fn total(&self) -> StatsSnapshot {
+        let mut total = StatsSnapshot::default();
+        self.snapshot_stats()
+            .iter()
+            .for_each(|chain| total.merge(chain));
+        total
+    }
+}
+
+#[cfg(feature = "parallel")]
+/// Marks a chain as finished in the [`StatsMonitor`] when it is dropped, also
+/// if the chain panics.
+struct FinishGuard<'a> {
+    monitor: &'a StatsMonitor,
+    chain: usize,
+}
+
+#[cfg(feature = "parallel")]
+impl Drop for FinishGuard<'_> {
+    fn drop(&mut self) {
+        self.monitor.finish(self.chain);
+    }
+}
+
+#[cfg(feature = "parallel")]
 /// Sample several chains in parallel and return all of the samples live in a channel
+///
+/// The handle returns a result for each chain. A chain that fails, for
+/// example because its initial point or a logp evaluation failed, does not
+/// stop the other chains.
 pub fn sample_parallel<F: CpuLogpFuncMaker + 'static, I: InitPointFunc>(
     logp_func_maker: F,
     init_point_func: &mut I,
@@ -101,21 +633,141 @@ pub fn sample_parallel<F: CpuLogpFuncMaker + 'static, I: InitPointFunc>(
     ),
     ParallelSamplingError,
 > {
+    let (handle, receiver, _) = sample_parallel_monitored(
+        logp_func_maker,
+        init_point_func,
+        settings,
+        n_chains,
+        n_draws,
+        seed,
+        n_try_init,
+    )?;
+    Ok((handle, receiver))
+}
+
+#[cfg(feature = "parallel")]
+/// A draw after tuning from one of the chains in [`sample_pooled`]
+#[derive(Debug)]
+pub struct PooledDraw {
+    pub chain: u64,
+    pub position: Box<[f64]>,
+    pub stats: Box<dyn SampleStats>,
+}
+
+#[cfg(feature = "parallel")]
+/// The draws after tuning of all chains in [`sample_pooled`], in the order
+/// in which they were made
+#[derive(Debug)]
+pub struct PooledDraws {
+    receiver: crossbeam::channel::Receiver<(Box<[f64]>, Box<dyn SampleStats>)>,
+    num_tune: u64,
+    totals: StatsSnapshot,
+}
+
+#[cfg(feature = "parallel")]
+impl PooledDraws {
+    /// The statistics of the draws that were returned so far, summed over
+    /// all chains.
+    pub fn totals(&self) -> StatsSnapshot {
+        self.totals
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Iterator for PooledDraws {
+    type Item = PooledDraw;
+
+    fn next(&mut self) -> Option<PooledDraw> {
+        loop {
+            let (position, stats) = self.receiver.recv().ok()?;
+            if stats.draw() < self.num_tune {
+                continue;
+            }
+            self.totals.num_draws += 1;
+            self.totals.num_divergences += stats.divergence_info().is_some() as u64;
+            self.totals.num_maxdepth_reached += stats.maxdepth_reached() as u64;
+            self.totals.n_leapfrog += stats.n_leapfrog();
+            self.totals.n_leapfrog_discarded += stats.n_leapfrog_discarded();
+            return Some(PooledDraw {
+                chain: stats.chain(),
+                position,
+                stats,
+            });
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+/// Sample several chains in parallel, and treat them as exchangeable
+///
+/// This is like [`sample_parallel`], but tuning draws are dropped, and the
+/// draws of all chains are returned as a single stream of pooled posterior
+/// draws, for consumers that do not care which chain a draw came from.
+pub fn sample_pooled<F: CpuLogpFuncMaker + 'static, I: InitPointFunc>(
+    logp_func_maker: F,
+    init_point_func: &mut I,
+    settings: SamplerArgs,
+    n_chains: u64,
+    n_draws: u64,
+    seed: u64,
+    n_try_init: u64,
+) -> Result<(JoinHandle<Vec<ParallelChainResult>>, PooledDraws), ParallelSamplingError> {
+    let (handle, receiver) = sample_parallel(
+        logp_func_maker,
+        init_point_func,
+        settings,
+        n_chains,
+        n_draws,
+        seed,
+        n_try_init,
+    )?;
+    let draws = PooledDraws {
+        receiver,
+        num_tune: settings.num_tune,
+        totals: StatsSnapshot::default(),
+    };
+    Ok((handle, draws))
+}
+
+#[cfg(feature = "parallel")]
+/// Like [`sample_parallel`], but also return a [`StatsMonitor`] that can be
+/// used to poll statistics of the chains while they are running.
+pub fn sample_parallel_monitored<F: CpuLogpFuncMaker + 'static, I: InitPointFunc>(
+    logp_func_maker: F,
+    init_point_func: &mut I,
+    settings: SamplerArgs,
+    n_chains: u64,
+    n_draws: u64,
+    seed: u64,
+    n_try_init: u64,
+) -> Result<
+    (
+        JoinHandle<Vec<ParallelChainResult>>,
+        crossbeam::channel::Receiver<(Box<[f64]>, Box<dyn SampleStats>)>,
+        StatsMonitor,
+    ),
+    ParallelSamplingError,
+> {
+    if settings.parallelism.logp_threads == 0 {
+        return Err(
+            NutsError::InvalidSettings("logp_threads must be at least 1".to_string()).into(),
+        );
+    }
     let ndim = logp_func_maker.dim();
     let mut func = logp_func_maker.make_logp_func()?;
-    assert!(ndim == func.dim());
+    check_dim(ndim, func.dim())?;
     let draws = settings.num_tune + n_draws;
     let mut rng = StdRng::seed_from_u64(seed.wrapping_sub(1));
-    let mut points: Vec<Result<(Box<[f64]>, Box<[f64]>), <F::Func as CpuLogpFunc>::Err>> = (0
-        ..n_chains)
+    // Chains whose initial point fails still report the last point they tried
+    let points: Vec<(Box<[f64]>, Option<NutsError>)> = (0..n_chains)
         .map(|_| {
             let mut position = vec![0.; ndim];
             let mut grad = vec![0.; ndim];
-            init_point_func.new_init_point(&mut rng, &mut position);
+            init_point_func.new_init_point(&mut rng, &mut position)?;
 
             let mut error = None;
             for _ in 0..n_try_init {
-                match func.logp(&mut position, &mut grad) {
+                match func.logp(&position, &mut grad) {
                     Err(e) => error = Some(e),
                     Ok(_) => {
                         error = None;
@@ -123,45 +775,183 @@ pub fn sample_parallel<F: CpuLogpFuncMaker + 'static, I: InitPointFunc>(
                     }
                 }
             }
-            match error {
-                Some(e) => Err(e),
-                None => Ok((position.into(), grad.into())),
-            }
+            let error = error.map(|e| NutsError::LogpFailure(Box::new(e)));
+            Ok((position.into(), error))
         })
-        .collect();
+        .collect::<Result<_, NutsError>>()?;
 
-    let points: Result<Vec<(Box<[f64]>, Box<[f64]>)>, _> = points.drain(..).collect();
-    let points = points.map_err(|e| NutsError::LogpFailure(Box::new(e)))?;
+    let parallelism = settings.parallelism;
+    let (sender, receiver) = crossbeam::channel::bounded(parallelism.draw_buffer);
+    let monitor = StatsMonitor::new(n_chains, ndim, parallelism.monitor_draws);
+    let chain_monitor = monitor.clone();
+
+    let chain_pool = match parallelism.chain_threads {
+        Some(num_threads) if !parallelism.schedule_draws & !parallelism.pool_adaptation => Some(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()?,
+        ),
+        _ => None,
+    };
+
+    let pool = parallelism.pool_adaptation.then(|| {
+        (
+            AdaptPool::new(n_chains as usize),
+            AdaptPool::new(n_chains as usize),
+        )
+    });
 
-    let (sender, receiver) = crossbeam::channel::bounded(128);
+    let scheduler = if parallelism.schedule_draws & pool.is_none() {
+        let num_permits = parallelism.chain_threads.unwrap_or_else(|| {
+            let cores = std::thread::available_parallelism().map_or(1, |val| val.get());
+            cores / parallelism.logp_threads
+        });
+        Some(DrawScheduler::new(num_permits))
+    } else {
+        None
+    };
 
     let handle = std::thread::spawn(move || {
-        let results: Vec<Result<(), ParallelSamplingError>> = points
-            .into_par_iter()
-            .with_max_len(1)
-            .enumerate()
-            .map_with(sender, |sender, (chain, point)| {
-                let func = logp_func_maker.make_logp_func()?;
-                let mut sampler = new_sampler(
-                    func,
-                    settings,
-                    chain as u64,
-                    seed.wrapping_add(chain as u64),
-                );
-                sampler.set_position(&point.0)?;
-                for _ in 0..draws {
-                    let (point2, info) = sampler.draw()?;
-                    sender
-                        .send((point2, Box::new(info) as Box<dyn SampleStats>))
-                        .map_err(|_| ParallelSamplingError::ChannelClosed())?;
+        let sample_chain =
+            |chain: usize,
+             (init_point, init_error): (Box<[f64]>, Option<NutsError>),
+             sender: &Sender<(Box<[f64]>, Box<dyn SampleStats>)>| {
+                let _finish = FinishGuard {
+                    monitor: &chain_monitor,
+                    chain,
+                };
+                let chain_seed = seed.wrapping_add(chain as u64);
+                let mut metadata = ChainMetadata {
+                    chain: chain as u64,
+                    seed: chain_seed,
+                    init_point,
+                    num_tune: settings.num_tune,
+                    num_draws: 0,
+                    termination: ChainTermination::Finished,
+                    start_time: SystemTime::now(),
+                    tuning_time: Duration::ZERO,
+                    sampling_time: Duration::ZERO,
+                };
+                // Leaves the pool when this chain fails or finishes, also if
+                // its logp pool can not be built and run_chain never runs
+                let pool_member = pool
+                    .as_ref()
+                    .map(|(variance, step_size)| (variance.member(), step_size.member()));
+                let run_chain = |metadata: &mut ChainMetadata| {
+                    if let Some(source) = init_error {
+                        return Err(ParallelSamplingError::InitError { source });
+                    }
+                    // The threads of a logp pool are pinned when they start
+                    let _pin = if parallelism.pin_threads & (parallelism.logp_threads <= 1) {
+                        pin_current_thread(chain)
+                    } else {
+                        None
+                    };
+                    let func = logp_func_maker.make_logp_func()?;
+                    let mut sampler =
+                        new_pooled_sampler(func, settings, chain as u64, chain_seed, pool_member);
+                    sampler.set_position(&metadata.init_point)?;
+                    let start = Instant::now();
+                    for draw in 0..draws {
+                        if chain_monitor.stop_requested() {
+                            metadata.termination = ChainTermination::Stopped;
+                            break;
+                        }
+                        if draw == settings.num_tune {
+                            metadata.tuning_time = start.elapsed();
+                        }
+                        let permit = scheduler
+                            .as_ref()
+                            .map(|scheduler| scheduler.acquire(chain, draw));
+                        let (point2, info) = sampler.draw()?;
+                        drop(permit);
+                        chain_monitor.update(chain, sampler.snapshot_stats());
+                        if chain_monitor.stores_draws() && (draw >= settings.num_tune) {
+                            chain_monitor.push_draw(chain, point2[..].into());
+                        }
+                        sender
+                            .send((point2, Box::new(info) as Box<dyn SampleStats>))
+                            .map_err(|_| ParallelSamplingError::ChannelClosed())?;
+                        metadata.num_draws += 1;
+                    }
+                    let elapsed = start.elapsed();
+                    if metadata.num_draws <= settings.num_tune {
+                        metadata.tuning_time = elapsed;
+                    } else {
+                        metadata.sampling_time = elapsed - metadata.tuning_time;
+                    }
+                    Ok(())
+                };
+                let result = if parallelism.logp_threads > 1 {
+                    let first_core = chain * parallelism.logp_threads;
+                    rayon::ThreadPoolBuilder::new()
+                        .num_threads(parallelism.logp_threads)
+                        .start_handler(move |thread| {
+                            if parallelism.pin_threads {
+                                if let Some(guard) = pin_current_thread(first_core + thread) {
+                                    guard.keep();
+                                }
+                            }
+                        })
+                        .build()
+                        .map_err(ParallelSamplingError::from)
+                        .and_then(|pool| pool.install(|| run_chain(&mut metadata)))
+                } else {
+                    run_chain(&mut metadata)
+                };
+                match result {
+                    Ok(()) => Ok(metadata),
+                    Err(source) => Err(ChainError {
+                        chain: chain as u64,
+                        metadata: Some(Box::new(metadata)),
+                        source,
+                    }),
                 }
-                Ok(())
-            })
-            .collect();
-        results
+            };
+
+        if scheduler.is_some() | pool.is_some() {
+            return std::thread::scope(|scope| {
+                let handles: Vec<_> = points
+                    .into_iter()
+                    .enumerate()
+                    .map(|(chain, point)| {
+                        let sender = sender.clone();
+                        let sample_chain = &sample_chain;
+                        scope.spawn(move || sample_chain(chain, point, &sender))
+                    })
+                    .collect();
+                drop(sender);
+                handles
+                    .into_iter()
+                    .enumerate()
+                    .map(|(chain, handle)| {
+                        handle.join().unwrap_or(Err(ChainError {
+                            chain: chain as u64,
+                            metadata: None,
+                            source: ParallelSamplingError::Panic,
+                        }))
+                    })
+                    .collect()
+            });
+        }
+
+        let run = || {
+            points
+                .into_par_iter()
+                .with_max_len(1)
+                .enumerate()
+                .map_with(sender, |sender, (chain, point)| {
+                    sample_chain(chain, point, sender)
+                })
+                .collect::<Vec<ParallelChainResult>>()
+        };
+        match chain_pool {
+            Some(pool) => pool.install(run),
+            None => run(),
+        }
     });
 
-    Ok((handle, receiver))
+    Ok((handle, receiver, monitor))
 }
 
 /// Create a new sampler
@@ -171,27 +961,227 @@ pub fn new_sampler<F: CpuLogpFunc>(
     chain: u64,
     seed: u64,
 ) -> impl Chain {
+    new_pooled_sampler(logp, settings, chain, seed, None)
+}
+
+/// Like [`new_sampler`], but pool the mass matrix and step size adaptation
+/// with other chains if `pool_member` is given.
+fn new_pooled_sampler<F: CpuLogpFunc>(
+    logp: F,
+    settings: SamplerArgs,
+    chain: u64,
+    seed: u64,
+    pool_member: Option<(PoolMember<VarianceEstimate>, PoolMember<StepSizeEstimate>)>,
+) -> impl Chain {
+    use crate::nuts::AdaptStrategy;
+    let num_tune = settings.num_tune;
+    let mut step_size_adapt: DualAverageStrategy<F, DiagMassMatrix> =
+        DualAverageStrategy::new(settings.step_size_adapt, num_tune, logp.dim());
+    let mut mass_matrix_adapt =
+        ExpWindowDiagAdapt::new(settings.mass_matrix_adapt, num_tune, logp.dim());
+    if let Some((variance, step_size)) = pool_member {
+        mass_matrix_adapt.set_pool(variance);
+        let every = settings.mass_matrix_adapt.window_switch_freq;
+        step_size_adapt.set_pool(step_size, every);
+    }
+
+    let strategy = CombinedStrategy::new(step_size_adapt, mass_matrix_adapt);
+
+    let mut mass_matrix = DiagMassMatrix::new(logp.dim());
+    mass_matrix.reproducible_sums = settings.reproducible_sums;
+    let potential = new_potential(logp, mass_matrix, &settings);
+
+    //let rng = RngStreams::<rand::rngs::StdRng>::seed_from_u64(seed);
+    let rng = RngStreams::<rand::rngs::SmallRng>::seed_from_u64(seed);
+
+    NutsChain::new(
+        potential,
+        strategy,
+        nuts_options(&settings),
+        rng,
+        chain,
+        seed,
+    )
+}
+
+/// Create a new sampler with a dense mass matrix
+///
+/// The mass matrix is adapted from the covariance of the tuning draws, see
+/// [`SamplerArgs::dense_mass_matrix_adapt`]. This helps with strongly
+/// correlated posteriors, but each leapfrog step costs `O(dim^2)`
+/// operations and each mass matrix update `O(dim^3)`, so it is only
+/// worthwhile for moderate dimensions. [`Chain::metric`] and
+/// [`Chain::set_metric`] access the diagonal of the inverse mass matrix,
+/// and setting it removes the off-diagonal entries.
+/// `settings.mass_matrix_adapt` and `settings.reproducible_sums` are
+/// ignored. Returns [`NutsError::InvalidSettings`] if the fixed shrinkage
+/// weight is not between zero and one.
+pub fn new_dense_sampler<F: CpuLogpFunc>(
+    logp: F,
+    settings: SamplerArgs,
+    chain: u64,
+    seed: u64,
+) -> Result<impl Chain, NutsError> {
     use crate::nuts::AdaptStrategy;
+    settings.dense_mass_matrix_adapt.validate()?;
     let num_tune = settings.num_tune;
     let step_size_adapt = DualAverageStrategy::new(settings.step_size_adapt, num_tune, logp.dim());
     let mass_matrix_adapt =
-        ExpWindowDiagAdapt::new(settings.mass_matrix_adapt, num_tune, logp.dim());
+        DenseWindowAdapt::new(settings.dense_mass_matrix_adapt, num_tune, logp.dim());
 
     let strategy = CombinedStrategy::new(step_size_adapt, mass_matrix_adapt);
 
-    let mass_matrix = DiagMassMatrix::new(logp.dim());
+    let mass_matrix = DenseMassMatrix::new(logp.dim());
+    let potential = new_potential(logp, mass_matrix, &settings);
+
+    let rng = RngStreams::<rand::rngs::SmallRng>::seed_from_u64(seed);
+
+    Ok(NutsChain::new(
+        potential,
+        strategy,
+        nuts_options(&settings),
+        rng,
+        chain,
+        seed,
+    ))
+}
+
+/// Create a new sampler with a fixed dense mass matrix
+///
+/// The inverse mass matrix is set to `metric` and is not adapted, only the
+/// step size is tuned. This is useful if a good estimate of the posterior
+/// covariance is known, for example from a Laplace approximation. Returns
+/// [`NutsError::InvalidDenseMetric`] if `metric` is not valid.
+pub fn new_fixed_dense_sampler<F: CpuLogpFunc>(
+    logp: F,
+    metric: DenseMetric,
+    settings: SamplerArgs,
+    chain: u64,
+    seed: u64,
+) -> Result<impl Chain, NutsError> {
+    use crate::nuts::AdaptStrategy;
+    let strategy: DualAverageStrategy<F, DenseMassMatrix> =
+        DualAverageStrategy::new(settings.step_size_adapt, settings.num_tune, logp.dim());
+
+    let mut mass_matrix = DenseMassMatrix::new(logp.dim());
+    mass_matrix.set_metric(metric)?;
+    let potential = new_potential(logp, mass_matrix, &settings);
+
+    let rng = RngStreams::<rand::rngs::SmallRng>::seed_from_u64(seed);
+
+    Ok(NutsChain::new(
+        potential,
+        strategy,
+        nuts_options(&settings),
+        rng,
+        chain,
+        seed,
+    ))
+}
+
+/// Create a new experimental sampler with the Riemannian SoftAbs metric
+///
+/// The metric is computed at each position from the hessian of the logp,
+/// with eigenvalues close to zero regularized as described in
+/// [`SoftAbsSettings`], and trajectories are integrated with the implicit
+/// generalized leapfrog integrator. This adapts to the local scale of
+/// posteriors like funnels, where a Euclidean metric diverges for any step
+/// size. Each leapfrog step needs several evaluations of the logp, its
+/// hessian and the derivatives of the hessian, and `O(dim^3)` work per
+/// evaluation, so this is only feasible for low dimensional models. The
+/// implicit updates converge less reliably for large step sizes, so a
+/// `target_accept` around 0.95 often works better than the default. Only the
+/// step size is tuned, `settings.mass_matrix_adapt` is ignored, and
+/// [`Chain::set_metric`] returns [`NutsError::Unsupported`]. Returns
+/// [`NutsError::InvalidSettings`] if `settings.softabs` is invalid.
+pub fn new_softabs_sampler<F: CpuLogpHessianFunc>(
+    logp: F,
+    settings: SamplerArgs,
+    chain: u64,
+    seed: u64,
+) -> Result<impl Chain, NutsError> {
+    use crate::nuts::AdaptStrategy;
+    let strategy: SoftAbsStepSizeAdapt<F> =
+        SoftAbsStepSizeAdapt::new(settings.step_size_adapt, settings.num_tune, logp.dim());
+
+    let mut potential = SoftAbsPotential::new(logp, settings.softabs, settings.max_energy_error)?;
+    potential.store_divergence_states = settings.store_divergence_states;
+
+    let rng = RngStreams::<rand::rngs::SmallRng>::seed_from_u64(seed);
+
+    Ok(NutsChain::new(
+        potential,
+        strategy,
+        nuts_options(&settings),
+        rng,
+        chain,
+        seed,
+    ))
+}
+
+/// Create a new sampler that only tunes the step size
+///
+/// The metric starts at the identity and is only changed by
+/// [`Chain::set_metric`], which should be called after
+/// [`Chain::set_position`]. This is meant for refitting a model to slightly
+/// different data with the metric of an earlier run and a short warmup,
+/// see [`TunedBundle::retune_sampler`](crate::TunedBundle::retune_sampler).
+/// `settings.mass_matrix_adapt` is ignored.
+pub fn new_step_size_sampler<F: CpuLogpFunc>(
+    logp: F,
+    settings: SamplerArgs,
+    chain: u64,
+    seed: u64,
+) -> impl Chain {
+    use crate::nuts::AdaptStrategy;
+    let strategy: DualAverageStrategy<F, DiagMassMatrix> =
+        DualAverageStrategy::new(settings.step_size_adapt, settings.num_tune, logp.dim());
+
+    let mut mass_matrix = DiagMassMatrix::new(logp.dim());
+    mass_matrix.reproducible_sums = settings.reproducible_sums;
+    let potential = new_potential(logp, mass_matrix, &settings);
+
+    let rng = RngStreams::<rand::rngs::SmallRng>::seed_from_u64(seed);
+
+    NutsChain::new(
+        potential,
+        strategy,
+        nuts_options(&settings),
+        rng,
+        chain,
+        seed,
+    )
+}
+
+fn new_potential<F: CpuLogpFunc, M: MassMatrix>(
+    logp: F,
+    mass_matrix: M,
+    settings: &SamplerArgs,
+) -> EuclideanPotential<F, M> {
     let max_energy_error = settings.max_energy_error;
-    let potential = EuclideanPotential::new(logp, mass_matrix, max_energy_error, 1f64);
+    let mut potential = EuclideanPotential::new(logp, mass_matrix, max_energy_error, 1f64);
+    potential.max_step_retries = settings.max_step_retries;
+    potential.curvature_diagnostics = settings.curvature_diagnostics;
+    potential.store_divergence_states = settings.store_divergence_states;
+    potential.catch_logp_panics = settings.catch_logp_panics;
+    potential.set_energy_error_bins(settings.energy_error_histogram);
+    potential
+}
 
-    let options = NutsOptions {
+/// The options of the NUTS trajectories in `settings`
+fn nuts_options(settings: &SamplerArgs) -> NutsOptions {
+    NutsOptions {
         maxdepth: settings.maxdepth,
         store_gradient: settings.store_gradient,
-    };
-
-    //let rng = { rand::rngs::StdRng::seed_from_u64(seed) };
-    let rng = rand::rngs::SmallRng::seed_from_u64(seed);
-
-    NutsChain::new(potential, strategy, options, rng, chain)
+        check_invariants: settings.check_invariants,
+        num_trajectories: settings.num_trajectories,
+        step_size_jitter: settings.step_size_jitter,
+        strict_reproducibility: settings.strict_reproducibility,
+        reproducible_sums: settings.reproducible_sums,
+        gumbel_selection: settings.gumbel_selection,
+        termination: None,
+        merge_audit: None,
+    }
 }
 
 pub fn sample_sequentially<F: CpuLogpFunc>(
@@ -204,7 +1194,7 @@ pub fn sample_sequentially<F: CpuLogpFunc>(
 ) -> Result<impl Iterator<Item = Result<(Box<[f64]>, impl SampleStats), NutsError>>, NutsError> {
     let mut sampler = new_sampler(logp, settings, chain, seed);
     sampler.set_position(start)?;
-    Ok((0..draws).into_iter().map(move |_| sampler.draw()))
+    Ok((0..draws).map(move |_| sampler.draw()))
 }
 
 /// Initialize chains using uniform jitter around zero or some other provided value
@@ -219,30 +1209,190 @@ impl JitterInitFunc {
     }
 
     /// Initialize new chains with jitter in [mu - 1, mu + 1].
-    pub fn new_with_mean(mu: Box<[f64]>) -> Self {
-        Self { mu: Some(mu) }
+    ///
+    /// Returns an error if the length of `mu` does not match the dimension
+    /// `dim` of the model.
+    pub fn new_with_mean(mu: Box<[f64]>, dim: usize) -> Result<Self, NutsError> {
+        check_dim(dim, mu.len())?;
+        Ok(Self { mu: Some(mu) })
+    }
+}
+
+impl Default for JitterInitFunc {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl InitPointFunc for JitterInitFunc {
-    fn new_init_point<R: Rng + ?Sized>(&mut self, rng: &mut R, out: &mut [f64]) {
+    fn new_init_point<R: Rng + ?Sized>(
+        &mut self,
+        rng: &mut R,
+        out: &mut [f64],
+    ) -> Result<(), NutsError> {
         rng.fill(out);
-        if self.mu.is_none() {
-            out.iter_mut().for_each(|val| *val = 2. * *val - 1.);
-        } else {
-            let mu = self.mu.as_ref().unwrap();
-            out.iter_mut()
-                .zip(mu.iter().copied())
-                .for_each(|(val, mu)| *val = 2. * *val - 1. + mu);
+        match &self.mu {
+            None => {
+                out.iter_mut().for_each(|val| *val = 2. * *val - 1.);
+            }
+            Some(mu) => {
+                check_dim(mu.len(), out.len())?;
+                out.iter_mut()
+                    .zip(mu.iter().copied())
+                    .for_each(|(val, mu)| *val = 2. * *val - 1. + mu);
+            }
         }
+        Ok(())
+    }
+}
+
+/// How [`TraceInitFunc`] chooses initial points from a previous run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceInitStrategy {
+    /// Draws chosen uniformly at random from all chains
+    Random,
+    /// The last draw of each chain, in the order of the chains
+    Last,
+    /// The draws with the largest logp, starting with the largest
+    MaxLogp,
+}
+
+/// Initialize chains with draws from a previous run, for example of an
+/// earlier version of the model
+///
+/// If more initial points are requested than there are chains (for
+/// [`TraceInitStrategy::Last`]) or draws (for
+/// [`TraceInitStrategy::MaxLogp`]), the points are reused from the start.
+pub struct TraceInitFunc {
+    /// The draws and their logp of each chain
+    chains: Vec<Vec<(Box<[f64]>, f64)>>,
+    strategy: TraceInitStrategy,
+    /// The draws in the order that they are used for `Last` and `MaxLogp`
+    order: Vec<(usize, usize)>,
+    count: usize,
+}
+
+impl TraceInitFunc {
+    /// Use the draws and logp values of each chain.
+    ///
+    /// Fails with [`NutsError::InvalidSettings`] if there are no draws, and
+    /// with [`NutsError::DimensionMismatch`] if the draws have different
+    /// lengths.
+    pub fn new(
+        chains: Vec<Vec<(Box<[f64]>, f64)>>,
+        strategy: TraceInitStrategy,
+    ) -> Result<Self, NutsError> {
+        let dim = chains
+            .iter()
+            .flatten()
+            .next()
+            .ok_or_else(|| NutsError::InvalidSettings("Need at least one draw".to_string()))?
+            .0
+            .len();
+        for (draw, _) in chains.iter().flatten() {
+            check_dim(dim, draw.len())?;
+        }
+        let mut order: Vec<(usize, usize)> = match strategy {
+            TraceInitStrategy::Last => chains
+                .iter()
+                .enumerate()
+                .filter(|(_, draws)| !draws.is_empty())
+                .map(|(chain, draws)| (chain, draws.len() - 1))
+                .collect(),
+            _ => chains
+                .iter()
+                .enumerate()
+                .flat_map(|(chain, draws)| (0..draws.len()).map(move |draw| (chain, draw)))
+                .collect(),
+        };
+        if strategy == TraceInitStrategy::MaxLogp {
+            let logp = |&(chain, draw): &(usize, usize)| chains[chain][draw].1;
+            order.sort_by(|a, b| logp(b).total_cmp(&logp(a)));
+        }
+        Ok(TraceInitFunc {
+            chains,
+            strategy,
+            order,
+            count: 0,
+        })
+    }
+
+    /// Use the draws and their stats of each chain, for example collected
+    /// from [`sample_sequentially`] after tuning.
+    pub fn from_draws<S: SampleStats>(
+        chains: &[Vec<(Box<[f64]>, S)>],
+        strategy: TraceInitStrategy,
+    ) -> Result<Self, NutsError> {
+        let chains = chains
+            .iter()
+            .map(|draws| {
+                draws
+                    .iter()
+                    .map(|(draw, stats)| (draw.clone(), stats.logp()))
+                    .collect()
+            })
+            .collect();
+        Self::new(chains, strategy)
+    }
+}
+
+impl InitPointFunc for TraceInitFunc {
+    fn new_init_point<R: Rng + ?Sized>(
+        &mut self,
+        rng: &mut R,
+        out: &mut [f64],
+    ) -> Result<(), NutsError> {
+        let (chain, draw) = match self.strategy {
+            TraceInitStrategy::Random => self.order[rng.gen_range(0..self.order.len())],
+            _ => self.order[self.count % self.order.len()],
+        };
+        self.count += 1;
+        let position = &self.chains[chain][draw].0;
+        check_dim(out.len(), position.len())?;
+        out.copy_from_slice(position);
+        Ok(())
     }
 }
 
 pub mod test_logps {
+    #[cfg(test)]
+    use crate::nuts::Chain;
     use crate::{cpu_potential::CpuLogpFunc, nuts::LogpError, CpuLogpFuncMaker};
     use multiversion::multiversion;
     use thiserror::Error;
 
+    /// Start `chain` at `init`, discard 200 draws, and return the mean and
+    /// variance of each coordinate over the next `num_draws` draws.
+    #[cfg(test)]
+    pub(crate) fn sample_moments<C: Chain>(
+        chain: &mut C,
+        init: &[f64],
+        num_draws: usize,
+    ) -> (Vec<f64>, Vec<f64>) {
+        chain.set_position(init).unwrap();
+        for _ in 0..200 {
+            chain.draw().unwrap();
+        }
+        let draws = (0..num_draws)
+            .map(|_| chain.draw().unwrap().0)
+            .collect::<Vec<_>>();
+        let means = (0..init.len())
+            .map(|i| draws.iter().map(|draw| draw[i]).sum::<f64>() / num_draws as f64)
+            .collect::<Vec<_>>();
+        let vars = means
+            .iter()
+            .enumerate()
+            .map(|(i, mean)| {
+                draws
+                    .iter()
+                    .map(|draw| (draw[i] - mean).powi(2))
+                    .sum::<f64>()
+                    / num_draws as f64
+            })
+            .collect();
+        (means, vars)
+    }
+
     #[derive(Clone)]
     pub struct NormalLogp {
         dim: usize,
@@ -290,7 +1440,7 @@ pub mod test_logps {
 
             #[cfg(feature = "simd_support")]
             #[multiversion]
-            #[clone(target = "[x64|x86_64]+avx+avx2+fma")]
+            #[clone(target = "[x86|x86_64]+avx+avx2+fma")]
             #[clone(target = "x86+sse")]
             fn logp_inner(mu: f64, position: &[f64], gradient: &mut [f64]) -> f64 {
                 use std::simd::f64x4;
@@ -327,7 +1477,7 @@ pub mod test_logps {
 
             #[cfg(not(feature = "simd_support"))]
             #[multiversion]
-            #[clone(target = "[x64|x86_64]+avx+avx2+fma")]
+            #[clone(target = "[x86|x86_64]+avx+avx2+fma")]
             #[clone(target = "x86+sse")]
             fn logp_inner(mu: f64, position: &[f64], gradient: &mut [f64]) -> f64 {
                 let n = position.len();
@@ -352,12 +1502,22 @@ pub mod test_logps {
 
 #[cfg(test)]
 mod tests {
-    use std::error::Error;
+    use std::sync::Mutex;
 
     use crate::{
-        sample_parallel, sample_sequentially,
-        test_logps::NormalLogp, CpuLogpFunc, CpuLogpFuncMaker, JitterInitFunc, SampleStats,
-        SamplerArgs,
+        new_sampler, new_step_size_sampler, sample_sequentially,
+        test_logps::{sample_moments, NormalLogp},
+        Chain, CpuLogpFunc, DiagMassMatrixEstimator, EnergyErrorBins, GeneralizedUTurn, NutsError,
+        SampleStatValue, SampleStats, SamplerArgs, TerminationCriterion, TrajectoryEnd,
+    };
+    #[cfg(feature = "parallel")]
+    use {
+        super::DrawScheduler,
+        crate::{
+            sample_parallel, sample_parallel_monitored, sample_pooled, ChainTermination,
+            CpuLogpFuncMaker, JitterInitFunc, ParallelismSettings,
+        },
+        std::{error::Error, time::Duration},
     };
 
     use itertools::Itertools;
@@ -366,8 +1526,10 @@ mod tests {
     #[test]
     fn sample_seq() {
         let logp = NormalLogp::new(10, 0.1);
-        let mut settings = SamplerArgs::default();
-        settings.num_tune = 100;
+        let settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
         let start = vec![0.2; 10];
 
         let chain = sample_sequentially(logp.clone(), settings, &start, 200, 1, 42).unwrap();
@@ -379,13 +1541,27 @@ mod tests {
         assert_eq!(vals.len(), 10);
         assert_eq!(stats.chain(), 1);
         assert_eq!(stats.draw(), 100);
+        assert_eq!(stats.draw_seed(), crate::draw_seed(42, 1, 100));
+        assert!(stats.n_leapfrog() >= stats.n_leapfrog_discarded());
+        assert!(stats.n_leapfrog() > 0);
+        assert_ne!(stats.draw_seed(), crate::draw_seed(42, 1, 101));
         assert!(stats
             .to_vec()
             .iter()
             .any(|(key, _)| *key == "index_in_trajectory"));
+    }
 
-        struct Maker {
-            logp: NormalLogp,
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn sample_par() {
+        let logp = NormalLogp::new(10, 0.1);
+        let settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
+
+        struct Maker {
+            logp: NormalLogp,
         }
         impl CpuLogpFuncMaker for Maker {
             type Func = NormalLogp;
@@ -415,4 +1591,1878 @@ mod tests {
             .iter()
             .any(|(key, _)| *key == "index_in_trajectory"));
     }
+
+    #[test]
+    fn tempered_sampling() {
+        let logp = NormalLogp::new(10, 0.);
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(logp, settings, 0, 42);
+        sampler.set_position(&[0.5; 10]).unwrap();
+        for invalid in [-1., f64::INFINITY, f64::NAN] {
+            assert!(matches!(
+                sampler.set_inverse_temperature(invalid),
+                Err(NutsError::InvalidInverseTemperature(_))
+            ));
+        }
+        sampler.set_inverse_temperature(0.25).unwrap();
+
+        let mut sum_sq = 0f64;
+        let mut count = 0f64;
+        for i in 0..1200 {
+            let (draw, stats) = sampler.draw().unwrap();
+            let beta = stats
+                .to_vec()
+                .into_iter()
+                .find(|(key, _)| *key == "inverse_temperature")
+                .unwrap();
+            assert!(matches!(beta.1, crate::SampleStatValue::F64(val) if val == 0.25));
+            if i >= 200 {
+                sum_sq += draw.iter().map(|x| x * x).sum::<f64>();
+                count += draw.len() as f64;
+            }
+        }
+        // The tempered target is a normal with variance 1 / 0.25
+        let var = sum_sq / count;
+        assert!((var - 4.).abs() < 1., "variance {}", var);
+    }
+
+    #[test]
+    fn dimension_mismatch() {
+        let logp = NormalLogp::new(10, 0.);
+        let mut sampler = new_sampler(logp, SamplerArgs::default(), 0, 42);
+        let err = sampler.set_position(&[0.; 3]).unwrap_err();
+        assert!(matches!(
+            err,
+            NutsError::DimensionMismatch {
+                expected: 10,
+                got: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn fixed_momentum() {
+        let momentum: Vec<f64> = (0..10).map(|i| i as f64 / 5. - 1.).collect();
+        let draw_with = |momentum: &[f64]| {
+            let mut sampler = new_sampler(NormalLogp::new(10, 0.), SamplerArgs::default(), 0, 42);
+            sampler.set_position(&[0.5; 10]).unwrap();
+            sampler.set_next_momentum(momentum).unwrap();
+            let (first, _) = sampler.draw().unwrap();
+            let (second, _) = sampler.draw().unwrap();
+            (first, second)
+        };
+        let (first_a, second_a) = draw_with(&momentum);
+        let (first_b, second_b) = draw_with(&momentum);
+        assert_eq!(first_a, first_b);
+        assert_eq!(second_a, second_b);
+
+        let negated: Vec<f64> = momentum.iter().map(|x| -x).collect();
+        let (first_c, _) = draw_with(&negated);
+        assert_ne!(first_a, first_c);
+
+        let mut sampler = new_sampler(NormalLogp::new(10, 0.), SamplerArgs::default(), 0, 42);
+        assert!(sampler.set_next_momentum(&[0.; 3]).is_err());
+    }
+
+    #[test]
+    fn momentum_distribution() {
+        use rand::SeedableRng;
+
+        let mut sampler = new_sampler(NormalLogp::new(2, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_position(&[0.5; 2]).unwrap();
+        sampler.set_metric(&[4., 0.25]).unwrap();
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut momentum = [0f64; 2];
+        let n = 20_000;
+        let mut second_moment = [0f64; 2];
+        let mut mean_logp = 0f64;
+        for _ in 0..n {
+            sampler.sample_momentum(&mut rng, &mut momentum).unwrap();
+            second_moment
+                .iter_mut()
+                .zip(momentum)
+                .for_each(|(moment, p)| *moment += p * p / n as f64);
+            mean_logp += sampler.logp_momentum(&momentum).unwrap() / n as f64;
+        }
+        // The momentum variance is the inverse of the metric
+        assert!(
+            (second_moment[0] - 0.25).abs() < 0.01,
+            "{:?}",
+            second_moment
+        );
+        assert!((second_moment[1] - 4.).abs() < 0.15, "{:?}", second_moment);
+        // The expected log density of a normal is the negative entropy
+        let entropy = 0.5 * (0.25f64.ln() + 4f64.ln()) + (1. + (2. * std::f64::consts::PI).ln());
+        assert!((mean_logp + entropy).abs() < 0.05, "{}", mean_logp);
+
+        let mut wrong = [0f64; 3];
+        assert!(matches!(
+            sampler.sample_momentum(&mut rng, &mut wrong),
+            Err(NutsError::DimensionMismatch { .. })
+        ));
+        assert!(matches!(
+            sampler.logp_momentum(&wrong),
+            Err(NutsError::DimensionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn evaluate_hamiltonian() {
+        let mut sampler = new_sampler(NormalLogp::new(2, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_position(&[0.5; 2]).unwrap();
+        sampler.set_metric(&[4., 0.25]).unwrap();
+
+        let position = [0.3, -1.];
+        let momentum = [1., -0.7];
+        let point = sampler.evaluate(&position, &momentum).unwrap();
+        let h = 1e-5;
+        for i in 0..2 {
+            let mut p = momentum;
+            p[i] += h;
+            let up = sampler.evaluate(&position, &p).unwrap().kinetic_energy;
+            p[i] -= 2. * h;
+            let down = sampler.evaluate(&position, &p).unwrap().kinetic_energy;
+            let velocity = (up - down) / (2. * h);
+            assert!((point.velocity[i] - velocity).abs() < 1e-6, "{}", velocity);
+            assert!((point.gradient[i] + position[i]).abs() < 1e-12);
+        }
+        // The chain does not move
+        assert_eq!(sampler.current_logp(), Some(-0.25));
+
+        assert!(matches!(
+            sampler.evaluate(&[0.; 3], &momentum),
+            Err(NutsError::DimensionMismatch { .. })
+        ));
+        assert!(matches!(
+            sampler.evaluate(&position, &[0.; 3]),
+            Err(NutsError::DimensionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rao_blackwell_expectation() {
+        let mut sampler = new_sampler(NormalLogp::new(10, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_trajectory_expectation(1, |position, out| out[0] = position[0]);
+        sampler.set_position(&[0.5; 10]).unwrap();
+
+        let mut draws = vec![];
+        let mut estimates = vec![];
+        for i in 0..2000 {
+            let (draw, stats) = sampler.draw().unwrap();
+            let estimate = stats.trajectory_expectation().unwrap();
+            assert_eq!(estimate.len(), 1);
+            if i >= 1000 {
+                draws.push(draw[0]);
+                estimates.push(estimate[0]);
+            }
+        }
+        let mean = |vals: &[f64]| vals.iter().sum::<f64>() / vals.len() as f64;
+        let var = |vals: &[f64]| {
+            let mu = mean(vals);
+            vals.iter().map(|x| (x - mu) * (x - mu)).sum::<f64>() / vals.len() as f64
+        };
+        assert!(mean(&estimates).abs() < 0.1);
+        assert!(var(&estimates) < var(&draws));
+    }
+
+    #[test]
+    fn jittered_trajectories() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            num_trajectories: 3,
+            ..Default::default()
+        };
+        let chain =
+            sample_sequentially(NormalLogp::new(5, 2.), settings, &[0.; 5], 1200, 0, 42).unwrap();
+        let mut draws = vec![];
+        for (i, draw) in chain.enumerate() {
+            let (draw, stats) = draw.unwrap();
+            // The leapfrog steps of all three trajectories are counted
+            assert!(stats.n_leapfrog() >= 3);
+            if i >= 200 {
+                draws.push(draw[0]);
+            }
+        }
+        let mean = draws.iter().sum::<f64>() / draws.len() as f64;
+        let var = draws.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / draws.len() as f64;
+        assert!((mean - 2.).abs() < 0.2);
+        assert!((0.8..1.2).contains(&var));
+
+        for jitter in [-0.1, 1.] {
+            let settings = SamplerArgs {
+                num_trajectories: 3,
+                step_size_jitter: jitter,
+                ..Default::default()
+            };
+            let mut sampler = new_sampler(NormalLogp::new(5, 0.), settings, 0, 42);
+            sampler.set_position(&[0.5; 5]).unwrap();
+            assert!(matches!(sampler.draw(), Err(NutsError::InvalidSettings(_))));
+
+            // The jitter is not used with a single trajectory
+            let settings = SamplerArgs {
+                step_size_jitter: jitter,
+                ..Default::default()
+            };
+            let mut sampler = new_sampler(NormalLogp::new(5, 0.), settings, 0, 42);
+            sampler.set_position(&[0.5; 5]).unwrap();
+            assert!(sampler.draw().is_ok());
+        }
+
+        // Steps in the trees of the trajectories that were not chosen are
+        // not discarded subtrees
+        let settings = SamplerArgs {
+            maxdepth: 4,
+            num_trajectories: 3,
+            num_tune: 0,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(5, 0.), settings, 0, 42);
+        sampler.set_termination_criterion(NeverTurn {});
+        sampler.set_position(&[0.5; 5]).unwrap();
+        for _ in 0..20 {
+            let (_, stats) = sampler.draw().unwrap();
+            if stats.divergence_info().is_none() {
+                assert_eq!(stats.n_leapfrog(), 3 * 15);
+                assert_eq!(stats.n_leapfrog_discarded(), 0);
+            }
+        }
+    }
+
+    /// A termination criterion that only stops at `maxdepth`
+    struct NeverTurn {}
+
+    impl TerminationCriterion for NeverTurn {
+        fn is_turning(&self, _: &TrajectoryEnd, _: &TrajectoryEnd, _: &[f64]) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn draw_into_buffer() {
+        let mut sampler = new_sampler(NormalLogp::new(10, 0.), SamplerArgs::default(), 0, 42);
+        let mut sampler2 = new_sampler(NormalLogp::new(10, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_position(&[0.5; 10]).unwrap();
+        sampler2.set_position(&[0.5; 10]).unwrap();
+
+        let mut out = [0f64; 10];
+        for _ in 0..10 {
+            let (draw, _) = sampler.draw().unwrap();
+            let stats = sampler2.draw_into(&mut out).unwrap();
+            assert_eq!(&draw[..], &out[..]);
+            assert!(stats.n_leapfrog() > 0);
+        }
+        assert!(matches!(
+            sampler2.draw_into(&mut [0f64; 3]),
+            Err(NutsError::DimensionMismatch {
+                expected: 10,
+                got: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn draw_many() {
+        let mut sampler = new_sampler(NormalLogp::new(4, 0.), SamplerArgs::default(), 0, 42);
+        let mut sampler2 = new_sampler(NormalLogp::new(4, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_position(&[0.5; 4]).unwrap();
+        sampler2.set_position(&[0.5; 4]).unwrap();
+
+        let mut out = vec![0f64; 5 * 4];
+        let mut stats = vec![];
+        sampler2.draw_many(5, &mut out, &mut stats).unwrap();
+        assert_eq!(stats.len(), 5);
+        for (row, stats) in out.chunks(4).zip(stats.iter()) {
+            let (draw, expected) = sampler.draw().unwrap();
+            assert_eq!(&draw[..], row);
+            assert_eq!(stats.draw(), expected.draw());
+        }
+        assert!(matches!(
+            sampler2.draw_many(2, &mut out, &mut stats),
+            Err(NutsError::DimensionMismatch {
+                expected: 8,
+                got: 20
+            })
+        ));
+        assert_eq!(stats.len(), 5);
+    }
+
+    #[test]
+    fn strict_reproducibility() {
+        let settings = SamplerArgs {
+            num_tune: 50,
+            strict_reproducibility: true,
+            ..Default::default()
+        };
+        let draws: Vec<u64> =
+            sample_sequentially(NormalLogp::new(4, 0.), settings, &[0.; 4], 60, 0, 42)
+                .unwrap()
+                .map(|draw| draw.unwrap().0[0].to_bits())
+                .collect();
+        // These values must be the same on all platforms. They only change
+        // with changes to the sampler itself.
+        assert_eq!(
+            &draws[57..],
+            &[0x400073e30edbbd8a, 0x3ff61bed58caa343, 0xbfe878fda191956b]
+        );
+    }
+
+    // With simd_support, the leapfrog steps may use fused multiply-adds, so
+    // the draws differ from the pinned values even with reproducible sums.
+    #[cfg(not(feature = "simd_support"))]
+    #[test]
+    fn reproducible_sums() {
+        let run = |reproducible_sums: bool| {
+            let settings = SamplerArgs {
+                num_tune: 50,
+                strict_reproducibility: true,
+                reproducible_sums,
+                ..Default::default()
+            };
+            let chain =
+                sample_sequentially(NormalLogp::new(20, 1.), settings, &[0.; 20], 60, 0, 42)
+                    .unwrap();
+            let (draws, energies): (Vec<u64>, Vec<u64>) = chain
+                .map(|draw| {
+                    let (draw, stats) = draw.unwrap();
+                    let energy = stats
+                        .to_vec()
+                        .into_iter()
+                        .find(|(key, _)| *key == "energy")
+                        .map(|(_, val)| val);
+                    let Some(SampleStatValue::F64(energy)) = energy else {
+                        panic!("Missing energy stat");
+                    };
+                    (draw[0].to_bits(), energy.to_bits())
+                })
+                .unzip();
+            (draws, energies)
+        };
+        let (draws, energies) = run(true);
+        // These values must be the same on all platforms.
+        assert_eq!(
+            &draws[57..],
+            &[0x3fc9ccce2ef5ef75, 0x3ff4e0b4bac013c2, 0xbfd11e01f70a9488]
+        );
+
+        // Without reproducible sums, the energies are only accurate up to
+        // rounding errors that depend on the summation order.
+        let (_, other_energies) = run(false);
+        assert_ne!(energies, other_energies);
+    }
+
+    #[test]
+    fn empirical_fisher_mass_matrix() {
+        let logp = NormalLogp::new(10, 0.);
+        let mut settings = SamplerArgs::default();
+        settings.mass_matrix_adapt.estimator = DiagMassMatrixEstimator::EmpiricalFisher;
+        settings.mass_matrix_adapt.store_mass_matrix = true;
+
+        let chain = sample_sequentially(logp, settings, &[0.5; 10], 1100, 0, 7).unwrap();
+        let (_, stats) = chain.last().unwrap().unwrap();
+        let diag = match stats
+            .to_vec()
+            .into_iter()
+            .find(|(key, _)| *key == "mass_matrix_inv")
+        {
+            Some((_, SampleStatValue::OptionArray(Some(val)))) => val,
+            _ => panic!("Mass matrix not stored"),
+        };
+        // The fisher information of a standard normal is the identity
+        assert!(
+            diag.iter().all(|&val| (0.3..3.).contains(&val)),
+            "{:?}",
+            diag
+        );
+    }
+
+    #[test]
+    fn draw_variance_mass_matrix() {
+        use crate::test_logps::NormalLogpError;
+
+        // Independent coordinates, each an equal mixture of normals at -1 and
+        // 1 with standard deviation 0.5. The variance of the draws is 1.25, but the square root
+        // of the ratio of the variances of draws and gradients is only 0.656,
+        // because the gradients only see the width of the components.
+        struct Bimodal {}
+
+        impl CpuLogpFunc for Bimodal {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                10
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                let mut logp = 0f64;
+                for (x, g) in position.iter().zip(grad.iter_mut()) {
+                    let a = (-(x - 1.).powi(2) * 2.).exp();
+                    let b = (-(x + 1.).powi(2) * 2.).exp();
+                    *g = -4. * ((x - 1.) * a + (x + 1.) * b) / (a + b);
+                    logp += (a + b).ln();
+                }
+                Ok(logp)
+            }
+        }
+
+        let final_mass_matrix = |estimator| {
+            let mut settings = SamplerArgs::default();
+            settings.mass_matrix_adapt.estimator = estimator;
+            settings.mass_matrix_adapt.store_mass_matrix = true;
+            let chain = sample_sequentially(Bimodal {}, settings, &[0.5; 10], 1100, 0, 42).unwrap();
+            let (_, stats) = chain.last().unwrap().unwrap();
+            let diag = match stats
+                .to_vec()
+                .into_iter()
+                .find(|(key, _)| *key == "mass_matrix_inv")
+            {
+                Some((_, SampleStatValue::OptionArray(Some(val)))) => val,
+                _ => panic!("Mass matrix not stored"),
+            };
+            diag.iter().sum::<f64>() / diag.len() as f64
+        };
+
+        let mean = final_mass_matrix(DiagMassMatrixEstimator::DrawVariance);
+        assert!((0.95..1.4).contains(&mean), "{}", mean);
+        let mean = final_mass_matrix(DiagMassMatrixEstimator::DrawGradVariance);
+        assert!((0.5..0.8).contains(&mean), "{}", mean);
+    }
+
+    #[test]
+    fn dense_mass_matrix() {
+        use crate::{new_dense_sampler, test_logps::NormalLogpError};
+
+        // A bivariate normal with correlation 0.99 and unit variances
+        struct Correlated {}
+
+        impl CpuLogpFunc for Correlated {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                2
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                let rho = 0.99;
+                let (x, y) = (position[0], position[1]);
+                let scale = 1. / (1. - rho * rho);
+                grad[0] = -scale * (x - rho * y);
+                grad[1] = -scale * (y - rho * x);
+                Ok(-scale * (x * x - 2. * rho * x * y + y * y) / 2.)
+            }
+        }
+
+        let mut settings = SamplerArgs {
+            num_tune: 1000,
+            ..Default::default()
+        };
+        settings.dense_mass_matrix_adapt.store_mass_matrix = true;
+
+        fn mean_leapfrog(mut sampler: impl Chain) -> (f64, Vec<crate::nuts::SampleStatItem>) {
+            sampler.set_position(&[0.5, 0.5]).unwrap();
+            let mut stats = vec![];
+            for _ in 0..1500 {
+                stats.push(sampler.draw().unwrap().1);
+            }
+            let n_leapfrog = stats[1000..]
+                .iter()
+                .map(|stats| stats.n_leapfrog() as f64)
+                .sum::<f64>()
+                / 500.;
+            (n_leapfrog, stats.pop().unwrap().to_vec())
+        }
+
+        let (dense, stats) =
+            mean_leapfrog(new_dense_sampler(Correlated {}, settings, 0, 42).unwrap());
+        let (diag, _) = mean_leapfrog(new_sampler(Correlated {}, settings, 0, 42));
+        assert!(dense < diag / 2., "{} {}", dense, diag);
+
+        let get = |name| {
+            stats
+                .iter()
+                .find(|(key, _)| *key == name)
+                .unwrap()
+                .1
+                .clone()
+        };
+        assert!(matches!(get("mass_matrix_updates"), SampleStatValue::U64(n) if n > 0));
+        let inv_mass = match get("mass_matrix_inv") {
+            SampleStatValue::OptionArray(Some(val)) => val,
+            _ => panic!("Mass matrix not stored"),
+        };
+        assert_eq!(inv_mass.len(), 4);
+        assert!((inv_mass[1] - inv_mass[2]).abs() < 1e-12);
+        let corr = inv_mass[1] / (inv_mass[0] * inv_mass[3]).sqrt();
+        assert!(corr > 0.95, "{:?}", inv_mass);
+    }
+
+    #[test]
+    fn fixed_dense_mass_matrix() {
+        use crate::{new_fixed_dense_sampler, test_logps::NormalLogpError, DenseMetric};
+
+        // A bivariate normal with correlation 0.99 and unit variances
+        struct Correlated {}
+
+        impl CpuLogpFunc for Correlated {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                2
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                let rho = 0.99;
+                let (x, y) = (position[0], position[1]);
+                let scale = 1. / (1. - rho * rho);
+                grad[0] = -scale * (x - rho * y);
+                grad[1] = -scale * (y - rho * x);
+                Ok(-scale * (x * x - 2. * rho * x * y + y * y) / 2.)
+            }
+        }
+
+        let settings = SamplerArgs {
+            num_tune: 1000,
+            ..Default::default()
+        };
+
+        fn mean_leapfrog(mut sampler: impl Chain) -> (f64, Vec<crate::nuts::SampleStatItem>) {
+            sampler.set_position(&[0.5, 0.5]).unwrap();
+            let mut stats = vec![];
+            for _ in 0..1500 {
+                stats.push(sampler.draw().unwrap().1);
+            }
+            let n_leapfrog = stats[1000..]
+                .iter()
+                .map(|stats| stats.n_leapfrog() as f64)
+                .sum::<f64>()
+                / 500.;
+            (n_leapfrog, stats.pop().unwrap().to_vec())
+        }
+
+        let (diag, _) = mean_leapfrog(new_sampler(Correlated {}, settings, 0, 42));
+        let cov = [1., 0.99, 0.99, 1.];
+        let chol = [1., 0., 0.99, (1f64 - 0.99 * 0.99).sqrt()];
+        let (fixed, _) = mean_leapfrog(
+            new_fixed_dense_sampler(
+                Correlated {},
+                DenseMetric::Covariance(&cov),
+                settings,
+                0,
+                42,
+            )
+            .unwrap(),
+        );
+        assert!(fixed < diag / 2., "{} {}", fixed, diag);
+        let (fixed_chol, fixed_stats) = mean_leapfrog(
+            new_fixed_dense_sampler(Correlated {}, DenseMetric::Cholesky(&chol), settings, 0, 42)
+                .unwrap(),
+        );
+        assert!((fixed - fixed_chol).abs() < 0.5, "{} {}", fixed, fixed_chol);
+        assert!(!fixed_stats
+            .iter()
+            .any(|(key, _)| *key == "mass_matrix_updates"));
+
+        for metric in [
+            DenseMetric::Covariance(&[1., 2., 2., 1.]),
+            DenseMetric::Covariance(&[1., 0.5, 0.4, 1.]),
+            DenseMetric::Cholesky(&[1., 0.5, 0.5, 1.]),
+            DenseMetric::Cholesky(&[1., 0., 0.5, -1.]),
+        ] {
+            let err = new_fixed_dense_sampler(Correlated {}, metric, settings, 0, 42).err();
+            assert!(matches!(err, Some(NutsError::InvalidDenseMetric)));
+        }
+    }
+
+    #[test]
+    fn invalid_dense_shrinkage() {
+        use crate::{new_dense_sampler, DenseShrinkage};
+
+        for weight in [-0.1, 1.5, f64::NAN] {
+            let mut settings = SamplerArgs::default();
+            settings.dense_mass_matrix_adapt.shrinkage = DenseShrinkage::Fixed(weight);
+            let sampler = new_dense_sampler(NormalLogp::new(3, 0.), settings, 0, 42);
+            assert!(matches!(sampler, Err(NutsError::InvalidSettings(_))));
+        }
+    }
+
+    #[test]
+    fn external_metric() {
+        let logp = NormalLogp::new(3, 0.);
+        let mut settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
+        settings.mass_matrix_adapt.store_mass_matrix = true;
+        let mut sampler = new_sampler(logp, settings, 0, 42);
+        sampler.set_position(&[0.5; 3]).unwrap();
+        for _ in 0..150 {
+            sampler.draw().unwrap();
+        }
+
+        let variance = [0.5, 1., 2.];
+        sampler.set_metric(&variance).unwrap();
+        let (draw, stats) = sampler.draw().unwrap();
+        assert!(draw.iter().all(|val| val.is_finite()));
+        let diag = match stats
+            .to_vec()
+            .into_iter()
+            .find(|(key, _)| *key == "mass_matrix_inv")
+        {
+            Some((_, SampleStatValue::OptionArray(Some(val)))) => val,
+            _ => panic!("Mass matrix not stored"),
+        };
+        assert_eq!(&diag[..], &variance[..]);
+
+        let err = sampler.set_metric(&[1., f64::NAN, 1.]).unwrap_err();
+        assert!(matches!(err, NutsError::InvalidMetric { index: 1, .. }));
+        let err = sampler.set_metric(&[1., 1.]).unwrap_err();
+        assert!(matches!(
+            err,
+            NutsError::DimensionMismatch {
+                expected: 3,
+                got: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn custom_termination_criterion() {
+        let draws = |criterion: Option<GeneralizedUTurn>| {
+            let mut sampler = new_sampler(NormalLogp::new(5, 0.), SamplerArgs::default(), 0, 42);
+            if let Some(criterion) = criterion {
+                sampler.set_termination_criterion(criterion);
+            }
+            sampler.set_position(&[0.5; 5]).unwrap();
+            (0..50)
+                .map(|_| sampler.draw().unwrap().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(None), draws(Some(GeneralizedUTurn {})));
+
+        let settings = SamplerArgs {
+            maxdepth: 4,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(5, 0.), settings, 0, 42);
+        sampler.set_termination_criterion(NeverTurn {});
+        sampler.set_position(&[0.5; 5]).unwrap();
+        for _ in 0..20 {
+            let (_, stats) = sampler.draw().unwrap();
+            assert!(stats.maxdepth_reached() | stats.divergence_info().is_some());
+        }
+    }
+
+    #[test]
+    fn step_size_fn() {
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        };
+
+        let draws = |scale: Option<f64>| {
+            let mut sampler = new_sampler(NormalLogp::new(5, 0.), SamplerArgs::default(), 0, 42);
+            if let Some(scale) = scale {
+                sampler.set_step_size_fn(move |_| scale);
+            }
+            sampler.set_position(&[0.5; 5]).unwrap();
+            (0..50)
+                .map(|_| sampler.draw().unwrap().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(None), draws(Some(1.)));
+
+        // The step size is scaled once per leapfrog step
+        let calls = Arc::new(AtomicU64::new(0));
+        let calls_inner = calls.clone();
+        let mut sampler = new_sampler(NormalLogp::new(5, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_step_size_fn(move |position| {
+            assert_eq!(position.len(), 5);
+            calls_inner.fetch_add(1, Ordering::Relaxed);
+            0.5
+        });
+        sampler.set_position(&[0.5; 5]).unwrap();
+        let mut n_leapfrog = 0;
+        for _ in 0..50 {
+            let (_, stats) = sampler.draw().unwrap();
+            n_leapfrog += stats.n_leapfrog();
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), n_leapfrog);
+
+        // The integration time adds up the scaled step sizes
+        let settings = SamplerArgs {
+            num_tune: 0,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(5, 0.), settings, 0, 42);
+        sampler.set_step_size_fn(|position| if position[0] > 0. { 0.5 } else { 0.25 });
+        sampler.set_position(&[0.5; 5]).unwrap();
+        let step_size = sampler.step_size();
+        for _ in 0..50 {
+            let (_, stats) = sampler.draw().unwrap();
+            let unscaled = step_size * ((1u64 << stats.depth()) - 1) as f64;
+            let time = stats.integration_time();
+            assert!((0.25 * unscaled - 1e-12..=0.5 * unscaled + 1e-12).contains(&time));
+        }
+
+        let mut sampler = new_sampler(NormalLogp::new(5, 0.), SamplerArgs::default(), 0, 42);
+        sampler.set_step_size_fn(|position| if position[0] > 0. { -1. } else { 1. });
+        sampler.set_position(&[0.5; 5]).unwrap();
+        assert!(matches!(
+            sampler.draw(),
+            Err(NutsError::InvalidStepSizeMultiplier(val)) if val == -1.
+        ));
+    }
+
+    #[test]
+    fn merge_audit() {
+        use crate::MergeAudit;
+        use std::sync::Arc;
+
+        for gumbel_selection in [false, true] {
+            let settings = SamplerArgs {
+                num_tune: 100,
+                gumbel_selection,
+                ..Default::default()
+            };
+            let records = Arc::new(Mutex::new(Vec::<MergeAudit>::new()));
+            let records_inner = records.clone();
+            let mut sampler = new_sampler(NormalLogp::new(10, 0.), settings, 0, 42);
+            sampler.set_merge_audit(move |record| records_inner.lock().unwrap().push(*record));
+            sampler.set_position(&[0.5; 10]).unwrap();
+            for _ in 0..1000 {
+                sampler.draw().unwrap();
+            }
+
+            let records = records.lock().unwrap();
+            for is_main in [false, true] {
+                let (mut accepted, mut expected, mut var) = (0f64, 0f64, 0f64);
+                for record in records.iter().filter(|record| record.is_main == is_main) {
+                    assert!((0f64..=1f64).contains(&record.accept_prob));
+                    if is_main {
+                        assert!(record.accept_prob >= record.multinomial_prob);
+                    } else {
+                        assert_eq!(record.accept_prob, record.multinomial_prob);
+                    }
+                    accepted += record.accepted as u8 as f64;
+                    expected += record.accept_prob;
+                    var += record.accept_prob * (1. - record.accept_prob);
+                }
+                assert!(var > 10.);
+                assert!(
+                    (accepted - expected).abs() < 4. * var.sqrt(),
+                    "{} {} {}",
+                    accepted,
+                    expected,
+                    var
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gumbel_selection() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            gumbel_selection: true,
+            check_invariants: true,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(3, 1.), settings, 0, 42);
+        let (means, vars) = sample_moments(&mut sampler, &[0.; 3], 2000);
+        assert!((means[0] - 1.).abs() < 0.15, "mean {}", means[0]);
+        assert!((vars[0] - 1.).abs() < 0.2, "var {}", vars[0]);
+    }
+
+    #[test]
+    fn step_size_sampler_identity_metric() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_step_size_sampler(NormalLogp::new(3, 1.), settings, 0, 42);
+        let (means, _) = sample_moments(&mut sampler, &[0.; 3], 1000);
+        assert!((means[0] - 1.).abs() < 0.2, "mean {}", means[0]);
+    }
+
+    #[test]
+    fn curvature_diagnostics() {
+        fn stat(stats: &impl SampleStats, name: &str) -> Option<f64> {
+            match stats.to_vec().into_iter().find(|(key, _)| *key == name) {
+                Some((_, SampleStatValue::OptionF64(val))) => val,
+                _ => panic!("Missing stat {}", name),
+            }
+        }
+
+        let settings = SamplerArgs {
+            num_tune: 0,
+            curvature_diagnostics: true,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(NormalLogp::new(3, 1.), settings, 0, 42);
+        sampler.set_position(&[0.5; 3]).unwrap();
+        sampler.set_metric(&[0.25; 3]).unwrap();
+        let (_, stats) = sampler.draw().unwrap();
+        // The hessian is the identity, so the preconditioned curvature is the
+        // inverse mass matrix.
+        let curvature = stat(&stats, "max_curvature").unwrap();
+        assert!((curvature - 0.25).abs() < 1e-10, "{}", curvature);
+        let stable_step_size = stat(&stats, "stable_step_size").unwrap();
+        assert!((stable_step_size - 4.).abs() < 1e-8, "{}", stable_step_size);
+
+        let mut sampler = new_sampler(NormalLogp::new(3, 1.), SamplerArgs::default(), 0, 42);
+        sampler.set_position(&[0.5; 3]).unwrap();
+        let (_, stats) = sampler.draw().unwrap();
+        assert!(stat(&stats, "max_curvature").is_none());
+        assert!(stat(&stats, "stable_step_size").is_none());
+    }
+
+    #[test]
+    fn logp_outside_support() {
+        use crate::test_logps::NormalLogpError;
+
+        struct HalfNormal {}
+
+        impl CpuLogpFunc for HalfNormal {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                3
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                if position.iter().any(|&x| x < 0.) {
+                    return Ok(f64::NEG_INFINITY);
+                }
+                grad.iter_mut().zip(position).for_each(|(g, x)| *g = -x);
+                Ok(-position.iter().map(|x| x * x).sum::<f64>() / 2.)
+            }
+        }
+
+        let settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
+        let chain = sample_sequentially(HalfNormal {}, settings, &[0.5; 3], 500, 0, 42).unwrap();
+        let mut boundary_hits = 0;
+        let mut num_outside = 0;
+        for draw in chain {
+            let (draw, stats) = draw.unwrap();
+            assert!(draw.iter().all(|&x| x >= 0.));
+            if let Some(info) = stats.divergence_info() {
+                num_outside += info.outside_support() as u64;
+            }
+            boundary_hits = match stats
+                .to_vec()
+                .into_iter()
+                .find(|(key, _)| *key == "boundary_hits")
+            {
+                Some((_, SampleStatValue::U64(val))) => val,
+                _ => panic!("Missing boundary_hits stat"),
+            };
+        }
+        assert!(num_outside > 0);
+        assert_eq!(boundary_hits, num_outside);
+
+        let mut sampler = new_sampler(HalfNormal {}, SamplerArgs::default(), 0, 42);
+        let err = sampler.set_position(&[-1.; 3]).unwrap_err();
+        assert!(matches!(err, NutsError::InitOutsideSupport));
+    }
+
+    #[test]
+    fn divergence_summaries() {
+        let run = |store_divergence_states| {
+            let mut settings = SamplerArgs {
+                num_tune: 0,
+                max_energy_error: 1.,
+                store_divergence_states,
+                ..Default::default()
+            };
+            settings.step_size_adapt.params.initial_step = 3.;
+            let chain = sample_sequentially(NormalLogp::new(3, 0.), settings, &[0.5; 3], 20, 0, 42)
+                .unwrap();
+            chain.map(|draw| draw.unwrap()).collect::<Vec<_>>()
+        };
+        let full = run(true);
+        let summary = run(false);
+        let mut num_divergences = 0;
+        for ((draw, stats), (draw_summary, stats_summary)) in full.iter().zip(summary.iter()) {
+            assert_eq!(draw, draw_summary);
+            let (info, info_summary) =
+                match (stats.divergence_info(), stats_summary.divergence_info()) {
+                    (Some(info), Some(info_summary)) => (info, info_summary),
+                    (None, None) => continue,
+                    _ => panic!("Divergences differ"),
+                };
+            num_divergences += 1;
+            assert!(info.start_location().is_some());
+            assert!(info_summary.start_location().is_none());
+            assert!(info_summary.end_location().is_none());
+            assert_eq!(info.energy_error(), info_summary.energy_error());
+            assert_eq!(
+                info.start_idx_in_trajectory(),
+                info_summary.start_idx_in_trajectory()
+            );
+            assert_eq!(
+                info.end_idx_in_trajectory(),
+                info_summary.end_idx_in_trajectory()
+            );
+        }
+        assert!(num_divergences > 0);
+    }
+
+    #[test]
+    fn stat_schema() {
+        let mut settings = SamplerArgs {
+            num_tune: 10,
+            max_energy_error: 1.,
+            store_gradient: true,
+            ..Default::default()
+        };
+        settings.step_size_adapt.params.initial_step = 3.;
+        settings.step_size_adapt.store_depth_accept = true;
+        settings.mass_matrix_adapt.store_mass_matrix = true;
+        let mut sampler = new_sampler(NormalLogp::new(3, 0.), settings, 0, 42);
+        sampler.set_trajectory_expectation(2, |x, out| out.copy_from_slice(&x[..2]));
+        sampler.set_position(&[0.5; 3]).unwrap();
+        let schema = sampler.stat_schema();
+
+        let mut num_divergences = 0;
+        for _ in 0..20 {
+            let (_, stats) = sampler.draw().unwrap();
+            let diverging = stats.divergence_info().is_some();
+            num_divergences += diverging as u64;
+            let fields: Vec<_> = schema
+                .iter()
+                .filter(|field| diverging | !field.diverging_only)
+                .collect();
+            let values = stats.to_vec();
+            assert_eq!(fields.len(), values.len());
+            for (field, (name, value)) in fields.iter().zip(values.iter()) {
+                assert_eq!(field.name, *name);
+                assert_eq!(field.kind, value.kind());
+                let length = match value {
+                    SampleStatValue::Array(val) => Some(val.len()),
+                    SampleStatValue::OptionArray(val) => val.as_ref().map(|val| val.len()),
+                    _ => None,
+                };
+                if let (Some(expected), Some(length)) = (field.length, length) {
+                    assert_eq!(expected, length, "{}", name);
+                }
+            }
+        }
+        assert!(num_divergences > 0);
+
+        let length = |name: &str| {
+            schema
+                .iter()
+                .find(|field| field.name == name)
+                .unwrap()
+                .length
+        };
+        assert_eq!(length("gradient"), Some(3));
+        assert_eq!(length("mass_matrix_inv"), Some(3));
+        assert_eq!(length("trajectory_expectation"), Some(2));
+        assert_eq!(length("divergence_start"), Some(3));
+        assert_eq!(length("depth_accept_mean"), None);
+    }
+
+    #[test]
+    fn retry_failed_steps() {
+        use crate::LogpError;
+        use thiserror::Error;
+
+        #[derive(Debug, Error)]
+        #[error("Solver did not converge")]
+        struct SolverError;
+
+        impl LogpError for SolverError {
+            fn is_recoverable(&self) -> bool {
+                true
+            }
+        }
+
+        /// Fails with a recoverable error on the second evaluation, which is
+        /// the first leapfrog step.
+        struct FailOnce {
+            calls: u64,
+        }
+
+        impl CpuLogpFunc for FailOnce {
+            type Err = SolverError;
+
+            fn dim(&self) -> usize {
+                3
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, SolverError> {
+                self.calls += 1;
+                if self.calls == 2 {
+                    return Err(SolverError);
+                }
+                grad.iter_mut().zip(position).for_each(|(g, x)| *g = -x);
+                Ok(-position.iter().map(|x| x * x).sum::<f64>() / 2.)
+            }
+        }
+
+        let first_draw = |max_step_retries| {
+            let settings = SamplerArgs {
+                max_step_retries,
+                ..Default::default()
+            };
+            let mut sampler = new_sampler(FailOnce { calls: 0 }, settings, 0, 42);
+            sampler.set_position(&[0.5; 3]).unwrap();
+            let (_, stats) = sampler.draw().unwrap();
+            let retries = match stats
+                .to_vec()
+                .into_iter()
+                .find(|(key, _)| *key == "step_retries")
+            {
+                Some((_, SampleStatValue::U64(val))) => val,
+                _ => panic!("Missing step_retries stat"),
+            };
+            (stats.divergence_info().is_some(), retries)
+        };
+        assert_eq!(first_draw(0), (true, 0));
+        assert_eq!(first_draw(2), (false, 1));
+    }
+
+    #[test]
+    fn downcast_logp_error() {
+        use crate::LogpError;
+        use thiserror::Error;
+
+        #[derive(Debug, Error)]
+        enum SolverError {
+            #[error("solver did not reach tolerance")]
+            Tolerance,
+        }
+
+        impl LogpError for SolverError {
+            fn is_recoverable(&self) -> bool {
+                true
+            }
+        }
+
+        struct BoundedLogp {}
+
+        impl CpuLogpFunc for BoundedLogp {
+            type Err = SolverError;
+
+            fn dim(&self) -> usize {
+                2
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, SolverError> {
+                if position.iter().any(|x| x.abs() > 1.) {
+                    return Err(SolverError::Tolerance);
+                }
+                grad.iter_mut().zip(position).for_each(|(g, x)| *g = -x);
+                Ok(-position.iter().map(|x| x * x).sum::<f64>() / 2.)
+            }
+        }
+
+        let settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        let chain = sample_sequentially(BoundedLogp {}, settings, &[0.; 2], 200, 0, 42).unwrap();
+        let mut found = false;
+        for draw in chain {
+            let (_, stats) = draw.unwrap();
+            if let Some(info) = stats.divergence_info() {
+                if info.logp_function_error().is_some() {
+                    let err = info.logp_function_error_as::<SolverError>();
+                    assert!(matches!(err, Some(SolverError::Tolerance)));
+                    assert!(info.logp_function_error_as::<NutsError>().is_none());
+                    found = true;
+                }
+            }
+        }
+        assert!(found);
+    }
+
+    #[test]
+    fn catch_logp_panics() {
+        use crate::{test_logps::NormalLogpError, LogpPanic};
+
+        struct PanickingLogp {}
+
+        impl CpuLogpFunc for PanickingLogp {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                2
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                if position[0] > 1. {
+                    panic!("position {} is too large", position[0]);
+                }
+                grad.iter_mut().zip(position).for_each(|(g, x)| *g = -x);
+                Ok(-position.iter().map(|x| x * x).sum::<f64>() / 2.)
+            }
+        }
+
+        let settings = SamplerArgs {
+            catch_logp_panics: true,
+            ..Default::default()
+        };
+        let mut chain = sample_sequentially(PanickingLogp {}, settings, &[0.; 2], 200, 0, 42)
+            .unwrap()
+            .skip_while(|draw| draw.is_ok());
+        let Some(Err(NutsError::LogpFailure(err))) = chain.next() else {
+            panic!("Sampling should fail");
+        };
+        let panic = err.downcast_ref::<LogpPanic>().unwrap();
+        assert!(panic.message.contains("is too large"), "{}", panic.message);
+
+        let mut sampler = new_sampler(PanickingLogp {}, settings, 0, 42);
+        let err = sampler.set_position(&[2.; 2]).unwrap_err();
+        assert!(matches!(err, NutsError::LogpFailure(_)));
+    }
+
+    #[test]
+    fn energy_error_histogram() {
+        fn histogram(stats: &impl SampleStats) -> Option<Box<[f64]>> {
+            match stats
+                .to_vec()
+                .into_iter()
+                .find(|(key, _)| *key == "energy_error_histogram")
+            {
+                Some((_, SampleStatValue::OptionArray(val))) => val,
+                _ => panic!("Missing energy error histogram"),
+            }
+        }
+
+        let bins = EnergyErrorBins::new(1e-4, 1e2, 6).unwrap();
+        assert_eq!(bins.bin(5e-5), 0);
+        assert_eq!(bins.bin(-2e-4), 1);
+        assert_eq!(bins.bin(1e-3), 2);
+        assert_eq!(bins.bin(99.), 6);
+        assert_eq!(bins.bin(1e2), 7);
+        assert_eq!(bins.bin(f64::NAN), 7);
+        for (lower, upper, num_bins) in [
+            (0., 1., 3),
+            (1., 1., 3),
+            (1., f64::INFINITY, 3),
+            (1., 2., 0),
+        ] {
+            assert!(matches!(
+                EnergyErrorBins::new(lower, upper, num_bins),
+                Err(NutsError::InvalidSettings(_))
+            ));
+        }
+
+        let settings = SamplerArgs {
+            num_tune: 100,
+            energy_error_histogram: Some(bins),
+            ..Default::default()
+        };
+        let chain =
+            sample_sequentially(NormalLogp::new(5, 1.), settings, &[0.; 5], 300, 0, 42).unwrap();
+        let mut n_leapfrog = 0;
+        let mut counts = Box::default();
+        for draw in chain {
+            let (_, stats) = draw.unwrap();
+            n_leapfrog += stats.n_leapfrog();
+            counts = histogram(&stats).unwrap();
+        }
+        assert_eq!(counts.len(), 8);
+        assert_eq!(counts.iter().sum::<f64>(), n_leapfrog as f64);
+        // A tuned step size on a normal gives moderate energy errors
+        assert!(counts[2..6].iter().sum::<f64>() > 0.5 * n_leapfrog as f64);
+
+        let mut sampler = new_sampler(NormalLogp::new(5, 1.), SamplerArgs::default(), 0, 42);
+        sampler.set_position(&[0.; 5]).unwrap();
+        let (_, stats) = sampler.draw().unwrap();
+        assert!(histogram(&stats).is_none());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn sample_parallel_dedicated_pools() {
+        let logp = NormalLogp::new(10, 0.1);
+        let settings = SamplerArgs {
+            num_tune: 50,
+            parallelism: ParallelismSettings {
+                chain_threads: Some(2),
+                logp_threads: 2,
+                schedule_draws: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains) =
+            sample_parallel(maker, &mut JitterInitFunc::new(), settings, 3, 50, 42, 10).unwrap();
+        assert_eq!(chains.iter().count(), 300);
+        let results = handle.join().unwrap();
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let settings = SamplerArgs {
+            parallelism: ParallelismSettings {
+                logp_threads: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker {
+            logp: NormalLogp::new(10, 0.1),
+        };
+        let result = sample_parallel(maker, &mut JitterInitFunc::new(), settings, 3, 50, 42, 10);
+        assert!(matches!(
+            result,
+            Err(super::ParallelSamplingError::NutsError {
+                source: NutsError::InvalidSettings(_)
+            })
+        ));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn pinned_logp_pools() {
+        use crate::test_logps::NormalLogpError;
+
+        /// Checks that the threads of the logp pool run on a single core
+        #[derive(Clone)]
+        struct Pinned {
+            logp: NormalLogp,
+        }
+
+        impl CpuLogpFunc for Pinned {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                self.logp.dim()
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                #[cfg(all(feature = "affinity", target_os = "linux"))]
+                {
+                    use rayon::prelude::*;
+
+                    (0..16).into_par_iter().for_each(|_| unsafe {
+                        let mut set: libc::cpu_set_t = std::mem::zeroed();
+                        let size = std::mem::size_of::<libc::cpu_set_t>();
+                        libc::sched_getaffinity(0, size, &mut set);
+                        assert_eq!(libc::CPU_COUNT(&set), 1);
+                    });
+                }
+                self.logp.logp(position, grad)
+            }
+        }
+
+        impl CpuLogpFuncMaker for Pinned {
+            type Func = Self;
+
+            fn make_logp_func(&self) -> Result<Self, Box<dyn Error + Send + Sync>> {
+                Ok(self.clone())
+            }
+
+            fn dim(&self) -> usize {
+                self.logp.dim()
+            }
+        }
+
+        for logp_threads in [1, 2] {
+            let settings = SamplerArgs {
+                num_tune: 50,
+                parallelism: ParallelismSettings {
+                    chain_threads: Some(2),
+                    logp_threads,
+                    pin_threads: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let maker = Pinned {
+                logp: NormalLogp::new(10, 0.1),
+            };
+            let (handle, chains) =
+                sample_parallel(maker, &mut JitterInitFunc::new(), settings, 3, 50, 42, 10)
+                    .unwrap();
+            assert_eq!(chains.iter().count(), 300);
+            let results = handle.join().unwrap();
+            assert!(results.iter().all(|result| result.is_ok()));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn scheduled_draws() {
+        let logp = NormalLogp::new(10, 0.1);
+        let settings = SamplerArgs {
+            num_tune: 50,
+            parallelism: ParallelismSettings {
+                chain_threads: Some(2),
+                schedule_draws: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains) =
+            sample_parallel(maker, &mut JitterInitFunc::new(), settings, 5, 50, 42, 10).unwrap();
+        let mut counts = [0u64; 5];
+        for (_, stats) in chains.iter() {
+            counts[stats.chain() as usize] += 1;
+        }
+        assert_eq!(counts, [100; 5]);
+        let results = handle.join().unwrap();
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn draw_scheduler_order() {
+        let scheduler = DrawScheduler::new(1);
+        let order = Mutex::new(vec![]);
+        std::thread::scope(|scope| {
+            let permit = scheduler.acquire(0, 0);
+            for (chain, num_draws) in [(1, 7), (2, 3), (3, 5)] {
+                let (scheduler, order) = (&scheduler, &order);
+                scope.spawn(move || {
+                    let _permit = scheduler.acquire(chain, num_draws);
+                    order.lock().unwrap().push(chain);
+                });
+            }
+            while scheduler.state.lock().unwrap().waiting.len() < 3 {
+                std::thread::yield_now();
+            }
+            drop(permit);
+        });
+        // The chain with the fewest draws goes first
+        assert_eq!(*order.lock().unwrap(), vec![2, 3, 1]);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn monitored_stats() {
+        let logp = NormalLogp::new(10, 0.1);
+        let settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains, monitor) =
+            sample_parallel_monitored(maker, &mut JitterInitFunc::new(), settings, 3, 50, 42, 10)
+                .unwrap();
+        let draws: Vec<_> = chains.iter().collect();
+        assert!(handle.join().unwrap().iter().all(|result| result.is_ok()));
+
+        let snapshot = monitor.snapshot_stats();
+        assert_eq!(snapshot.len(), 3);
+        assert!(snapshot.iter().all(|chain| chain.num_draws == 100));
+
+        let total = monitor.total();
+        assert_eq!(total.num_draws, 300);
+        let n_leapfrog: u64 = draws.iter().map(|(_, stats)| stats.n_leapfrog()).sum();
+        assert_eq!(total.n_leapfrog, n_leapfrog);
+        assert!(monitor.run_state().is_none());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn heartbeat() {
+        let logp = NormalLogp::new(10, 0.1);
+        let settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains, monitor) =
+            sample_parallel_monitored(maker, &mut JitterInitFunc::new(), settings, 2, 100, 42, 10)
+                .unwrap();
+        let beats = std::sync::Arc::new(Mutex::new(vec![]));
+        let heartbeat = {
+            let beats = beats.clone();
+            monitor.heartbeat(Duration::from_millis(1), move |chains| {
+                beats.lock().unwrap().push(chains.to_vec())
+            })
+        };
+        let _draws: Vec<_> = chains.iter().collect();
+        assert!(handle.join().unwrap().iter().all(|result| result.is_ok()));
+        heartbeat.join().unwrap();
+
+        let beats = beats.lock().unwrap();
+        let last = beats.last().unwrap();
+        assert_eq!(last.len(), 2);
+        assert!(last
+            .iter()
+            .all(|chain| chain.finished & (chain.num_draws == 200)));
+        assert!(beats.iter().any(|beat| beat[0].draws_per_second > 0.));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn heartbeat_after_panic() {
+        use crate::test_logps::NormalLogpError;
+
+        /// A standard normal whose logp panics after 100 evaluations
+        struct Panicking {
+            count: u64,
+        }
+
+        impl CpuLogpFunc for Panicking {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                2
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                self.count += 1;
+                assert!(self.count < 100, "logp failed");
+                grad.iter_mut().zip(position).for_each(|(g, x)| *g = -x);
+                Ok(-position.iter().map(|x| x * x).sum::<f64>() / 2.)
+            }
+        }
+
+        struct Maker {}
+
+        impl CpuLogpFuncMaker for Maker {
+            type Func = Panicking;
+
+            fn make_logp_func(&self) -> Result<Self::Func, Box<dyn Error + Send + Sync>> {
+                Ok(Panicking { count: 0 })
+            }
+
+            fn dim(&self) -> usize {
+                2
+            }
+        }
+
+        let settings = SamplerArgs::default();
+        let (handle, chains, monitor) = sample_parallel_monitored(
+            Maker {},
+            &mut JitterInitFunc::new(),
+            settings,
+            2,
+            100,
+            42,
+            10,
+        )
+        .unwrap();
+        let heartbeat = monitor.heartbeat(Duration::from_millis(1), |_| {});
+        let _draws: Vec<_> = chains.iter().collect();
+        let _ = handle.join();
+        // The heartbeat ends once the panicking chains count as finished
+        heartbeat.join().unwrap();
+        assert!(monitor.is_finished());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn monitored_draws() {
+        let logp = NormalLogp::new(3, 0.);
+        let mut settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        settings.parallelism.monitor_draws = true;
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains, monitor) =
+            sample_parallel_monitored(maker, &mut JitterInitFunc::new(), settings, 3, 200, 42, 10)
+                .unwrap();
+
+        let diagnostics = {
+            let monitor = monitor.clone();
+            std::thread::spawn(move || {
+                let mut num_checks = 0;
+                loop {
+                    // Also stop if a chain failed before its last draw
+                    let finished = monitor.is_finished();
+                    let state = monitor.run_state().unwrap();
+                    assert!(state.draws.iter().all(|chain| chain.len() <= 200));
+                    if let Some(r_hat) = state.max_r_hat() {
+                        assert!(r_hat.is_finite());
+                        num_checks += 1;
+                    }
+                    if finished {
+                        return num_checks;
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        let draws: Vec<_> = chains
+            .iter()
+            .filter(|(_, stats)| stats.draw() >= 50)
+            .collect();
+        assert!(handle.join().unwrap().iter().all(|result| result.is_ok()));
+        assert!(diagnostics.join().unwrap() > 0);
+
+        let state = monitor.run_state().unwrap();
+        assert_eq!(state.dim, 3);
+        assert_eq!(state.num_complete_draws(), 200);
+        for (draw, stats) in draws {
+            let chain = &state.draws[stats.chain() as usize];
+            assert_eq!(chain[stats.draw() as usize - 50][..], draw[..]);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn draw_buffer_backpressure() {
+        let logp = NormalLogp::new(10, 0.1);
+        let mut settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        settings.parallelism.draw_buffer = 4;
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains, monitor) =
+            sample_parallel_monitored(maker, &mut JitterInitFunc::new(), settings, 2, 50, 42, 10)
+                .unwrap();
+
+        chains.recv().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        // One received draw, a full buffer and one blocked draw per chain
+        assert!(monitor.total().num_draws <= 1 + 4 + 2);
+
+        assert_eq!(chains.iter().count(), 199);
+        assert!(handle.join().unwrap().iter().all(|result| result.is_ok()));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn pooled_adaptation() {
+        use crate::test_logps::NormalLogpError;
+
+        // The mass matrix estimate of a normal distribution does not depend
+        // on the draws, so use `p(x) = prod 1 / cosh(x_i)`
+        #[derive(Clone)]
+        struct Sech {}
+
+        impl CpuLogpFunc for Sech {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                5
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                grad.iter_mut()
+                    .zip(position)
+                    .for_each(|(g, x)| *g = -x.tanh());
+                Ok(-position.iter().map(|x| x.cosh().ln()).sum::<f64>())
+            }
+        }
+
+        impl CpuLogpFuncMaker for Sech {
+            type Func = Sech;
+
+            fn make_logp_func(&self) -> Result<Sech, Box<dyn Error + Send + Sync>> {
+                Ok(self.clone())
+            }
+
+            fn dim(&self) -> usize {
+                5
+            }
+        }
+
+        let run = |pool_adaptation| {
+            let mut settings = SamplerArgs {
+                num_tune: 300,
+                ..Default::default()
+            };
+            settings.mass_matrix_adapt.store_mass_matrix = true;
+            settings.parallelism.pool_adaptation = pool_adaptation;
+            settings.parallelism.chain_threads = Some(1);
+            let (handle, chains) =
+                sample_parallel(Sech {}, &mut JitterInitFunc::new(), settings, 4, 50, 42, 10)
+                    .unwrap();
+            let mut metrics = vec![vec![None; 350]; 4];
+            let mut step_sizes = vec![vec![None; 350]; 4];
+            for (_, stats) in chains.iter() {
+                let (chain, draw) = (stats.chain() as usize, stats.draw() as usize);
+                for (key, val) in stats.to_vec() {
+                    match (key, val) {
+                        ("mass_matrix_inv", SampleStatValue::OptionArray(val)) => {
+                            metrics[chain][draw] = val
+                        }
+                        ("step_size", SampleStatValue::F64(val)) => {
+                            step_sizes[chain][draw] = Some(val)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            assert!(handle.join().unwrap().iter().all(|result| result.is_ok()));
+            // The draws after the first window at which all chains have the
+            // same mass matrix or step size
+            fn agree<T: PartialEq>(vals: &[Vec<Option<T>>]) -> usize {
+                (60..350)
+                    .filter(|&draw| vals.iter().all(|chain| chain[draw] == vals[0][draw]))
+                    .count()
+            }
+            (agree(&metrics), agree(&step_sizes))
+        };
+        // The estimates only agree after the pooled estimates are used
+        let (metrics, step_sizes) = run(true);
+        assert!(metrics > 0);
+        assert!(step_sizes > 0);
+        assert_eq!(run(false), (0, 0));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn chain_metadata() {
+        let logp = NormalLogp::new(10, 0.1);
+        let settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker { logp };
+        let (handle, chains) =
+            sample_parallel(maker, &mut JitterInitFunc::new(), settings, 3, 20, 42, 10).unwrap();
+        let first_draws: Vec<_> = chains
+            .iter()
+            .filter(|(_, stats)| stats.draw() == 0)
+            .map(|(_, stats)| (stats.chain(), stats.draw_seed()))
+            .collect();
+        let results = handle.join().unwrap();
+        assert_eq!(results.len(), 3);
+        for result in results {
+            let metadata = result.unwrap();
+            assert_eq!(metadata.seed, 42 + metadata.chain);
+            assert_eq!(metadata.init_point.len(), 10);
+            assert_eq!(metadata.num_tune, 50);
+            assert_eq!(metadata.num_draws, 70);
+            assert_eq!(metadata.termination, ChainTermination::Finished);
+            assert!(metadata.tuning_time > Duration::ZERO);
+            assert!(metadata.sampling_time > Duration::ZERO);
+            // The seed reproduces the draw seeds of the chain
+            let draw_seed = crate::draw_seed(metadata.seed, metadata.chain, 0);
+            assert!(first_draws.contains(&(metadata.chain, draw_seed)));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn jitter_init_dimension() {
+        use crate::{InitPointFunc, ParallelSamplingError};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        assert!(matches!(
+            JitterInitFunc::new_with_mean(vec![1.; 3].into(), 4),
+            Err(NutsError::DimensionMismatch {
+                expected: 4,
+                got: 3
+            })
+        ));
+        let mut init = JitterInitFunc::new_with_mean(vec![1.; 3].into(), 3).unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut out = [0f64; 3];
+        init.new_init_point(&mut rng, &mut out).unwrap();
+        assert!(out.iter().all(|val| (0f64..=2f64).contains(val)));
+        assert!(init.new_init_point(&mut rng, &mut [0f64; 4]).is_err());
+
+        // The error stops sampling before any chain starts
+        let maker = crate::test_logps::Maker {
+            logp: NormalLogp::new(4, 1.),
+        };
+        let result = sample_parallel(maker, &mut init, SamplerArgs::default(), 2, 10, 42, 10);
+        assert!(matches!(
+            result,
+            Err(ParallelSamplingError::NutsError {
+                source: NutsError::DimensionMismatch { .. }
+            })
+        ));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn init_from_trace() {
+        use crate::{InitPointFunc, TraceInitFunc, TraceInitStrategy};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let settings = SamplerArgs {
+            num_tune: 50,
+            ..Default::default()
+        };
+        let previous: Vec<Vec<_>> = (0..2)
+            .map(|chain| {
+                let seed = 42 + chain;
+                sample_sequentially(NormalLogp::new(4, 1.), settings, &[0.; 4], 100, chain, seed)
+                    .unwrap()
+                    .skip(50)
+                    .map(|draw| draw.unwrap())
+                    .collect()
+            })
+            .collect();
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut out = [0f64; 4];
+
+        let mut init = TraceInitFunc::from_draws(&previous, TraceInitStrategy::Last).unwrap();
+        for chain in [0, 1, 0] {
+            init.new_init_point(&mut rng, &mut out).unwrap();
+            assert_eq!(&out[..], &previous[chain][49].0[..]);
+        }
+        assert_ne!(previous[0][49].0, previous[1][49].0);
+        assert!(matches!(
+            init.new_init_point(&mut rng, &mut [0f64; 3]),
+            Err(NutsError::DimensionMismatch {
+                expected: 3,
+                got: 4
+            })
+        ));
+
+        let empty = TraceInitFunc::new(vec![vec![], vec![]], TraceInitStrategy::Last);
+        assert!(matches!(empty, Err(NutsError::InvalidSettings(_))));
+        let ragged = vec![vec![(vec![0.; 4].into(), 0.), (vec![0.; 3].into(), 0.)]];
+        let ragged = TraceInitFunc::new(ragged, TraceInitStrategy::Random);
+        assert!(matches!(ragged, Err(NutsError::DimensionMismatch { .. })));
+
+        let mut init = TraceInitFunc::from_draws(&previous, TraceInitStrategy::Random).unwrap();
+        init.new_init_point(&mut rng, &mut out).unwrap();
+        assert!(previous
+            .iter()
+            .flatten()
+            .any(|(draw, _)| draw[..] == out[..]));
+
+        let mut init = TraceInitFunc::from_draws(&previous, TraceInitStrategy::MaxLogp).unwrap();
+        let max_logp = previous
+            .iter()
+            .flatten()
+            .map(|(_, stats)| stats.logp())
+            .fold(f64::NEG_INFINITY, f64::max);
+        let maker = crate::test_logps::Maker {
+            logp: NormalLogp::new(4, 1.),
+        };
+        let (handle, chains) = sample_parallel(maker, &mut init, settings, 2, 10, 42, 10).unwrap();
+        assert_eq!(chains.iter().count(), 120);
+        let mut results: Vec<_> = handle.join().unwrap();
+        results.sort_by_key(|result| result.as_ref().unwrap().chain);
+        let metadata = results[0].as_ref().unwrap();
+        let logp = -metadata
+            .init_point
+            .iter()
+            .map(|x| (x - 1.) * (x - 1.) / 2.)
+            .sum::<f64>();
+        assert!((logp - max_logp).abs() < 1e-12, "{} {}", logp, max_logp);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn pooled_draws() {
+        let settings = SamplerArgs {
+            num_tune: 20,
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker {
+            logp: NormalLogp::new(4, 0.1),
+        };
+        let (handle, mut draws) =
+            sample_pooled(maker, &mut JitterInitFunc::new(), settings, 3, 30, 42, 10).unwrap();
+        let mut counts = [0u64; 3];
+        let mut n_leapfrog = 0;
+        for draw in draws.by_ref() {
+            assert_eq!(draw.chain, draw.stats.chain());
+            assert!(draw.stats.draw() >= 20);
+            assert_eq!(draw.position.len(), 4);
+            counts[draw.chain as usize] += 1;
+            n_leapfrog += draw.stats.n_leapfrog();
+        }
+        assert_eq!(counts, [30; 3]);
+        assert_eq!(draws.totals().num_draws, 90);
+        assert_eq!(draws.totals().n_leapfrog, n_leapfrog);
+        assert!(handle.join().unwrap().iter().all(|result| result.is_ok()));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn failed_chain() {
+        use crate::{InitPointFunc, LogpError, ParallelSamplingError};
+        use rand::Rng;
+
+        #[derive(Debug, thiserror::Error)]
+        #[error("position out of bounds")]
+        struct BoundsError {}
+
+        impl LogpError for BoundsError {
+            fn is_recoverable(&self) -> bool {
+                false
+            }
+        }
+
+        #[derive(Clone)]
+        struct Bounded {}
+
+        impl CpuLogpFunc for Bounded {
+            type Err = BoundsError;
+
+            fn dim(&self) -> usize {
+                2
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, BoundsError> {
+                if position[0] > 100. {
+                    return Err(BoundsError {});
+                }
+                grad.iter_mut().zip(position).for_each(|(g, x)| *g = -x);
+                Ok(-position.iter().map(|x| x * x).sum::<f64>() / 2.)
+            }
+        }
+
+        impl CpuLogpFuncMaker for Bounded {
+            type Func = Bounded;
+
+            fn make_logp_func(
+                &self,
+            ) -> Result<Self::Func, Box<dyn std::error::Error + Send + Sync>> {
+                Ok(self.clone())
+            }
+
+            fn dim(&self) -> usize {
+                2
+            }
+        }
+
+        /// Start the second chain outside of the bounds
+        struct BadSecondPoint {
+            count: usize,
+        }
+
+        impl InitPointFunc for BadSecondPoint {
+            fn new_init_point<R: Rng + ?Sized>(
+                &mut self,
+                _rng: &mut R,
+                out: &mut [f64],
+            ) -> Result<(), NutsError> {
+                out.fill(if self.count == 1 { 1000. } else { 0.5 });
+                self.count += 1;
+                Ok(())
+            }
+        }
+
+        let settings = SamplerArgs {
+            num_tune: 20,
+            ..Default::default()
+        };
+        let mut init = BadSecondPoint { count: 0 };
+        let (handle, chains) =
+            sample_parallel(Bounded {}, &mut init, settings, 3, 20, 42, 1).unwrap();
+        let mut counts = [0u64; 3];
+        for (_, stats) in chains.iter() {
+            counts[stats.chain() as usize] += 1;
+        }
+        assert_eq!(counts, [40, 0, 40]);
+
+        let results = handle.join().unwrap();
+        assert_eq!(results[0].as_ref().unwrap().num_draws, 40);
+        assert_eq!(results[2].as_ref().unwrap().num_draws, 40);
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(err.chain, 1);
+        assert!(matches!(
+            err.source,
+            ParallelSamplingError::InitError { .. }
+        ));
+        let metadata = err.metadata.as_ref().unwrap();
+        assert_eq!(metadata.num_draws, 0);
+        assert_eq!(&metadata.init_point[..], &[1000.; 2]);
+    }
 }
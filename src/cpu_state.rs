@@ -5,7 +5,8 @@ use std::{
     rc::{Rc, Weak},
 };
 
-use crate::math::{axpy, axpy_out, scalar_prods2, scalar_prods3};
+use crate::math::{axpy, axpy_out, compensated_scalar_prods2, scalar_prods2};
+use crate::nuts::TrajectoryEnd;
 
 #[derive(Debug)]
 struct StateStorage {
@@ -67,7 +68,6 @@ pub(crate) struct InnerState {
     pub(crate) p: Box<[f64]>,
     pub(crate) q: Box<[f64]>,
     pub(crate) v: Box<[f64]>,
-    pub(crate) p_sum: Box<[f64]>,
     pub(crate) grad: Box<[f64]>,
     pub(crate) idx_in_trajectory: i64,
     pub(crate) kinetic_energy: f64,
@@ -80,12 +80,14 @@ pub(crate) struct InnerStateReusable {
     reuser: Weak<dyn ReuseState>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub(crate) struct AlignedArray {
     size: usize,
     data: *mut f64,
 }
 
+#[allow(dead_code)]
 impl AlignedArray {
     pub(crate) fn new(size: usize) -> Self {
         let layout = AlignedArray::make_layout(size);
@@ -119,7 +121,7 @@ impl Drop for AlignedArray {
 impl Clone for AlignedArray {
     fn clone(&self) -> Self {
         let mut new = AlignedArray::new(self.size);
-        new.copy_from_slice(&self);
+        new.copy_from_slice(self);
         new
     }
 }
@@ -150,8 +152,6 @@ impl InnerStateReusable {
                 //q: AlignedArray::new(size),
                 v: vec![0.; size].into(),
                 //v: AlignedArray::new(size),
-                p_sum: vec![0.; size].into(),
-                //p_sum: AlignedArray::new(size),
                 grad: vec![0.; size].into(),
                 //grad: AlignedArray::new(size),
                 idx_in_trajectory: 0,
@@ -216,29 +216,33 @@ impl Clone for State {
 impl crate::nuts::State for State {
     type Pool = StatePool;
 
-    fn is_turning(&self, other: &Self) -> bool {
-        let (start, end) = if self.idx_in_trajectory < other.idx_in_trajectory {
-            (&*self, other)
-        } else {
-            (other, &*self)
-        };
-
-        let a = start.idx_in_trajectory;
-        let b = end.idx_in_trajectory;
-
-        assert!(a < b);
-        let (turn1, turn2) = if (a >= 0) & (b >= 0) {
-            scalar_prods3(&end.p_sum, &start.p_sum, &start.p, &end.v, &start.v)
-        } else if (b >= 0) & (a < 0) {
-            scalar_prods2(&end.p_sum, &start.p_sum, &end.v, &start.v)
-        } else {
-            assert!((a < 0) & (b < 0));
-            scalar_prods3(&start.p_sum, &end.p_sum, &end.p, &end.v, &start.v)
-        };
+    /// The generalized U-turn criterion.
+    ///
+    /// The momentum sum is projected onto the velocities `v = M⁻¹ p` of the
+    /// end points, not onto the momenta, so the criterion is invariant
+    /// under a linear reparametrization that is matched by the mass matrix.
+    fn is_turning(&self, other: &Self, sum1: &[f64], sum2: &[f64]) -> bool {
+        let (turn1, turn2) = scalar_prods2(sum1, sum2, &self.v, &other.v);
+        (turn1 < 0.) | (turn2 < 0.)
+    }
 
+    fn is_turning_reproducible(&self, other: &Self, sum1: &[f64], sum2: &[f64]) -> bool {
+        let (turn1, turn2) = compensated_scalar_prods2(sum1, sum2, &self.v, &other.v);
         (turn1 < 0.) | (turn2 < 0.)
     }
 
+    fn momentum(&self) -> &[f64] {
+        &self.p
+    }
+
+    fn trajectory_end(&self) -> TrajectoryEnd<'_> {
+        TrajectoryEnd {
+            position: &self.q,
+            momentum: &self.p,
+            velocity: &self.v,
+        }
+    }
+
     fn write_position(&self, out: &mut [f64]) {
         out.copy_from_slice(&self.q)
     }
@@ -258,7 +262,6 @@ impl crate::nuts::State for State {
     fn make_init_point(&mut self) {
         let inner = self.try_mut_inner().unwrap();
         inner.idx_in_trajectory = 0;
-        inner.p_sum.copy_from_slice(&inner.p);
     }
 
     fn potential_energy(&self) -> f64 {
@@ -286,18 +289,6 @@ impl State {
         axpy(&inner.grad, &mut inner.p, epsilon / 2.);
     }
 
-    pub(crate) fn set_psum(&self, target: &mut Self, _dir: crate::nuts::Direction) {
-        let out = target.try_mut_inner().expect("State already in use");
-
-        assert!(out.idx_in_trajectory != 0);
-
-        if out.idx_in_trajectory == -1 {
-            out.p_sum.copy_from_slice(&out.p);
-        } else {
-            axpy_out(&out.p, &self.p_sum, 1., &mut out.p_sum);
-        }
-    }
-
     pub(crate) fn index_in_trajectory(&self) -> i64 {
         self.idx_in_trajectory
     }
@@ -313,6 +304,7 @@ impl State {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use itertools::izip;
 
     #[test]
     fn crate_pool() {
@@ -333,10 +325,48 @@ mod tests {
         let a = pool.new_state();
 
         assert_eq!(a.idx_in_trajectory, 0);
-        assert!(a.p_sum.iter().all(|&x| x == 0f64));
-        assert_eq!(a.p_sum.len(), dim);
         assert_eq!(a.grad.len(), dim);
         assert_eq!(a.q.len(), dim);
         assert_eq!(a.p.len(), dim);
     }
+
+    #[test]
+    fn turning_is_metric_invariant() {
+        use crate::nuts::State as _;
+        use rand::{Rng, SeedableRng};
+
+        fn make_state(pool: &mut StatePool, p: &[f64], inv_mass: &[f64]) -> State {
+            let mut state = pool.new_state();
+            let inner = state.try_mut_inner().unwrap();
+            inner.p.copy_from_slice(p);
+            izip!(inner.v.iter_mut(), p, inv_mass).for_each(|(v, p, m)| *v = m * p);
+            state
+        }
+
+        let dim = 5;
+        let mut pool = StatePool::new(dim);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for _ in 0..300 {
+            let mut sample =
+                || -> Vec<f64> { (0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect() };
+            let (p1, p2, sum1, sum2) = (sample(), sample(), sample(), sample());
+            let inv_mass: Vec<f64> = (0..dim).map(|_| rng.gen_range(0.01..100.0)).collect();
+            let stds: Vec<f64> = inv_mass.iter().map(|x| x.sqrt()).collect();
+            let ones = vec![1f64; dim];
+            let rescale =
+                |x: &[f64]| -> Vec<f64> { x.iter().zip(&stds).map(|(x, s)| x * s).collect() };
+
+            // In the rescaled space x' = x / std the mass matrix is the
+            // identity and the momentum is p' = std * p.
+            let start = make_state(&mut pool, &p1, &inv_mass);
+            let end = make_state(&mut pool, &p2, &inv_mass);
+            let start_identity = make_state(&mut pool, &rescale(&p1), &ones);
+            let end_identity = make_state(&mut pool, &rescale(&p2), &ones);
+
+            assert_eq!(
+                start.is_turning(&end, &sum1, &sum2),
+                start_identity.is_turning(&end_identity, &rescale(&sum1), &rescale(&sum2))
+            );
+        }
+    }
 }
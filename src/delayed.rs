@@ -0,0 +1,378 @@
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::cpu_potential::CpuLogpFunc;
+use crate::nuts::{
+    check_dim, Chain, DivergenceInfo, HamiltonianSnapshot, LogpError, MergeAudit, NutsError,
+    Result, SampleStatItem, SampleStatValue, SampleStats, StatField, StatsSnapshot,
+    TerminationCriterion,
+};
+
+/// The stats of a draw of a [`DelayedAcceptanceChain`]
+///
+/// Everything except the logp describes the surrogate trajectory of the
+/// proposal, also if the proposal was rejected.
+#[derive(Debug, Clone)]
+pub struct DelayedAcceptanceStats<S> {
+    proposal: S,
+    logp: f64,
+    surrogate_logp: f64,
+    accepted: bool,
+}
+
+impl<S> DelayedAcceptanceStats<S> {
+    /// The stats of the surrogate chain for the proposal
+    pub fn proposal(&self) -> &S {
+        &self.proposal
+    }
+
+    /// Whether the proposal passed the correction with the exact logp
+    pub fn accepted(&self) -> bool {
+        self.accepted
+    }
+
+    /// The surrogate logp at the draw
+    pub fn surrogate_logp(&self) -> f64 {
+        self.surrogate_logp
+    }
+}
+
+impl<S: SampleStats> SampleStats for DelayedAcceptanceStats<S> {
+    fn depth(&self) -> u64 {
+        self.proposal.depth()
+    }
+    fn maxdepth_reached(&self) -> bool {
+        self.proposal.maxdepth_reached()
+    }
+    fn index_in_trajectory(&self) -> i64 {
+        self.proposal.index_in_trajectory()
+    }
+    fn logp(&self) -> f64 {
+        self.logp
+    }
+    fn energy(&self) -> f64 {
+        self.proposal.energy()
+    }
+    fn divergence_info(&self) -> Option<&dyn DivergenceInfo> {
+        self.proposal.divergence_info()
+    }
+    fn chain(&self) -> u64 {
+        self.proposal.chain()
+    }
+    fn draw(&self) -> u64 {
+        self.proposal.draw()
+    }
+    fn draw_seed(&self) -> u64 {
+        self.proposal.draw_seed()
+    }
+    fn n_leapfrog(&self) -> u64 {
+        self.proposal.n_leapfrog()
+    }
+    fn n_leapfrog_discarded(&self) -> u64 {
+        self.proposal.n_leapfrog_discarded()
+    }
+    fn integration_time(&self) -> f64 {
+        self.proposal.integration_time()
+    }
+    fn gradient(&self) -> Option<&[f64]> {
+        self.proposal.gradient()
+    }
+    fn trajectory_expectation(&self) -> Option<&[f64]> {
+        self.proposal.trajectory_expectation()
+    }
+    fn to_vec(&self) -> Vec<SampleStatItem> {
+        let mut vec: Vec<_> = self
+            .proposal
+            .to_vec()
+            .into_iter()
+            .map(|(name, value)| match name {
+                "logp" => (name, self.logp.into()),
+                _ => (name, value),
+            })
+            .collect();
+        vec.push(("surrogate_logp", self.surrogate_logp.into()));
+        vec.push(("delayed_accepted", self.accepted.into()));
+        vec
+    }
+}
+
+/// A sampler for models with an expensive logp function and a cheap
+/// approximation of it (delayed acceptance).
+///
+/// The wrapped chain samples with the cheap surrogate logp, and each of its
+/// draws is a proposal that is accepted with probability
+/// `min(1, exp((logp(new) - surrogate(new)) - (logp(old) - surrogate(old))))`,
+/// otherwise the chain stays at the old position. The exact logp is only
+/// evaluated once per draw, and the draws are samples from the exact
+/// posterior. Step size and mass matrix are tuned for the surrogate, so the
+/// acceptance rate of the correction is high if the surrogate is good.
+/// Recoverable errors and a logp of `-inf` of the exact logp function reject
+/// the proposal.
+pub struct DelayedAcceptanceChain<C: Chain, F: CpuLogpFunc> {
+    chain: C,
+    exact: F,
+    rng: SmallRng,
+    position: Box<[f64]>,
+    grad: Box<[f64]>,
+    inverse_temperature: f64,
+    /// The exact logp at `position`, multiplied by the inverse temperature
+    logp: f64,
+    /// The logp of `chain` at `position`
+    surrogate_logp: f64,
+}
+
+impl<C: Chain, F: CpuLogpFunc> DelayedAcceptanceChain<C, F> {
+    /// Correct the draws of `chain`, which samples from a surrogate of the
+    /// exact logp function `exact`.
+    ///
+    /// `chain` has to implement [`Chain::current_logp`] and
+    /// [`Chain::restore_position`]. Returns an error if it does not report
+    /// its logp, or if the surrogate and the exact logp have different
+    /// dimensions.
+    pub fn new(chain: C, exact: F, seed: u64) -> Result<Self> {
+        check_dim(chain.dim(), exact.dim())?;
+        if chain.current_logp().is_none() {
+            return Err(NutsError::Unsupported(
+                "delayed acceptance with a chain that does not report its logp",
+            ));
+        }
+        let dim = chain.dim();
+        Ok(Self {
+            chain,
+            exact,
+            rng: SmallRng::seed_from_u64(seed),
+            position: vec![0f64; dim].into(),
+            grad: vec![0f64; dim].into(),
+            inverse_temperature: 1f64,
+            logp: f64::NEG_INFINITY,
+            surrogate_logp: f64::NEG_INFINITY,
+        })
+    }
+
+    fn chain_logp(&self) -> Result<f64> {
+        self.chain.current_logp().ok_or(NutsError::Unsupported(
+            "delayed acceptance with a chain that does not report its logp",
+        ))
+    }
+
+    fn exact_logp(&mut self, position: &[f64]) -> Result<f64> {
+        match self.exact.logp(position, &mut self.grad) {
+            Ok(logp) if logp == f64::NEG_INFINITY => Ok(logp),
+            Ok(logp) => Ok(self.inverse_temperature * logp),
+            Err(err) if err.is_recoverable() => Ok(f64::NEG_INFINITY),
+            Err(err) => Err(NutsError::LogpFailure(Box::new(err))),
+        }
+    }
+
+    /// Evaluate both logp functions at the current position of the chain.
+    fn update_logps(&mut self) -> Result<()> {
+        let position = self.position.clone();
+        self.logp = self.exact_logp(&position)?;
+        if !self.logp.is_finite() {
+            return Err(NutsError::InitOutsideSupport);
+        }
+        self.surrogate_logp = self.chain_logp()?;
+        Ok(())
+    }
+}
+
+impl<C: Chain, F: CpuLogpFunc> Chain for DelayedAcceptanceChain<C, F> {
+    type Hamiltonian = C::Hamiltonian;
+    type AdaptStrategy = C::AdaptStrategy;
+    type Stats = DelayedAcceptanceStats<C::Stats>;
+
+    fn set_position(&mut self, position: &[f64]) -> Result<()> {
+        self.chain.set_position(position)?;
+        self.position.copy_from_slice(position);
+        self.update_logps()
+    }
+
+    fn draw(&mut self) -> Result<(Box<[f64]>, Self::Stats)> {
+        let mut position: Box<[f64]> = vec![0f64; self.chain.dim()].into();
+        let stats = self.draw_into(&mut position)?;
+        Ok((position, stats))
+    }
+
+    fn draw_into(&mut self, out: &mut [f64]) -> Result<Self::Stats> {
+        let proposal = self.chain.draw_into(out)?;
+        let surrogate_logp = self.chain_logp()?;
+        let logp = self.exact_logp(out)?;
+        let log_accept = (logp - surrogate_logp) - (self.logp - self.surrogate_logp);
+        let accepted = (log_accept >= 0f64) | (self.rng.gen::<f64>().ln() < log_accept);
+        if accepted {
+            self.position.copy_from_slice(out);
+            self.logp = logp;
+            self.surrogate_logp = surrogate_logp;
+        } else {
+            out.copy_from_slice(&self.position);
+            self.chain.restore_position(&self.position)?;
+        }
+        Ok(DelayedAcceptanceStats {
+            proposal,
+            logp: self.logp,
+            surrogate_logp: self.surrogate_logp,
+            accepted,
+        })
+    }
+
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<()> {
+        self.chain.set_inverse_temperature(inverse_temperature)?;
+        self.inverse_temperature = inverse_temperature;
+        self.update_logps()
+    }
+
+    fn reevaluate_position(&mut self) -> Result<()> {
+        self.chain.reevaluate_position()?;
+        self.update_logps()
+    }
+
+    fn restore_position(&mut self, position: &[f64]) -> Result<()> {
+        self.chain.restore_position(position)?;
+        self.position.copy_from_slice(position);
+        self.update_logps()
+    }
+
+    fn current_logp(&self) -> Option<f64> {
+        Some(self.logp)
+    }
+
+    fn set_metric(&mut self, variance: &[f64]) -> Result<()> {
+        self.chain.set_metric(variance)
+    }
+
+    fn metric(&self) -> &[f64] {
+        self.chain.metric()
+    }
+
+    fn step_size(&self) -> f64 {
+        self.chain.step_size()
+    }
+
+    fn set_next_momentum(&mut self, momentum: &[f64]) -> Result<()> {
+        self.chain.set_next_momentum(momentum)
+    }
+
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, out: &mut [f64]) -> Result<()> {
+        self.chain.sample_momentum(rng, out)
+    }
+
+    fn logp_momentum(&self, momentum: &[f64]) -> Result<f64> {
+        self.chain.logp_momentum(momentum)
+    }
+
+    /// Evaluates the hamiltonian of the surrogate chain, the exact logp is
+    /// not used.
+    fn evaluate(&mut self, position: &[f64], momentum: &[f64]) -> Result<HamiltonianSnapshot> {
+        self.chain.evaluate(position, momentum)
+    }
+
+    fn set_trajectory_expectation<G>(&mut self, num_values: usize, func: G)
+    where
+        G: FnMut(&[f64], &mut [f64]) + Send + 'static,
+    {
+        self.chain.set_trajectory_expectation(num_values, func)
+    }
+
+    fn set_termination_criterion<Criterion>(&mut self, criterion: Criterion)
+    where
+        Criterion: TerminationCriterion + 'static,
+    {
+        self.chain.set_termination_criterion(criterion)
+    }
+
+    fn set_step_size_fn<G>(&mut self, func: G)
+    where
+        G: FnMut(&[f64]) -> f64 + Send + 'static,
+    {
+        self.chain.set_step_size_fn(func)
+    }
+
+    fn set_merge_audit<G>(&mut self, sink: G)
+    where
+        G: FnMut(&MergeAudit) + Send + 'static,
+    {
+        self.chain.set_merge_audit(sink)
+    }
+
+    fn set_check_invariants(&mut self, check: bool) {
+        self.chain.set_check_invariants(check)
+    }
+
+    fn stat_schema(&self) -> Vec<StatField> {
+        let mut schema = self.chain.stat_schema();
+        schema.push(StatField::from_value(
+            "surrogate_logp",
+            &SampleStatValue::F64(0f64),
+        ));
+        schema.push(StatField::from_value(
+            "delayed_accepted",
+            &SampleStatValue::Bool(false),
+        ));
+        schema
+    }
+
+    fn snapshot_stats(&self) -> StatsSnapshot {
+        self.chain.snapshot_stats()
+    }
+
+    fn dim(&self) -> usize {
+        self.chain.dim()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_sampler, test_logps::NormalLogp, SamplerArgs};
+
+    #[test]
+    fn corrects_surrogate() {
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let surrogate = new_sampler(NormalLogp::new(2, 0.5), settings, 0, 42);
+        let mut sampler =
+            DelayedAcceptanceChain::new(surrogate, NormalLogp::new(2, 0.), 43).unwrap();
+        sampler.set_position(&[0.5, 0.5]).unwrap();
+        for _ in 0..200 {
+            sampler.draw().unwrap();
+        }
+
+        let n = 4000;
+        let mut sum = [0f64; 2];
+        let mut accepted = 0;
+        for _ in 0..n {
+            let (draw, stats) = sampler.draw().unwrap();
+            sum.iter_mut()
+                .zip(draw.iter())
+                .for_each(|(sum, x)| *sum += x);
+            accepted += stats.accepted() as usize;
+            let expected = -draw.iter().map(|x| x * x).sum::<f64>() / 2.;
+            assert!((stats.logp() - expected).abs() < 1e-12);
+        }
+        // The surrogate chain alone would have mean 0.5
+        for sum in sum {
+            assert!((sum / n as f64).abs() < 0.1, "{}", sum / n as f64);
+        }
+        assert!((accepted > n / 4) & (accepted < n), "{}", accepted);
+
+        let stats = sampler.draw().unwrap().1;
+        let names: Vec<_> = stats.to_vec().iter().map(|(name, _)| *name).collect();
+        let schema: Vec<_> = sampler
+            .stat_schema()
+            .iter()
+            .filter(|field| !field.diverging_only)
+            .map(|field| field.name)
+            .collect();
+        assert_eq!(names, schema);
+
+        let surrogate = new_sampler(NormalLogp::new(3, 0.5), SamplerArgs::default(), 0, 42);
+        assert!(matches!(
+            DelayedAcceptanceChain::new(surrogate, NormalLogp::new(2, 0.), 43),
+            Err(NutsError::DimensionMismatch {
+                expected: 3,
+                got: 2
+            })
+        ));
+    }
+}
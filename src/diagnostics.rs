@@ -0,0 +1,586 @@
+use itertools::Itertools;
+
+use crate::{compare::CompareError, nuts::SampleStats};
+
+/// The autocovariance of `x` at `lag`, normalized by the length of `x`.
+fn autocovariance(x: &[f64], mean: f64, lag: usize) -> f64 {
+    let n = x.len();
+    x[..n - lag]
+        .iter()
+        .zip(x[lag..].iter())
+        .map(|(a, b)| (a - mean) * (b - mean))
+        .sum::<f64>()
+        / n as f64
+}
+
+fn mean(x: &[f64]) -> f64 {
+    x.iter().sum::<f64>() / x.len() as f64
+}
+
+fn variance(x: &[f64]) -> f64 {
+    let mu = mean(x);
+    x.iter().map(|val| (val - mu) * (val - mu)).sum::<f64>() / (x.len() - 1) as f64
+}
+
+/// The common length of the chains, which must be at least four.
+fn check_chains(chains: &[&[f64]]) -> Result<usize, CompareError> {
+    let n = chains.first().ok_or(CompareError::NoChains)?.len();
+    let expected = n.max(4);
+    match chains.iter().position(|chain| chain.len() != expected) {
+        Some(chain) => Err(CompareError::ChainLength {
+            chain,
+            expected,
+            got: chains[chain].len(),
+        }),
+        None => Ok(n),
+    }
+}
+
+/// Estimate the effective sample size of a scalar quantity from several chains.
+///
+/// This uses the multi-chain autocorrelation estimate and Geyer's initial
+/// monotone sequence, as in Stan. All chains must have the same length of
+/// at least four draws, otherwise this returns an error. Returns `NaN` if
+/// the draws are constant.
+pub fn ess(chains: &[&[f64]]) -> Result<f64, CompareError> {
+    let n = check_chains(chains)?;
+    let num_chains = chains.len();
+
+    let means = chains.iter().map(|chain| mean(chain)).collect_vec();
+    let within = chains.iter().map(|chain| variance(chain)).sum::<f64>() / num_chains as f64;
+    let between_over_n = if num_chains > 1 {
+        variance(&means)
+    } else {
+        0f64
+    };
+    let var_plus = within * (n - 1) as f64 / n as f64 + between_over_n;
+    if !var_plus.is_finite() | (var_plus <= 0f64) {
+        return Ok(f64::NAN);
+    }
+
+    let rho = |lag: usize| {
+        let mean_acov = chains
+            .iter()
+            .zip(means.iter())
+            .map(|(chain, &mu)| autocovariance(chain, mu, lag))
+            .sum::<f64>()
+            / num_chains as f64;
+        1f64 - (within - mean_acov) / var_plus
+    };
+
+    // Geyer's initial monotone sequence over pairs of autocorrelations
+    let mut sum = 0f64;
+    let mut last_pair = f64::INFINITY;
+    let mut lag = 0;
+    while lag + 1 < n {
+        let pair = (rho(lag) + rho(lag + 1)).min(last_pair);
+        if pair <= 0f64 {
+            break;
+        }
+        sum += pair;
+        last_pair = pair;
+        lag += 2;
+    }
+    let tau = (2f64 * sum - 1f64).max(1f64 / ((num_chains * n) as f64).log10());
+    Ok((num_chains * n) as f64 / tau)
+}
+
+/// The integrated autocorrelation time of a scalar quantity from several
+/// chains.
+///
+/// This is the number of draws per effective sample, see [`ess`], so every
+/// `ceil(autocorr_time)`-th draw is approximately independent. Returns `NaN`
+/// if the draws are constant.
+pub fn autocorr_time(chains: &[&[f64]]) -> Result<f64, CompareError> {
+    let num_draws = chains.iter().map(|chain| chain.len()).sum::<usize>();
+    Ok(num_draws as f64 / ess(chains)?)
+}
+
+/// The split R-hat convergence diagnostic of a scalar quantity.
+///
+/// Each chain is split in half, and the between-chain variance of the halves
+/// is compared to the within-chain variance. Values close to 1 indicate
+/// convergence. All chains must have the same length of at least four
+/// draws, otherwise this returns an error.
+pub fn r_hat(chains: &[&[f64]]) -> Result<f64, CompareError> {
+    let n = check_chains(chains)? / 2;
+    let halves = chains
+        .iter()
+        .flat_map(|chain| [&chain[..n], &chain[chain.len() - n..]])
+        .collect_vec();
+    let means = halves.iter().map(|half| mean(half)).collect_vec();
+    let within = halves.iter().map(|half| variance(half)).sum::<f64>() / halves.len() as f64;
+    let var_plus = within * (n - 1) as f64 / n as f64 + variance(&means);
+    Ok((var_plus / within).sqrt())
+}
+
+/// The scalar summary of a draw that [`ChainCorrelationMonitor`] compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainSummary {
+    Energy,
+    Logp,
+}
+
+/// Two chains whose summaries are suspiciously correlated, see
+/// [`ChainCorrelationMonitor`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorrelationWarning {
+    pub chain_a: usize,
+    pub chain_b: usize,
+    pub correlation: f64,
+    /// The number of draws of both chains that the correlation is based on
+    pub num_draws: usize,
+}
+
+/// Running moments of a pair of chains over their common draws
+#[derive(Debug, Clone, Default)]
+struct PairMoments {
+    count: usize,
+    mean_a: f64,
+    mean_b: f64,
+    m2_a: f64,
+    m2_b: f64,
+    comoment: f64,
+    warned: bool,
+}
+
+impl PairMoments {
+    fn add(&mut self, a: f64, b: f64) {
+        self.count += 1;
+        let n = self.count as f64;
+        let delta_a = a - self.mean_a;
+        self.mean_a += delta_a / n;
+        let delta_b = b - self.mean_b;
+        self.mean_b += delta_b / n;
+        self.m2_a += delta_a * (a - self.mean_a);
+        self.m2_b += delta_b * (b - self.mean_b);
+        self.comoment += delta_a * (b - self.mean_b);
+    }
+
+    fn correlation(&self) -> Option<f64> {
+        let norm = (self.m2_a * self.m2_b).sqrt();
+        (self.count > 1 && norm > 0f64).then(|| self.comoment / norm)
+    }
+}
+
+/// Watch the correlation between the chains of a parallel run while draws
+/// arrive
+///
+/// Independent chains have uncorrelated draws. If the same draw of two
+/// chains is correlated in a scalar summary like the energy, the chains
+/// probably share a seed or some mutable state in the logp function. The
+/// monitor keeps the Pearson correlation of every pair of chains over their
+/// common draws and warns once per pair if its absolute value exceeds
+/// `threshold` after at least `min_draws` draws. The noise of the
+/// correlation of independent chains is about `1 / sqrt(num_draws)`.
+#[derive(Debug, Clone)]
+pub struct ChainCorrelationMonitor {
+    threshold: f64,
+    min_draws: usize,
+    values: Vec<Vec<f64>>,
+    /// The moments of chains `a < b` at index `b * (b - 1) / 2 + a`
+    pairs: Vec<PairMoments>,
+}
+
+impl ChainCorrelationMonitor {
+    pub fn new(num_chains: usize, threshold: f64, min_draws: usize) -> Self {
+        ChainCorrelationMonitor {
+            threshold,
+            min_draws,
+            values: vec![vec![]; num_chains],
+            pairs: vec![PairMoments::default(); num_chains * num_chains.saturating_sub(1) / 2],
+        }
+    }
+
+    fn pair_index(a: usize, b: usize) -> usize {
+        let (a, b) = if a < b { (a, b) } else { (b, a) };
+        b * (b - 1) / 2 + a
+    }
+
+    /// Add the next value of `chain` and return warnings for pairs of chains
+    /// that became suspiciously correlated.
+    pub fn add(&mut self, chain: usize, value: f64) -> Vec<CorrelationWarning> {
+        self.values[chain].push(value);
+        let mut warnings = vec![];
+        for other in (0..self.values.len()).filter(|&other| other != chain) {
+            let (a, b) = (chain.min(other), chain.max(other));
+            let pair = &mut self.pairs[Self::pair_index(a, b)];
+            let common = self.values[a].len().min(self.values[b].len());
+            while pair.count < common {
+                pair.add(self.values[a][pair.count], self.values[b][pair.count]);
+            }
+            if pair.warned || (pair.count < self.min_draws) {
+                continue;
+            }
+            if let Some(correlation) = pair.correlation() {
+                if correlation.abs() > self.threshold {
+                    pair.warned = true;
+                    warnings.push(CorrelationWarning {
+                        chain_a: a,
+                        chain_b: b,
+                        correlation,
+                        num_draws: pair.count,
+                    });
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Add the summary of a draw from a parallel run, for example from the
+    /// receiver of [`sample_parallel`](crate::sample_parallel).
+    ///
+    /// Draws with an index smaller than `skip` are ignored, so that the
+    /// common trend of all chains during tuning does not look like a
+    /// correlation.
+    pub fn add_stats(
+        &mut self,
+        stats: &dyn SampleStats,
+        summary: ChainSummary,
+        skip: u64,
+    ) -> Vec<CorrelationWarning> {
+        if stats.draw() < skip {
+            return vec![];
+        }
+        let value = match summary {
+            ChainSummary::Energy => stats.energy(),
+            ChainSummary::Logp => stats.logp(),
+        };
+        self.add(stats.chain() as usize, value)
+    }
+
+    /// The current correlation between two chains, or `None` if there are
+    /// not enough draws yet or the chains are the same.
+    pub fn correlation(&self, chain_a: usize, chain_b: usize) -> Option<f64> {
+        if chain_a == chain_b {
+            return None;
+        }
+        self.pairs[Self::pair_index(chain_a, chain_b)].correlation()
+    }
+}
+
+/// What limits the length of the trajectories of a run, see
+/// [`trajectory_calibration`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrajectoryLimit {
+    /// Many trajectories stopped at `maxdepth` before they turned. A larger
+    /// `maxdepth` gives longer trajectories.
+    MaxDepth,
+    /// Trajectories turn before they cover the largest posterior scale,
+    /// because the step size has to resolve the smallest one. A better mass
+    /// matrix or a reparametrization helps, a larger `maxdepth` does not.
+    StepSize,
+    /// Trajectories cover the posterior scale in every dimension.
+    Neither,
+}
+
+/// The length of the trajectories of a run compared to the posterior scale
+#[derive(Debug, Clone)]
+pub struct TrajectoryCalibration {
+    /// The mean of [`SampleStats::integration_time`]
+    pub mean_integration_time: f64,
+    /// The fraction of draws whose trajectory reached `maxdepth`
+    pub maxdepth_fraction: f64,
+    /// The posterior standard deviation of each parameter, in units of the
+    /// metric
+    pub scale: Box<[f64]>,
+    /// The mean integration time divided by `pi` times the scale. A
+    /// trajectory in a normal posterior turns after about half an
+    /// oscillation, which takes `pi` times the scale, so values well below
+    /// one mean that trajectories only cover a small part of the posterior
+    /// in that dimension.
+    pub relative_length: Box<[f64]>,
+    pub limit: TrajectoryLimit,
+}
+
+/// Compare the integration time of the trajectories of several chains to
+/// the posterior scale in each dimension.
+///
+/// `metric` is the diagonal of the inverse mass matrix that the draws were
+/// made with, for example from [`Chain::metric`](crate::Chain::metric)
+/// after tuning, or `None` for the identity. The draws should come from
+/// after tuning. Trajectories are limited by `maxdepth` if more than 10% of
+/// them reached it, and by the step size if they cover less than half of
+/// the largest scale.
+///
+/// Returns an error if there are fewer than two draws in total, or if the
+/// draws or the metric have different lengths.
+pub fn trajectory_calibration<S: SampleStats>(
+    chains: &[Vec<(Box<[f64]>, S)>],
+    metric: Option<&[f64]>,
+) -> Result<TrajectoryCalibration, CompareError> {
+    const MAXDEPTH_FRACTION: f64 = 0.1;
+    const MIN_RELATIVE_LENGTH: f64 = 0.5;
+
+    let draws = chains.iter().flatten().collect_vec();
+    if draws.len() < 2 {
+        return Err(CompareError::TooFewDraws {
+            expected: 2,
+            got: draws.len(),
+        });
+    }
+    let dim = draws[0].0.len();
+    for (chain_idx, chain) in chains.iter().enumerate() {
+        for (draw_idx, (draw, _)) in chain.iter().enumerate() {
+            if draw.len() != dim {
+                return Err(CompareError::DrawLength {
+                    chain: chain_idx,
+                    draw: draw_idx,
+                    expected: dim,
+                    got: draw.len(),
+                });
+            }
+        }
+    }
+    if let Some(metric) = metric {
+        if metric.len() != dim {
+            return Err(CompareError::MetricLength {
+                expected: dim,
+                got: metric.len(),
+            });
+        }
+    }
+    let num_draws = draws.len() as f64;
+    let mean_integration_time = draws
+        .iter()
+        .map(|(_, stats)| stats.integration_time())
+        .sum::<f64>()
+        / num_draws;
+    let maxdepth_fraction = draws
+        .iter()
+        .filter(|(_, stats)| stats.maxdepth_reached())
+        .count() as f64
+        / num_draws;
+    let scale: Box<[f64]> = (0..dim)
+        .map(|i| {
+            let values = draws.iter().map(|(draw, _)| draw[i]).collect_vec();
+            let metric_var = metric.map(|metric| metric[i]).unwrap_or(1f64);
+            (variance(&values) / metric_var).sqrt()
+        })
+        .collect();
+    let relative_length: Box<[f64]> = scale
+        .iter()
+        .map(|scale| mean_integration_time / (std::f64::consts::PI * scale))
+        .collect();
+    let min_relative_length = relative_length
+        .iter()
+        .copied()
+        .fold(f64::INFINITY, f64::min);
+    let limit = if maxdepth_fraction > MAXDEPTH_FRACTION {
+        TrajectoryLimit::MaxDepth
+    } else if min_relative_length < MIN_RELATIVE_LENGTH {
+        TrajectoryLimit::StepSize
+    } else {
+        TrajectoryLimit::Neither
+    };
+    Ok(TrajectoryCalibration {
+        mean_integration_time,
+        maxdepth_fraction,
+        scale,
+        relative_length,
+        limit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn ar1(rng: &mut StdRng, phi: f64, n: usize, offset: f64) -> Vec<f64> {
+        let mut x = 0f64;
+        (0..n)
+            .map(|_| {
+                let noise: f64 = rng.sample(rand_distr::StandardNormal);
+                x = phi * x + noise;
+                x + offset
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ess_independent_and_correlated() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let chains = (0..4).map(|_| ar1(&mut rng, 0., 1000, 0.)).collect_vec();
+        let chains = chains.iter().map(|c| &c[..]).collect_vec();
+        let value = ess(&chains).unwrap();
+        assert!((3000f64..5000f64).contains(&value), "{}", value);
+
+        let chains = (0..4).map(|_| ar1(&mut rng, 0.9, 1000, 0.)).collect_vec();
+        let chains = chains.iter().map(|c| &c[..]).collect_vec();
+        // The asymptotic value is 4000 * 0.1 / 1.9
+        let value = ess(&chains).unwrap();
+        assert!((100f64..400f64).contains(&value), "{}", value);
+
+        assert!(matches!(ess(&[]), Err(CompareError::NoChains)));
+        assert!(matches!(
+            ess(&[&[1., 2., 3.]]),
+            Err(CompareError::ChainLength {
+                chain: 0,
+                expected: 4,
+                got: 3
+            })
+        ));
+        assert!(matches!(
+            ess(&[chains[0], &chains[1][1..]]),
+            Err(CompareError::ChainLength { chain: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn autocorr_time_of_ar1() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let chains = (0..4).map(|_| ar1(&mut rng, 0.9, 2000, 0.)).collect_vec();
+        let chains = chains.iter().map(|c| &c[..]).collect_vec();
+        // The asymptotic value is (1 + 0.9) / (1 - 0.9)
+        let value = autocorr_time(&chains).unwrap();
+        assert!((12f64..30f64).contains(&value), "{}", value);
+        assert!(autocorr_time(&[&[1.; 10]]).unwrap().is_nan());
+    }
+
+    #[test]
+    fn r_hat_detects_shifted_chains() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let chains = (0..4).map(|_| ar1(&mut rng, 0.5, 500, 0.)).collect_vec();
+        let chains = chains.iter().map(|c| &c[..]).collect_vec();
+        assert!((r_hat(&chains).unwrap() - 1.).abs() < 0.02);
+
+        let chains = (0..4)
+            .map(|i| ar1(&mut rng, 0.5, 500, i as f64))
+            .collect_vec();
+        let chains = chains.iter().map(|c| &c[..]).collect_vec();
+        assert!(r_hat(&chains).unwrap() > 1.1);
+        assert!(matches!(r_hat(&[]), Err(CompareError::NoChains)));
+        assert!(matches!(
+            r_hat(&[&[]]),
+            Err(CompareError::ChainLength { got: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn chain_correlation() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let shared = ar1(&mut rng, 0., 400, 0.);
+        let chains = [
+            ar1(&mut rng, 0.5, 400, 0.),
+            ar1(&mut rng, 0.5, 400, 0.),
+            shared.clone(),
+            shared.iter().map(|val| val + 0.1).collect_vec(),
+        ];
+        let mut monitor = ChainCorrelationMonitor::new(4, 0.5, 50);
+        let mut warnings = vec![];
+        // Chains report their draws at different speeds
+        for draw in 0..400 {
+            for (chain, values) in chains.iter().enumerate() {
+                if (chain != 1) | (draw % 2 == 0) {
+                    warnings.extend(monitor.add(chain, values[draw]));
+                }
+            }
+        }
+        for draw in (1..400).step_by(2) {
+            warnings.extend(monitor.add(1, chains[1][draw]));
+        }
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].chain_a, warnings[0].chain_b), (2, 3));
+        assert_eq!(warnings[0].num_draws, 50);
+        assert!((monitor.correlation(3, 2).unwrap() - 1.).abs() < 1e-10);
+        assert!(monitor.correlation(0, 1).unwrap().abs() < 0.2);
+    }
+
+    #[test]
+    fn chain_correlation_from_stats() {
+        use crate::{sample_sequentially, test_logps::NormalLogp, SamplerArgs};
+
+        let settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
+        let run = |chain, seed| {
+            sample_sequentially(NormalLogp::new(5, 0.), settings, &[0.; 5], 400, chain, seed)
+                .unwrap()
+                .map(|draw| draw.unwrap().1)
+        };
+        let mut monitor = ChainCorrelationMonitor::new(3, 0.5, 100);
+        let mut warnings = vec![];
+        for stats in run(0, 42).chain(run(1, 43)) {
+            warnings.extend(monitor.add_stats(&stats, ChainSummary::Energy, 100));
+        }
+        assert!(warnings.is_empty());
+        assert_eq!(monitor.values[0].len(), 300);
+
+        // A third chain that accidentally reuses the seed of chain 0
+        for stats in run(2, 42) {
+            warnings.extend(monitor.add_stats(&stats, ChainSummary::Energy, 100));
+        }
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].chain_a, warnings[0].chain_b), (0, 2));
+    }
+
+    #[test]
+    fn trajectory_calibration_limits() {
+        use crate::{sample_sequentially, test_logps::NormalLogp, SamplerArgs};
+
+        let run = |maxdepth| {
+            let settings = SamplerArgs {
+                num_tune: 200,
+                maxdepth,
+                ..Default::default()
+            };
+            let draws = sample_sequentially(NormalLogp::new(4, 0.), settings, &[0.; 4], 600, 0, 42)
+                .unwrap()
+                .skip(200)
+                .map(|draw| draw.unwrap())
+                .collect_vec();
+            vec![draws]
+        };
+
+        let chains = run(10);
+        let (_, stats) = &chains[0][0];
+        let step_size = match stats
+            .to_vec()
+            .into_iter()
+            .find(|(key, _)| *key == "step_size")
+        {
+            Some((_, crate::SampleStatValue::F64(val))) => val,
+            _ => panic!("Missing step size"),
+        };
+        let num_steps = ((1u64 << stats.depth()) - 1) as f64;
+        assert!((stats.integration_time() - step_size * num_steps).abs() < 1e-12);
+
+        // The adapted metric is close to the identity for this posterior
+        let calibration = trajectory_calibration(&chains, None).unwrap();
+        assert_eq!(
+            calibration.limit,
+            TrajectoryLimit::Neither,
+            "{:?}",
+            calibration
+        );
+        assert!(calibration.maxdepth_fraction < 0.1);
+        assert!(calibration.scale.iter().all(|&val| (val - 1.).abs() < 0.2));
+
+        // A metric that is much too small makes the posterior look wide
+        let calibration = trajectory_calibration(&chains, Some(&[1e-4; 4])).unwrap();
+        assert_eq!(calibration.limit, TrajectoryLimit::StepSize);
+
+        let calibration = trajectory_calibration(&run(1), None).unwrap();
+        assert!(calibration.maxdepth_fraction > 0.9);
+        assert_eq!(calibration.limit, TrajectoryLimit::MaxDepth);
+
+        assert!(matches!(
+            trajectory_calibration(&chains, Some(&[1.; 3])),
+            Err(CompareError::MetricLength {
+                expected: 4,
+                got: 3
+            })
+        ));
+        let mut chains = chains;
+        chains[0].truncate(1);
+        assert!(matches!(
+            trajectory_calibration(&chains, None),
+            Err(CompareError::TooFewDraws {
+                expected: 2,
+                got: 1
+            })
+        ));
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::nuts::{
+    Chain, HamiltonianSnapshot, MergeAudit, Result, StatField, StatsSnapshot, TerminationCriterion,
+};
+
+/// A handle to discrete state shared between a logp function and a [`DiscreteKernel`]
+#[derive(Debug, Default)]
+pub struct DiscreteContext<T> {
+    state: Arc<Mutex<T>>,
+}
+
+impl<T> Clone for DiscreteContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> DiscreteContext<T> {
+    pub fn new(state: T) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Access the current discrete state.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.state.lock().expect("Discrete state was poisoned")
+    }
+}
+
+/// Update discrete parameters conditional on the continuous parameters
+pub trait DiscreteKernel<T> {
+    /// Update `state` in place, given the current continuous `position`.
+    fn update<R: Rng + ?Sized>(&mut self, state: &mut T, position: &[f64], rng: &mut R);
+}
+
+/// A sampler that alternates between NUTS draws of the continuous parameters
+/// and updates of the discrete parameters (Metropolis-within-NUTS).
+///
+/// After each draw the kernel updates the discrete state in the shared
+/// [`DiscreteContext`], and the logp at the current position is recomputed.
+pub struct MixedChain<C: Chain, T, K: DiscreteKernel<T>> {
+    chain: C,
+    context: DiscreteContext<T>,
+    kernel: K,
+    rng: SmallRng,
+}
+
+impl<C: Chain, T, K: DiscreteKernel<T>> MixedChain<C, T, K> {
+    /// Combine a NUTS sampler with a kernel for the discrete state in `context`.
+    ///
+    /// The logp function of `chain` must read the discrete state from a clone of
+    /// `context`.
+    pub fn new(chain: C, context: DiscreteContext<T>, kernel: K, seed: u64) -> Self {
+        Self {
+            chain,
+            context,
+            kernel,
+            rng: SmallRng::seed_from_u64(seed),
+        }
+    }
+
+    /// The handle to the discrete state.
+    pub fn context(&self) -> &DiscreteContext<T> {
+        &self.context
+    }
+}
+
+impl<C: Chain, T, K: DiscreteKernel<T>> Chain for MixedChain<C, T, K> {
+    type Hamiltonian = C::Hamiltonian;
+    type AdaptStrategy = C::AdaptStrategy;
+    type Stats = C::Stats;
+
+    fn set_position(&mut self, position: &[f64]) -> Result<()> {
+        self.chain.set_position(position)
+    }
+
+    fn draw(&mut self) -> Result<(Box<[f64]>, Self::Stats)> {
+        let mut position: Box<[f64]> = vec![0f64; self.chain.dim()].into();
+        let stats = self.draw_into(&mut position)?;
+        Ok((position, stats))
+    }
+
+    fn draw_into(&mut self, out: &mut [f64]) -> Result<Self::Stats> {
+        let stats = self.chain.draw_into(out)?;
+        {
+            let mut state = self.context.lock();
+            self.kernel.update(&mut state, out, &mut self.rng);
+        }
+        self.chain.reevaluate_position()?;
+        Ok(stats)
+    }
+
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<()> {
+        self.chain.set_inverse_temperature(inverse_temperature)
+    }
+
+    fn reevaluate_position(&mut self) -> Result<()> {
+        self.chain.reevaluate_position()
+    }
+
+    fn restore_position(&mut self, position: &[f64]) -> Result<()> {
+        self.chain.restore_position(position)
+    }
+
+    fn current_logp(&self) -> Option<f64> {
+        self.chain.current_logp()
+    }
+
+    fn set_metric(&mut self, variance: &[f64]) -> Result<()> {
+        self.chain.set_metric(variance)
+    }
+
+    fn metric(&self) -> &[f64] {
+        self.chain.metric()
+    }
+
+    fn step_size(&self) -> f64 {
+        self.chain.step_size()
+    }
+
+    fn set_next_momentum(&mut self, momentum: &[f64]) -> Result<()> {
+        self.chain.set_next_momentum(momentum)
+    }
+
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, out: &mut [f64]) -> Result<()> {
+        self.chain.sample_momentum(rng, out)
+    }
+
+    fn logp_momentum(&self, momentum: &[f64]) -> Result<f64> {
+        self.chain.logp_momentum(momentum)
+    }
+
+    fn evaluate(&mut self, position: &[f64], momentum: &[f64]) -> Result<HamiltonianSnapshot> {
+        self.chain.evaluate(position, momentum)
+    }
+
+    fn set_trajectory_expectation<F>(&mut self, num_values: usize, func: F)
+    where
+        F: FnMut(&[f64], &mut [f64]) + Send + 'static,
+    {
+        self.chain.set_trajectory_expectation(num_values, func)
+    }
+
+    fn set_termination_criterion<Criterion>(&mut self, criterion: Criterion)
+    where
+        Criterion: TerminationCriterion + 'static,
+    {
+        self.chain.set_termination_criterion(criterion)
+    }
+
+    fn set_step_size_fn<F>(&mut self, func: F)
+    where
+        F: FnMut(&[f64]) -> f64 + Send + 'static,
+    {
+        self.chain.set_step_size_fn(func)
+    }
+
+    fn set_merge_audit<F>(&mut self, sink: F)
+    where
+        F: FnMut(&MergeAudit) + Send + 'static,
+    {
+        self.chain.set_merge_audit(sink)
+    }
+
+    fn set_check_invariants(&mut self, check: bool) {
+        self.chain.set_check_invariants(check)
+    }
+
+    fn stat_schema(&self) -> Vec<StatField> {
+        self.chain.stat_schema()
+    }
+
+    fn snapshot_stats(&self) -> StatsSnapshot {
+        self.chain.snapshot_stats()
+    }
+
+    fn dim(&self) -> usize {
+        self.chain.dim()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_sampler, CpuLogpFunc, LogpError, SamplerArgs};
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    enum MixtureError {}
+
+    impl LogpError for MixtureError {
+        fn is_recoverable(&self) -> bool {
+            false
+        }
+    }
+
+    const MU: [f64; 2] = [-2., 2.];
+
+    /// x | z ~ N(MU[z], 1) with z uniform on {0, 1}
+    struct MixtureLogp {
+        component: DiscreteContext<usize>,
+    }
+
+    impl CpuLogpFunc for MixtureLogp {
+        type Err = MixtureError;
+
+        fn dim(&self) -> usize {
+            1
+        }
+
+        fn logp(
+            &mut self,
+            position: &[f64],
+            grad: &mut [f64],
+        ) -> std::result::Result<f64, Self::Err> {
+            let mu = MU[*self.component.lock()];
+            let diff = position[0] - mu;
+            grad[0] = -diff;
+            Ok(-diff * diff / 2.)
+        }
+    }
+
+    struct GibbsComponent {}
+
+    impl DiscreteKernel<usize> for GibbsComponent {
+        fn update<R: Rng + ?Sized>(&mut self, state: &mut usize, position: &[f64], rng: &mut R) {
+            let logps = MU.map(|mu| -(position[0] - mu) * (position[0] - mu) / 2.);
+            let prob_1 = 1. / (1. + (logps[0] - logps[1]).exp());
+            *state = if rng.gen_bool(prob_1) { 1 } else { 0 };
+        }
+    }
+
+    #[test]
+    fn sample_mixture() {
+        let context = DiscreteContext::new(0usize);
+        let logp = MixtureLogp {
+            component: context.clone(),
+        };
+        let settings = SamplerArgs {
+            num_tune: 100,
+            ..Default::default()
+        };
+        let sampler = new_sampler(logp, settings, 0, 42);
+        let mut sampler = MixedChain::new(sampler, context, GibbsComponent {}, 43);
+        sampler.set_position(&[0.]).unwrap();
+
+        let mut counts = [0usize; 2];
+        for _ in 0..1000 {
+            sampler.draw().unwrap();
+            counts[*sampler.context().lock()] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 200), "{:?}", counts);
+    }
+}
@@ -0,0 +1,359 @@
+use rand::{rngs::SmallRng, seq::index::sample, Rng, SeedableRng};
+use rand_distr::StandardNormal;
+
+use crate::{
+    cpu_potential::CpuLogpFunc,
+    nuts::{
+        check_dim, draw_seed, DivergenceInfo, LogpError, NutsError, Result, SampleStatItem,
+        SampleStats,
+    },
+};
+
+/// The proposal of the affine invariant ensemble sampler
+#[derive(Debug, Clone, Copy)]
+pub enum EnsembleMove {
+    /// The stretch move of Goodman & Weare (2010) with scale parameter `a > 1`.
+    Stretch { scale: f64 },
+    /// The walk move of Goodman & Weare (2010), using the covariance of
+    /// `subset_size` randomly chosen other walkers.
+    Walk { subset_size: usize },
+}
+
+/// Settings for the affine invariant ensemble sampler
+#[derive(Debug, Clone, Copy)]
+pub struct EnsembleSettings {
+    /// The number of walkers. This should be at least twice the dimension.
+    pub num_walkers: usize,
+    pub proposal: EnsembleMove,
+}
+
+impl Default for EnsembleSettings {
+    fn default() -> Self {
+        Self {
+            num_walkers: 32,
+            proposal: EnsembleMove::Stretch { scale: 2f64 },
+        }
+    }
+}
+
+/// Sample statistics of one walker for one iteration of the ensemble sampler
+#[derive(Debug, Clone)]
+pub struct EnsembleSampleStats {
+    walker: u64,
+    draw: u64,
+    seed: u64,
+    logp: f64,
+    accepted: bool,
+    acceptance_rate: f64,
+}
+
+impl SampleStats for EnsembleSampleStats {
+    fn depth(&self) -> u64 {
+        0
+    }
+    fn maxdepth_reached(&self) -> bool {
+        false
+    }
+    fn index_in_trajectory(&self) -> i64 {
+        0
+    }
+    fn logp(&self) -> f64 {
+        self.logp
+    }
+    fn energy(&self) -> f64 {
+        -self.logp
+    }
+    fn divergence_info(&self) -> Option<&dyn DivergenceInfo> {
+        None
+    }
+    fn chain(&self) -> u64 {
+        self.walker
+    }
+    fn draw(&self) -> u64 {
+        self.draw
+    }
+    fn draw_seed(&self) -> u64 {
+        draw_seed(self.seed, self.walker, self.draw)
+    }
+    fn n_leapfrog(&self) -> u64 {
+        0
+    }
+    fn n_leapfrog_discarded(&self) -> u64 {
+        0
+    }
+    fn gradient(&self) -> Option<&[f64]> {
+        None
+    }
+    fn trajectory_expectation(&self) -> Option<&[f64]> {
+        None
+    }
+    fn to_vec(&self) -> Vec<SampleStatItem> {
+        vec![
+            ("logp", self.logp.into()),
+            ("accepted", self.accepted.into()),
+            ("acceptance_rate", self.acceptance_rate.into()),
+            ("draw_seed", self.draw_seed().into()),
+        ]
+    }
+}
+
+/// An affine invariant ensemble sampler for logp functions without useful
+/// gradients.
+///
+/// The gradient that the logp function writes is ignored. Each call to
+/// [`draw`](Self::draw) updates all walkers in turn and returns the new
+/// position of each walker, with the walker index reported as the chain.
+/// Recoverable logp errors and a logp of `-inf` reject the proposal.
+pub struct EnsembleSampler<F: CpuLogpFunc> {
+    logp: F,
+    settings: EnsembleSettings,
+    rng: SmallRng,
+    seed: u64,
+    walkers: Vec<Box<[f64]>>,
+    walker_logp: Vec<f64>,
+    grad: Box<[f64]>,
+    draw_count: u64,
+    num_accepted: Vec<u64>,
+}
+
+impl<F: CpuLogpFunc> EnsembleSampler<F> {
+    /// Create a sampler without walkers, see [`set_walkers`](Self::set_walkers).
+    ///
+    /// Fails with [`NutsError::InvalidSettings`] if there are fewer than
+    /// three walkers, or if the parameters of the proposal are out of range.
+    pub fn new(logp: F, settings: EnsembleSettings, seed: u64) -> Result<Self> {
+        if settings.num_walkers < 3 {
+            return Err(NutsError::InvalidSettings(format!(
+                "Need at least three walkers, but got {}",
+                settings.num_walkers
+            )));
+        }
+        match settings.proposal {
+            EnsembleMove::Stretch { scale } => {
+                if scale.is_nan() | (scale <= 1f64) {
+                    return Err(NutsError::InvalidSettings(format!(
+                        "Stretch scale must be larger than one, but is {}",
+                        scale
+                    )));
+                }
+            }
+            EnsembleMove::Walk { subset_size } => {
+                if !(2..settings.num_walkers).contains(&subset_size) {
+                    return Err(NutsError::InvalidSettings(format!(
+                        "Walk subset size must be between two and the number of walkers, but is {}",
+                        subset_size
+                    )));
+                }
+            }
+        }
+        let dim = logp.dim();
+        Ok(Self {
+            logp,
+            settings,
+            rng: SmallRng::seed_from_u64(seed),
+            seed,
+            walkers: vec![],
+            walker_logp: vec![],
+            grad: vec![0f64; dim].into(),
+            draw_count: 0,
+            num_accepted: vec![0; settings.num_walkers],
+        })
+    }
+
+    /// Set the initial positions of all walkers.
+    ///
+    /// There must be one position per walker, otherwise this fails with
+    /// [`NutsError::InvalidSettings`]. This also fails if the logp function
+    /// fails or returns `-inf` at one of them.
+    pub fn set_walkers(&mut self, positions: &[&[f64]]) -> Result<()> {
+        if positions.len() != self.settings.num_walkers {
+            return Err(NutsError::InvalidSettings(format!(
+                "Need one initial position per walker, but got {} for {} walkers",
+                positions.len(),
+                self.settings.num_walkers
+            )));
+        }
+        let mut walkers = Vec::with_capacity(positions.len());
+        let mut walker_logp = Vec::with_capacity(positions.len());
+        for &position in positions.iter() {
+            check_dim(self.logp.dim(), position.len())?;
+            let logp = self
+                .logp
+                .logp(position, &mut self.grad)
+                .map_err(|err| NutsError::LogpFailure(Box::new(err)))?;
+            if logp == f64::NEG_INFINITY {
+                return Err(NutsError::InitOutsideSupport);
+            }
+            walkers.push(position.into());
+            walker_logp.push(logp);
+        }
+        self.walkers = walkers;
+        self.walker_logp = walker_logp;
+        Ok(())
+    }
+
+    /// Update all walkers and return their new positions.
+    ///
+    /// Fails with [`NutsError::InvalidSettings`] if the walkers were not
+    /// initialized with [`set_walkers`](Self::set_walkers).
+    pub fn draw(&mut self) -> Result<Vec<(Box<[f64]>, EnsembleSampleStats)>> {
+        if self.walkers.is_empty() {
+            return Err(NutsError::InvalidSettings(
+                "Walkers must be initialized with set_walkers".to_string(),
+            ));
+        }
+        let num_walkers = self.walkers.len();
+        let mut draws = Vec::with_capacity(num_walkers);
+        for walker in 0..num_walkers {
+            let (proposal, log_jacobian) = self.propose(walker);
+            let logp = match self.logp.logp(&proposal, &mut self.grad) {
+                Ok(logp) => logp,
+                Err(err) if err.is_recoverable() => f64::NEG_INFINITY,
+                Err(err) => return Err(NutsError::LogpFailure(Box::new(err))),
+            };
+            let log_accept = log_jacobian + logp - self.walker_logp[walker];
+            let accepted = !log_accept.is_nan()
+                & (logp != f64::NEG_INFINITY)
+                & ((log_accept >= 0f64) || (self.rng.gen::<f64>().ln() < log_accept));
+            if accepted {
+                self.walkers[walker] = proposal;
+                self.walker_logp[walker] = logp;
+                self.num_accepted[walker] += 1;
+            }
+            let stats = EnsembleSampleStats {
+                walker: walker as u64,
+                draw: self.draw_count,
+                seed: self.seed,
+                logp: self.walker_logp[walker],
+                accepted,
+                acceptance_rate: self.num_accepted[walker] as f64 / (self.draw_count + 1) as f64,
+            };
+            draws.push((self.walkers[walker].clone(), stats));
+        }
+        self.draw_count += 1;
+        Ok(draws)
+    }
+
+    /// Propose a new position for `walker` and return it together with the
+    /// log of the correction factor in the acceptance probability.
+    fn propose(&mut self, walker: usize) -> (Box<[f64]>, f64) {
+        let num_walkers = self.walkers.len();
+        let current = &self.walkers[walker];
+        match self.settings.proposal {
+            EnsembleMove::Stretch { scale } => {
+                let mut other = self.rng.gen_range(0..num_walkers - 1);
+                if other >= walker {
+                    other += 1;
+                }
+                let other = &self.walkers[other];
+                let u: f64 = self.rng.gen();
+                let z = ((scale - 1f64) * u + 1f64).powi(2) / scale;
+                let proposal = other
+                    .iter()
+                    .zip(current.iter())
+                    .map(|(&o, &x)| o + z * (x - o))
+                    .collect();
+                (proposal, (current.len() as f64 - 1f64) * z.ln())
+            }
+            EnsembleMove::Walk { subset_size } => {
+                let subset: Vec<usize> = sample(&mut self.rng, num_walkers - 1, subset_size)
+                    .into_iter()
+                    .map(|idx| if idx >= walker { idx + 1 } else { idx })
+                    .collect();
+                let mut mean = vec![0f64; current.len()];
+                for &idx in subset.iter() {
+                    mean.iter_mut()
+                        .zip(self.walkers[idx].iter())
+                        .for_each(|(m, x)| *m += x / subset_size as f64);
+                }
+                let mut proposal: Box<[f64]> = current.clone();
+                for &idx in subset.iter() {
+                    let weight: f64 = self.rng.sample(StandardNormal);
+                    proposal
+                        .iter_mut()
+                        .zip(self.walkers[idx].iter().zip(mean.iter()))
+                        .for_each(|(y, (x, m))| *y += weight * (x - m));
+                }
+                (proposal, 0f64)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_logps::NormalLogp;
+
+    fn check_moments(proposal: EnsembleMove) {
+        let settings = EnsembleSettings {
+            num_walkers: 16,
+            proposal,
+        };
+        let mut sampler = EnsembleSampler::new(NormalLogp::new(3, 1.), settings, 42).unwrap();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let init: Vec<Vec<f64>> = (0..16)
+            .map(|_| (0..3).map(|_| rng.gen_range(-1f64..1f64)).collect())
+            .collect();
+        let init: Vec<&[f64]> = init.iter().map(|x| &x[..]).collect();
+        sampler.set_walkers(&init).unwrap();
+
+        let mut values = vec![];
+        for i in 0..3000 {
+            let draws = sampler.draw().unwrap();
+            assert_eq!(draws.len(), 16);
+            if i >= 500 {
+                values.extend(draws.iter().map(|(draw, _)| draw[0]));
+            }
+            let (_, stats) = &draws[3];
+            assert_eq!(stats.chain(), 3);
+            assert_eq!(stats.draw(), i);
+        }
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let var = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / values.len() as f64;
+        assert!((mean - 1.).abs() < 0.1, "mean {}", mean);
+        assert!((0.85..1.15).contains(&var), "var {}", var);
+    }
+
+    #[test]
+    fn stretch_move() {
+        check_moments(EnsembleMove::Stretch { scale: 2. });
+    }
+
+    #[test]
+    fn walk_move() {
+        check_moments(EnsembleMove::Walk { subset_size: 5 });
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let invalid = [
+            (2, EnsembleMove::Stretch { scale: 2. }),
+            (16, EnsembleMove::Stretch { scale: 1. }),
+            (16, EnsembleMove::Stretch { scale: f64::NAN }),
+            (16, EnsembleMove::Walk { subset_size: 1 }),
+            (16, EnsembleMove::Walk { subset_size: 16 }),
+        ];
+        for (num_walkers, proposal) in invalid {
+            let settings = EnsembleSettings {
+                num_walkers,
+                proposal,
+            };
+            let sampler = EnsembleSampler::new(NormalLogp::new(3, 1.), settings, 42);
+            assert!(matches!(sampler, Err(NutsError::InvalidSettings(_))));
+        }
+
+        let settings = EnsembleSettings {
+            num_walkers: 4,
+            ..Default::default()
+        };
+        let mut sampler = EnsembleSampler::new(NormalLogp::new(3, 1.), settings, 42).unwrap();
+        assert!(matches!(sampler.draw(), Err(NutsError::InvalidSettings(_))));
+        let init = [[0f64; 3]; 3];
+        let init: Vec<&[f64]> = init.iter().map(|x| &x[..]).collect();
+        assert!(matches!(
+            sampler.set_walkers(&init),
+            Err(NutsError::InvalidSettings(_))
+        ));
+    }
+}
@@ -0,0 +1,138 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::{
+    cpu_potential::{CpuLogpFunc, LogpPanic},
+    nuts::LogpError,
+};
+
+/// Values that often trigger numerical problems in logp functions
+const ADVERSARIAL_VALUES: [f64; 12] = [
+    0f64,
+    -0f64,
+    1e300,
+    -1e300,
+    f64::MAX,
+    f64::MIN,
+    1e-300,
+    f64::MIN_POSITIVE / 4f64,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+    f64::NAN,
+    710f64,
+];
+
+/// A problem found by [`fuzz_logp`]
+#[derive(Debug, Clone)]
+pub enum LogpFuzzFailure {
+    /// The logp function panicked
+    Panic { message: String },
+    /// The logp function returned an unrecoverable error
+    UnrecoverableError { message: String },
+    /// The logp function returned `NaN` or `+inf`
+    InvalidLogp { logp: f64 },
+    /// The logp was finite, but the gradient was not
+    NonFiniteGradient { index: usize, value: f64 },
+}
+
+/// The position that caused a [`LogpFuzzFailure`]
+#[derive(Debug, Clone)]
+pub struct LogpFuzzReport {
+    pub position: Box<[f64]>,
+    pub failure: LogpFuzzFailure,
+}
+
+/// Evaluate a logp function at adversarial positions and report problems.
+///
+/// Positions are standard normal draws where a random subset of the
+/// coordinates is replaced by huge, subnormal, infinite or `NaN` values.
+/// Recoverable errors and a logp of `-inf` are treated as valid results.
+/// Positions that contain `NaN` or infinite values are included, so some
+/// reports might be expected for a given model.
+pub fn fuzz_logp<F: CpuLogpFunc>(func: &mut F, num_evals: usize, seed: u64) -> Vec<LogpFuzzReport> {
+    let dim = func.dim();
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut position = vec![0f64; dim];
+    let mut grad = vec![0f64; dim];
+    let mut reports = vec![];
+
+    for _ in 0..num_evals {
+        position.iter_mut().for_each(|x| {
+            *x = if rng.gen_bool(0.2) {
+                ADVERSARIAL_VALUES[rng.gen_range(0..ADVERSARIAL_VALUES.len())]
+            } else {
+                rng.sample(rand_distr::StandardNormal)
+            }
+        });
+        grad.fill(0f64);
+
+        let result = catch_unwind(AssertUnwindSafe(|| func.logp(&position, &mut grad)));
+        let failure = match result {
+            Err(payload) => Some(LogpFuzzFailure::Panic {
+                message: LogpPanic::new(payload).message,
+            }),
+            Ok(Err(err)) if !err.is_recoverable() => Some(LogpFuzzFailure::UnrecoverableError {
+                message: err.to_string(),
+            }),
+            Ok(Err(_)) => None,
+            Ok(Ok(logp)) if logp.is_nan() | (logp == f64::INFINITY) => {
+                Some(LogpFuzzFailure::InvalidLogp { logp })
+            }
+            Ok(Ok(logp)) if logp.is_finite() => grad
+                .iter()
+                .enumerate()
+                .find(|(_, val)| !val.is_finite())
+                .map(|(index, &value)| LogpFuzzFailure::NonFiniteGradient { index, value }),
+            Ok(Ok(_)) => None,
+        };
+
+        if let Some(failure) = failure {
+            reports.push(LogpFuzzReport {
+                position: position.clone().into(),
+                failure,
+            });
+        }
+    }
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_sampler::test_logps::{NormalLogp, NormalLogpError};
+
+    #[test]
+    fn fuzz_normal() {
+        let mut func = NormalLogp::new(5, 0.);
+        let reports = fuzz_logp(&mut func, 500, 42);
+        assert!(!reports.is_empty());
+        for report in reports.iter() {
+            assert!(report.position.iter().any(|x| !x.is_finite()));
+            assert!(!matches!(report.failure, LogpFuzzFailure::Panic { .. }));
+        }
+    }
+
+    #[test]
+    fn fuzz_panicking_logp() {
+        struct PanicLogp {}
+
+        impl CpuLogpFunc for PanicLogp {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                3
+            }
+
+            fn logp(&mut self, position: &[f64], _grad: &mut [f64]) -> Result<f64, Self::Err> {
+                assert!(position.iter().all(|x| x.abs() < 1e100), "Large value");
+                Ok(0f64)
+            }
+        }
+
+        let reports = fuzz_logp(&mut PanicLogp {}, 200, 42);
+        assert!(reports
+            .iter()
+            .any(|report| matches!(&report.failure, LogpFuzzFailure::Panic { message } if message == "Large value")));
+    }
+}
@@ -0,0 +1,315 @@
+//! Ready-made logp functions for generalized linear models
+//!
+//! The design matrix products use [`ndarray`]. Enable the `blas` feature of
+//! `ndarray` in your own `Cargo.toml` (and a `blas-src` backend) to run them
+//! with BLAS.
+
+use ndarray::{linalg::general_mat_vec_mul, Array1, Array2, ArrayView1, ArrayViewMut1};
+use thiserror::Error;
+
+use crate::{cpu_potential::CpuLogpFunc, nuts::LogpError};
+
+/// The likelihood and link function of a [`Glm`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlmFamily {
+    /// Normal observations with identity link and unknown noise `sigma`.
+    ///
+    /// The last entry of the position is `ln(sigma)`, and `sigma` has a
+    /// half-normal prior with scale `sigma_scale`.
+    Linear { sigma_scale: f64 },
+    /// Binary observations in `{0, 1}` with logit link.
+    Logistic,
+    /// Count observations with log link.
+    Poisson,
+}
+
+/// The prior of each regression coefficient
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoefPrior {
+    /// An improper flat prior
+    Flat,
+    /// A normal prior with mean zero
+    Normal { scale: f64 },
+    /// A Student-t prior with mean zero, for example a Cauchy prior with
+    /// `nu = 1`
+    StudentT { nu: f64, scale: f64 },
+}
+
+impl CoefPrior {
+    /// Add the gradient to `grad` and return the log density.
+    fn logp(&self, coef: &[f64], grad: &mut [f64]) -> f64 {
+        match *self {
+            CoefPrior::Flat => 0f64,
+            CoefPrior::Normal { scale } => coef
+                .iter()
+                .zip(grad)
+                .map(|(&val, grad)| {
+                    let val = val / scale;
+                    *grad -= val / scale;
+                    -val * val / 2f64
+                })
+                .sum(),
+            CoefPrior::StudentT { nu, scale } => coef
+                .iter()
+                .zip(grad)
+                .map(|(&val, grad)| {
+                    let norm = val / scale;
+                    *grad -= (nu + 1f64) * val / (nu * scale * scale + val * val);
+                    -(nu + 1f64) / 2f64 * (norm * norm / nu).ln_1p()
+                })
+                .sum(),
+        }
+    }
+}
+
+/// Errors when a [`Glm`] is created
+#[derive(Debug, Error)]
+pub enum GlmError {
+    #[error("Design matrix has {rows} rows, but there are {observed} observations")]
+    ShapeMismatch { rows: usize, observed: usize },
+    #[error("Invalid observation {value} at index {index}")]
+    InvalidObservation { index: usize, value: f64 },
+    #[error("Invalid prior parameter")]
+    InvalidPrior,
+}
+
+/// The logp of a [`Glm`] overflowed
+#[derive(Debug, Error)]
+#[error("Logp of the generalized linear model is not finite")]
+pub struct GlmLogpError {}
+
+impl LogpError for GlmLogpError {
+    fn is_recoverable(&self) -> bool {
+        true
+    }
+}
+
+/// A generalized linear model with a design matrix `x` and observations `y`
+///
+/// The position contains one coefficient for each column of `x`, followed
+/// by `ln(sigma)` for [`GlmFamily::Linear`]. Add a column of ones to `x`
+/// for an intercept. Constant terms of the log likelihood are dropped.
+#[derive(Debug, Clone)]
+pub struct Glm {
+    family: GlmFamily,
+    prior: CoefPrior,
+    x: Array2<f64>,
+    y: Array1<f64>,
+    eta: Array1<f64>,
+}
+
+impl Glm {
+    pub fn new(
+        family: GlmFamily,
+        prior: CoefPrior,
+        x: Array2<f64>,
+        y: Array1<f64>,
+    ) -> Result<Self, GlmError> {
+        if x.nrows() != y.len() {
+            return Err(GlmError::ShapeMismatch {
+                rows: x.nrows(),
+                observed: y.len(),
+            });
+        }
+        let valid = |val: f64| match family {
+            GlmFamily::Linear { .. } => val.is_finite(),
+            GlmFamily::Logistic => (val == 0f64) | (val == 1f64),
+            GlmFamily::Poisson => (val >= 0f64) & val.is_finite() & (val.fract() == 0f64),
+        };
+        if let Some((index, &value)) = y.iter().enumerate().find(|(_, &val)| !valid(val)) {
+            return Err(GlmError::InvalidObservation { index, value });
+        }
+        let prior_valid = match prior {
+            CoefPrior::Flat => true,
+            CoefPrior::Normal { scale } => scale > 0f64,
+            CoefPrior::StudentT { nu, scale } => (nu > 0f64) & (scale > 0f64),
+        };
+        let family_valid = match family {
+            GlmFamily::Linear { sigma_scale } => sigma_scale > 0f64,
+            _ => true,
+        };
+        if !prior_valid | !family_valid {
+            return Err(GlmError::InvalidPrior);
+        }
+        let eta = Array1::zeros(y.len());
+        Ok(Glm {
+            family,
+            prior,
+            x,
+            y,
+            eta,
+        })
+    }
+
+    /// The number of regression coefficients
+    pub fn num_coefs(&self) -> usize {
+        self.x.ncols()
+    }
+}
+
+impl CpuLogpFunc for Glm {
+    type Err = GlmLogpError;
+
+    fn dim(&self) -> usize {
+        match self.family {
+            GlmFamily::Linear { .. } => self.num_coefs() + 1,
+            _ => self.num_coefs(),
+        }
+    }
+
+    fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+        let num_coefs = self.num_coefs();
+        let coef = &position[..num_coefs];
+        let (grad_coef, grad_sigma) = grad.split_at_mut(num_coefs);
+        general_mat_vec_mul(1f64, &self.x, &ArrayView1::from(coef), 0f64, &mut self.eta);
+
+        // Replace eta by the derivative of the log likelihood with respect to eta
+        let mut logp = 0f64;
+        let mut scale = 1f64;
+        match self.family {
+            GlmFamily::Linear { sigma_scale } => {
+                let log_sigma = position[num_coefs];
+                let sigma = log_sigma.exp();
+                let mut sum_sq = 0f64;
+                self.eta.zip_mut_with(&self.y, |eta, &y| {
+                    *eta = y - *eta;
+                    sum_sq += *eta * *eta;
+                });
+                let n = self.y.len() as f64;
+                let sigma_sq = sigma * sigma;
+                let prior_norm = sigma / sigma_scale;
+                logp += -n * log_sigma - sum_sq / (2f64 * sigma_sq);
+                // Half-normal prior on sigma and the jacobian of exp
+                logp += -prior_norm * prior_norm / 2f64 + log_sigma;
+                grad_sigma[0] = -n + sum_sq / sigma_sq - prior_norm * prior_norm + 1f64;
+                scale = 1f64 / sigma_sq;
+            }
+            GlmFamily::Logistic => {
+                self.eta.zip_mut_with(&self.y, |eta, &y| {
+                    // log(1 + exp(eta)), computed without overflow
+                    let softplus = eta.max(0f64) + (-eta.abs()).exp().ln_1p();
+                    logp += y * *eta - softplus;
+                    *eta = y - 1f64 / (1f64 + (-*eta).exp());
+                });
+            }
+            GlmFamily::Poisson => {
+                self.eta.zip_mut_with(&self.y, |eta, &y| {
+                    let rate = eta.exp();
+                    logp += y * *eta - rate;
+                    *eta = y - rate;
+                });
+            }
+        }
+
+        let mut grad_view = ArrayViewMut1::from(&mut *grad_coef);
+        general_mat_vec_mul(scale, &self.x.t(), &self.eta, 0f64, &mut grad_view);
+        logp += self.prior.logp(coef, grad_coef);
+
+        if !logp.is_finite() | grad.iter().any(|val| !val.is_finite()) {
+            return Err(GlmLogpError {});
+        }
+        Ok(logp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fuzz_logp, sample_sequentially, SamplerArgs};
+    use ndarray::array;
+
+    fn design() -> Array2<f64> {
+        Array2::from_shape_fn((40, 3), |(i, j)| match j {
+            0 => 1f64,
+            1 => (i as f64 * 0.7).sin(),
+            _ => (i as f64 * 0.3).cos(),
+        })
+    }
+
+    #[test]
+    fn gradients() {
+        let x = design();
+        let linear_y = x.dot(&array![0.5, -1., 2.]) + x.column(1).mapv(|val| 0.1 * val * val);
+        let logistic_y = linear_y.mapv(|val| (val > 0.5) as u8 as f64);
+        let poisson_y = linear_y.mapv(|val| val.exp().round());
+        let models = [
+            (
+                GlmFamily::Linear { sigma_scale: 2. },
+                CoefPrior::Normal { scale: 3. },
+                linear_y,
+            ),
+            (
+                GlmFamily::Logistic,
+                CoefPrior::StudentT { nu: 3., scale: 2. },
+                logistic_y,
+            ),
+            (GlmFamily::Poisson, CoefPrior::Flat, poisson_y),
+        ];
+        for (family, prior, y) in models {
+            let mut glm = Glm::new(family, prior, x.clone(), y).unwrap();
+            let dim = glm.dim();
+            let position: Vec<f64> = (0..dim).map(|i| 0.3 - 0.2 * i as f64).collect();
+            let mut grad = vec![0f64; dim];
+            let logp = glm.logp(&position, &mut grad).unwrap();
+            for i in 0..dim {
+                let mut shifted = position.clone();
+                shifted[i] += 1e-6;
+                let logp_shifted = glm.logp(&shifted, &mut vec![0f64; dim]).unwrap();
+                let numeric = (logp_shifted - logp) / 1e-6;
+                assert!(
+                    (numeric - grad[i]).abs() < 1e-3 * (1. + grad[i].abs()),
+                    "{:?} {}: {} != {}",
+                    family,
+                    i,
+                    numeric,
+                    grad[i]
+                );
+            }
+
+            let report = fuzz_logp(&mut glm, 200, 42);
+            assert!(report.is_empty(), "{:?} {:?}", family, report);
+        }
+
+        let err = Glm::new(GlmFamily::Logistic, CoefPrior::Flat, x, Array1::zeros(39));
+        assert!(matches!(err, Err(GlmError::ShapeMismatch { .. })));
+        let err = Glm::new(
+            GlmFamily::Poisson,
+            CoefPrior::Flat,
+            design(),
+            Array1::from_elem(40, 0.5),
+        );
+        assert!(matches!(
+            err,
+            Err(GlmError::InvalidObservation { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn sample_linear_regression() {
+        let x = design();
+        let y = x.dot(&array![1., -2., 0.5])
+            + Array1::from_shape_fn(40, |i| 0.1 * (i as f64 * 2.3).sin());
+        let glm = Glm::new(
+            GlmFamily::Linear { sigma_scale: 1. },
+            CoefPrior::Normal { scale: 10. },
+            x,
+            y,
+        )
+        .unwrap();
+        let settings = SamplerArgs {
+            num_tune: 300,
+            ..Default::default()
+        };
+        let chain = sample_sequentially(glm, settings, &[0.; 4], 800, 0, 42).unwrap();
+        let mut mean = [0f64; 4];
+        for draw in chain.skip(300) {
+            let (draw, _) = draw.unwrap();
+            mean.iter_mut()
+                .zip(draw.iter())
+                .for_each(|(mean, val)| *mean += val / 500.);
+        }
+        for (mean, truth) in mean.iter().zip([1., -2., 0.5, 0.07f64.ln()]) {
+            assert!((mean - truth).abs() < 0.5, "{:?}", mean);
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::metadata::TraceHasher;
+use crate::nuts::{Chain, NutsError, SampleStatValue, SampleStats};
+
+const HEADER: &str = "nuts-rs-golden-trace 3";
+
+/// Set this environment variable to overwrite golden files in
+/// [`GoldenTrace::check_file`] instead of verifying them.
+pub const UPDATE_GOLDEN_VAR: &str = "NUTS_RS_UPDATE_GOLDEN";
+
+/// Errors when a [`GoldenTrace`] is loaded or verified
+#[derive(Error, Debug)]
+pub enum GoldenError {
+    #[error("Could not read or write the golden trace: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid golden trace in line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error("Sampling failed: {0}")]
+    Sampler(#[from] NutsError),
+    #[error("The golden trace has stats {expected:?}, but the sampler reports {found:?}")]
+    StatNames {
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+    #[error("{name} of draw {draw} is {found}, but the golden trace has {expected}")]
+    Mismatch {
+        draw: usize,
+        name: String,
+        expected: GoldenStat,
+        found: GoldenStat,
+    },
+    #[error("Draw {draw} of the golden trace does not match its stored hash")]
+    Corrupted { draw: usize },
+    #[error("The golden trace ends after {found} draws, but should have {expected:?}")]
+    Truncated {
+        found: usize,
+        expected: Option<usize>,
+    },
+    #[error("The golden trace {0} does not exist, set {UPDATE_GOLDEN_VAR} to record it")]
+    MissingFile(PathBuf),
+}
+
+/// The value of a scalar stat in a [`GoldenTrace`]
+///
+/// Integers are stored as integers, so that large values like the draw
+/// seeds are compared exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GoldenStat {
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    /// A missing optional value
+    None,
+}
+
+impl GoldenStat {
+    fn from_stat(value: SampleStatValue) -> Option<Self> {
+        Some(match value {
+            SampleStatValue::U64(val) => GoldenStat::U64(val),
+            SampleStatValue::I64(val) => GoldenStat::I64(val),
+            SampleStatValue::F64(val) => GoldenStat::F64(val),
+            SampleStatValue::Bool(val) => GoldenStat::Bool(val),
+            SampleStatValue::OptionI64(val) => val.map_or(GoldenStat::None, GoldenStat::I64),
+            SampleStatValue::OptionF64(val) => val.map_or(GoldenStat::None, GoldenStat::F64),
+            SampleStatValue::Array(_)
+            | SampleStatValue::OptionArray(_)
+            | SampleStatValue::String(_) => return None,
+        })
+    }
+
+    /// Whether `found` reproduces `self`. Only floats may differ by
+    /// `tolerance`, see [`matches`].
+    fn reproduced_by(&self, found: &GoldenStat, tolerance: f64) -> bool {
+        match (self, found) {
+            (&GoldenStat::F64(expected), &GoldenStat::F64(found)) => {
+                matches(expected, found, tolerance)
+            }
+            (expected, found) => expected == found,
+        }
+    }
+
+    /// A tag of the type and the bits of the value, for the [`TraceHasher`]
+    fn tagged_bits(&self) -> (u8, u64) {
+        match *self {
+            GoldenStat::U64(val) => (0, val),
+            GoldenStat::I64(val) => (1, val as u64),
+            GoldenStat::F64(val) => (2, if val.is_nan() { f64::NAN } else { val }.to_bits()),
+            GoldenStat::Bool(val) => (3, val as u64),
+            GoldenStat::None => (4, 0),
+        }
+    }
+}
+
+/// Integers are written with a `u64` or `i64` suffix, and floats with
+/// enough digits to be read back exactly.
+impl Display for GoldenStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoldenStat::U64(val) => write!(f, "{}u64", val),
+            GoldenStat::I64(val) => write!(f, "{}i64", val),
+            GoldenStat::F64(val) => write!(f, "{}", val),
+            GoldenStat::Bool(val) => write!(f, "{}", val),
+            GoldenStat::None => write!(f, "none"),
+        }
+    }
+}
+
+impl FromStr for GoldenStat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "none" => return Ok(GoldenStat::None),
+            "true" => return Ok(GoldenStat::Bool(true)),
+            "false" => return Ok(GoldenStat::Bool(false)),
+            _ => {}
+        }
+        if let Some(val) = value.strip_suffix("u64") {
+            return val.parse().map(GoldenStat::U64).map_err(|_| ());
+        }
+        if let Some(val) = value.strip_suffix("i64") {
+            return val.parse().map(GoldenStat::I64).map_err(|_| ());
+        }
+        value.parse().map(GoldenStat::F64).map_err(|_| ())
+    }
+}
+
+/// The exact draws and scalar stats of a short run with a fixed model and
+/// seed
+///
+/// A golden trace is recorded once, stored with [`save`](Self::save), and
+/// later [`verify`](Self::verify)-ed against a new run with the same model,
+/// settings and seed. A refactoring that should not change the behaviour of
+/// the sampler must reproduce it bit by bit. Changes that are only meant to
+/// be numerically equivalent can be checked with a tolerance. With
+/// `strict_reproducibility` traces are the same on all platforms.
+///
+/// Stats are only compared if they are numbers or booleans, see
+/// [`GoldenStat`].
+///
+/// Each saved draw can be followed by the [`TraceHasher`] hash of all draws
+/// and stats up to it, so that [`load`](Self::load) detects damaged files.
+/// The file ends with the number of draws, so that a file that was cut off
+/// after a complete draw is detected with or without hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldenTrace {
+    /// The names of the stored stats, in the order of [`SampleStats::to_vec`]
+    pub stat_names: Vec<String>,
+    pub draws: Vec<Box<[f64]>>,
+    /// The values of the stats in `stat_names` for each draw
+    pub stats: Vec<Box<[GoldenStat]>>,
+}
+
+fn scalar_stats(stats: &impl SampleStats) -> (Vec<&'static str>, Box<[GoldenStat]>) {
+    let (names, values): (Vec<_>, Vec<_>) = stats
+        .to_vec()
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, GoldenStat::from_stat(value)?)))
+        .unzip();
+    (names, values.into())
+}
+
+fn tagged_bits(stats: &[GoldenStat]) -> Vec<(u8, u64)> {
+    stats.iter().map(GoldenStat::tagged_bits).collect()
+}
+
+/// Whether `found` reproduces `expected`. With `tolerance == 0` the values
+/// must be identical, otherwise they may differ by `tolerance` relative to
+/// the larger of `1` and `|expected|`.
+fn matches(expected: f64, found: f64, tolerance: f64) -> bool {
+    if expected.is_nan() | found.is_nan() {
+        return expected.is_nan() & found.is_nan();
+    }
+    if tolerance == 0f64 {
+        return expected.to_bits() == found.to_bits();
+    }
+    (expected == found) | ((expected - found).abs() <= tolerance * expected.abs().max(1f64))
+}
+
+impl GoldenTrace {
+    /// Make `num_draws` draws with `chain`, which must already be at its
+    /// initial position, and store them with their stats.
+    pub fn record<C: Chain>(chain: &mut C, num_draws: usize) -> Result<Self, GoldenError> {
+        let mut trace = GoldenTrace {
+            stat_names: vec![],
+            draws: Vec::with_capacity(num_draws),
+            stats: Vec::with_capacity(num_draws),
+        };
+        for _ in 0..num_draws {
+            let (draw, stats) = chain.draw()?;
+            let (names, values) = scalar_stats(&stats);
+            if trace.stat_names.is_empty() {
+                trace.stat_names = names.iter().map(|name| name.to_string()).collect();
+            }
+            trace.draws.push(draw);
+            trace.stats.push(values);
+        }
+        Ok(trace)
+    }
+
+    /// Make the same number of draws with `chain` as in the trace, and
+    /// compare them and their stats to the trace.
+    ///
+    /// `chain` must have been created like the recorded chain, with the same
+    /// model, settings, seed and initial position. Fails at the first value
+    /// that does not match, see [`GoldenError::Mismatch`]. A `tolerance` of
+    /// zero requires bit-exact reproduction. Integer and boolean stats must
+    /// always match exactly.
+    pub fn verify<C: Chain>(&self, chain: &mut C, tolerance: f64) -> Result<(), GoldenError> {
+        for (idx, (expected_draw, expected_stats)) in
+            self.draws.iter().zip(self.stats.iter()).enumerate()
+        {
+            let (draw, stats) = chain.draw()?;
+            let (names, values) = scalar_stats(&stats);
+            if names.iter().ne(self.stat_names.iter()) {
+                return Err(GoldenError::StatNames {
+                    expected: self.stat_names.clone(),
+                    found: names.iter().map(|name| name.to_string()).collect(),
+                });
+            }
+            let positions = expected_draw.iter().zip(draw.iter()).enumerate().map(
+                |(param, (&expected, &found))| {
+                    let values = (GoldenStat::F64(expected), GoldenStat::F64(found));
+                    (format!("Position {}", param), values)
+                },
+            );
+            let stats = self
+                .stat_names
+                .iter()
+                .zip(expected_stats.iter().zip(values.iter()))
+                .map(|(name, (&expected, &found))| (format!("Stat {}", name), (expected, found)));
+            for (name, (expected, found)) in positions.chain(stats) {
+                if !expected.reproduced_by(&found, tolerance) {
+                    return Err(GoldenError::Mismatch {
+                        draw: idx,
+                        name,
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify `chain` against the golden trace in the file at `path`.
+    ///
+    /// If the environment variable [`UPDATE_GOLDEN_VAR`] is set, `num_draws`
+    /// draws are recorded and written to `path` instead. Review the diff of
+    /// the file before you commit an updated trace. Without the variable, a
+    /// missing file is an error, so that a test can not pass by recording
+    /// its own expectation.
+    pub fn check_file<C: Chain>(
+        path: impl AsRef<Path>,
+        chain: &mut C,
+        num_draws: usize,
+        tolerance: f64,
+    ) -> Result<(), GoldenError> {
+        let path = path.as_ref();
+        if std::env::var_os(UPDATE_GOLDEN_VAR).is_none() {
+            if !path.exists() {
+                return Err(GoldenError::MissingFile(path.to_path_buf()));
+            }
+            let trace = Self::load(BufReader::new(File::open(path)?))?;
+            return trace.verify(chain, tolerance);
+        }
+        let trace = Self::record(chain, num_draws)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        trace.save(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the trace in a line based text format with a hash after each
+    /// draw, see [`save_with_hashes`](Self::save_with_hashes).
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), GoldenError> {
+        self.save_with_hashes(writer, true)
+    }
+
+    /// Write the trace in a line based text format. Floats are written
+    /// with enough digits to be read back exactly. With `hashes`, each draw
+    /// is followed by the [`TraceHasher`] hash of the trace up to it.
+    pub fn save_with_hashes<W: Write>(
+        &self,
+        mut writer: W,
+        hashes: bool,
+    ) -> Result<(), GoldenError> {
+        fn join<T: Display>(values: &[T]) -> String {
+            values
+                .iter()
+                .map(|val| val.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        writeln!(writer, "{}", HEADER)?;
+        writeln!(writer, "stats {}", self.stat_names.join(" "))?;
+        let mut hasher = TraceHasher::new();
+        for (draw, stats) in self.draws.iter().zip(self.stats.iter()) {
+            writeln!(writer, "draw {}", join(draw))?;
+            writeln!(writer, "values {}", join(stats))?;
+            if hashes {
+                let hash = hasher.update_tagged(draw, &tagged_bits(stats));
+                writeln!(writer, "hash {}", hash)?;
+            }
+        }
+        writeln!(writer, "end {}", self.draws.len())?;
+        Ok(())
+    }
+
+    /// Read a trace that was written with [`save`](Self::save).
+    ///
+    /// Fails with [`GoldenError::Corrupted`] at the first draw that does not
+    /// match its hash, or if the hashes stop before the last draw, and with
+    /// [`GoldenError::Truncated`] if the number of draws at the end of the
+    /// file is missing or wrong.
+    pub fn load<R: BufRead>(reader: R) -> Result<Self, GoldenError> {
+        let mut lines = reader.lines().enumerate().peekable();
+        let mut next =
+            |key: &str, optional: bool| -> Result<Option<(usize, String)>, GoldenError> {
+                if let Some((_, Ok(line))) = lines.peek() {
+                    if optional && !line.starts_with(key) {
+                        return Ok(None);
+                    }
+                }
+                let Some((idx, line)) = lines.next() else {
+                    return Ok(None);
+                };
+                let line = line?;
+                let value = line
+                    .strip_prefix(key)
+                    .and_then(|rest| rest.strip_prefix(' ').or(rest.is_empty().then_some("")))
+                    .ok_or_else(|| GoldenError::Parse {
+                        line: idx + 1,
+                        message: format!("Expected {}", key),
+                    })?;
+                Ok(Some((idx + 1, value.to_string())))
+            };
+        fn parse_array<T: FromStr>(
+            (line, value): (usize, String),
+        ) -> Result<Box<[T]>, GoldenError> {
+            value
+                .split_whitespace()
+                .map(|val| {
+                    val.parse().map_err(|_| GoldenError::Parse {
+                        line,
+                        message: format!("Invalid value {}", val),
+                    })
+                })
+                .collect()
+        }
+        let missing = |key: &str| GoldenError::Parse {
+            line: 0,
+            message: format!("Missing {}", key),
+        };
+
+        next(HEADER, false)?.ok_or_else(|| missing(HEADER))?;
+        let (_, names) = next("stats", false)?.ok_or_else(|| missing("stats"))?;
+        let stat_names: Vec<String> = names.split_whitespace().map(String::from).collect();
+        let mut trace = GoldenTrace {
+            stat_names,
+            draws: vec![],
+            stats: vec![],
+        };
+        let mut hasher = TraceHasher::new();
+        let mut hashed = None;
+        while let Some(draw) = next("draw", true)? {
+            let draw: Box<[f64]> = parse_array(draw)?;
+            let (line, values) = next("values", false)?.ok_or_else(|| missing("values"))?;
+            let values: Box<[GoldenStat]> = parse_array((line, values))?;
+            if values.len() != trace.stat_names.len() {
+                return Err(GoldenError::Parse {
+                    line,
+                    message: "Wrong number of stats".to_string(),
+                });
+            }
+            let hash = next("hash", true)?
+                .map(|(line, hash)| {
+                    hash.parse::<u64>().map_err(|_| GoldenError::Parse {
+                        line,
+                        message: format!("Invalid hash {}", hash),
+                    })
+                })
+                .transpose()?;
+            let expected = hasher.update_tagged(&draw, &tagged_bits(&values));
+            let corrupted = GoldenError::Corrupted {
+                draw: trace.draws.len(),
+            };
+            match (*hashed.get_or_insert(hash.is_some()), hash) {
+                (false, None) => {}
+                (true, Some(hash)) if hash == expected => {}
+                _ => return Err(corrupted),
+            }
+            trace.draws.push(draw);
+            trace.stats.push(values);
+        }
+        let truncated = |expected| GoldenError::Truncated {
+            found: trace.draws.len(),
+            expected,
+        };
+        let Some((line, count)) = next("end", false)? else {
+            return Err(truncated(None));
+        };
+        let count = count.parse::<usize>().map_err(|_| GoldenError::Parse {
+            line,
+            message: format!("Invalid number of draws {}", count),
+        })?;
+        if count != trace.draws.len() {
+            return Err(truncated(Some(count)));
+        }
+        if let Some((idx, _)) = lines.next() {
+            return Err(GoldenError::Parse {
+                line: idx + 1,
+                message: "Unexpected line after the end of the trace".to_string(),
+            });
+        }
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_sampler, test_logps::NormalLogp, SamplerArgs};
+
+    fn make_chain(seed: u64) -> impl Chain {
+        let settings = SamplerArgs {
+            num_tune: 20,
+            strict_reproducibility: true,
+            ..Default::default()
+        };
+        let mut chain = new_sampler(NormalLogp::new(3, 1.), settings, 0, seed);
+        chain.set_position(&[0.; 3]).unwrap();
+        chain
+    }
+
+    #[test]
+    fn record_and_verify() {
+        let trace = GoldenTrace::record(&mut make_chain(42), 30).unwrap();
+        assert_eq!(trace.draws.len(), 30);
+        assert!(trace.stat_names.iter().any(|name| name == "step_size"));
+        let seed_idx = trace
+            .stat_names
+            .iter()
+            .position(|name| name == "draw_seed")
+            .unwrap();
+        assert!(matches!(trace.stats[0][seed_idx], GoldenStat::U64(_)));
+
+        let mut buffer = vec![];
+        trace.save(&mut buffer).unwrap();
+        let loaded = GoldenTrace::load(&buffer[..]).unwrap();
+        // NaN stats are not equal to themselves
+        assert_eq!(loaded.draws, trace.draws);
+        assert_eq!(loaded.stat_names, trace.stat_names);
+        let seeds = |trace: &GoldenTrace| {
+            trace
+                .stats
+                .iter()
+                .map(|stats| stats[seed_idx])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(seeds(&loaded), seeds(&trace));
+        loaded.verify(&mut make_chain(42), 0.).unwrap();
+
+        // Integer stats must match exactly, even with a tolerance
+        let mut perturbed = loaded.clone();
+        let GoldenStat::U64(seed) = perturbed.stats[3][seed_idx] else {
+            panic!("The draw seed is not an integer");
+        };
+        perturbed.stats[3][seed_idx] = GoldenStat::U64(seed ^ 1);
+        let err = perturbed.verify(&mut make_chain(42), 1e-8).unwrap_err();
+        assert!(
+            matches!(err, GoldenError::Mismatch { draw: 3, ref name, .. } if name == "Stat draw_seed"),
+            "{}",
+            err
+        );
+
+        let err = loaded.verify(&mut make_chain(43), 1e-8).unwrap_err();
+        assert!(
+            matches!(err, GoldenError::Mismatch { ref name, .. } if name.starts_with("Position")),
+            "{}",
+            err
+        );
+
+        let mut perturbed = loaded.clone();
+        perturbed.draws[10][1] *= 1. + 1e-12;
+        assert!(perturbed.verify(&mut make_chain(42), 0.).is_err());
+        perturbed.verify(&mut make_chain(42), 1e-10).unwrap();
+    }
+
+    #[test]
+    fn corrupted_file() {
+        let trace = GoldenTrace::record(&mut make_chain(42), 5).unwrap();
+        let mut buffer = vec![];
+        trace.save(&mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        let load = |lines: &[&str]| GoldenTrace::load(lines.join("\n").as_bytes());
+        assert_eq!(load(&lines).unwrap().draws, trace.draws);
+
+        // Header and stat names come before the first draw, and each draw
+        // takes three lines
+        let mut changed = lines.clone();
+        let draw = changed[2 + 3 * 3].replacen('.', "", 1);
+        changed[2 + 3 * 3] = &draw;
+        let err = load(&changed).unwrap_err();
+        assert!(matches!(err, GoldenError::Corrupted { draw: 3 }), "{}", err);
+
+        let mut removed = lines.clone();
+        removed.remove(2 + 3 * 2 + 2);
+        let err = load(&removed).unwrap_err();
+        assert!(matches!(err, GoldenError::Corrupted { draw: 2 }), "{}", err);
+
+        let unhashed: Vec<&str> = lines
+            .iter()
+            .copied()
+            .filter(|line| !line.starts_with("hash"))
+            .collect();
+        assert_eq!(load(&unhashed).unwrap().draws, trace.draws);
+        let mut buffer = vec![];
+        trace.save_with_hashes(&mut buffer, false).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer)
+                .unwrap()
+                .lines()
+                .collect::<Vec<_>>(),
+            unhashed
+        );
+
+        // Files that were cut off after a complete draw
+        for lines in [&lines, &unhashed] {
+            let err = load(&lines[..lines.len() - 1]).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    GoldenError::Truncated {
+                        found: 5,
+                        expected: None
+                    }
+                ),
+                "{}",
+                err
+            );
+            let per_draw = (lines.len() - 3) / 5;
+            let err = load(&lines[..2 + 3 * per_draw]).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    GoldenError::Truncated {
+                        found: 3,
+                        expected: None
+                    }
+                ),
+                "{}",
+                err
+            );
+        }
+        let mut wrong_count = lines.clone();
+        *wrong_count.last_mut().unwrap() = "end 6";
+        let err = load(&wrong_count).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                GoldenError::Truncated {
+                    found: 5,
+                    expected: Some(6)
+                }
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn golden_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/normal_3.txt");
+        GoldenTrace::check_file(path, &mut make_chain(42), 30, 0.).unwrap();
+
+        if std::env::var_os(UPDATE_GOLDEN_VAR).is_none() {
+            let path = std::env::temp_dir().join("nuts-rs-missing-golden-trace.txt");
+            let err = GoldenTrace::check_file(&path, &mut make_chain(42), 30, 0.).unwrap_err();
+            assert!(matches!(err, GoldenError::MissingFile(_)), "{}", err);
+            assert!(!path.exists());
+        }
+    }
+}
@@ -1,6 +1,10 @@
 #![cfg_attr(feature = "simd_support", feature(stdsimd))]
 #![cfg_attr(feature = "simd_support", feature(portable_simd))]
 #![cfg_attr(feature = "simd_support", feature(slice_as_chunks))]
+#![allow(clippy::type_complexity)]
+// Many docs link to `sample_parallel` and its helpers, which only exist with
+// the `parallel` feature. CI checks the links with all features enabled.
+#![cfg_attr(not(feature = "parallel"), allow(rustdoc::broken_intra_doc_links))]
 //! Sample from posterior distributions using the No U-turn Sampler (NUTS).
 //! For details see the original [NUTS paper](https://arxiv.org/abs/1111.4246)
 //! and the more recent [introduction](https://arxiv.org/abs/1701.02434).
@@ -82,7 +86,21 @@
 //! ```
 //!
 //! Sampling several chains in parallel so that samples are accessable as they are generated
-//! is implemented in [`sample_parallel`].
+//! is implemented in [`sample_parallel`], which needs the `parallel` feature.
+//!
+//! ## Features
+//!
+//! No features are enabled by default, so that embedded and WASM builds
+//! only get the single-chain sampler and its diagnostics. The only
+//! dependencies are then `rand`, `rand_distr`, `multiversion`, `itertools`
+//! and `thiserror`.
+//!
+//! - `parallel`: multi-chain sampling in threads with [`sample_parallel`]
+//!   and related functions. Depends on `rayon` and `crossbeam`.
+//! - `ndarray`: conversion of draws to `ndarray` arrays.
+//! - `nalgebra`: conversion of draws to `nalgebra` matrices.
+//! - `glm`: ready-made generalized linear models, implies `ndarray`.
+//! - `affinity`: pin chain threads to cores on Linux.
 //!
 //! ## Implementation details
 //!
@@ -96,20 +114,81 @@
 //! and keep adapting it live until `stop_tune_at`.
 
 pub(crate) mod adapt_strategy;
+#[cfg(feature = "parallel")]
+pub(crate) mod affinity;
+pub(crate) mod budget;
+pub(crate) mod bundle;
+pub(crate) mod compare;
+pub(crate) mod convert;
 pub(crate) mod cpu_potential;
 pub(crate) mod cpu_sampler;
 pub(crate) mod cpu_state;
+pub(crate) mod delayed;
+pub(crate) mod diagnostics;
+pub(crate) mod discrete;
+pub(crate) mod ensemble;
+pub(crate) mod fuzz;
+#[cfg(feature = "glm")]
+pub mod glm;
+pub(crate) mod golden;
 pub(crate) mod mass_matrix;
 pub mod math;
+pub(crate) mod metadata;
 pub(crate) mod nuts;
+pub(crate) mod preconditioner;
+pub(crate) mod reparam;
+pub(crate) mod riemannian;
+pub(crate) mod sparse_grad;
 pub(crate) mod stepsize;
+pub(crate) mod stopping;
+pub(crate) mod validation;
 
 pub use adapt_strategy::DualAverageSettings;
-pub use cpu_potential::CpuLogpFunc;
+pub use budget::{recommend_run, PilotRun, RunBudget, RunRecommendation};
+pub use bundle::{BundleError, TunedBundle};
+pub use compare::{compare_runs, CompareError, RunComparison, RunDiagnostics};
+#[cfg(feature = "ndarray")]
+pub use convert::draws_to_array;
+pub use convert::DrawShapeError;
+pub use cpu_potential::{leapfrog_n, CpuLogpFunc, EnergyErrorBins, LeapfrogPoint, LogpPanic};
 pub use cpu_sampler::test_logps;
 pub use cpu_sampler::{
-    new_sampler, sample_parallel, sample_sequentially, CpuLogpFuncMaker, InitPointFunc,
-    JitterInitFunc, ParallelChainResult, ParallelSamplingError, SamplerArgs,
+    new_dense_sampler, new_fixed_dense_sampler, new_sampler, new_softabs_sampler,
+    new_step_size_sampler, sample_sequentially, ChainError, ChainMetadata, ChainTermination,
+    CpuLogpFuncMaker, InitPointFunc, JitterInitFunc, ParallelChainResult, ParallelSamplingError,
+    ParallelismSettings, SamplerArgs, TraceInitFunc, TraceInitStrategy,
+};
+#[cfg(feature = "parallel")]
+pub use cpu_sampler::{
+    sample_parallel, sample_parallel_monitored, sample_pooled, ChainHeartbeat, PooledDraw,
+    PooledDraws, StatsMonitor,
+};
+pub use delayed::{DelayedAcceptanceChain, DelayedAcceptanceStats};
+pub use diagnostics::{
+    autocorr_time, ess, r_hat, trajectory_calibration, ChainCorrelationMonitor, ChainSummary,
+    CorrelationWarning, TrajectoryCalibration, TrajectoryLimit,
+};
+pub use discrete::{DiscreteContext, DiscreteKernel, MixedChain};
+pub use ensemble::{EnsembleMove, EnsembleSampleStats, EnsembleSampler, EnsembleSettings};
+pub use fuzz::{fuzz_logp, LogpFuzzFailure, LogpFuzzReport};
+pub use golden::{GoldenError, GoldenStat, GoldenTrace, UPDATE_GOLDEN_VAR};
+pub use mass_matrix::{
+    DenseAdaptSettings, DenseMetric, DenseShrinkage, DiagAdaptExpSettings, DiagMassMatrixEstimator,
+};
+pub use metadata::{options_hash, RunMetadata, TraceHasher};
+pub use nuts::{
+    draw_seed, Chain, DivergenceInfo, GeneralizedUTurn, HamiltonianSnapshot, LogpError, MergeAudit,
+    MergeAuditFn, NutsError, SampleStatKind, SampleStatValue, SampleStats, StatField,
+    StatsSnapshot, StepSizeFn, TerminationCriterion, TrajectoryEnd,
+};
+pub use preconditioner::{Permutation, Preconditioned, Preconditioner};
+pub use reparam::{NonCenteredAdapter, NonCenteredGroup, ScaleParam};
+pub use riemannian::{CpuLogpHessianFunc, SoftAbsSettings};
+pub use sparse_grad::{CpuLogpFuncSparseGrad, SparseGradLogp};
+#[cfg(feature = "parallel")]
+pub use stopping::sample_until;
+pub use stopping::{
+    EssTarget, MonitorAlarm, Monitored, RHatThreshold, RunState, ScalarMonitor, StoppingRule,
+    WallTime,
 };
-pub use mass_matrix::DiagAdaptExpSettings;
-pub use nuts::{Chain, DivergenceInfo, LogpError, NutsError, SampleStatValue, SampleStats};
+pub use validation::{ks_test, normal_cdf, sbc_rank, sbc_uniformity_test, TestResult};
@@ -3,16 +3,38 @@ use multiversion::multiversion;
 
 use crate::{
     cpu_state::{InnerState, State},
-    math::{multiply, vector_dot},
-    nuts::Collector,
+    math::{compensated_sum_of_products, fill_normal, multiply, portable_normal, vector_dot},
+    nuts::{check_dim, AsSampleStatVec, Collector, NutsError},
 };
 
 pub(crate) trait MassMatrix {
     fn update_velocity(&self, state: &mut InnerState);
     fn update_kinetic_energy(&self, state: &mut InnerState);
-    fn randomize_momentum<R: rand::Rng + ?Sized>(&self, state: &mut InnerState, rng: &mut R);
+    /// Compute `x^T M x` for the mass matrix `M`.
+    fn mass_norm_sq(&mut self, x: &[f64]) -> f64;
+    /// Replace the inverse mass matrix by a diagonal one. The values must be
+    /// positive and finite.
+    fn set_variance(&mut self, variance: &[f64]);
+    /// The diagonal of the inverse mass matrix.
+    fn variance(&self) -> &[f64];
+    /// Write a draw from the momentum distribution `N(0, M)` to `out`.
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, portable: bool, out: &mut [f64]);
+    /// The log density of `momentum` under `N(0, M)`, including the
+    /// normalization constant.
+    fn logp_momentum(&self, momentum: &[f64]) -> f64;
+    fn randomize_momentum<R: rand::Rng + ?Sized>(
+        &self,
+        state: &mut InnerState,
+        rng: &mut R,
+        portable: bool,
+    ) {
+        self.sample_momentum(rng, portable, &mut state.p);
+    }
 }
 
+/// `-log(2 pi) / 2`
+pub(crate) const LOG_NORM: f64 = -0.918_938_533_204_672_7;
+
 pub(crate) struct NullCollector {}
 
 impl Collector for NullCollector {
@@ -23,13 +45,19 @@ impl Collector for NullCollector {
 pub(crate) struct DiagMassMatrix {
     inv_stds: Box<[f64]>,
     pub(crate) variance: Box<[f64]>,
+    /// Compute kinetic energies like [`compensated_dot`](crate::math::compensated_dot), see
+    /// [`SamplerArgs::reproducible_sums`].
+    ///
+    /// [`SamplerArgs::reproducible_sums`]: crate::SamplerArgs::reproducible_sums
+    pub(crate) reproducible_sums: bool,
 }
 
 impl DiagMassMatrix {
     pub(crate) fn new(ndim: usize) -> Self {
         Self {
-            inv_stds: vec![0f64; ndim].into(),
-            variance: vec![0f64; ndim].into(),
+            inv_stds: vec![1f64; ndim].into(),
+            variance: vec![1f64; ndim].into(),
+            reproducible_sums: false,
         }
     }
 
@@ -39,7 +67,7 @@ impl DiagMassMatrix {
 }
 
 #[multiversion]
-#[clone(target = "[x64|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 fn update_diag(
     variance_out: &mut [f64],
@@ -60,19 +88,253 @@ impl MassMatrix for DiagMassMatrix {
     }
 
     fn update_kinetic_energy(&self, state: &mut InnerState) {
-        state.kinetic_energy = 0.5 * vector_dot(&state.p, &state.v);
+        let norm = if self.reproducible_sums {
+            compensated_sum_of_products(state.p.iter().copied().zip(state.v.iter().copied()))
+        } else {
+            vector_dot(&state.p, &state.v)
+        };
+        state.kinetic_energy = 0.5 * norm;
+    }
+
+    fn mass_norm_sq(&mut self, x: &[f64]) -> f64 {
+        x.iter()
+            .zip(self.variance.iter())
+            .map(|(x, var)| x * x / var)
+            .sum()
+    }
+
+    fn set_variance(&mut self, variance: &[f64]) {
+        self.update_diag(variance.iter().copied());
+    }
+
+    fn variance(&self) -> &[f64] {
+        &self.variance
+    }
+
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, portable: bool, out: &mut [f64]) {
+        if portable {
+            out.iter_mut()
+                .zip(self.inv_stds.iter())
+                .for_each(|(p, &s)| *p = s * portable_normal(rng));
+        } else {
+            fill_normal(rng, out);
+            out.iter_mut()
+                .zip(self.inv_stds.iter())
+                .for_each(|(p, &s)| *p *= s);
+        }
+    }
+
+    fn logp_momentum(&self, momentum: &[f64]) -> f64 {
+        // The momentum variance is 1 / variance
+        momentum
+            .iter()
+            .zip(self.variance.iter())
+            .map(|(p, var)| LOG_NORM + 0.5 * (var.ln() - var * p * p))
+            .sum()
+    }
+}
+
+/// A dense inverse mass matrix `S` with its lower Cholesky factor `L`,
+/// `S = L L^T`. Both are stored in row-major order.
+#[derive(Debug)]
+pub(crate) struct DenseMassMatrix {
+    dim: usize,
+    inv_mass: Box<[f64]>,
+    chol: Box<[f64]>,
+    diag: Box<[f64]>,
+    /// Scratch space for the triangular solve in `mass_norm_sq`
+    solve: Box<[f64]>,
+}
+
+impl DenseMassMatrix {
+    pub(crate) fn new(dim: usize) -> Self {
+        let mut mass_matrix = Self {
+            dim,
+            inv_mass: vec![0f64; dim * dim].into(),
+            chol: vec![0f64; dim * dim].into(),
+            diag: vec![0f64; dim].into(),
+            solve: vec![0f64; dim].into(),
+        };
+        mass_matrix.set_variance(&vec![1f64; dim]);
+        mass_matrix
     }
 
-    fn randomize_momentum<R: rand::Rng + ?Sized>(&self, state: &mut InnerState, rng: &mut R) {
-        let dist = rand_distr::StandardNormal;
+    /// Replace the inverse mass matrix, given in row-major order.
+    ///
+    /// Returns `false` and keeps the current matrix if `inv_mass` is not
+    /// symmetric positive definite.
+    pub(crate) fn update(&mut self, inv_mass: &[f64]) -> bool {
+        let dim = self.dim;
+        assert_eq!(
+            inv_mass.len(),
+            dim * dim,
+            "Inverse mass matrix has wrong size"
+        );
+        let mut chol: Box<[f64]> = vec![0f64; dim * dim].into();
+        if !cholesky(inv_mass, dim, &mut chol) {
+            return false;
+        }
+        self.chol = chol;
+        self.inv_mass.copy_from_slice(inv_mass);
+        self.diag
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, diag)| *diag = inv_mass[i * dim + i]);
+        true
+    }
+
+    pub(crate) fn inv_mass(&self) -> &[f64] {
+        &self.inv_mass
+    }
+
+    /// Replace the inverse mass matrix by a matrix given by the user.
+    ///
+    /// Unlike [`update`](Self::update) this also checks that a covariance
+    /// is symmetric, and it uses a Cholesky factor without refactorizing.
+    pub(crate) fn set_metric(&mut self, metric: DenseMetric) -> Result<(), NutsError> {
+        let dim = self.dim;
+        match metric {
+            DenseMetric::Covariance(cov) => {
+                check_dim(dim * dim, cov.len())?;
+                let mut sym = cov.to_vec();
+                for i in 0..dim {
+                    for j in 0..i {
+                        let (a, b) = (cov[i * dim + j], cov[j * dim + i]);
+                        let scale = (cov[i * dim + i] * cov[j * dim + j]).abs().sqrt();
+                        let diff = (a - b).abs();
+                        if !diff.is_finite() | (diff > 1e-8 * scale) {
+                            return Err(NutsError::InvalidDenseMetric);
+                        }
+                        sym[i * dim + j] = (a + b) / 2f64;
+                        sym[j * dim + i] = (a + b) / 2f64;
+                    }
+                }
+                if !self.update(&sym) {
+                    return Err(NutsError::InvalidDenseMetric);
+                }
+            }
+            DenseMetric::Cholesky(chol) => {
+                check_dim(dim * dim, chol.len())?;
+                for i in 0..dim {
+                    let pivot = chol[i * dim + i];
+                    let upper_zero = chol[i * dim + i + 1..(i + 1) * dim]
+                        .iter()
+                        .all(|&val| val == 0f64);
+                    if !(pivot.is_finite() & (pivot > 0f64) & upper_zero) {
+                        return Err(NutsError::InvalidDenseMetric);
+                    }
+                }
+                let mut inv_mass = vec![0f64; dim * dim];
+                for i in 0..dim {
+                    for j in 0..=i {
+                        let val = vector_dot(
+                            &chol[i * dim..i * dim + j + 1],
+                            &chol[j * dim..j * dim + j + 1],
+                        );
+                        if !val.is_finite() {
+                            return Err(NutsError::InvalidDenseMetric);
+                        }
+                        inv_mass[i * dim + j] = val;
+                        inv_mass[j * dim + i] = val;
+                    }
+                }
+                self.chol = chol.into();
+                self.inv_mass = inv_mass.into();
+                for i in 0..dim {
+                    self.diag[i] = self.inv_mass[i * dim + i];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compute the lower Cholesky factor of the symmetric matrix `a`. Returns
+/// `false` if `a` is not positive definite.
+fn cholesky(a: &[f64], dim: usize, out: &mut [f64]) -> bool {
+    out.fill(0f64);
+    for j in 0..dim {
+        let row_j = &out[j * dim..j * dim + j];
+        let pivot = a[j * dim + j] - vector_dot(row_j, row_j);
+        if (pivot <= 0f64) | !pivot.is_finite() {
+            return false;
+        }
+        let pivot = pivot.sqrt();
+        out[j * dim + j] = pivot;
+        for i in j + 1..dim {
+            let dot = vector_dot(&out[i * dim..i * dim + j], &out[j * dim..j * dim + j]);
+            out[i * dim + j] = (a[i * dim + j] - dot) / pivot;
+        }
+    }
+    true
+}
+
+impl MassMatrix for DenseMassMatrix {
+    fn update_velocity(&self, state: &mut InnerState) {
         state
-            .p
+            .v
             .iter_mut()
-            .zip(self.inv_stds.iter())
-            .for_each(|(p, &s)| {
-                let norm: f64 = rng.sample(dist);
-                *p = s * norm;
-            });
+            .zip(self.inv_mass.chunks_exact(self.dim))
+            .for_each(|(v, row)| *v = vector_dot(row, &state.p));
+    }
+
+    fn update_kinetic_energy(&self, state: &mut InnerState) {
+        state.kinetic_energy = 0.5 * vector_dot(&state.p, &state.v);
+    }
+
+    fn mass_norm_sq(&mut self, x: &[f64]) -> f64 {
+        // Solve L y = x, then x^T S^-1 x = y^T y
+        let dim = self.dim;
+        let y = &mut self.solve;
+        for i in 0..dim {
+            let dot = vector_dot(&self.chol[i * dim..i * dim + i], &y[..i]);
+            y[i] = (x[i] - dot) / self.chol[i * dim + i];
+        }
+        vector_dot(y, y)
+    }
+
+    fn set_variance(&mut self, variance: &[f64]) {
+        let dim = self.dim;
+        let mut inv_mass = vec![0f64; dim * dim];
+        variance.iter().enumerate().for_each(|(i, &var)| {
+            assert!(var.is_finite(), "Illegal value on mass matrix: {}", var);
+            assert!(var > 0f64, "Illegal value on mass matrix: {}", var);
+            inv_mass[i * dim + i] = var;
+        });
+        assert!(self.update(&inv_mass));
+    }
+
+    fn variance(&self) -> &[f64] {
+        &self.diag
+    }
+
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, portable: bool, z: &mut [f64]) {
+        // With z standard normal, the solution p of L^T p = z has
+        // covariance S^-1, the mass matrix.
+        let dim = self.dim;
+        if portable {
+            z.iter_mut().for_each(|val| *val = portable_normal(rng));
+        } else {
+            fill_normal(rng, z);
+        }
+        for i in (0..dim).rev() {
+            let dot: f64 = (i + 1..dim).map(|k| self.chol[k * dim + i] * z[k]).sum();
+            z[i] = (z[i] - dot) / self.chol[i * dim + i];
+        }
+    }
+
+    fn logp_momentum(&self, momentum: &[f64]) -> f64 {
+        // log det S = 2 sum log L_ii
+        let half_log_det: f64 = (0..self.dim)
+            .map(|i| self.chol[i * self.dim + i].ln())
+            .sum();
+        let quad: f64 = self
+            .inv_mass
+            .chunks_exact(self.dim)
+            .zip(momentum)
+            .map(|(row, p)| p * vector_dot(row, momentum))
+            .sum();
+        self.dim as f64 * LOG_NORM + half_log_det - 0.5 * quad
     }
 }
 
@@ -119,13 +381,17 @@ impl ExpWeightedVariance {
         &self.variance
     }
 
+    pub(crate) fn mean(&self) -> &[f64] {
+        &self.mean
+    }
+
     pub(crate) fn count(&self) -> u64 {
         self.count
     }
 }
 
 #[multiversion]
-#[clone(target = "[x64|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 fn add_sample(self_: &mut ExpWeightedVariance, value: impl Iterator<Item = f64>) {
     if self_.use_mean {
@@ -151,8 +417,22 @@ fn add_sample(self_: &mut ExpWeightedVariance, value: impl Iterator<Item = f64>)
     }
 }
 
+/// The estimator used for the diagonal of the mass matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagMassMatrixEstimator {
+    /// Use `sqrt(draw_var / grad_var)`. The gradients carry information about
+    /// the scale of the posterior from the very first draws, so this
+    /// converges much faster early in tuning.
+    DrawGradVariance,
+    /// Use the variance of the draws only, like Stan and PyMC.
+    DrawVariance,
+    /// Use the inverse of the mean of squared gradients, the diagonal of the
+    /// empirical Fisher information.
+    EmpiricalFisher,
+}
+
 /// Settings for mass matrix adaptation
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct DiagAdaptExpSettings {
     /// An exponenital decay parameter for the variance estimator
     pub variance_decay: f64,
@@ -164,7 +444,34 @@ pub struct DiagAdaptExpSettings {
     pub store_mass_matrix: bool,
     /// Switch to a new variance estimator every `window_switch_freq` draws.
     pub window_switch_freq: u64,
+    /// Initialize the mass matrix from the gradient at the initial position
+    /// instead of the identity.
+    ///
+    /// The inverse mass matrix starts at `1 / sqrt(grad^2 + grad_init_regularization)`,
+    /// so that directions with a steep gradient get smaller steps from the
+    /// first draw on, which avoids many divergences early in tuning. This
+    /// only affects the first adaptation window.
     pub grad_init: bool,
+    /// Added to the squared initial gradient, see `grad_init`. With the
+    /// default of one, the initial inverse mass matrix is never larger than
+    /// the identity, and dimensions with a zero gradient keep a unit entry.
+    pub grad_init_regularization: f64,
+    /// How the mass matrix is computed from draws and gradients
+    pub estimator: DiagMassMatrixEstimator,
+    /// Keep adapting the mass matrix after tuning with a vanishing step size.
+    ///
+    /// The estimator weight for new draws decays as
+    /// `variance_decay * n ^ (-continuous_decay_exponent)`, where `n` is the number
+    /// of draws since the end of tuning.
+    pub continuous_adaptation: bool,
+    /// The decay exponent for continuous adaptation. Values in (0.5, 1] preserve
+    /// ergodicity.
+    pub continuous_decay_exponent: f64,
+    /// Report the dimensions whose gradient was zero at all tuning draws in
+    /// the `zero_gradient_dims` stat of the last tuning draw. A gradient
+    /// entry counts as zero if it is below machine epsilon relative to the
+    /// largest entry of the gradient.
+    pub detect_zero_gradients: bool,
 }
 
 impl Default for DiagAdaptExpSettings {
@@ -176,10 +483,118 @@ impl Default for DiagAdaptExpSettings {
             window_switch_freq: 50,
             early_variance_decay: 0.1,
             grad_init: false,
+            grad_init_regularization: 1f64,
+            estimator: DiagMassMatrixEstimator::DrawGradVariance,
+            continuous_adaptation: false,
+            continuous_decay_exponent: 0.75,
+            detect_zero_gradients: true,
         }
     }
 }
 
+/// Shrinkage of the dense covariance estimate towards its diagonal
+///
+/// The estimate `(1 - weight) * cov + weight * diag(cov)` has the same
+/// variances as the sample covariance but smaller correlations, and is well
+/// conditioned even if a window has fewer draws than dimensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenseShrinkage {
+    /// Use a fixed weight between zero (no shrinkage) and one (a diagonal
+    /// mass matrix).
+    Fixed(f64),
+    /// Choose the weight for each window like Ledoit and Wolf, with the
+    /// variance of the sample covariances of a normal distribution
+    /// `(cov_ij^2 + cov_ii * cov_jj) / (n - 1)`, so that short windows and
+    /// weak correlations are shrunk more.
+    LedoitWolf,
+}
+
+/// A dense inverse mass matrix given by the user, in row-major order, see
+/// [`new_fixed_dense_sampler`](crate::new_fixed_dense_sampler)
+#[derive(Debug, Clone, Copy)]
+pub enum DenseMetric<'a> {
+    /// The covariance of the posterior, for example the inverse Hessian of
+    /// the negative logp at the mode. It must be symmetric up to rounding
+    /// errors and positive definite.
+    Covariance(&'a [f64]),
+    /// The lower triangular Cholesky factor `L` of the covariance `L L^T`,
+    /// with a positive diagonal.
+    Cholesky(&'a [f64]),
+}
+
+/// Settings for dense mass matrix adaptation, see
+/// [`new_dense_sampler`](crate::new_dense_sampler)
+///
+/// After an early window, the covariance of the draws is estimated in
+/// windows that double in length, and the inverse mass matrix is set to the
+/// estimate of each window when it ends. The last window is extended to
+/// `final_window` draws before the end of tuning. The estimate is shrunk
+/// towards its diagonal with `shrinkage`, and then towards a small multiple
+/// of the identity, more so for short windows.
+///
+/// If `max_window_extensions` is not zero, the first and second half of
+/// each window are compared before the mass matrix is updated. For each
+/// coordinate this computes the Wasserstein distance between the draws of
+/// the two halves, in units of the standard deviation of the second half,
+/// which is least affected by a chain that is still converging, and
+/// multiplied by `sqrt(ess / 2)`, where `ess` is the effective sample size
+/// of the second half, so that it is about one for draws from the same
+/// distribution, also if they are autocorrelated. If the
+/// largest distance is above `max_window_distance`, the chain was probably
+/// still moving towards the typical set. The first half is then dropped,
+/// and the window is extended to twice the length of the second half.
+#[derive(Debug, Clone, Copy)]
+pub struct DenseAdaptSettings {
+    /// The number of draws at the start of tuning that are not used for the
+    /// covariance estimate, because the chain might still be far from the
+    /// typical set.
+    pub early_window: u64,
+    /// The length of the first covariance window
+    pub base_window: u64,
+    /// Stop adaptation `final_window` draws before tuning ends.
+    pub final_window: u64,
+    /// Shrink the covariance of each window towards its diagonal.
+    pub shrinkage: DenseShrinkage,
+    /// The largest distance between the halves of a window that does not
+    /// extend it.
+    pub max_window_distance: f64,
+    /// The largest number of window extensions over all of tuning. Zero
+    /// disables the comparison.
+    pub max_window_extensions: u64,
+    /// Save the current inverse mass matrix in row-major order as sampler
+    /// stat
+    pub store_mass_matrix: bool,
+}
+
+impl Default for DenseAdaptSettings {
+    fn default() -> Self {
+        Self {
+            early_window: 75,
+            base_window: 25,
+            final_window: 50,
+            shrinkage: DenseShrinkage::Fixed(0f64),
+            max_window_distance: 3f64,
+            max_window_extensions: 0,
+            store_mass_matrix: false,
+        }
+    }
+}
+
+impl DenseAdaptSettings {
+    /// Check that a fixed shrinkage weight is between zero and one.
+    pub(crate) fn validate(&self) -> Result<(), NutsError> {
+        if let DenseShrinkage::Fixed(weight) = self.shrinkage {
+            if !(0f64..=1f64).contains(&weight) {
+                return Err(NutsError::InvalidSettings(format!(
+                    "Shrinkage weight must be between 0 and 1, but is {}",
+                    weight
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub(crate) struct DrawGradCollector {
     pub(crate) draw: Box<[f64]>,
     pub(crate) grad: Box<[f64]>,
@@ -199,14 +614,84 @@ impl DrawGradCollector {
 impl Collector for DrawGradCollector {
     type State = State;
 
-    fn register_draw(&mut self, state: &Self::State, info: &crate::nuts::SampleInfo) {
+    fn register_draw(
+        &mut self,
+        state: &Self::State,
+        info: &crate::nuts::SampleInfo,
+        _potential_stats: &dyn AsSampleStatVec,
+    ) {
         self.draw.copy_from_slice(&state.q);
         self.grad.copy_from_slice(&state.grad);
         let idx = state.index_in_trajectory();
-        if let Some(_) = info.divergence_info {
+        if info.divergence_info.is_some() {
             self.is_good = (idx <= -4) | (idx >= 4);
         } else {
             self.is_good = idx != 0;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_state::StatePool;
+    use rand::SeedableRng;
+
+    #[test]
+    fn dense_mass_matrix() {
+        let inv_mass = [4., 1., 0.5, 1., 2., 0.3, 0.5, 0.3, 1.];
+        let mut mass_matrix = DenseMassMatrix::new(3);
+        assert!(mass_matrix.update(&inv_mass));
+        assert_eq!(mass_matrix.variance(), &[4., 2., 1.]);
+        assert!(!mass_matrix.update(&[1., 2., 0., 2., 1., 0., 0., 0., 1.]));
+        assert_eq!(mass_matrix.inv_mass(), &inv_mass);
+
+        // mass_norm_sq(S p) = p^T S p for the velocity S p
+        let mut pool = StatePool::new(3);
+        let mut state = pool.new_state();
+        let inner = state.try_mut_inner().unwrap();
+        inner.p.copy_from_slice(&[0.3, -1., 2.]);
+        mass_matrix.update_velocity(inner);
+        mass_matrix.update_kinetic_energy(inner);
+        let norm = mass_matrix.mass_norm_sq(&inner.v);
+        assert!((norm - 2. * inner.kinetic_energy).abs() < 1e-12);
+
+        // The momentum has the mass matrix S^-1 as covariance, so the
+        // velocity S p has covariance S.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let n = 20_000;
+        let mut cov = [0f64; 9];
+        for _ in 0..n {
+            mass_matrix.randomize_momentum(inner, &mut rng, false);
+            mass_matrix.update_velocity(inner);
+            for i in 0..3 {
+                for j in 0..3 {
+                    cov[i * 3 + j] += inner.v[i] * inner.v[j] / n as f64;
+                }
+            }
+        }
+        for (est, val) in cov.iter().zip(inv_mass.iter()) {
+            assert!((est - val).abs() < 0.1, "{:?}", cov);
+        }
+    }
+
+    #[test]
+    fn logp_momentum() {
+        let mut diag = DiagMassMatrix::new(2);
+        diag.set_variance(&[4., 0.5]);
+        let mut dense = DenseMassMatrix::new(2);
+        dense.set_variance(&[4., 0.5]);
+        let p = [0.3, -2.];
+        assert!((diag.logp_momentum(&p) - dense.logp_momentum(&p)).abs() < 1e-12);
+        // N(0, 1/4) and N(0, 2)
+        let expected =
+            2. * LOG_NORM - 0.5 * (0.25f64.ln() + 2f64.ln()) - 0.5 * (0.09 * 4. + 4. * 0.5);
+        assert!((diag.logp_momentum(&p) - expected).abs() < 1e-12);
+
+        // S = [[2, 1], [1, 2]] has determinant 3
+        assert!(dense.update(&[2., 1., 1., 2.]));
+        let quad = 2. * 0.09 + 2. * 0.3 * -2. + 2. * 4.;
+        let expected = 2. * LOG_NORM + 0.5 * 3f64.ln() - 0.5 * quad;
+        assert!((dense.logp_momentum(&p) - expected).abs() < 1e-12);
+    }
+}
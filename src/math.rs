@@ -1,6 +1,8 @@
 use itertools::izip;
 use multiversion::multiversion;
 
+use crate::nuts::{check_dim, NutsError};
+
 #[cfg(feature = "simd_support")]
 use std::simd::{f64x4, SimdFloat, StdFloat};
 
@@ -19,9 +21,230 @@ pub(crate) fn logaddexp(a: f64, b: f64) -> f64 {
     }
 }
 
+/// Upper bits of `ln(2)`, such that `k * LN2_HI` is exact for `|k| < 2^11`
+const LN2_HI: f64 = f64::from_bits(0x3fe62e42fee00000);
+/// `ln(2) - LN2_HI`
+const LN2_LO: f64 = f64::from_bits(0x3dea39ef35793c76);
+
+/// Compute `2^k * x` without overflow in the intermediate factors.
+fn scale_pow2(mut x: f64, mut k: i64) -> f64 {
+    while k > 1023 {
+        x *= f64::from_bits(2046 << 52);
+        k -= 1023;
+    }
+    while k < -1022 {
+        x *= f64::from_bits(1 << 52);
+        k += 1022;
+    }
+    x * f64::from_bits(((k + 1023) as u64) << 52)
+}
+
+/// The exponential function, using only basic IEEE 754 arithmetic.
+///
+/// Unlike `f64::exp`, which calls into the platform libm, the result is
+/// bit-for-bit identical on all platforms. The error is a few ulp.
+pub(crate) fn portable_exp(x: f64) -> f64 {
+    if x.is_nan() {
+        return x;
+    }
+    if x > 709.782712893384 {
+        return f64::INFINITY;
+    }
+    if x < -745.1332191019412 {
+        return 0f64;
+    }
+    let k = (x * std::f64::consts::LOG2_E).round();
+    let r = (x - k * LN2_HI) - k * LN2_LO;
+    // Taylor series in Horner form, |r| <= ln(2) / 2
+    let mut poly = 1f64;
+    for n in (1..=13).rev() {
+        poly = 1f64 + r * poly / n as f64;
+    }
+    scale_pow2(poly, k as i64)
+}
+
+/// The natural logarithm, using only basic IEEE 754 arithmetic.
+///
+/// See [`portable_exp`].
+pub(crate) fn portable_ln(x: f64) -> f64 {
+    if x.is_nan() | (x < 0f64) {
+        return f64::NAN;
+    }
+    if x == 0f64 {
+        return f64::NEG_INFINITY;
+    }
+    if x == f64::INFINITY {
+        return x;
+    }
+    let (x, mut exponent) = if x < f64::MIN_POSITIVE {
+        (x * f64::from_bits((1023 + 54) << 52), -54i64)
+    } else {
+        (x, 0i64)
+    };
+    let bits = x.to_bits();
+    exponent += ((bits >> 52) & 0x7ff) as i64 - 1023;
+    let mut mantissa = f64::from_bits((bits & ((1 << 52) - 1)) | (1023 << 52));
+    if mantissa > std::f64::consts::SQRT_2 {
+        mantissa /= 2f64;
+        exponent += 1;
+    }
+    // ln(m) = 2 atanh(s) with |s| < 0.172
+    let s = (mantissa - 1f64) / (mantissa + 1f64);
+    let s2 = s * s;
+    let mut series = 1f64 / 23f64;
+    for k in (0..11).rev() {
+        series = 1f64 / (2 * k + 1) as f64 + s2 * series;
+    }
+    let exponent = exponent as f64;
+    exponent * LN2_HI + (exponent * LN2_LO + 2f64 * s * series)
+}
+
+/// `ln(1 + x)`, accurate for small `x`. See [`portable_exp`].
+pub(crate) fn portable_ln_1p(x: f64) -> f64 {
+    let u = 1f64 + x;
+    if u == 1f64 {
+        x
+    } else {
+        portable_ln(u) * x / (u - 1f64)
+    }
+}
+
+/// `x^y` for positive `x`. See [`portable_exp`].
+pub(crate) fn portable_powf(x: f64, y: f64) -> f64 {
+    portable_exp(y * portable_ln(x))
+}
+
+/// The hyperbolic tangent. See [`portable_exp`].
+pub(crate) fn portable_tanh(x: f64) -> f64 {
+    let e = portable_exp(-2f64 * x.abs());
+    ((1f64 - e) / (1f64 + e)).copysign(x)
+}
+
+/// [`logaddexp`] using [`portable_exp`] and [`portable_ln_1p`].
+pub(crate) fn portable_logaddexp(a: f64, b: f64) -> f64 {
+    if a == b {
+        return a + std::f64::consts::LN_2;
+    }
+    let diff = a - b;
+    if diff > 0. {
+        a + portable_ln_1p(portable_exp(-diff))
+    } else if diff < 0. {
+        b + portable_ln_1p(portable_exp(diff))
+    } else {
+        // diff is NAN
+        diff
+    }
+}
+
+/// Draw a standard normal value with the polar method, using only
+/// uniform draws, basic IEEE 754 arithmetic and [`portable_ln`].
+///
+/// `rand_distr::StandardNormal` uses the platform libm in rare cases, so
+/// its draws can differ between platforms.
+pub(crate) fn portable_normal<R: rand::Rng + ?Sized>(rng: &mut R) -> f64 {
+    loop {
+        let u = 2f64 * rng.gen::<f64>() - 1f64;
+        let v = 2f64 * rng.gen::<f64>() - 1f64;
+        let s = u * u + v * v;
+        if (s > 0f64) & (s < 1f64) {
+            return u * (-2f64 * portable_ln(s) / s).sqrt();
+        }
+    }
+}
+
+/// The layers of the ziggurat in [`fill_normal`]: the right edges `x` and
+/// the density `exp(-x^2 / 2)` at those edges
+struct Ziggurat {
+    x: [f64; 257],
+    f: [f64; 257],
+}
+
+/// The start of the tail and the area of each layer of a ziggurat with
+/// 256 layers for the unnormalized standard normal density
+const ZIGGURAT_R: f64 = 3.654_152_885_361_009;
+const ZIGGURAT_V: f64 = 0.004_928_673_233_99;
+
+fn ziggurat() -> &'static Ziggurat {
+    static TABLE: std::sync::OnceLock<Ziggurat> = std::sync::OnceLock::new();
+    TABLE.get_or_init(|| {
+        let pdf = |x: f64| (-0.5 * x * x).exp();
+        let mut x = [0f64; 257];
+        x[0] = ZIGGURAT_V / pdf(ZIGGURAT_R);
+        x[1] = ZIGGURAT_R;
+        for i in 2..256 {
+            x[i] = (-2f64 * (pdf(x[i - 1]) + ZIGGURAT_V / x[i - 1]).ln()).sqrt();
+        }
+        let mut f = [0f64; 257];
+        f.iter_mut().zip(x.iter()).for_each(|(f, &x)| *f = pdf(x));
+        Ziggurat { x, f }
+    })
+}
+
+/// Fill `out` with independent standard normal draws.
+///
+/// This uses the ziggurat method like `rand_distr::StandardNormal`, but
+/// draws the random bits for a whole block of values at once, which is
+/// cheaper for long vectors.
+pub fn fill_normal<R: rand::Rng + ?Sized>(rng: &mut R, out: &mut [f64]) {
+    const BLOCK: usize = 64;
+    let table = ziggurat();
+    let mut bits = [0u64; BLOCK];
+    for chunk in out.chunks_mut(BLOCK) {
+        let bits = &mut bits[..chunk.len()];
+        rng.fill(bits);
+        for (out, &bits) in chunk.iter_mut().zip(bits.iter()) {
+            *out = ziggurat_normal(table, rng, bits);
+        }
+    }
+}
+
+/// Draw one normal value, starting with the random bits `bits`.
+#[inline]
+fn ziggurat_normal<R: rand::Rng + ?Sized>(table: &Ziggurat, rng: &mut R, mut bits: u64) -> f64 {
+    const SCALE: f64 = 1f64 / (1u64 << 52) as f64;
+    loop {
+        let layer = (bits & 0xff) as usize;
+        // Uniform in (-1, 1)
+        let u = (((bits >> 12) as f64 + 0.5) * SCALE) * 2f64 - 1f64;
+        let x = u * table.x[layer];
+        if x.abs() < table.x[layer + 1] {
+            return x;
+        }
+        if layer == 0 {
+            // Sample from the tail beyond `ZIGGURAT_R` with Marsaglia's method
+            loop {
+                let a: f64 = rng.sample(rand::distributions::Open01);
+                let b: f64 = rng.sample(rand::distributions::Open01);
+                let tail = a.ln() / ZIGGURAT_R;
+                if -2f64 * b.ln() >= tail * tail {
+                    return if u < 0f64 {
+                        tail - ZIGGURAT_R
+                    } else {
+                        ZIGGURAT_R - tail
+                    };
+                }
+            }
+        }
+        let (f_outer, f_inner) = (table.f[layer], table.f[layer + 1]);
+        if f_inner + (f_outer - f_inner) * rng.gen::<f64>() < (-0.5 * x * x).exp() {
+            return x;
+        }
+        bits = rng.gen();
+    }
+}
+
+/// Draw a standard Gumbel value `-ln(-ln(u))` with `u` uniform in `(0, 1)`.
+///
+/// With `portable`, this uses [`portable_ln`] instead of the platform libm.
+pub(crate) fn gumbel<R: rand::Rng + ?Sized>(rng: &mut R, portable: bool) -> f64 {
+    let ln = if portable { portable_ln } else { f64::ln };
+    let u: f64 = rng.sample(rand::distributions::Open01);
+    -ln(-ln(u))
+}
+
 #[cfg(feature = "simd_support")]
 #[multiversion]
-#[clone(target = "[x64|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 pub fn multiply(x: &[f64], y: &[f64], out: &mut [f64]) {
     let n = x.len();
@@ -45,7 +268,7 @@ pub fn multiply(x: &[f64], y: &[f64], out: &mut [f64]) {
 
 #[cfg(not(feature = "simd_support"))]
 #[multiversion]
-#[clone(target = "[x64|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 pub fn multiply(x: &[f64], y: &[f64], out: &mut [f64]) {
     let n = x.len();
@@ -59,7 +282,7 @@ pub fn multiply(x: &[f64], y: &[f64], out: &mut [f64]) {
 
 #[cfg(feature = "simd_support")]
 #[multiversion]
-#[clone(target = "[x84|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 pub fn scalar_prods2(positive1: &[f64], positive2: &[f64], x: &[f64], y: &[f64]) -> (f64, f64) {
     let n = positive1.len();
@@ -100,7 +323,7 @@ pub fn scalar_prods2(positive1: &[f64], positive2: &[f64], x: &[f64], y: &[f64])
 
 #[cfg(not(feature = "simd_support"))]
 #[multiversion]
-#[clone(target = "[x84|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 pub fn scalar_prods2(positive1: &[f64], positive2: &[f64], x: &[f64], y: &[f64]) -> (f64, f64) {
     let n = positive1.len();
@@ -117,7 +340,7 @@ pub fn scalar_prods2(positive1: &[f64], positive2: &[f64], x: &[f64], y: &[f64])
 
 #[cfg(feature = "simd_support")]
 #[multiversion]
-#[clone(target = "[x84|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 pub fn scalar_prods3(
     positive1: &[f64],
@@ -168,7 +391,7 @@ pub fn scalar_prods3(
 
 #[cfg(not(feature = "simd_support"))]
 #[multiversion]
-#[clone(target = "[x84|x86_64]+avx+avx2+fma")]
+#[clone(target = "[x86|x86_64]+avx+avx2+fma")]
 #[clone(target = "x86+sse")]
 pub fn scalar_prods3(
     positive1: &[f64],
@@ -229,6 +452,71 @@ pub fn vector_dot(a: &[f64], b: &[f64]) -> f64 {
     result
 }
 
+/// Compute `a + b` and its rounding error.
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let sum = a + b;
+    let b_virtual = sum - a;
+    (sum, (a - (sum - b_virtual)) + (b - b_virtual))
+}
+
+/// The sum of products in a fixed order with compensation of the rounding
+/// errors, which is about as accurate as computing in twice the precision
+/// (`Dot2` of Ogita, Rump and Oishi, 2005).
+pub(crate) fn compensated_sum_of_products(values: impl Iterator<Item = (f64, f64)>) -> f64 {
+    let mut sum = 0f64;
+    let mut error = 0f64;
+    for (x, y) in values {
+        let prod = x * y;
+        let prod_error = x.mul_add(y, -prod);
+        let (new_sum, sum_error) = two_sum(sum, prod);
+        sum = new_sum;
+        error += sum_error + prod_error;
+    }
+    let result = sum + error;
+    // The error terms are NaN if a product overflows
+    if result.is_finite() {
+        result
+    } else {
+        sum
+    }
+}
+
+/// Like [`vector_dot`], but the products are summed in a fixed order with
+/// compensated summation.
+///
+/// The result only depends on the inputs, not on the `simd_support`
+/// feature or the target features of the cpu, and is more accurate for
+/// long vectors or when terms cancel. This is a few times slower.
+///
+/// Returns an error if `a` and `b` have different lengths.
+pub fn compensated_dot(a: &[f64], b: &[f64]) -> Result<f64, NutsError> {
+    check_dim(a.len(), b.len())?;
+    Ok(compensated_sum_of_products(
+        a.iter().copied().zip(b.iter().copied()),
+    ))
+}
+
+/// Like [`scalar_prods2`], but with fixed order compensated summation, see
+/// [`compensated_dot`].
+pub fn compensated_scalar_prods2(
+    positive1: &[f64],
+    positive2: &[f64],
+    x: &[f64],
+    y: &[f64],
+) -> (f64, f64) {
+    let n = positive1.len();
+
+    assert!(positive2.len() == n);
+    assert!(x.len() == n);
+    assert!(y.len() == n);
+
+    let sums = || positive1.iter().zip(positive2).map(|(a, b)| a + b);
+    (
+        compensated_sum_of_products(sums().zip(x.iter().copied())),
+        compensated_sum_of_products(sums().zip(y.iter().copied())),
+    )
+}
+
 #[cfg(feature = "simd_support")]
 #[multiversion]
 #[clone(target = "[x86|x86_64]+avx+avx2+fma")]
@@ -314,20 +602,15 @@ pub fn axpy_out(x: &[f64], y: &[f64], a: f64, out: &mut [f64]) {
 mod tests {
     use super::*;
     use approx::assert_ulps_eq;
-    use ndarray::prelude::*;
     use pretty_assertions::assert_eq;
     use proptest::prelude::*;
 
     fn assert_approx_eq(a: f64, b: f64) {
-        if a.is_nan() {
-            if b.is_nan() | b.is_infinite() {
-                return;
-            }
+        if a.is_nan() && (b.is_nan() | b.is_infinite()) {
+            return;
         }
-        if b.is_nan() {
-            if a.is_nan() | a.is_infinite() {
-                return;
-            }
+        if b.is_nan() && (a.is_nan() | a.is_infinite()) {
+            return;
         }
         assert_ulps_eq!(a, b);
     }
@@ -383,8 +666,8 @@ mod tests {
         fn check_logaddexp(x in -10f64..10f64, y in -10f64..10f64) {
             let a = (x.exp() + y.exp()).ln();
             let b = logaddexp(x, y);
-            let neginf = std::f64::NEG_INFINITY;
-            let nan = std::f64::NAN;
+            let neginf = f64::NEG_INFINITY;
+            let nan = f64::NAN;
             prop_assert!((a - b).abs() < 1e-10);
             prop_assert_eq!(b, logaddexp(y, x));
             prop_assert_eq!(x, logaddexp(x, neginf));
@@ -449,9 +732,134 @@ mod tests {
         }
     }
 
+    #[test]
+    fn compensated_sums() {
+        let x = [1e16, 1., -1e16, 0.5];
+        let ones = [1.; 4];
+        assert_eq!(compensated_dot(&x, &ones).unwrap(), 1.5);
+        let (p1, p2) = compensated_scalar_prods2(&x, &[0.; 4], &ones, &[2.; 4]);
+        assert_eq!((p1, p2), (1.5, 3.));
+
+        let x = [0.1, 0.2, 0.3];
+        assert!((compensated_dot(&x, &x).unwrap() - vector_dot(&x, &x)).abs() < 1e-16);
+        assert_eq!(compensated_dot(&[], &[]).unwrap(), 0.);
+        assert_eq!(
+            compensated_dot(&[1e200, 1.], &[1e200, 1.]).unwrap(),
+            f64::INFINITY
+        );
+        assert!(matches!(
+            compensated_dot(&x, &ones),
+            Err(NutsError::DimensionMismatch {
+                expected: 3,
+                got: 4
+            })
+        ));
+    }
+
     #[test]
     fn check_neginf() {
-        assert_eq!(logaddexp(std::f64::NEG_INFINITY, 2.), 2.);
-        assert_eq!(logaddexp(2., std::f64::NEG_INFINITY), 2.);
+        assert_eq!(logaddexp(f64::NEG_INFINITY, 2.), 2.);
+        assert_eq!(logaddexp(2., f64::NEG_INFINITY), 2.);
+        assert_eq!(portable_logaddexp(f64::NEG_INFINITY, 2.), 2.);
+        assert_eq!(portable_logaddexp(2., f64::NEG_INFINITY), 2.);
+    }
+
+    #[test]
+    fn portable_functions() {
+        for i in -7000..7000 {
+            let x = i as f64 / 10. + 0.0123;
+            let rel_err = (portable_exp(x) - x.exp()) / x.exp();
+            assert!(rel_err.abs() < 1e-15, "exp({}) error {}", x, rel_err);
+        }
+        for i in -3000..3000 {
+            let x = 1.37f64.powi(i / 10) * (1. + (i % 10) as f64 / 10.);
+            let err = portable_ln(x) - x.ln();
+            assert!(
+                err.abs() < 1e-15 * x.ln().abs().max(1.),
+                "ln({}) error {}",
+                x,
+                err
+            );
+        }
+        assert_eq!(portable_exp(0.), 1.);
+        assert_eq!(portable_ln(1.), 0.);
+        assert_eq!(portable_exp(f64::NEG_INFINITY), 0.);
+        assert_eq!(portable_ln(0.), f64::NEG_INFINITY);
+        assert!(portable_ln(-1.).is_nan());
+        assert!(
+            (portable_ln(f64::MIN_POSITIVE / 8.) - (f64::MIN_POSITIVE / 8.).ln()).abs() < 1e-12
+        );
+        assert!((portable_exp(-744.) - (-744f64).exp()).abs() < 1e-320);
+        assert!((portable_tanh(-0.7) - (-0.7f64).tanh()).abs() < 1e-15);
+        assert!((portable_ln_1p(1e-20) - 1e-20).abs() < 1e-35);
+    }
+
+    #[test]
+    fn fill_normal_distribution() {
+        use crate::validation::{ks_test, normal_cdf};
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        // A length that is not a multiple of the block size
+        let mut draws = vec![0f64; 100_001];
+        fill_normal(&mut rng, &mut draws);
+        assert!(draws.iter().all(|val| val.is_finite()));
+        let result = ks_test(&draws, |x| normal_cdf(x, 0., 1.)).unwrap();
+        assert!(result.p_value > 0.01, "{:?}", result);
+
+        // The tail beyond the last layer is sampled separately
+        let tail = draws.iter().filter(|val| val.abs() > ZIGGURAT_R).count() as f64;
+        let expected = 2f64 * (1f64 - normal_cdf(ZIGGURAT_R, 0., 1.)) * draws.len() as f64;
+        assert!((tail - expected).abs() < 4f64 * expected.sqrt(), "{}", tail);
+    }
+
+    #[test]
+    fn portable_normal_golden() {
+        use rand::SeedableRng;
+
+        // These values must be the same on all platforms
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let draws: Vec<u64> = (0..3)
+            .map(|_| portable_normal(&mut rng).to_bits())
+            .collect();
+        assert_eq!(
+            draws,
+            vec![0x3ff999a47f18ca08, 0x3ffbae4f0c3c3c7d, 0xbfdd76c82c6cb530]
+        );
+    }
+
+    #[test]
+    fn gumbel_max_is_multinomial() {
+        use rand::SeedableRng;
+
+        // The index of the largest perturbed log weight is a draw from the
+        // normalized weights.
+        let log_weights = [-1f64, 0., 0.5, -3.];
+        let total: f64 = log_weights.iter().map(|w| w.exp()).sum();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let n = 100_000;
+        for portable in [false, true] {
+            let mut counts = [0usize; 4];
+            for _ in 0..n {
+                let (argmax, _) = log_weights
+                    .iter()
+                    .map(|w| w + gumbel(&mut rng, portable))
+                    .enumerate()
+                    .fold((0, f64::NEG_INFINITY), |best, (i, key)| {
+                        if key > best.1 {
+                            (i, key)
+                        } else {
+                            best
+                        }
+                    });
+                counts[argmax] += 1;
+            }
+            for (count, w) in counts.iter().zip(log_weights.iter()) {
+                let p = w.exp() / total;
+                let stderr = (p * (1. - p) / n as f64).sqrt();
+                let freq = *count as f64 / n as f64;
+                assert!((freq - p).abs() < 5. * stderr, "{} {}", freq, p);
+            }
+        }
     }
 }
@@ -0,0 +1,423 @@
+use std::io::Write;
+
+use crate::{
+    adapt_strategy::DualAverageSettings,
+    cpu_sampler::{ParallelismSettings, SamplerArgs},
+    mass_matrix::{
+        DenseAdaptSettings, DenseShrinkage, DiagAdaptExpSettings, DiagMassMatrixEstimator,
+    },
+    riemannian::SoftAbsSettings,
+    stepsize::DualAverageOptions,
+};
+
+/// A record of the code, settings and model that produced a result
+///
+/// It is written into every export of this crate, currently the
+/// [`TunedBundle`](crate::TunedBundle), so that archived results can be
+/// traced to the exact version of the code that made them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunMetadata {
+    /// The version of nuts-rs
+    pub crate_version: String,
+    /// The git commit of nuts-rs at build time, or `unknown` if the crate
+    /// was not built from a git checkout
+    pub git_hash: String,
+    /// A hash of the sampler settings, see [`options_hash`]
+    pub options_hash: u64,
+    /// A name of the model that is chosen by the user
+    pub model_id: String,
+    /// A hash of the model that is chosen by the user, for example from the
+    /// model source and data
+    pub model_hash: u64,
+}
+
+impl RunMetadata {
+    /// Describe a run of this build of nuts-rs with `settings`.
+    ///
+    /// The model id must not contain line breaks, or writing the metadata
+    /// fails.
+    pub fn new(settings: &SamplerArgs, model_id: &str, model_hash: u64) -> Self {
+        RunMetadata {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: env!("NUTS_RS_GIT_HASH").to_string(),
+            options_hash: options_hash(settings),
+            model_id: model_id.to_string(),
+            model_hash,
+        }
+    }
+
+    /// Write the metadata as lines of keys and values.
+    ///
+    /// Returns an error of kind `InvalidInput` if one of the text fields
+    /// contains a line break.
+    pub(crate) fn write_lines<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        for (name, value) in [
+            ("crate_version", &self.crate_version),
+            ("git_hash", &self.git_hash),
+            ("model_id", &self.model_id),
+        ] {
+            if value.contains(['\n', '\r']) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("The metadata field {} must not contain line breaks", name),
+                ));
+            }
+        }
+        writeln!(writer, "crate_version {}", self.crate_version)?;
+        writeln!(writer, "git_hash {}", self.git_hash)?;
+        writeln!(writer, "options_hash {}", self.options_hash)?;
+        writeln!(writer, "model_id {}", self.model_id)?;
+        writeln!(writer, "model_hash {}", self.model_hash)?;
+        Ok(())
+    }
+}
+
+/// A hash of all sampler settings
+///
+/// This is the 64 bit FNV-1a hash of the little endian bytes of all
+/// fields in a fixed order, with all NaN values mapped to the same bit
+/// pattern, so it does not depend on the platform or on the formatting of
+/// the settings.
+pub fn options_hash(settings: &SamplerArgs) -> u64 {
+    let mut hasher = OptionsHasher { hash: FNV_OFFSET };
+    hasher.sampler_args(settings);
+    hasher.hash
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Feeds the settings into the hash field by field.
+///
+/// The settings are destructured without `..`, so that new fields do not
+/// compile until they are added to the hash.
+struct OptionsHasher {
+    hash: u64,
+}
+
+impl OptionsHasher {
+    fn u64(&mut self, value: u64) {
+        self.hash = fnv1a(self.hash, value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u64(value as u64);
+    }
+
+    fn f64(&mut self, value: f64) {
+        let value = if value.is_nan() { f64::NAN } else { value };
+        self.u64(value.to_bits());
+    }
+
+    fn sampler_args(&mut self, settings: &SamplerArgs) {
+        let SamplerArgs {
+            num_tune,
+            maxdepth,
+            store_gradient,
+            check_invariants,
+            num_trajectories,
+            step_size_jitter,
+            strict_reproducibility,
+            reproducible_sums,
+            gumbel_selection,
+            max_energy_error,
+            max_step_retries,
+            curvature_diagnostics,
+            store_divergence_states,
+            catch_logp_panics,
+            energy_error_histogram,
+            step_size_adapt,
+            mass_matrix_adapt,
+            dense_mass_matrix_adapt,
+            softabs,
+            parallelism,
+        } = settings;
+        self.u64(*num_tune);
+        self.u64(*maxdepth);
+        self.bool(*store_gradient);
+        self.bool(*check_invariants);
+        self.u64(*num_trajectories);
+        self.f64(*step_size_jitter);
+        self.bool(*strict_reproducibility);
+        self.bool(*reproducible_sums);
+        self.bool(*gumbel_selection);
+        self.f64(*max_energy_error);
+        self.u64(*max_step_retries);
+        self.bool(*curvature_diagnostics);
+        self.bool(*store_divergence_states);
+        self.bool(*catch_logp_panics);
+        match energy_error_histogram {
+            None => self.u64(0),
+            Some(bins) => {
+                self.u64(1);
+                self.f64(bins.lower());
+                self.f64(bins.upper());
+                self.usize(bins.num_bins());
+            }
+        }
+        self.step_size_adapt(step_size_adapt);
+        self.mass_matrix_adapt(mass_matrix_adapt);
+        self.dense_mass_matrix_adapt(dense_mass_matrix_adapt);
+        self.softabs(softabs);
+        self.parallelism(parallelism);
+    }
+
+    fn step_size_adapt(&mut self, settings: &DualAverageSettings) {
+        let DualAverageSettings {
+            early_target_accept,
+            target_accept,
+            final_window_ratio,
+            params,
+            store_depth_accept,
+            num_exploration,
+            exploration_step_scale,
+            exploration_jitter,
+            accept_window,
+            max_accept_se,
+            max_window_extensions,
+        } = settings;
+        self.f64(*early_target_accept);
+        self.f64(*target_accept);
+        self.f64(*final_window_ratio);
+        let DualAverageOptions {
+            k,
+            t0,
+            gamma,
+            initial_step,
+        } = params;
+        self.f64(*k);
+        self.f64(*t0);
+        self.f64(*gamma);
+        self.f64(*initial_step);
+        self.bool(*store_depth_accept);
+        self.u64(*num_exploration);
+        self.f64(*exploration_step_scale);
+        self.f64(*exploration_jitter);
+        self.u64(*accept_window);
+        self.f64(*max_accept_se);
+        self.u64(*max_window_extensions);
+    }
+
+    fn mass_matrix_adapt(&mut self, settings: &DiagAdaptExpSettings) {
+        let DiagAdaptExpSettings {
+            variance_decay,
+            early_variance_decay,
+            final_window,
+            store_mass_matrix,
+            window_switch_freq,
+            grad_init,
+            grad_init_regularization,
+            estimator,
+            continuous_adaptation,
+            continuous_decay_exponent,
+            detect_zero_gradients,
+        } = settings;
+        self.f64(*variance_decay);
+        self.f64(*early_variance_decay);
+        self.u64(*final_window);
+        self.bool(*store_mass_matrix);
+        self.u64(*window_switch_freq);
+        self.bool(*grad_init);
+        self.f64(*grad_init_regularization);
+        self.u64(match estimator {
+            DiagMassMatrixEstimator::DrawGradVariance => 0,
+            DiagMassMatrixEstimator::DrawVariance => 1,
+            DiagMassMatrixEstimator::EmpiricalFisher => 2,
+        });
+        self.bool(*continuous_adaptation);
+        self.f64(*continuous_decay_exponent);
+        self.bool(*detect_zero_gradients);
+    }
+
+    fn dense_mass_matrix_adapt(&mut self, settings: &DenseAdaptSettings) {
+        let DenseAdaptSettings {
+            early_window,
+            base_window,
+            final_window,
+            shrinkage,
+            max_window_distance,
+            max_window_extensions,
+            store_mass_matrix,
+        } = settings;
+        self.u64(*early_window);
+        self.u64(*base_window);
+        self.u64(*final_window);
+        match shrinkage {
+            DenseShrinkage::Fixed(weight) => {
+                self.u64(0);
+                self.f64(*weight);
+            }
+            DenseShrinkage::LedoitWolf => self.u64(1),
+        }
+        self.f64(*max_window_distance);
+        self.u64(*max_window_extensions);
+        self.bool(*store_mass_matrix);
+    }
+
+    fn softabs(&mut self, settings: &SoftAbsSettings) {
+        let SoftAbsSettings {
+            alpha,
+            max_fixed_point_steps,
+            fixed_point_tol,
+            diverge_on_fixed_point_failure,
+        } = settings;
+        self.f64(*alpha);
+        self.u64(*max_fixed_point_steps);
+        self.f64(*fixed_point_tol);
+        self.bool(*diverge_on_fixed_point_failure);
+    }
+
+    fn parallelism(&mut self, settings: &ParallelismSettings) {
+        let ParallelismSettings {
+            chain_threads,
+            logp_threads,
+            pin_threads,
+            schedule_draws,
+            draw_buffer,
+            monitor_draws,
+            pool_adaptation,
+        } = settings;
+        match chain_threads {
+            None => self.u64(0),
+            Some(threads) => {
+                self.u64(1);
+                self.usize(*threads);
+            }
+        }
+        self.usize(*logp_threads);
+        self.bool(*pin_threads);
+        self.bool(*schedule_draws);
+        self.usize(*draw_buffer);
+        self.bool(*monitor_draws);
+        self.bool(*pool_adaptation);
+    }
+}
+
+fn fnv1a(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(hash, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A rolling hash of the draws and stats of a trace
+///
+/// The hash after each record depends on all previous records, so if it is
+/// stored next to each record, a reader can find the first record that was
+/// changed since it was written. This is the 64 bit FNV-1a hash of the
+/// little endian bytes of the values, with all NaN values mapped to the
+/// same bit pattern, so it does not depend on the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceHasher {
+    hash: u64,
+}
+
+impl Default for TraceHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceHasher {
+    pub fn new() -> Self {
+        TraceHasher { hash: FNV_OFFSET }
+    }
+
+    /// Add the next draw and its stats to the hash and return the new hash.
+    pub fn update(&mut self, draw: &[f64], stats: &[f64]) -> u64 {
+        for values in [draw, stats] {
+            self.add_floats(values);
+        }
+        self.hash
+    }
+
+    /// Like [`update`](Self::update), but for stats of different types.
+    /// Each stat is given as a tag of its type and the bits of its value.
+    pub(crate) fn update_tagged(&mut self, draw: &[f64], stats: &[(u8, u64)]) -> u64 {
+        self.add_floats(draw);
+        self.hash = fnv1a(self.hash, (stats.len() as u64).to_le_bytes());
+        for &(tag, bits) in stats {
+            self.hash = fnv1a(self.hash, [tag]);
+            self.hash = fnv1a(self.hash, bits.to_le_bytes());
+        }
+        self.hash
+    }
+
+    fn add_floats(&mut self, values: &[f64]) {
+        self.hash = fnv1a(self.hash, (values.len() as u64).to_le_bytes());
+        for &value in values {
+            let value = if value.is_nan() { f64::NAN } else { value };
+            self.hash = fnv1a(self.hash, value.to_bits().to_le_bytes());
+        }
+    }
+
+    /// The hash of all records so far
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_hash_changes() {
+        let settings = SamplerArgs::default();
+        let metadata = RunMetadata::new(&settings, "normal", 17);
+        assert_eq!(metadata.crate_version, env!("CARGO_PKG_VERSION"));
+        assert!(!metadata.git_hash.is_empty());
+        assert_eq!(metadata.options_hash, options_hash(&SamplerArgs::default()));
+
+        let mut changed = settings;
+        changed.mass_matrix_adapt.variance_decay *= 2.;
+        assert_ne!(options_hash(&changed), metadata.options_hash);
+
+        // Fields with the same value in different places hash differently
+        let mut changed = settings;
+        changed.maxdepth = settings.num_tune;
+        changed.num_tune = settings.maxdepth;
+        assert_ne!(options_hash(&changed), metadata.options_hash);
+
+        // The hash does not depend on the platform, so it can be pinned
+        assert_eq!(metadata.options_hash, 1770749094334754432);
+    }
+
+    #[test]
+    fn line_breaks_in_metadata() {
+        let mut metadata = RunMetadata::new(&SamplerArgs::default(), "normal", 17);
+        let mut out = vec![];
+        metadata.write_lines(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("model_id normal\n"));
+
+        metadata.model_id = "two\nlines".to_string();
+        let error = metadata.write_lines(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn trace_hash() {
+        let records = [([1., 2.], [0.5, f64::NAN]), ([3., -0.], [1.5, 2.])];
+        let hashes: Vec<u64> = {
+            let mut hasher = TraceHasher::new();
+            records
+                .iter()
+                .map(|(draw, stats)| hasher.update(draw, stats))
+                .collect()
+        };
+        assert_ne!(hashes[0], hashes[1]);
+
+        let mut hasher = TraceHasher::new();
+        let nan = f64::from_bits(f64::NAN.to_bits() | 1);
+        assert_eq!(hasher.update(&[1., 2.], &[0.5, nan]), hashes[0]);
+        assert_ne!(hasher.update(&[3., 0.], &[1.5, 2.]), hashes[1]);
+
+        // Values must not move between draw and stats unnoticed
+        let mut hasher = TraceHasher::new();
+        assert_ne!(hasher.update(&[1., 2., 0.5], &[f64::NAN]), hashes[0]);
+    }
+}
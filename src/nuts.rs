@@ -1,13 +1,62 @@
 use thiserror::Error;
 
-use std::{fmt::Debug, marker::PhantomData};
+use std::{cell::RefCell, fmt::Debug, marker::PhantomData};
 
-use crate::math::logaddexp;
+use crate::math::{
+    axpy, gumbel, logaddexp, portable_exp, portable_ln, portable_logaddexp, vector_dot,
+};
 
 #[derive(Error, Debug)]
 pub enum NutsError {
     #[error("Logp function returned error: {0}")]
     LogpFailure(Box<dyn std::error::Error + Send>),
+    #[error("Array has length {got}, but the model has dimension {expected}")]
+    DimensionMismatch { expected: usize, got: usize },
+    #[error("Logp function returned -inf at the initial position")]
+    InitOutsideSupport,
+    #[error("Metric entry {index} is {value}, but must be positive and finite")]
+    InvalidMetric { index: usize, value: f64 },
+    #[error("The dense metric is not symmetric positive definite")]
+    InvalidDenseMetric,
+    #[error("This sampler does not support {0}")]
+    Unsupported(&'static str),
+    #[error("Inverse temperature {0} must be finite and non-negative")]
+    InvalidInverseTemperature(f64),
+    #[error("Step size multiplier {0} must be positive and finite")]
+    InvalidStepSizeMultiplier(f64),
+    #[error("Invalid sampler settings: {0}")]
+    InvalidSettings(String),
+    #[error("Trajectory tree invariant violated: {0}")]
+    InvariantViolation(String),
+}
+
+/// Return an error if an array passed in by the user does not match the dimension.
+pub(crate) fn check_dim(expected: usize, got: usize) -> Result<()> {
+    if expected != got {
+        return Err(NutsError::DimensionMismatch { expected, got });
+    }
+    Ok(())
+}
+
+/// Return an error if a diagonal of the inverse mass matrix has an entry
+/// that is not positive and finite.
+pub(crate) fn check_metric(variance: &[f64]) -> Result<()> {
+    if let Some((index, &value)) = variance
+        .iter()
+        .enumerate()
+        .find(|(_, &value)| !value.is_finite() | (value <= 0f64))
+    {
+        return Err(NutsError::InvalidMetric { index, value });
+    }
+    Ok(())
+}
+
+/// Return an error if an inverse temperature can not scale the potential.
+pub(crate) fn check_inverse_temperature(inverse_temperature: f64) -> Result<()> {
+    if !(inverse_temperature.is_finite() & (inverse_temperature >= 0f64)) {
+        return Err(NutsError::InvalidInverseTemperature(inverse_temperature));
+    }
+    Ok(())
 }
 
 pub type Result<T> = std::result::Result<T, NutsError>;
@@ -20,6 +69,8 @@ pub type Result<T> = std::result::Result<T, NutsError>;
 ///   a cutoff value or nan.
 /// - The logp function caused a recoverable error (eg if an ODE solver
 ///   failed)
+/// - The logp function returned `-inf`, so the leapfrog step left the
+///   support of the posterior.
 pub trait DivergenceInfo: AsSampleStatVec + std::fmt::Debug + Send {
     /// The position in parameter space where the diverging leapfrog started
     fn start_location(&self) -> Option<&[f64]>;
@@ -43,7 +94,25 @@ pub trait DivergenceInfo: AsSampleStatVec + std::fmt::Debug + Send {
     ///
     /// This is not available if the divergence was cause because of a large energy
     /// difference.
-    fn logp_function_error(&self) -> Option<&dyn std::error::Error>;
+    fn logp_function_error(&self) -> Option<&(dyn std::error::Error + 'static)>;
+
+    /// Whether the divergence was caused by a logp of `-inf` at the end
+    /// of the leapfrog step.
+    fn outside_support(&self) -> bool {
+        false
+    }
+}
+
+impl<'a> dyn DivergenceInfo + 'a {
+    /// Return the logp function error as the concrete error type of the logp function.
+    ///
+    /// Logp functions can use this to carry structured information about a
+    /// failure (for example a solver tolerance failure vs a domain error).
+    /// Returns `None` if there was no logp function error or if it has a
+    /// different type.
+    pub fn logp_function_error_as<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        self.logp_function_error()?.downcast_ref::<E>()
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -76,8 +145,19 @@ pub trait Collector {
         _divergence_info: Option<&dyn DivergenceInfo>,
     ) {
     }
-    fn register_draw(&mut self, _state: &Self::State, _info: &SampleInfo) {}
+    /// Called after each draw. `potential_stats` are the current stats of
+    /// the hamiltonian, for example its step size, at the time of the draw.
+    fn register_draw(
+        &mut self,
+        _state: &Self::State,
+        _info: &SampleInfo,
+        _potential_stats: &dyn AsSampleStatVec,
+    ) {
+    }
     fn register_init(&mut self, _state: &Self::State, _options: &NutsOptions) {}
+    /// Called before each doubling of the trajectory. The leapfrog steps
+    /// until the next call belong to a subtree of depth `depth`.
+    fn register_doubling(&mut self, _depth: u64) {}
 }
 
 /// Errors that happen when we evaluate the logp and gradient function
@@ -121,11 +201,85 @@ pub trait Hamiltonian {
     ) -> Result<Self::State>;
 
     /// Randomize the momentum part of a state
-    fn randomize_momentum<R: rand::Rng + ?Sized>(&self, state: &mut Self::State, rng: &mut R);
+    ///
+    /// With `portable` the normal draws must not depend on the platform libm,
+    /// see [`NutsOptions::strict_reproducibility`].
+    fn randomize_momentum<R: rand::Rng + ?Sized>(
+        &self,
+        state: &mut Self::State,
+        rng: &mut R,
+        portable: bool,
+    );
+
+    /// Write a draw from the momentum distribution to `out`, see
+    /// [`Chain::sample_momentum`].
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, portable: bool, out: &mut [f64]);
+
+    /// The log density of `momentum` under the momentum distribution.
+    fn logp_momentum(&self, momentum: &[f64]) -> f64;
+
+    /// Set the momentum part of a state to a fixed value
+    fn set_momentum(&self, state: &mut Self::State, momentum: &[f64]);
+
+    /// Evaluate the hamiltonian at a point in phase space.
+    ///
+    /// This is meant for tests of mass matrix and potential implementations,
+    /// for example to compare the velocity with finite differences of the
+    /// kinetic energy.
+    fn evaluate(
+        &mut self,
+        pool: &mut <Self::State as State>::Pool,
+        position: &[f64],
+        momentum: &[f64],
+    ) -> Result<HamiltonianSnapshot>;
 
     /// Return sampler statistics defined in Self::Stats
     fn current_stats(&self) -> Self::Stats;
 
+    /// Scale the potential energy and its gradient by `inverse_temperature`.
+    ///
+    /// States that were created before the change still store the old energy.
+    /// Returns an error if `inverse_temperature` is negative or not finite.
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<()>;
+
+    /// Called before the trajectories of a new draw are built.
+    fn register_draw_start(&mut self) {}
+
+    /// Called whenever the chain moves to a new state, for potentials whose
+    /// momentum distribution and [`Self::metric`] depend on the position.
+    fn register_current_state(&mut self, _state: &Self::State) {}
+
+    /// Describe the stats that [`Self::DivergenceInfo`] adds for diverging
+    /// draws.
+    fn divergence_stat_schema(&self) -> Vec<StatField> {
+        vec![]
+    }
+
+    /// Replace the diagonal of the inverse mass matrix.
+    ///
+    /// States that were created before the change still store the old
+    /// velocity and kinetic energy.
+    fn set_metric(&mut self, variance: &[f64]) -> Result<()>;
+
+    /// The diagonal of the inverse mass matrix, see [`Self::set_metric`].
+    fn metric(&self) -> &[f64];
+
+    /// The step size of the leapfrog integrator
+    fn step_size(&self) -> f64;
+
+    /// The absolute step size that the last call to [`Self::leapfrog`]
+    /// used. This differs from [`Self::step_size`] if a step size function
+    /// was set with [`Self::set_step_size_fn`].
+    fn last_step_size(&self) -> f64 {
+        self.step_size()
+    }
+
+    fn set_step_size(&mut self, step_size: f64);
+
+    /// Multiply the step size of each leapfrog step by the value of `func`
+    /// at the start of the step.
+    fn set_step_size_fn(&mut self, func: Option<StepSizeFn>);
+
     fn new_empty_state(&mut self, pool: &mut <Self::State as State>::Pool) -> Self::State;
 
     /// Crate a new state pool that can be used to crate new states.
@@ -135,12 +289,94 @@ pub trait Hamiltonian {
     fn dim(&self) -> usize;
 }
 
+/// The parts of the hamiltonian at a point in phase space, see
+/// [`Chain::evaluate`]
+#[derive(Debug, Clone)]
+pub struct HamiltonianSnapshot {
+    pub potential_energy: f64,
+    pub kinetic_energy: f64,
+    /// The gradient of the logp function, the negative gradient of the
+    /// potential energy
+    pub gradient: Box<[f64]>,
+    /// The derivative of the kinetic energy with respect to the momentum
+    pub velocity: Box<[f64]>,
+}
+
+/// One end of a part of a trajectory, see [`TerminationCriterion`]
+#[derive(Debug, Clone, Copy)]
+pub struct TrajectoryEnd<'a> {
+    pub position: &'a [f64],
+    pub momentum: &'a [f64],
+    /// The velocity `M⁻¹ p` for the current mass matrix `M`
+    pub velocity: &'a [f64],
+}
+
+/// Decide when a trajectory stops growing
+///
+/// Trajectories are doubled until the criterion reports a turn for the
+/// whole trajectory, or for one of the subtrajectories that NUTS checks
+/// after each doubling. The default is [`GeneralizedUTurn`].
+pub trait TerminationCriterion: Send {
+    /// Whether the part of a trajectory from `left` to `right` has turned.
+    ///
+    /// `left` is the end with the smaller index in the trajectory, and
+    /// `p_sum` is the sum of the momenta of all points from `left` to
+    /// `right`, including both ends.
+    fn is_turning(&self, left: &TrajectoryEnd, right: &TrajectoryEnd, p_sum: &[f64]) -> bool;
+}
+
+/// A position dependent multiplier for the leapfrog step size, see
+/// [`Chain::set_step_size_fn`]
+pub type StepSizeFn = Box<dyn FnMut(&[f64]) -> f64 + Send>;
+
+/// A record of the choice of the draw when two subtrees of a trajectory are
+/// merged, see [`Chain::set_merge_audit`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergeAudit {
+    /// The depth of each of the two subtrees before the merge
+    pub depth: u64,
+    /// Whether the old subtree contains the initial point of the trajectory.
+    /// Merges into it use biased progressive sampling, all others use
+    /// multinomial sampling.
+    pub is_main: bool,
+    /// The log of the summed weights `exp(-energy)` of the old subtree,
+    /// relative to the initial point
+    pub log_weight: f64,
+    /// The log of the summed weights of the new subtree
+    pub other_log_weight: f64,
+    /// The probability that the draw of the merged tree comes from the new
+    /// subtree, as used by the sampler
+    pub accept_prob: f64,
+    /// The probability `w_new / (w_old + w_new)` of the new subtree in the
+    /// merged tree. In the main tree, `accept_prob` differs from it by the
+    /// correction of biased progressive sampling, which favours moving away
+    /// from the initial point.
+    pub multinomial_prob: f64,
+    /// Whether the draw moved to the new subtree
+    pub accepted: bool,
+}
+
+/// A sink for [`MergeAudit`] records, see [`Chain::set_merge_audit`]
+pub type MergeAuditFn = Box<dyn FnMut(&MergeAudit) + Send>;
+
+/// The generalized U-turn criterion of Betancourt (2017), which the sampler
+/// uses by default
+///
+/// A part of the trajectory has turned if the sum of its momenta points
+/// away from the velocity at either end.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GeneralizedUTurn {}
+
+impl TerminationCriterion for GeneralizedUTurn {
+    fn is_turning(&self, left: &TrajectoryEnd, right: &TrajectoryEnd, p_sum: &[f64]) -> bool {
+        (vector_dot(p_sum, left.velocity) < 0.) | (vector_dot(p_sum, right.velocity) < 0.)
+    }
+}
+
 /// A point in phase space
 ///
-/// This also needs to store the sum of momentum terms
-/// from the initial point of the trajectory to this point,
-/// so that it can compute the termination criterion in
-/// `is_turming`.
+/// Sums of momentum terms over parts of the trajectory are kept by
+/// the trajectory tree, not by the states.
 pub trait State: Clone + Debug {
     /// The state pool can be used to crate new states
     type Pool;
@@ -151,8 +387,26 @@ pub trait State: Clone + Debug {
     /// Write the gradient stored in the state to a different location
     fn write_gradient(&self, out: &mut [f64]);
 
-    /// Compute the termination criterion for NUTS
-    fn is_turning(&self, other: &Self) -> bool;
+    /// The momentum stored in the state
+    fn momentum(&self) -> &[f64];
+
+    /// The position, momentum and velocity of the state, for a
+    /// [`TerminationCriterion`]
+    fn trajectory_end(&self) -> TrajectoryEnd<'_>;
+
+    /// Compute the termination criterion for NUTS for the part of the
+    /// trajectory between `self` and `other`.
+    ///
+    /// `sum1 + sum2` is the sum of the momenta of all points in that part of
+    /// the trajectory, including `self` and `other`.
+    fn is_turning(&self, other: &Self, sum1: &[f64], sum2: &[f64]) -> bool;
+
+    /// Like `is_turning`, but sums must be computed in a fixed order that
+    /// does not depend on the platform, see
+    /// [`NutsOptions::reproducible_sums`].
+    fn is_turning_reproducible(&self, other: &Self, sum1: &[f64], sum2: &[f64]) -> bool {
+        self.is_turning(other, sum1, sum2)
+    }
 
     /// The total energy (potential + kinetic)
     fn energy(&self) -> f64;
@@ -161,8 +415,7 @@ pub trait State: Clone + Debug {
 
     /// Initialize the point to be the first in the trajectory.
     ///
-    /// Set index_in_trajectory to 0 and reinitialize the sum of
-    /// the momentum terms.
+    /// Set index_in_trajectory to 0.
     fn make_init_point(&mut self);
 
     fn log_acceptance_probability(&self, initial_energy: f64) -> f64 {
@@ -183,6 +436,111 @@ pub struct SampleInfo {
     /// Whether the trajectory was terminated because it reached
     /// the maximum tree depth.
     pub reached_maxdepth: bool,
+
+    /// The log of the sum of the multinomial weights of all points in the
+    /// final tree, relative to the initial point.
+    pub log_size: f64,
+
+    /// The number of leapfrog steps used to build the trajectory, including
+    /// steps in subtrees that were discarded.
+    pub n_leapfrog: u64,
+
+    /// The number of leapfrog steps in the final trees of the trajectories
+    /// that were built for this draw but not chosen, see
+    /// [`NutsOptions::num_trajectories`].
+    pub n_leapfrog_unchosen: u64,
+
+    /// The sum of the step sizes of the leapfrog steps in the final tree.
+    /// With several trajectories per draw, this is the trajectory that the
+    /// draw came from.
+    pub integration_time: f64,
+
+    /// The multinomial weighted average of the registered trajectory
+    /// function over all points in the final tree, see
+    /// [`Chain::set_trajectory_expectation`].
+    pub trajectory_expectation: Option<Box<[f64]>>,
+}
+
+impl SampleInfo {
+    /// The number of leapfrog steps in subtrees that were discarded because
+    /// they turned or diverged, and were not merged into the final tree of
+    /// their trajectory.
+    pub fn n_leapfrog_discarded(&self) -> u64 {
+        let in_tree = (1u64 << self.depth) - 1;
+        self.n_leapfrog - in_tree - self.n_leapfrog_unchosen
+    }
+}
+
+/// A function of the position whose expectation is estimated as a weighted
+/// average over all points of each trajectory.
+pub(crate) struct TrajectoryExpectation {
+    func: Box<dyn FnMut(&[f64], &mut [f64]) + Send>,
+    num_values: usize,
+    position: Box<[f64]>,
+    spare: BufferPool,
+}
+
+impl TrajectoryExpectation {
+    pub(crate) fn new<F>(dim: usize, num_values: usize, func: F) -> Self
+    where
+        F: FnMut(&[f64], &mut [f64]) + Send + 'static,
+    {
+        TrajectoryExpectation {
+            func: Box::new(func),
+            num_values,
+            position: vec![0f64; dim].into(),
+            spare: BufferPool::default(),
+        }
+    }
+
+    fn evaluate<S: State>(&mut self, state: &S) -> Box<[f64]> {
+        state.write_position(&mut self.position);
+        let mut out = self.spare.zeroed(self.num_values);
+        (self.func)(&self.position, &mut out);
+        out
+    }
+
+    /// Keep the values of a merged or discarded tree for later evaluations
+    fn recycle(&mut self, values: Box<[f64]>) {
+        self.spare.recycle(values);
+    }
+}
+
+/// Spare vectors for the momentum sums and expectations of trajectory trees,
+/// so that leapfrog steps do not allocate once the first trajectory has been
+/// built.
+#[derive(Default)]
+pub(crate) struct BufferPool {
+    free: Vec<Box<[f64]>>,
+}
+
+impl BufferPool {
+    /// Return a buffer that contains a copy of `values`
+    fn copy_of(&mut self, values: &[f64]) -> Box<[f64]> {
+        match self.free.pop() {
+            Some(mut buffer) if buffer.len() == values.len() => {
+                buffer.copy_from_slice(values);
+                buffer
+            }
+            _ => values.into(),
+        }
+    }
+
+    /// Return a buffer of length `len` that contains only zeros
+    fn zeroed(&mut self, len: usize) -> Box<[f64]> {
+        match self.free.pop() {
+            Some(mut buffer) if buffer.len() == len => {
+                buffer.fill(0f64);
+                buffer
+            }
+            _ => vec![0f64; len].into(),
+        }
+    }
+
+    /// Return a buffer to the pool
+    fn recycle(&mut self, buffer: Box<[f64]>) {
+        self.free.push(buffer);
+    }
 }
 
 /// A part of the trajectory tree during NUTS sampling.
@@ -198,9 +556,28 @@ struct NutsTree<P: Hamiltonian, C: Collector<State = P::State>> {
     /// multinomial sampling.
     draw: P::State,
     log_size: f64,
+    /// The largest Gumbel perturbed log weight of the points in the tree,
+    /// which belongs to `draw`. This is only used with
+    /// [`NutsOptions::gumbel_selection`].
+    log_key: f64,
     depth: u64,
     initial_energy: f64,
 
+    /// The sum of the momenta of all points in the tree.
+    p_sum: Box<[f64]>,
+
+    /// The number of leapfrog steps that were used to build this tree,
+    /// including steps in discarded subtrees.
+    n_leapfrog: u64,
+
+    /// The sum of the step sizes of the leapfrog steps in the tree,
+    /// excluding discarded subtrees.
+    integration_time: f64,
+
+    /// The weighted average of the trajectory function over all points
+    /// in the tree, if one was registered.
+    expectation: Option<Box<[f64]>>,
+
     /// A tree is the main tree if it contains the initial point
     /// of the trajectory.
     is_main: bool,
@@ -221,48 +598,82 @@ enum ExtendResult<P: Hamiltonian, C: Collector<State = P::State>> {
 }
 
 impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
-    fn new(state: P::State) -> NutsTree<P, C> {
+    fn new(
+        state: P::State,
+        buffers: &mut BufferPool,
+        expectation: Option<Box<[f64]>>,
+    ) -> NutsTree<P, C> {
         let initial_energy = state.energy();
         NutsTree {
+            p_sum: buffers.copy_of(state.momentum()),
             right: state.clone(),
             left: state.clone(),
             draw: state,
             depth: 0,
             log_size: 0.,
+            log_key: 0.,
             initial_energy,
+            n_leapfrog: 0,
+            integration_time: 0f64,
+            expectation,
             is_main: true,
             collector: PhantomData,
         }
     }
 
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     fn extend<R>(
         mut self,
         pool: &mut <P::State as State>::Pool,
+        buffers: &mut BufferPool,
         rng: &mut R,
         potential: &mut P,
         direction: Direction,
         options: &NutsOptions,
         collector: &mut C,
+        expectation: &mut Option<TrajectoryExpectation>,
     ) -> ExtendResult<P, C>
     where
         P: Hamiltonian,
         R: rand::Rng + ?Sized,
     {
-        let mut other = match self.single_step(pool, potential, direction, collector) {
-            Ok(Ok(tree)) => tree,
-            Ok(Err(info)) => return ExtendResult::Diverging(self, info),
+        let step = self.single_step(pool, buffers, potential, direction, collector, expectation);
+        let mut other = match step {
+            Ok(Ok(mut tree)) => {
+                if options.gumbel_selection {
+                    tree.log_key += gumbel(rng, options.strict_reproducibility);
+                }
+                tree
+            }
+            Ok(Err(info)) => {
+                self.n_leapfrog += 1;
+                return ExtendResult::Diverging(self, info);
+            }
             Err(err) => return ExtendResult::Err(err),
         };
 
         while other.depth < self.depth {
             use ExtendResult::*;
-            other = match other.extend(pool, rng, potential, direction, options, collector) {
+            other = match other.extend(
+                pool,
+                buffers,
+                rng,
+                potential,
+                direction,
+                options,
+                collector,
+                expectation,
+            ) {
                 Ok(tree) => tree,
-                Turning(_) => {
+                Turning(other) => {
+                    self.n_leapfrog += other.n_leapfrog;
+                    other.recycle(buffers, expectation);
                     return Turning(self);
                 }
-                Diverging(_, info) => {
+                Diverging(other, info) => {
+                    self.n_leapfrog += other.n_leapfrog;
+                    other.recycle(buffers, expectation);
                     return Diverging(self, info);
                 }
                 Err(error) => {
@@ -276,17 +687,49 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
             Direction::Backward => (&other.left, &self.right),
         };
 
-        let mut turning = first.is_turning(last);
+        let mut turning = check_turning(options, buffers, first, last, &self.p_sum, &other.p_sum);
         if self.depth > 0 {
+            // Check the subtrajectories that contain one subtree and the
+            // closest point of the other subtree.
+            let (right_sums, left_sums) = match direction {
+                Direction::Forward => (
+                    (self.right.momentum(), &other.p_sum[..]),
+                    (&self.p_sum[..], other.left.momentum()),
+                ),
+                Direction::Backward => (
+                    (&self.p_sum[..], other.right.momentum()),
+                    (&other.p_sum[..], self.left.momentum()),
+                ),
+            };
+            let ((right_first, right_last), (left_first, left_last)) = match direction {
+                Direction::Forward => ((&self.right, &other.right), (&self.left, &other.left)),
+                Direction::Backward => ((&other.right, &self.right), (&other.left, &self.left)),
+            };
             if !turning {
-                turning = self.right.is_turning(&other.right);
+                turning = check_turning(
+                    options,
+                    buffers,
+                    right_first,
+                    right_last,
+                    right_sums.0,
+                    right_sums.1,
+                );
             }
             if !turning {
-                turning = self.left.is_turning(&other.left);
+                turning = check_turning(
+                    options,
+                    buffers,
+                    left_first,
+                    left_last,
+                    left_sums.0,
+                    left_sums.1,
+                );
             }
         }
 
-        self.merge_into(other, rng, direction);
+        if let Err(error) = self.merge_into(other, buffers, expectation, rng, direction, options) {
+            return ExtendResult::Err(error);
+        }
 
         if turning {
             ExtendResult::Turning(self)
@@ -298,9 +741,23 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
     fn merge_into<R: rand::Rng + ?Sized>(
         &mut self,
         other: NutsTree<P, C>,
+        buffers: &mut BufferPool,
+        expectation: &mut Option<TrajectoryExpectation>,
         rng: &mut R,
         direction: Direction,
-    ) {
+        options: &NutsOptions,
+    ) -> Result<()> {
+        let check_invariants = options.check_invariants;
+        if check_invariants {
+            self.check_invariants()?;
+            other.check_invariants()?;
+        }
+        let (exp, logaddexp): (fn(f64) -> f64, fn(f64, f64) -> f64) =
+            if options.strict_reproducibility {
+                (portable_exp, portable_logaddexp)
+            } else {
+                (f64::exp, logaddexp)
+            };
         assert!(self.depth == other.depth);
         assert!(self.left.index_in_trajectory() <= self.right.index_in_trajectory());
         match direction {
@@ -312,6 +769,26 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
             }
         }
         let log_size = logaddexp(self.log_size, other.log_size);
+        let other_n_leapfrog = other.n_leapfrog;
+        let other_integration_time = other.integration_time;
+        axpy(&other.p_sum, &mut self.p_sum, 1f64);
+        buffers.recycle(other.p_sum);
+
+        if let (Some(expectation), Some(other_expectation)) =
+            (self.expectation.as_mut(), other.expectation.as_ref())
+        {
+            let self_weight = exp(self.log_size - log_size);
+            let other_weight = exp(other.log_size - log_size);
+            expectation
+                .iter_mut()
+                .zip(other_expectation.iter())
+                .for_each(|(val, &other_val)| {
+                    *val = self_weight * *val + other_weight * other_val;
+                });
+        }
+        if let (Some(func), Some(values)) = (expectation.as_mut(), other.expectation) {
+            func.recycle(values);
+        }
 
         let self_log_size = if self.is_main {
             assert!(self.left.index_in_trajectory() <= 0);
@@ -321,22 +798,135 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
             log_size
         };
 
-        if other.log_size >= self_log_size {
-            self.draw = other.draw;
-        } else if rng.gen_bool((other.log_size - self_log_size).exp()) {
+        if check_invariants
+            && !(!log_size.is_nan() & (log_size >= self.log_size) & (log_size >= other.log_size))
+        {
+            return Err(NutsError::InvariantViolation(format!(
+                "Merged tree size {} is inconsistent with subtree sizes {} and {}",
+                log_size, self.log_size, other.log_size
+            )));
+        }
+
+        let other_index = other.draw.index_in_trajectory();
+        let accept_prob = if options.gumbel_selection & !self.is_main {
+            exp(other.log_size - log_size)
+        } else {
+            exp(other.log_size - self_log_size).min(1f64)
+        };
+
+        if options.gumbel_selection {
+            if self.is_main {
+                // Biased progressive sampling: move to the new subtree with
+                // probability min(1, w_other / w_self).
+                let ln = if options.strict_reproducibility {
+                    portable_ln
+                } else {
+                    f64::ln
+                };
+                let log_u = ln(rng.sample(rand::distributions::Open01));
+                if other.log_size - self.log_size >= log_u {
+                    self.draw = other.draw;
+                }
+            } else if other.log_key > self.log_key {
+                // The largest perturbed log weight over all points is the
+                // largest of the two subtrees, and its point is a multinomial
+                // draw from the merged tree.
+                self.draw = other.draw;
+                self.log_key = other.log_key;
+            }
+        } else if (other.log_size >= self_log_size)
+            || rng.gen_bool(exp(other.log_size - self_log_size))
+        {
             self.draw = other.draw;
         }
 
+        if let Some(audit) = options.merge_audit.as_ref() {
+            (audit.borrow_mut())(&MergeAudit {
+                depth: self.depth,
+                is_main: self.is_main,
+                log_weight: self.log_size,
+                other_log_weight: other.log_size,
+                accept_prob,
+                multinomial_prob: exp(other.log_size - log_size),
+                accepted: self.draw.index_in_trajectory() == other_index,
+            });
+        }
+
         self.depth += 1;
         self.log_size = log_size;
+        self.n_leapfrog += other_n_leapfrog;
+        self.integration_time += other_integration_time;
+
+        if check_invariants {
+            self.check_invariants()?;
+        }
+        Ok(())
+    }
+
+    /// Return an error if the tree is not a contiguous trajectory of
+    /// `2 ^ depth` points that contains its draw, or if its momentum sum is
+    /// not consistent with its points.
+    fn check_invariants(&self) -> Result<()> {
+        let violation = |message: String| Err(NutsError::InvariantViolation(message));
+        let left = self.left.index_in_trajectory();
+        let right = self.right.index_in_trajectory();
+        let draw = self.draw.index_in_trajectory();
+        if left > right {
+            return violation(format!(
+                "Left index {} larger than right index {}",
+                left, right
+            ));
+        }
+        if right - left + 1 != 1 << self.depth {
+            return violation(format!(
+                "Tree of depth {} covers indices {} to {}",
+                self.depth, left, right
+            ));
+        }
+        if (draw < left) | (draw > right) {
+            return violation(format!(
+                "Draw index {} outside of tree [{}, {}]",
+                draw, left, right
+            ));
+        }
+        if self.is_main & ((left > 0) | (right < 0)) {
+            return violation(format!(
+                "Main tree [{}, {}] does not contain the initial point",
+                left, right
+            ));
+        }
+        if !(self.left.energy().is_finite() & self.right.energy().is_finite()) {
+            return violation("Energy at the end of the tree is not finite".to_string());
+        }
+        let momentum = self.left.momentum();
+        if self.p_sum.len() != momentum.len() {
+            return violation(format!(
+                "Momentum sum has length {}, but the momentum has length {}",
+                self.p_sum.len(),
+                momentum.len()
+            ));
+        }
+        if !self.p_sum.iter().all(|val| val.is_finite()) {
+            return violation("Momentum sum is not finite".to_string());
+        }
+        // A tree with a single point sums only its momentum
+        if (self.depth == 0) & (self.p_sum[..] != *momentum) {
+            return violation(format!(
+                "Momentum sum {:?} of a single point differs from its momentum {:?}",
+                self.p_sum, momentum
+            ));
+        }
+        Ok(())
     }
 
     fn single_step(
         &self,
         pool: &mut <P::State as State>::Pool,
+        buffers: &mut BufferPool,
         potential: &mut P,
         direction: Direction,
         collector: &mut C,
+        expectation: &mut Option<TrajectoryExpectation>,
     ) -> Result<std::result::Result<NutsTree<P, C>, P::DivergenceInfo>> {
         let start = match direction {
             Direction::Forward => &self.right,
@@ -349,19 +939,33 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
         };
 
         let log_size = self.initial_energy - end.energy();
+        let expectation = expectation.as_mut().map(|func| func.evaluate(&end));
         Ok(Ok(NutsTree {
+            p_sum: buffers.copy_of(end.momentum()),
             right: end.clone(),
             left: end.clone(),
             draw: end,
             depth: 0,
             log_size,
+            log_key: log_size,
             initial_energy: self.initial_energy,
+            n_leapfrog: 1,
+            integration_time: potential.last_step_size(),
+            expectation,
             is_main: false,
             collector: PhantomData,
         }))
     }
 
-    fn info(&self, maxdepth: bool, divergence_info: Option<P::DivergenceInfo>) -> SampleInfo {
+    /// Return the buffers of a tree that is no longer needed
+    fn recycle(self, buffers: &mut BufferPool, expectation: &mut Option<TrajectoryExpectation>) {
+        buffers.recycle(self.p_sum);
+        if let (Some(func), Some(values)) = (expectation.as_mut(), self.expectation) {
+            func.recycle(values);
+        }
+    }
+
+    fn info(&mut self, maxdepth: bool, divergence_info: Option<P::DivergenceInfo>) -> SampleInfo {
         let info: Option<Box<dyn DivergenceInfo>> = match divergence_info {
             Some(info) => Some(Box::new(info)),
             None => None,
@@ -370,6 +974,35 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
             depth: self.depth,
             divergence_info: info,
             reached_maxdepth: maxdepth,
+            log_size: self.log_size,
+            n_leapfrog: self.n_leapfrog,
+            n_leapfrog_unchosen: 0,
+            integration_time: self.integration_time,
+            trajectory_expectation: self.expectation.take(),
+        }
+    }
+}
+
+/// Evaluate the termination criterion for the part of the trajectory from
+/// `left` to `right`, whose momentum sum is `sum1 + sum2`.
+fn check_turning<S: State>(
+    options: &NutsOptions,
+    buffers: &mut BufferPool,
+    left: &S,
+    right: &S,
+    sum1: &[f64],
+    sum2: &[f64],
+) -> bool {
+    match options.termination.as_ref() {
+        None if options.reproducible_sums => left.is_turning_reproducible(right, sum1, sum2),
+        None => left.is_turning(right, sum1, sum2),
+        Some(criterion) => {
+            let mut p_sum = buffers.copy_of(sum1);
+            p_sum.iter_mut().zip(sum2.iter()).for_each(|(a, b)| *a += b);
+            let turning =
+                criterion.is_turning(&left.trajectory_end(), &right.trajectory_end(), &p_sum);
+            buffers.recycle(p_sum);
+            turning
         }
     }
 }
@@ -377,46 +1010,234 @@ impl<P: Hamiltonian, C: Collector<State = P::State>> NutsTree<P, C> {
 pub struct NutsOptions {
     pub maxdepth: u64,
     pub store_gradient: bool,
+    /// Check invariants of the trajectory tree after each doubling and fail
+    /// the draw with [`NutsError::InvariantViolation`] if one is violated.
+    /// This is useful in tests of the `State` and `Hamiltonian`
+    /// implementations of this crate, but slows down sampling.
+    pub check_invariants: bool,
+    /// The number of trajectories that are built after each momentum
+    /// refresh, each with a different step size jitter. One of them is
+    /// chosen uniformly at random as the next draw.
+    pub num_trajectories: u64,
+    /// The step size of each trajectory is multiplied by a factor drawn
+    /// uniformly from `[1 - step_size_jitter, 1 + step_size_jitter]` if
+    /// `num_trajectories` is larger than one.
+    pub step_size_jitter: f64,
+    /// Use the portable functions in [`crate::math`] instead of the platform
+    /// libm for momentum draws, the multinomial choice of the draw and the
+    /// acceptance statistic, so that chains are bit-for-bit identical on all
+    /// platforms. Step size and mass matrix adaptation also use them with this
+    /// option, and the platform libm otherwise.
+    pub strict_reproducibility: bool,
+    /// Evaluate the U-turn criterion of the states with
+    /// [`State::is_turning_reproducible`].
+    pub reproducible_sums: bool,
+    /// Choose the draw within subtrees with the Gumbel-max trick: each point
+    /// gets the log weight `-energy` plus an independent standard Gumbel
+    /// value, and the point with the largest value is the draw. In the tree
+    /// that contains the initial point, the draw moves to a new subtree with
+    /// probability `min(1, w_new / w_old)` based on an explicit log-uniform
+    /// value. This samples from the same distribution as the default
+    /// progressive sampling, but each choice is a comparison of explicit log
+    /// weights, which makes it easier to audit. It uses different random
+    /// numbers, so the draws differ from the default.
+    pub gumbel_selection: bool,
+    /// A custom termination criterion that replaces the generalized U-turn
+    /// criterion of the states.
+    pub termination: Option<Box<dyn TerminationCriterion>>,
+    /// Receives a record of each merge of two subtrees.
+    pub merge_audit: Option<RefCell<MergeAuditFn>>,
 }
 
+/// Separate random number streams for the different random choices in a
+/// draw
+///
+/// Changing how one of them is used, for example the algorithm for momentum
+/// draws, does not change the random numbers of the others. This keeps traces
+/// of different versions of the sampler comparable.
+#[derive(Debug, Clone)]
+pub(crate) struct RngStreams<R: rand::Rng> {
+    /// Momentum draws
+    pub(crate) momentum: R,
+    /// The direction of each doubling of the trajectory
+    pub(crate) direction: R,
+    /// The choice of the draw within the trajectory
+    pub(crate) proposal: R,
+    /// Step size jitter and the choice between several trajectories
+    pub(crate) trajectory: R,
+}
+
+impl<R: rand::Rng + rand::SeedableRng> RngStreams<R> {
+    pub(crate) fn seed_from_u64(seed: u64) -> Self {
+        // Each stream is seeded with the draw seed of a pseudo chain
+        // `u64::MAX - stream`, which does not collide with real chains.
+        let stream = |index: u64| R::seed_from_u64(draw_seed(seed, u64::MAX - index, 0));
+        Self {
+            momentum: stream(0),
+            direction: stream(1),
+            proposal: stream(2),
+            trajectory: stream(3),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn draw<P, R, C>(
     pool: &mut <P::State as State>::Pool,
+    buffers: &mut BufferPool,
     init: &mut P::State,
-    rng: &mut R,
+    rng: &mut RngStreams<R>,
     potential: &mut P,
     options: &NutsOptions,
     collector: &mut C,
+    momentum: Option<&[f64]>,
+    expectation: &mut Option<TrajectoryExpectation>,
 ) -> Result<(P::State, SampleInfo)>
 where
     P: Hamiltonian,
-    R: rand::Rng + ?Sized,
+    R: rand::Rng,
     C: Collector<State = P::State>,
 {
-    potential.randomize_momentum(init, rng);
+    match momentum {
+        Some(momentum) => potential.set_momentum(init, momentum),
+        None => {
+            potential.randomize_momentum(init, &mut rng.momentum, options.strict_reproducibility)
+        }
+    }
     init.make_init_point();
+    potential.register_draw_start();
     collector.register_init(init, options);
 
-    let mut tree = NutsTree::new(init.clone());
+    let num_trajectories = options.num_trajectories.max(1);
+    let (draw, info) = if num_trajectories == 1 {
+        build_trajectory(
+            pool,
+            buffers,
+            init,
+            rng,
+            potential,
+            options,
+            collector,
+            expectation,
+        )?
+    } else {
+        if !(0f64..1f64).contains(&options.step_size_jitter) {
+            return Err(NutsError::InvalidSettings(format!(
+                "step_size_jitter must be in [0, 1), but is {}",
+                options.step_size_jitter
+            )));
+        }
+        let step_size = potential.step_size();
+        let chosen = rng.trajectory.gen_range(0..num_trajectories);
+        let mut n_leapfrog = 0;
+        let mut n_leapfrog_unchosen = 0;
+        let mut expectation_sum: Option<Box<[f64]>> = None;
+        let mut result = None;
+        for k in 0..num_trajectories {
+            let factor = 1f64 + options.step_size_jitter * rng.trajectory.gen_range(-1f64..1f64);
+            potential.set_step_size(step_size * factor);
+            let trajectory = build_trajectory(
+                pool,
+                buffers,
+                init,
+                rng,
+                potential,
+                options,
+                collector,
+                expectation,
+            );
+            let (draw, info) = match trajectory {
+                Ok(trajectory) => trajectory,
+                Err(error) => {
+                    potential.set_step_size(step_size);
+                    return Err(error);
+                }
+            };
+            n_leapfrog += info.n_leapfrog;
+            if k != chosen {
+                n_leapfrog_unchosen += (1u64 << info.depth) - 1;
+            }
+            if let Some(val) = info.trajectory_expectation.as_ref() {
+                match expectation_sum.as_mut() {
+                    Some(sum) => sum.iter_mut().zip(val.iter()).for_each(|(a, b)| *a += b),
+                    None => expectation_sum = Some(val.clone()),
+                }
+            }
+            if k == chosen {
+                result = Some((draw, info));
+            }
+        }
+        potential.set_step_size(step_size);
+        let (draw, mut info) = result.expect("Chosen trajectory was not built");
+        // Choosing the draw uniformly at random makes this a mixture of NUTS
+        // kernels, so expectations can be averaged with equal weights.
+        info.n_leapfrog = n_leapfrog;
+        info.n_leapfrog_unchosen = n_leapfrog_unchosen;
+        info.trajectory_expectation = expectation_sum.map(|mut sum| {
+            sum.iter_mut()
+                .for_each(|val| *val /= num_trajectories as f64);
+            sum
+        });
+        (draw, info)
+    };
+    collector.register_draw(&draw, &info, &potential.current_stats());
+    Ok((draw, info))
+}
+
+/// Build a NUTS trajectory starting at `init` and return a draw from it.
+#[allow(clippy::too_many_arguments)]
+fn build_trajectory<P, R, C>(
+    pool: &mut <P::State as State>::Pool,
+    buffers: &mut BufferPool,
+    init: &P::State,
+    rng: &mut RngStreams<R>,
+    potential: &mut P,
+    options: &NutsOptions,
+    collector: &mut C,
+    expectation: &mut Option<TrajectoryExpectation>,
+) -> Result<(P::State, SampleInfo)>
+where
+    P: Hamiltonian,
+    R: rand::Rng,
+    C: Collector<State = P::State>,
+{
+    let init_expectation = expectation.as_mut().map(|func| func.evaluate(init));
+    let mut tree = NutsTree::new(init.clone(), buffers, init_expectation);
+    let mut divergence_info = None;
+    let mut reached_maxdepth = true;
     while tree.depth < options.maxdepth {
-        let direction: Direction = rng.gen();
-        tree = match tree.extend(pool, rng, potential, direction, options, collector) {
+        let direction: Direction = rng.direction.gen();
+        collector.register_doubling(tree.depth);
+        tree = match tree.extend(
+            pool,
+            buffers,
+            &mut rng.proposal,
+            potential,
+            direction,
+            options,
+            collector,
+            expectation,
+        ) {
             ExtendResult::Ok(tree) => tree,
             ExtendResult::Turning(tree) => {
-                let info = tree.info(false, None);
-                collector.register_draw(&tree.draw, &info);
-                return Ok((tree.draw, info));
+                reached_maxdepth = false;
+                tree
             }
             ExtendResult::Diverging(tree, info) => {
-                let info = tree.info(false, Some(info));
-                collector.register_draw(&tree.draw, &info);
-                return Ok((tree.draw, info));
+                reached_maxdepth = false;
+                divergence_info = Some(info);
+                tree
             }
             ExtendResult::Err(error) => {
                 return Err(error);
             }
         };
+        if !reached_maxdepth {
+            break;
+        }
     }
-    let info = tree.info(true, None);
+    let info = tree.info(reached_maxdepth, divergence_info);
+    buffers.recycle(tree.p_sum);
     Ok((tree.draw, info))
 }
 
@@ -430,6 +1251,12 @@ pub(crate) struct NutsSampleStats<HStats: Send + Debug, AdaptStats: Send + Debug
     pub divergence_info: Option<Box<dyn DivergenceInfo>>,
     pub chain: u64,
     pub draw: u64,
+    pub draw_seed: u64,
+    pub n_leapfrog: u64,
+    pub n_leapfrog_discarded: u64,
+    pub integration_time: f64,
+    pub discarded_leapfrog_fraction: f64,
+    pub trajectory_expectation: Option<Box<[f64]>>,
     pub gradient: Option<Box<[f64]>>,
     pub potential_stats: HStats,
     pub strategy_stats: AdaptStats,
@@ -448,6 +1275,68 @@ pub enum SampleStatValue {
     String(String),
 }
 
+/// The variant of a [`SampleStatValue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleStatKind {
+    Array,
+    OptionArray,
+    U64,
+    I64,
+    OptionI64,
+    F64,
+    OptionF64,
+    Bool,
+    String,
+}
+
+impl SampleStatValue {
+    pub fn kind(&self) -> SampleStatKind {
+        match self {
+            SampleStatValue::Array(_) => SampleStatKind::Array,
+            SampleStatValue::OptionArray(_) => SampleStatKind::OptionArray,
+            SampleStatValue::U64(_) => SampleStatKind::U64,
+            SampleStatValue::I64(_) => SampleStatKind::I64,
+            SampleStatValue::OptionI64(_) => SampleStatKind::OptionI64,
+            SampleStatValue::F64(_) => SampleStatKind::F64,
+            SampleStatValue::OptionF64(_) => SampleStatKind::OptionF64,
+            SampleStatValue::Bool(_) => SampleStatKind::Bool,
+            SampleStatValue::String(_) => SampleStatKind::String,
+        }
+    }
+}
+
+/// The description of one sample stat, see [`Chain::stat_schema`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatField {
+    pub name: &'static str,
+    pub kind: SampleStatKind,
+    /// The length of an array stat if it is present, if that is the same for
+    /// all draws. This is `None` for scalars, for arrays whose length varies
+    /// between draws and for optional arrays that are not stored with the
+    /// current settings.
+    pub length: Option<usize>,
+    /// Whether the stat is only reported for draws from a diverging
+    /// trajectory.
+    pub diverging_only: bool,
+}
+
+impl StatField {
+    /// Describe a stat with the kind and length of `value`.
+    pub fn from_value(name: &'static str, value: &SampleStatValue) -> Self {
+        let length = match value {
+            SampleStatValue::Array(val) => Some(val.len()),
+            SampleStatValue::OptionArray(val) => val.as_ref().map(|val| val.len()),
+            _ => None,
+        };
+        StatField {
+            name,
+            kind: value.kind(),
+            length,
+            diverging_only: false,
+        }
+    }
+}
+
 impl From<Box<[f64]>> for SampleStatValue {
     fn from(val: Box<[f64]>) -> Self {
         SampleStatValue::Array(val)
@@ -502,8 +1391,36 @@ impl From<String> for SampleStatValue {
     }
 }
 
+/// The seed of the random number stream for a draw, as reported in
+/// [`SampleStats::draw_seed`].
+///
+/// This mixes the sampler seed, chain and draw number with the SplitMix64
+/// finalizer, so that it is stable across platforms and crate versions.
+pub fn draw_seed(seed: u64, chain: u64, draw: u64) -> u64 {
+    fn mix(mut z: u64) -> u64 {
+        z = z.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    mix(mix(mix(seed) ^ chain) ^ draw)
+}
+
 pub trait AsSampleStatVec: Debug {
     fn add_to_vec(&self, vec: &mut Vec<SampleStatItem>);
+
+    /// Describe the stats that [`AsSampleStatVec::add_to_vec`] adds.
+    ///
+    /// By default the schema is derived from the current values. This needs
+    /// to be overridden for arrays whose length changes between draws.
+    fn add_to_schema(&self, schema: &mut Vec<StatField>) {
+        let mut vec = vec![];
+        self.add_to_vec(&mut vec);
+        schema.extend(
+            vec.iter()
+                .map(|(name, value)| StatField::from_value(name, value)),
+        );
+    }
 }
 
 pub type SampleStatItem = (&'static str, SampleStatValue);
@@ -527,9 +1444,30 @@ pub trait SampleStats: Send + Debug {
     fn chain(&self) -> u64;
     /// The draw number
     fn draw(&self) -> u64;
+    /// A seed that identifies the random number stream for this draw.
+    ///
+    /// It only depends on the seed of the sampler, the chain and the draw number,
+    /// see [`draw_seed`], so external generated-quantities pipelines can use it to
+    /// reproduce stochastic transformations of draws across reruns.
+    fn draw_seed(&self) -> u64;
+    /// The number of leapfrog steps (gradient evaluations) for this draw
+    fn n_leapfrog(&self) -> u64;
+    /// The number of leapfrog steps for this draw that were spent in
+    /// subtrees that were discarded because they turned or diverged.
+    fn n_leapfrog_discarded(&self) -> u64;
+    /// The sum of the step sizes of the leapfrog steps in the trajectory
+    /// that the draw came from, see [`trajectory_calibration`].
+    ///
+    /// [`trajectory_calibration`]: crate::trajectory_calibration
+    fn integration_time(&self) -> f64 {
+        0f64
+    }
     /// The logp gradient at the location of the draw. This is only stored
     /// if NutsOptions.store_gradient is `true`.
     fn gradient(&self) -> Option<&[f64]>;
+    /// The Rao-Blackwellized estimate of the function registered with
+    /// [`Chain::set_trajectory_expectation`] for this draw.
+    fn trajectory_expectation(&self) -> Option<&[f64]>;
     /// Export the sample statisitcs to a vector. This might include some additional
     /// diagnostics coming from the step size and matrix adaptation strategies.
     fn to_vec(&self) -> Vec<SampleStatItem>;
@@ -564,28 +1502,86 @@ where
     fn draw(&self) -> u64 {
         self.draw
     }
+    fn draw_seed(&self) -> u64 {
+        self.draw_seed
+    }
+    fn n_leapfrog(&self) -> u64 {
+        self.n_leapfrog
+    }
+    fn n_leapfrog_discarded(&self) -> u64 {
+        self.n_leapfrog_discarded
+    }
+    fn integration_time(&self) -> f64 {
+        self.integration_time
+    }
     fn gradient(&self) -> Option<&[f64]> {
         self.gradient.as_ref().map(|x| &x[..])
     }
+    fn trajectory_expectation(&self) -> Option<&[f64]> {
+        self.trajectory_expectation.as_deref()
+    }
     fn to_vec(&self) -> Vec<SampleStatItem> {
         let mut vec = Vec::with_capacity(20);
+        self.add_draw_stats(&mut vec);
+        self.potential_stats.add_to_vec(&mut vec);
+        self.strategy_stats.add_to_vec(&mut vec);
+        if let Some(info) = self.divergence_info() {
+            info.add_to_vec(&mut vec);
+        }
+        vec.push(("gradient", self.gradient_stat()));
+        vec
+    }
+}
+
+impl<H, A> NutsSampleStats<H, A>
+where
+    H: Send + Debug + AsSampleStatVec,
+    A: Send + Debug + AsSampleStatVec,
+{
+    /// Add the stats that do not come from the potential, the adaptation
+    /// strategy or a divergence.
+    fn add_draw_stats(&self, vec: &mut Vec<SampleStatItem>) {
         vec.push(("depth", self.depth.into()));
         vec.push(("maxdepth_reached", self.maxdepth_reached.into()));
         vec.push(("index_in_trajectory", self.idx_in_trajectory.into()));
         vec.push(("logp", self.logp.into()));
         vec.push(("energy", self.energy.into()));
         vec.push(("diverging", self.divergence_info.is_some().into()));
-        self.potential_stats.add_to_vec(&mut vec);
-        self.strategy_stats.add_to_vec(&mut vec);
-        if let Some(info) = self.divergence_info() {
-            info.add_to_vec(&mut vec);
-        }
-        if let Some(grad) = self.gradient() {
-            vec.push(("gradient", grad.to_vec().into_boxed_slice().into()));
-        } else {
-            vec.push(("gradient", SampleStatValue::OptionArray(None)));
+        vec.push(("draw_seed", self.draw_seed.into()));
+        vec.push(("n_leapfrog", self.n_leapfrog.into()));
+        vec.push(("n_leapfrog_discarded", self.n_leapfrog_discarded.into()));
+        vec.push(("integration_time", self.integration_time.into()));
+        vec.push((
+            "discarded_leapfrog_fraction",
+            self.discarded_leapfrog_fraction.into(),
+        ));
+        vec.push((
+            "trajectory_expectation",
+            self.trajectory_expectation.clone().into(),
+        ));
+    }
+
+    /// Describe the stats of draws like this one, in the order of
+    /// [`SampleStats::to_vec`].
+    fn schema(&self, divergence_schema: Vec<StatField>) -> Vec<StatField> {
+        let mut vec = vec![];
+        self.add_draw_stats(&mut vec);
+        let mut schema: Vec<StatField> = vec
+            .iter()
+            .map(|(name, value)| StatField::from_value(name, value))
+            .collect();
+        self.potential_stats.add_to_schema(&mut schema);
+        self.strategy_stats.add_to_schema(&mut schema);
+        schema.extend(divergence_schema);
+        schema.push(StatField::from_value("gradient", &self.gradient_stat()));
+        schema
+    }
+
+    fn gradient_stat(&self) -> SampleStatValue {
+        match self.gradient() {
+            Some(grad) => grad.to_vec().into_boxed_slice().into(),
+            None => SampleStatValue::OptionArray(None),
         }
-        vec
     }
 }
 
@@ -604,10 +1600,210 @@ pub trait Chain {
     /// Draw a new sample and return the position and some diagnosic information.
     fn draw(&mut self) -> Result<(Box<[f64]>, Self::Stats)>;
 
+    /// Draw a new sample and write its position to `out` instead of
+    /// allocating a new array.
+    fn draw_into(&mut self, out: &mut [f64]) -> Result<Self::Stats>;
+
+    /// Make `n` draws and write their positions as the rows of the
+    /// row-major `n x dim` matrix `out`, and append their stats to `stats`.
+    ///
+    /// This amortizes the overhead of calls through language bindings. If a
+    /// draw fails, the rows and stats of the previous draws are kept.
+    fn draw_many(&mut self, n: usize, out: &mut [f64], stats: &mut Vec<Self::Stats>) -> Result<()> {
+        let dim = self.dim();
+        check_dim(n * dim, out.len())?;
+        stats.reserve(n);
+        for row in 0..n {
+            stats.push(self.draw_into(&mut out[row * dim..(row + 1) * dim])?);
+        }
+        Ok(())
+    }
+
+    /// Sample from `p(x)^inverse_temperature` instead of the posterior, for
+    /// example to drive an annealing schedule between draws.
+    ///
+    /// The logp and gradient at the current position are recomputed, so this
+    /// fails if the logp function returns an error. `inverse_temperature`
+    /// must be finite and non-negative.
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<()>;
+
+    /// Recompute the logp and gradient at the current position.
+    ///
+    /// This needs to be called if the density changed between draws, for example
+    /// because the logp function depends on discrete state that was updated.
+    fn reevaluate_position(&mut self) -> Result<()>;
+
+    /// Move the chain to `position` like [`set_position`](Self::set_position),
+    /// but without restarting adaptation.
+    ///
+    /// This is meant for samplers that wrap a chain and reject some of its
+    /// draws, like [`DelayedAcceptanceChain`](crate::DelayedAcceptanceChain).
+    /// Chains that can not do this return [`NutsError::Unsupported`].
+    fn restore_position(&mut self, _position: &[f64]) -> Result<()> {
+        Err(NutsError::Unsupported(
+            "restoring a position without restarting adaptation",
+        ))
+    }
+
+    /// The logp at the current position, multiplied by the inverse
+    /// temperature, or `None` if the chain does not keep track of it.
+    fn current_logp(&self) -> Option<f64> {
+        None
+    }
+
+    /// Replace the mass matrix by the diagonal matrix with inverse `variance`,
+    /// for example with posterior variances estimated elsewhere.
+    ///
+    /// All entries must be positive and finite. The current position is
+    /// reevaluated, so this fails if the logp function returns an error.
+    /// During tuning, mass matrix adaptation replaces the metric again at
+    /// its next update.
+    fn set_metric(&mut self, variance: &[f64]) -> Result<()>;
+
+    /// The diagonal of the current inverse mass matrix.
+    fn metric(&self) -> &[f64];
+
+    /// The current step size. After tuning, this is the adapted step size.
+    fn step_size(&self) -> f64;
+
+    /// Use `momentum` instead of a random momentum in the next draw.
+    ///
+    /// This is meant for experiments like coupled or antithetic chains and
+    /// for reproducing specific trajectories. The resulting draws are not
+    /// valid posterior samples unless the momentum is drawn from the correct
+    /// distribution.
+    fn set_next_momentum(&mut self, momentum: &[f64]) -> Result<()>;
+
+    /// Write a draw from the momentum distribution `N(0, M)` of the current
+    /// mass matrix `M` to `out`.
+    ///
+    /// The draw uses `rng` and not the random streams of the chain, so it
+    /// does not change the following draws of the chain. Together with
+    /// [`logp_momentum`](Self::logp_momentum) this is meant for diagnostics
+    /// of the marginal energy distribution and for checking custom kinetic
+    /// energies.
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, out: &mut [f64]) -> Result<()>;
+
+    /// The log density of `momentum` under the momentum distribution
+    /// `N(0, M)` of the current mass matrix, including the normalization
+    /// constant.
+    fn logp_momentum(&self, momentum: &[f64]) -> Result<f64>;
+
+    /// Evaluate the hamiltonian of the chain at `position` and `momentum`
+    /// with the current mass matrix, step size and inverse temperature.
+    ///
+    /// This does not move the chain. It is meant for tests of custom mass
+    /// matrices and potentials, for example to compare the velocity with
+    /// finite differences of the kinetic energy. Fails if the logp function
+    /// returns an error or the dimensions do not match.
+    fn evaluate(&mut self, position: &[f64], momentum: &[f64]) -> Result<HamiltonianSnapshot>;
+
+    /// Estimate the expectation of `func` for each draw as the average over all
+    /// points in the trajectory, weighted by their multinomial weights.
+    ///
+    /// `func` writes `num_values` values for a position. The estimates are
+    /// reported in [`SampleStats::trajectory_expectation`] and usually have a
+    /// smaller variance than `func` evaluated at the draws, but `func` is
+    /// evaluated after each leapfrog step.
+    fn set_trajectory_expectation<F>(&mut self, num_values: usize, func: F)
+    where
+        F: FnMut(&[f64], &mut [f64]) + Send + 'static;
+
+    /// End trajectories according to `criterion` instead of the generalized
+    /// U-turn criterion.
+    ///
+    /// This is meant for experiments with alternative criteria. The draws are
+    /// only valid posterior samples if the criterion gives the same answer for
+    /// a part of the trajectory independent of the starting point within it,
+    /// which is true for criteria that only depend on the ends and the
+    /// momentum sum that are passed to it.
+    fn set_termination_criterion<T: TerminationCriterion + 'static>(&mut self, criterion: T);
+
+    /// Multiply the step size of each leapfrog step by `func(position)` at
+    /// the start of the step, for example to take smaller steps near the
+    /// neck of a funnel.
+    ///
+    /// `func` must return positive and finite values, otherwise the draw
+    /// fails with [`NutsError::InvalidStepSizeMultiplier`]. Step size adaptation
+    /// still tunes the base step size. This is meant for experts: unless
+    /// `func` is constant along trajectories, the leapfrog integrator is no
+    /// longer reversible, and the draws are not exact posterior samples.
+    fn set_step_size_fn<F>(&mut self, func: F)
+    where
+        F: FnMut(&[f64]) -> f64 + Send + 'static;
+
+    /// Call `sink` with a [`MergeAudit`] record each time two subtrees of a
+    /// trajectory are merged and the draw of the merged tree is chosen.
+    ///
+    /// The records contain the exact probabilities that the sampler used,
+    /// so the choices can be verified statistically, for example by
+    /// comparing the mean of `accepted` and `accept_prob`. This slows down
+    /// sampling a bit and is meant for debugging.
+    fn set_merge_audit<F>(&mut self, sink: F)
+    where
+        F: FnMut(&MergeAudit) + Send + 'static;
+
+    /// Check invariants of the trajectory trees after each doubling, for
+    /// example that they are contiguous, contain their draws and have
+    /// consistent momentum sums and sizes.
+    ///
+    /// A violated invariant fails the draw with
+    /// [`NutsError::InvariantViolation`]. Non-finite logp values and gradients
+    /// end a trajectory as divergences before they reach the tree, so a
+    /// violation points to a bug in the sampler, not in the logp function.
+    /// This is meant for bug reports and tests, and slows down sampling.
+    fn set_check_invariants(&mut self, check: bool);
+
+    /// Describe the stats that [`SampleStats::to_vec`] returns for the next
+    /// draws with the current settings, for example to preallocate storage.
+    ///
+    /// Stats marked as [`StatField::diverging_only`] are missing for draws
+    /// that did not diverge.
+    fn stat_schema(&self) -> Vec<StatField>;
+
+    /// Statistics accumulated over all draws of this chain so far.
+    fn snapshot_stats(&self) -> StatsSnapshot;
+
     /// The dimensionality of the posterior.
     fn dim(&self) -> usize;
 }
 
+/// Statistics accumulated over all draws of a chain, see
+/// [`Chain::snapshot_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsSnapshot {
+    /// The number of draws, including tuning draws
+    pub num_draws: u64,
+    /// The number of draws from a diverging trajectory
+    pub num_divergences: u64,
+    /// The number of draws where the trajectory reached the maximum depth
+    pub num_maxdepth_reached: u64,
+    /// The total number of leapfrog steps
+    pub n_leapfrog: u64,
+    /// The total number of leapfrog steps in discarded subtrees
+    pub n_leapfrog_discarded: u64,
+}
+
+impl StatsSnapshot {
+    /// The fraction of leapfrog steps that were spent in discarded subtrees.
+    pub fn discarded_leapfrog_fraction(&self) -> f64 {
+        if self.n_leapfrog > 0 {
+            self.n_leapfrog_discarded as f64 / self.n_leapfrog as f64
+        } else {
+            0f64
+        }
+    }
+
+    /// Add the statistics of a different chain.
+    pub fn merge(&mut self, other: &StatsSnapshot) {
+        self.num_draws += other.num_draws;
+        self.num_divergences += other.num_divergences;
+        self.num_maxdepth_reached += other.num_maxdepth_reached;
+        self.n_leapfrog += other.n_leapfrog;
+        self.n_leapfrog_discarded += other.n_leapfrog_discarded;
+    }
+}
+
 pub(crate) struct NutsChain<P, R, S>
 where
     P: Hamiltonian,
@@ -615,13 +1811,18 @@ where
     S: AdaptStrategy<Potential = P>,
 {
     pool: <P::State as State>::Pool,
+    buffers: BufferPool,
     potential: P,
     collector: S::Collector,
     options: NutsOptions,
-    rng: R,
+    rng: RngStreams<R>,
     init: P::State,
+    next_momentum: Option<Box<[f64]>>,
+    expectation: Option<TrajectoryExpectation>,
     chain: u64,
+    seed: u64,
     draw_count: u64,
+    totals: StatsSnapshot,
     strategy: S,
 }
 
@@ -631,20 +1832,32 @@ where
     R: rand::Rng,
     S: AdaptStrategy<Potential = P>,
 {
-    pub fn new(mut potential: P, strategy: S, options: NutsOptions, rng: R, chain: u64) -> Self {
+    pub fn new(
+        mut potential: P,
+        strategy: S,
+        options: NutsOptions,
+        rng: RngStreams<R>,
+        chain: u64,
+        seed: u64,
+    ) -> Self {
         let pool_size: usize = options.maxdepth.checked_mul(2).unwrap().try_into().unwrap();
         let mut pool = potential.new_pool(pool_size);
         let init = potential.new_empty_state(&mut pool);
         let collector = strategy.new_collector();
         NutsChain {
             pool,
+            buffers: BufferPool::default(),
             potential,
             collector,
             options,
             rng,
             init,
+            next_momentum: None,
+            expectation: None,
             chain,
+            seed,
             draw_count: 0,
+            totals: StatsSnapshot::default(),
             strategy,
         }
     }
@@ -694,7 +1907,9 @@ where
     type Stats = NutsSampleStats<H::Stats, S::Stats>;
 
     fn set_position(&mut self, position: &[f64]) -> Result<()> {
+        check_dim(self.potential.dim(), position.len())?;
         let state = self.potential.init_state(&mut self.pool, position)?;
+        self.potential.register_current_state(&state);
         self.init = state;
         self.strategy
             .init(&mut self.options, &mut self.potential, &self.init);
@@ -702,16 +1917,31 @@ where
     }
 
     fn draw(&mut self) -> Result<(Box<[f64]>, Self::Stats)> {
+        let mut position: Box<[f64]> = vec![0f64; self.potential.dim()].into();
+        let stats = self.draw_into(&mut position)?;
+        Ok((position, stats))
+    }
+
+    fn draw_into(&mut self, out: &mut [f64]) -> Result<Self::Stats> {
+        check_dim(self.potential.dim(), out.len())?;
         let (state, info) = draw(
             &mut self.pool,
+            &mut self.buffers,
             &mut self.init,
             &mut self.rng,
             &mut self.potential,
             &self.options,
             &mut self.collector,
+            self.next_momentum.take().as_deref(),
+            &mut self.expectation,
         )?;
-        let mut position: Box<[f64]> = vec![0f64; self.potential.dim()].into();
-        state.write_position(&mut position);
+        state.write_position(out);
+        let n_leapfrog_discarded = info.n_leapfrog_discarded();
+        self.totals.num_draws += 1;
+        self.totals.num_divergences += info.divergence_info.is_some() as u64;
+        self.totals.num_maxdepth_reached += info.reached_maxdepth as u64;
+        self.totals.n_leapfrog += info.n_leapfrog;
+        self.totals.n_leapfrog_discarded += n_leapfrog_discarded;
         let stats = NutsSampleStats {
             depth: info.depth,
             maxdepth_reached: info.reached_maxdepth,
@@ -721,6 +1951,12 @@ where
             divergence_info: info.divergence_info,
             chain: self.chain,
             draw: self.draw_count,
+            draw_seed: draw_seed(self.seed, self.chain, self.draw_count),
+            n_leapfrog: info.n_leapfrog,
+            n_leapfrog_discarded,
+            integration_time: info.integration_time,
+            trajectory_expectation: info.trajectory_expectation,
+            discarded_leapfrog_fraction: self.totals.discarded_leapfrog_fraction(),
             potential_stats: self.potential.current_stats(),
             strategy_stats: self.strategy.current_stats(
                 &self.options,
@@ -741,12 +1977,325 @@ where
             self.draw_count,
             &self.collector,
         );
+        self.potential.register_current_state(&state);
         self.init = state;
         self.draw_count += 1;
-        Ok((position, stats))
+        Ok(stats)
+    }
+
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<()> {
+        check_inverse_temperature(inverse_temperature)?;
+        self.potential
+            .set_inverse_temperature(inverse_temperature)?;
+        self.reevaluate_position()
+    }
+
+    fn set_metric(&mut self, variance: &[f64]) -> Result<()> {
+        self.potential.set_metric(variance)?;
+        // The cached velocity and kinetic energy of the current state depend
+        // on the metric.
+        self.reevaluate_position()
+    }
+
+    fn metric(&self) -> &[f64] {
+        self.potential.metric()
+    }
+
+    fn step_size(&self) -> f64 {
+        self.potential.step_size()
+    }
+
+    fn reevaluate_position(&mut self) -> Result<()> {
+        let mut position = vec![0f64; self.potential.dim()];
+        self.init.write_position(&mut position);
+        self.restore_position(&position)
+    }
+
+    fn restore_position(&mut self, position: &[f64]) -> Result<()> {
+        self.init = self.potential.init_state(&mut self.pool, position)?;
+        self.potential.register_current_state(&self.init);
+        Ok(())
+    }
+
+    fn current_logp(&self) -> Option<f64> {
+        Some(-self.init.potential_energy())
+    }
+
+    fn set_next_momentum(&mut self, momentum: &[f64]) -> Result<()> {
+        check_dim(self.potential.dim(), momentum.len())?;
+        self.next_momentum = Some(momentum.into());
+        Ok(())
+    }
+
+    fn sample_momentum<G: rand::Rng + ?Sized>(&self, rng: &mut G, out: &mut [f64]) -> Result<()> {
+        check_dim(self.potential.dim(), out.len())?;
+        let portable = self.options.strict_reproducibility;
+        self.potential.sample_momentum(rng, portable, out);
+        Ok(())
+    }
+
+    fn logp_momentum(&self, momentum: &[f64]) -> Result<f64> {
+        check_dim(self.potential.dim(), momentum.len())?;
+        Ok(self.potential.logp_momentum(momentum))
+    }
+
+    fn evaluate(&mut self, position: &[f64], momentum: &[f64]) -> Result<HamiltonianSnapshot> {
+        check_dim(self.potential.dim(), position.len())?;
+        self.potential.evaluate(&mut self.pool, position, momentum)
+    }
+
+    fn set_trajectory_expectation<F>(&mut self, num_values: usize, func: F)
+    where
+        F: FnMut(&[f64], &mut [f64]) + Send + 'static,
+    {
+        self.expectation = Some(TrajectoryExpectation::new(
+            self.potential.dim(),
+            num_values,
+            func,
+        ));
+    }
+
+    fn set_termination_criterion<T: TerminationCriterion + 'static>(&mut self, criterion: T) {
+        self.options.termination = Some(Box::new(criterion));
+    }
+
+    fn set_step_size_fn<F>(&mut self, func: F)
+    where
+        F: FnMut(&[f64]) -> f64 + Send + 'static,
+    {
+        self.potential.set_step_size_fn(Some(Box::new(func)));
+    }
+
+    fn set_merge_audit<F>(&mut self, sink: F)
+    where
+        F: FnMut(&MergeAudit) + Send + 'static,
+    {
+        self.options.merge_audit = Some(RefCell::new(Box::new(sink)));
+    }
+
+    fn set_check_invariants(&mut self, check: bool) {
+        self.options.check_invariants = check;
+    }
+
+    fn stat_schema(&self) -> Vec<StatField> {
+        let dim = self.potential.dim();
+        let template = NutsSampleStats {
+            depth: 0,
+            maxdepth_reached: false,
+            idx_in_trajectory: 0,
+            logp: 0f64,
+            energy: 0f64,
+            divergence_info: None,
+            chain: self.chain,
+            draw: self.draw_count,
+            draw_seed: 0,
+            n_leapfrog: 0,
+            n_leapfrog_discarded: 0,
+            integration_time: 0f64,
+            discarded_leapfrog_fraction: 0f64,
+            trajectory_expectation: self
+                .expectation
+                .as_ref()
+                .map(|expectation| vec![0f64; expectation.num_values].into()),
+            gradient: self.options.store_gradient.then(|| vec![0f64; dim].into()),
+            potential_stats: self.potential.current_stats(),
+            strategy_stats: self.strategy.current_stats(
+                &self.options,
+                &self.potential,
+                &self.collector,
+            ),
+        };
+        template.schema(self.potential.divergence_stat_schema())
+    }
+
+    fn snapshot_stats(&self) -> StatsSnapshot {
+        self.totals
     }
 
     fn dim(&self) -> usize {
         self.potential.dim()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::{
+        cpu_potential::EuclideanPotential, cpu_sampler::test_logps::NormalLogp, cpu_state,
+        mass_matrix::DiagMassMatrix,
+    };
+
+    #[derive(Default)]
+    struct DrawCounter {
+        draws: u64,
+        leapfrogs: u64,
+        last_info: Option<(u64, u64, bool)>,
+        last_step_size: Option<f64>,
+    }
+
+    impl Collector for DrawCounter {
+        type State = cpu_state::State;
+
+        fn register_leapfrog(
+            &mut self,
+            _start: &Self::State,
+            _end: &Self::State,
+            _divergence_info: Option<&dyn DivergenceInfo>,
+        ) {
+            self.leapfrogs += 1;
+        }
+
+        fn register_draw(
+            &mut self,
+            _state: &Self::State,
+            info: &SampleInfo,
+            potential_stats: &dyn AsSampleStatVec,
+        ) {
+            self.draws += 1;
+            self.last_info = Some((info.depth, info.n_leapfrog, info.reached_maxdepth));
+            let mut stats = vec![];
+            potential_stats.add_to_vec(&mut stats);
+            self.last_step_size = stats.into_iter().find_map(|(key, val)| match val {
+                SampleStatValue::F64(val) if key == "step_size" => Some(val),
+                _ => None,
+            });
+        }
+    }
+
+    #[test]
+    fn invariant_violations() {
+        type Tree = NutsTree<EuclideanPotential<NormalLogp, DiagMassMatrix>, DrawCounter>;
+
+        let mut mass_matrix = DiagMassMatrix::new(3);
+        mass_matrix.update_diag(std::iter::repeat(1f64));
+        let mut potential =
+            EuclideanPotential::new(NormalLogp::new(3, 0.), mass_matrix, 1000f64, 0.1);
+        let mut pool = potential.new_pool(10);
+        let mut state = potential.init_state(&mut pool, &[0.5; 3]).unwrap();
+        potential.set_momentum(&mut state, &[1., -1., 0.5]);
+        let mut tree = Tree::new(state, &mut BufferPool::default(), None);
+        assert!(tree.check_invariants().is_ok());
+
+        tree.p_sum[1] += 1e-3;
+        assert!(matches!(
+            tree.check_invariants(),
+            Err(NutsError::InvariantViolation(_))
+        ));
+        tree.p_sum[1] -= 1e-3;
+
+        // A single point is not a tree of depth one
+        tree.depth = 1;
+        assert!(matches!(
+            tree.check_invariants(),
+            Err(NutsError::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn register_draw_at_maxdepth() {
+        let ndim = 10;
+        let mut mass_matrix = DiagMassMatrix::new(ndim);
+        mass_matrix.update_diag(std::iter::repeat(1f64));
+        let mut potential =
+            EuclideanPotential::new(NormalLogp::new(ndim, 0.), mass_matrix, 1000f64, 0.01);
+        let mut pool = potential.new_pool(10);
+        let mut init = potential.init_state(&mut pool, &[0.5; 10]).unwrap();
+        let mut rng = RngStreams::<rand::rngs::StdRng>::seed_from_u64(42);
+        let options = NutsOptions {
+            maxdepth: 3,
+            store_gradient: false,
+            check_invariants: true,
+            num_trajectories: 1,
+            step_size_jitter: 0f64,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
+            termination: None,
+            merge_audit: None,
+        };
+        let mut collector = DrawCounter::default();
+
+        for i in 1..=5 {
+            let (state, info) = draw(
+                &mut pool,
+                &mut BufferPool::default(),
+                &mut init,
+                &mut rng,
+                &mut potential,
+                &options,
+                &mut collector,
+                None,
+                &mut None,
+            )
+            .unwrap();
+            // The step size is too small to turn within 2^3 steps
+            assert!(info.reached_maxdepth);
+            assert_eq!(info.depth, 3);
+            assert_eq!(info.n_leapfrog, 7);
+            assert_eq!(info.n_leapfrog_discarded(), 0);
+            assert!(info.log_size.is_finite());
+            assert_eq!(collector.draws, i);
+            assert_eq!(collector.last_info, Some((3, 7, true)));
+            assert_eq!(collector.last_step_size, Some(0.01));
+            init = state;
+        }
+        assert_eq!(collector.leapfrogs, 5 * 7);
+    }
+    #[test]
+    fn independent_rng_streams() {
+        let ndim = 10;
+        let mut mass_matrix = DiagMassMatrix::new(ndim);
+        mass_matrix.update_diag(std::iter::repeat(1f64));
+        let mut potential =
+            EuclideanPotential::new(NormalLogp::new(ndim, 0.), mass_matrix, 1000f64, 0.3);
+        let mut pool = potential.new_pool(20);
+        let options = NutsOptions {
+            maxdepth: 10,
+            store_gradient: false,
+            check_invariants: true,
+            num_trajectories: 1,
+            step_size_jitter: 0f64,
+            strict_reproducibility: false,
+            reproducible_sums: false,
+            gumbel_selection: false,
+            termination: None,
+            merge_audit: None,
+        };
+        let momentum: Vec<f64> = (0..10).map(|i| i as f64 / 5. - 1.).collect();
+
+        type Streams = RngStreams<rand::rngs::StdRng>;
+        let mut run = |rng: &mut Streams| {
+            let mut init = potential.init_state(&mut pool, &[0.5; 10]).unwrap();
+            let (state, info) = draw(
+                &mut pool,
+                &mut BufferPool::default(),
+                &mut init,
+                rng,
+                &mut potential,
+                &options,
+                &mut DrawCounter::default(),
+                Some(&momentum),
+                &mut None,
+            )
+            .unwrap();
+            (state.q.clone(), info.depth, info.n_leapfrog)
+        };
+
+        let mut rng = Streams::seed_from_u64(42);
+        let expected = run(&mut rng);
+        // Using the momentum stream does not change directions and proposals
+        let mut rng = Streams::seed_from_u64(42);
+        for _ in 0..10 {
+            rng.momentum.gen::<f64>();
+        }
+        assert_eq!(run(&mut rng), expected);
+        // The direction stream does
+        assert!((0..10).any(|seed| {
+            let mut rng = Streams::seed_from_u64(42);
+            rng.direction = rand::SeedableRng::seed_from_u64(seed);
+            run(&mut rng) != expected
+        }));
+    }
+}
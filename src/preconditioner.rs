@@ -0,0 +1,316 @@
+use crate::{
+    cpu_potential::CpuLogpFunc,
+    nuts::{check_dim, NutsError},
+};
+
+/// A bijective map from the sampler space to the model space
+///
+/// Sampling a preconditioned density with [`Preconditioned`] lets NUTS work
+/// in a space where the posterior is closer to a standard normal, for
+/// example after fitting a normalizing flow (NeuTra). Draws have to be
+/// mapped back with [`Preconditioned::to_model_space`].
+pub trait Preconditioner {
+    /// The dimension of both spaces.
+    fn dim(&self) -> usize;
+
+    /// Map `z` in sampler space to `x` in model space and return the log of
+    /// the absolute determinant of the Jacobian `dx/dz` at `z`.
+    fn forward(&self, z: &[f64], x: &mut [f64]) -> f64;
+
+    /// Map `x` in model space back to `z` in sampler space.
+    fn inverse(&self, x: &[f64], z: &mut [f64]);
+
+    /// Compute the gradient with respect to `z` of `logp(forward(z)) + logdet(z)`.
+    ///
+    /// `grad_x` is the gradient of the model logp at `forward(z)`. The result is
+    /// the vector-Jacobian product `grad_x^T dx/dz` plus the gradient of the
+    /// log determinant, and is written to `grad_z`.
+    fn pullback(&self, z: &[f64], grad_x: &[f64], grad_z: &mut [f64]);
+}
+
+/// A logp function in sampler space, composed of a model logp function and a
+/// [`Preconditioner`]
+#[derive(Debug)]
+pub struct Preconditioned<F: CpuLogpFunc, P: Preconditioner> {
+    logp: F,
+    preconditioner: P,
+    position: Box<[f64]>,
+    grad: Box<[f64]>,
+}
+
+impl<F: CpuLogpFunc, P: Preconditioner> Preconditioned<F, P> {
+    /// Fails with [`NutsError::DimensionMismatch`]
+    /// if the preconditioner and the logp function have different dimensions.
+    pub fn new(logp: F, preconditioner: P) -> Result<Self, NutsError> {
+        let dim = logp.dim();
+        check_dim(dim, preconditioner.dim())?;
+        Ok(Self {
+            logp,
+            preconditioner,
+            position: vec![0f64; dim].into(),
+            grad: vec![0f64; dim].into(),
+        })
+    }
+
+    /// Map a draw in sampler space to the model space.
+    pub fn to_model_space(&self, z: &[f64], x: &mut [f64]) {
+        self.preconditioner.forward(z, x);
+    }
+
+    /// Map a position in model space, for example an initial point, to the
+    /// sampler space.
+    pub fn to_sampler_space(&self, x: &[f64], z: &mut [f64]) {
+        self.preconditioner.inverse(x, z);
+    }
+
+    pub fn preconditioner(&self) -> &P {
+        &self.preconditioner
+    }
+
+    pub fn into_inner(self) -> (F, P) {
+        (self.logp, self.preconditioner)
+    }
+}
+
+impl<F: CpuLogpFunc, P: Preconditioner> CpuLogpFunc for Preconditioned<F, P> {
+    type Err = F::Err;
+
+    fn dim(&self) -> usize {
+        self.logp.dim()
+    }
+
+    fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+        let logdet = self.preconditioner.forward(position, &mut self.position);
+        let logp = self.logp.logp(&self.position, &mut self.grad)?;
+        if logp == f64::NEG_INFINITY {
+            return Ok(logp);
+        }
+        self.preconditioner.pullback(position, &self.grad, grad);
+        Ok(logp + logdet)
+    }
+}
+
+/// A reordering of the dimensions of a model
+///
+/// Coordinate `i` of the sampler is coordinate `order[i]` of the model.
+/// For models with a known block structure, sampling
+/// `Preconditioned<F, Permutation>` with an order that groups interacting
+/// coordinates can improve the memory access pattern of a dense mass
+/// matrix, which works in sampler coordinates. The logp function still sees
+/// the model order, so it does not benefit, and each evaluation pays for
+/// copying the position and the gradient. The map preserves volume, so the
+/// density is unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permutation {
+    order: Box<[usize]>,
+    inverse: Box<[usize]>,
+}
+
+impl Permutation {
+    /// Fails with [`NutsError::InvalidSettings`] if `order` does not contain
+    /// each of `0..order.len()` exactly once.
+    pub fn new(order: &[usize]) -> Result<Self, NutsError> {
+        let mut inverse = vec![usize::MAX; order.len()];
+        for (i, &model_idx) in order.iter().enumerate() {
+            if inverse.get(model_idx) != Some(&usize::MAX) {
+                return Err(NutsError::InvalidSettings(format!(
+                    "Not a permutation: {:?}",
+                    order
+                )));
+            }
+            inverse[model_idx] = i;
+        }
+        Ok(Self {
+            order: order.into(),
+            inverse: inverse.into(),
+        })
+    }
+
+    /// The model coordinate of each sampler coordinate
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+}
+
+impl Preconditioner for Permutation {
+    fn dim(&self) -> usize {
+        self.order.len()
+    }
+
+    fn forward(&self, z: &[f64], x: &mut [f64]) -> f64 {
+        self.order
+            .iter()
+            .zip(z.iter())
+            .for_each(|(&idx, &val)| x[idx] = val);
+        0f64
+    }
+
+    fn inverse(&self, x: &[f64], z: &mut [f64]) {
+        self.inverse
+            .iter()
+            .zip(x.iter())
+            .for_each(|(&idx, &val)| z[idx] = val);
+    }
+
+    fn pullback(&self, _z: &[f64], grad_x: &[f64], grad_z: &mut [f64]) {
+        grad_z
+            .iter_mut()
+            .zip(self.order.iter())
+            .for_each(|(grad, &idx)| *grad = grad_x[idx]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        new_sampler,
+        test_logps::{sample_moments, NormalLogp},
+        NutsError, SamplerArgs,
+    };
+    use approx::assert_abs_diff_eq;
+
+    /// `x = mu + exp(log_sigma) * z`, elementwise
+    struct Affine {
+        mu: f64,
+        log_sigma: f64,
+        dim: usize,
+    }
+
+    impl Preconditioner for Affine {
+        fn dim(&self) -> usize {
+            self.dim
+        }
+
+        fn forward(&self, z: &[f64], x: &mut [f64]) -> f64 {
+            let sigma = self.log_sigma.exp();
+            x.iter_mut()
+                .zip(z.iter())
+                .for_each(|(x, z)| *x = self.mu + sigma * z);
+            self.log_sigma * self.dim as f64
+        }
+
+        fn inverse(&self, x: &[f64], z: &mut [f64]) {
+            let sigma = self.log_sigma.exp();
+            z.iter_mut()
+                .zip(x.iter())
+                .for_each(|(z, x)| *z = (x - self.mu) / sigma);
+        }
+
+        fn pullback(&self, _z: &[f64], grad_x: &[f64], grad_z: &mut [f64]) {
+            let sigma = self.log_sigma.exp();
+            grad_z
+                .iter_mut()
+                .zip(grad_x.iter())
+                .for_each(|(gz, gx)| *gz = sigma * gx);
+        }
+    }
+
+    #[test]
+    fn preconditioned_logp() {
+        let affine = Affine {
+            mu: 3.,
+            log_sigma: 0.5,
+            dim: 2,
+        };
+        let mut func = Preconditioned::new(NormalLogp::new(2, 3.), affine).unwrap();
+        let z = [0.3, -1.2];
+        let mut grad = [0f64; 2];
+        let logp = func.logp(&z, &mut grad).unwrap();
+
+        let mut x = [0f64; 2];
+        func.to_model_space(&z, &mut x);
+        let mut grad_x = [0f64; 2];
+        let model_logp = NormalLogp::new(2, 3.).logp(&x, &mut grad_x).unwrap();
+        assert_abs_diff_eq!(logp, model_logp + 1.);
+        assert_abs_diff_eq!(grad[0], 0.5f64.exp() * grad_x[0]);
+
+        let mut back = [0f64; 2];
+        func.to_sampler_space(&x, &mut back);
+        assert_abs_diff_eq!(back[0], z[0], epsilon = 1e-12);
+        assert_abs_diff_eq!(back[1], z[1], epsilon = 1e-12);
+    }
+
+    #[test]
+    fn sample_preconditioned() {
+        let affine = Affine {
+            mu: 3.,
+            log_sigma: 0.,
+            dim: 3,
+        };
+        let func = Preconditioned::new(NormalLogp::new(3, 3.), affine).unwrap();
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(func, settings, 0, 42);
+        let (means, _) = sample_moments(&mut sampler, &[0.; 3], 1000);
+        // The model is N(3, 1), so the sampler space is a standard normal
+        assert!(means[0].abs() < 0.2, "mean {}", means[0]);
+
+        let affine = Affine {
+            mu: 0.,
+            log_sigma: 0.,
+            dim: 2,
+        };
+        let func = Preconditioned::new(NormalLogp::new(3, 0.), affine);
+        assert!(matches!(
+            func,
+            Err(NutsError::DimensionMismatch {
+                expected: 3,
+                got: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn permuted_sampling() {
+        use crate::test_logps::NormalLogpError;
+
+        // Independent normals with mean i in dimension i
+        struct Shifted {}
+
+        impl CpuLogpFunc for Shifted {
+            type Err = NormalLogpError;
+
+            fn dim(&self) -> usize {
+                3
+            }
+
+            fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, NormalLogpError> {
+                let mut logp = 0f64;
+                for (i, (x, g)) in position.iter().zip(grad.iter_mut()).enumerate() {
+                    *g = i as f64 - x;
+                    logp -= (x - i as f64).powi(2) / 2.;
+                }
+                Ok(logp)
+            }
+        }
+
+        for order in [&[0, 0, 1][..], &[0, 3, 1]] {
+            assert!(matches!(
+                Permutation::new(order),
+                Err(NutsError::InvalidSettings(_))
+            ));
+        }
+
+        let permutation = Permutation::new(&[2, 0, 1]).unwrap();
+        let func = Preconditioned::new(Shifted {}, permutation.clone()).unwrap();
+        let mut z = [0f64; 3];
+        func.to_sampler_space(&[0., 1., 2.], &mut z);
+        assert_eq!(z, [2., 0., 1.]);
+
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(func, settings, 0, 42);
+        let (sampler_means, _) = sample_moments(&mut sampler, &z, 1000);
+        // The permutation is linear, so it maps means to means
+        let mut means = [0f64; 3];
+        permutation.forward(&sampler_means, &mut means);
+        for (i, mean) in means.iter().enumerate() {
+            assert!((mean - i as f64).abs() < 0.2, "means {:?}", means);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{cpu_potential::CpuLogpFunc, nuts::NutsError};
+
+/// How the scale of a [`NonCenteredGroup`] is stored in the position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleParam {
+    /// The position contains the logarithm of the scale
+    Log,
+    /// The position contains the scale itself, which must be positive
+    Positive,
+}
+
+/// Latent variables with `latent[i] ~ N(location, scale)` in a centered
+/// model, see [`NonCenteredAdapter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonCenteredGroup {
+    /// The index of the location in the position
+    pub location: usize,
+    /// The index of the scale in the position
+    pub scale: usize,
+    pub scale_param: ScaleParam,
+    /// The indices of the latent variables in the position
+    pub latent: Vec<usize>,
+}
+
+impl NonCenteredGroup {
+    fn scale(&self, position: &[f64]) -> f64 {
+        match self.scale_param {
+            ScaleParam::Log => position[self.scale].exp(),
+            ScaleParam::Positive => position[self.scale],
+        }
+    }
+}
+
+/// Sample a hierarchical model in the non-centered parametrization
+///
+/// Centered hierarchical models, where latent variables are drawn from a
+/// normal distribution whose scale is a parameter, have a funnel shaped
+/// posterior if the data is weak. This is the most common cause of
+/// divergences. The adapter wraps the logp function of the centered model
+/// and samples standardized latent variables `z` instead, and passes
+/// `theta = location + scale * z` to the wrapped function. The gradient
+/// and the jacobian of the transformation are computed automatically.
+///
+/// The position has the same layout as in the centered model, only the
+/// latent entries contain `z`. Use [`to_centered`](Self::to_centered) to
+/// transform draws back, and [`to_non_centered`](Self::to_non_centered)
+/// for initial points.
+#[derive(Debug)]
+pub struct NonCenteredAdapter<F: CpuLogpFunc> {
+    logp: F,
+    groups: Vec<NonCenteredGroup>,
+    centered: Box<[f64]>,
+    grad: Box<[f64]>,
+}
+
+impl<F: CpuLogpFunc> NonCenteredAdapter<F> {
+    /// Wrap the centered logp function `logp`.
+    ///
+    /// Latent variables must not be used as the location or scale of a
+    /// group, or appear in more than one group. Otherwise, or if an index is
+    /// out of bounds, this fails with [`NutsError::InvalidSettings`].
+    pub fn new(logp: F, groups: Vec<NonCenteredGroup>) -> Result<Self, NutsError> {
+        let dim = logp.dim();
+        let mut is_latent = vec![false; dim];
+        for group in groups.iter() {
+            for &idx in group.latent.iter() {
+                if idx >= dim {
+                    return Err(NutsError::InvalidSettings(format!(
+                        "Latent index {} out of bounds",
+                        idx
+                    )));
+                }
+                if is_latent[idx] {
+                    return Err(NutsError::InvalidSettings(format!(
+                        "Latent index {} used twice",
+                        idx
+                    )));
+                }
+                is_latent[idx] = true;
+            }
+        }
+        for group in groups.iter() {
+            if (group.location >= dim) | (group.scale >= dim) {
+                return Err(NutsError::InvalidSettings(
+                    "Location or scale index out of bounds".to_string(),
+                ));
+            }
+            if is_latent[group.location] | is_latent[group.scale] {
+                return Err(NutsError::InvalidSettings(
+                    "Location and scale must not be latent variables".to_string(),
+                ));
+            }
+        }
+        Ok(NonCenteredAdapter {
+            logp,
+            groups,
+            centered: vec![0f64; dim].into(),
+            grad: vec![0f64; dim].into(),
+        })
+    }
+
+    /// Transform a position of the non-centered model to the centered model.
+    pub fn to_centered(&self, position: &[f64]) -> Box<[f64]> {
+        let mut out: Box<[f64]> = position.into();
+        for group in self.groups.iter() {
+            let location = position[group.location];
+            let scale = group.scale(position);
+            for &idx in group.latent.iter() {
+                out[idx] = location + scale * position[idx];
+            }
+        }
+        out
+    }
+
+    /// Transform a position of the centered model to the non-centered model.
+    pub fn to_non_centered(&self, position: &[f64]) -> Box<[f64]> {
+        let mut out: Box<[f64]> = position.into();
+        for group in self.groups.iter() {
+            let location = position[group.location];
+            let scale = group.scale(position);
+            for &idx in group.latent.iter() {
+                out[idx] = (position[idx] - location) / scale;
+            }
+        }
+        out
+    }
+
+    pub fn into_inner(self) -> F {
+        self.logp
+    }
+}
+
+impl<F: CpuLogpFunc> CpuLogpFunc for NonCenteredAdapter<F> {
+    type Err = F::Err;
+
+    fn dim(&self) -> usize {
+        self.logp.dim()
+    }
+
+    fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+        self.centered.copy_from_slice(position);
+        for group in self.groups.iter() {
+            let scale = group.scale(position);
+            if (scale <= 0f64) | scale.is_nan() {
+                return Ok(f64::NEG_INFINITY);
+            }
+            let location = position[group.location];
+            for &idx in group.latent.iter() {
+                self.centered[idx] = location + scale * position[idx];
+            }
+        }
+
+        let mut logp = self.logp.logp(&self.centered, &mut self.grad)?;
+        grad.copy_from_slice(&self.grad);
+        for group in self.groups.iter() {
+            let scale = group.scale(position);
+            // The derivatives of scale and of ln(scale) with respect to the
+            // entry in the position
+            let (scale_diff, log_scale_diff) = match group.scale_param {
+                ScaleParam::Log => (scale, 1f64),
+                ScaleParam::Positive => (1f64, 1f64 / scale),
+            };
+            for &idx in group.latent.iter() {
+                let grad_centered = self.grad[idx];
+                grad[idx] = grad_centered * scale;
+                grad[group.location] += grad_centered;
+                grad[group.scale] += grad_centered * position[idx] * scale_diff;
+            }
+            let num_latent = group.latent.len() as f64;
+            logp += num_latent * scale.ln();
+            grad[group.scale] += num_latent * log_scale_diff;
+        }
+        Ok(logp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_sequentially, test_logps::NormalLogpError, SampleStats, SamplerArgs};
+
+    const Y: [f64; 8] = [28., 8., -3., 7., -1., 1., 18., 12.];
+    const SIGMA: [f64; 8] = [15., 10., 16., 11., 9., 11., 10., 18.];
+
+    /// The centered eight schools model with position `[mu, ln(tau), theta...]`
+    struct EightSchools {}
+
+    impl CpuLogpFunc for EightSchools {
+        type Err = NormalLogpError;
+
+        fn dim(&self) -> usize {
+            10
+        }
+
+        fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+            let mu = position[0];
+            let log_tau = position[1];
+            let tau = log_tau.exp();
+            // mu ~ N(0, 5), tau ~ HalfCauchy(5) and the jacobian of exp
+            let mut logp = -mu * mu / 50. - (tau * tau / 25.).ln_1p() + log_tau;
+            grad[0] = -mu / 25.;
+            grad[1] = -2. * tau * tau / (25. + tau * tau) + 1.;
+            for i in 0..8 {
+                let theta = position[i + 2];
+                let diff = (theta - mu) / tau;
+                let resid = (Y[i] - theta) / SIGMA[i];
+                logp += -log_tau - diff * diff / 2. - resid * resid / 2.;
+                grad[0] += diff / tau;
+                grad[1] += -1. + diff * diff;
+                grad[i + 2] = -diff / tau + resid / SIGMA[i];
+            }
+            Ok(logp)
+        }
+    }
+
+    fn group() -> NonCenteredGroup {
+        NonCenteredGroup {
+            location: 0,
+            scale: 1,
+            scale_param: ScaleParam::Log,
+            latent: (2..10).collect(),
+        }
+    }
+
+    #[test]
+    fn non_centered_gradient() {
+        let mut adapter = NonCenteredAdapter::new(EightSchools {}, vec![group()]).unwrap();
+        let position: Vec<f64> = (0..10).map(|i| (i as f64 * 1.3).sin()).collect();
+        let centered = adapter.to_centered(&position);
+        let back = adapter.to_non_centered(&centered);
+        for (a, b) in position.iter().zip(back.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+
+        let mut grad = vec![0f64; 10];
+        let logp = adapter.logp(&position, &mut grad).unwrap();
+        for i in 0..10 {
+            let mut shifted = position.clone();
+            shifted[i] += 1e-7;
+            let logp_shifted = adapter.logp(&shifted, &mut [0f64; 10]).unwrap();
+            let numeric = (logp_shifted - logp) / 1e-7;
+            assert!(
+                (numeric - grad[i]).abs() < 1e-4,
+                "{}: {} {}",
+                i,
+                numeric,
+                grad[i]
+            );
+        }
+
+        let positive = NonCenteredGroup {
+            scale_param: ScaleParam::Positive,
+            ..group()
+        };
+        let mut adapter = NonCenteredAdapter::new(EightSchools {}, vec![positive]).unwrap();
+        let mut position = position;
+        position[1] = -0.5;
+        let logp = adapter.logp(&position, &mut grad).unwrap();
+        assert_eq!(logp, f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn invalid_groups() {
+        let invalid = [
+            vec![NonCenteredGroup {
+                latent: vec![2, 10],
+                ..group()
+            }],
+            vec![group(), group()],
+            vec![NonCenteredGroup {
+                scale: 10,
+                ..group()
+            }],
+            vec![NonCenteredGroup {
+                location: 2,
+                ..group()
+            }],
+        ];
+        for groups in invalid {
+            let adapter = NonCenteredAdapter::new(EightSchools {}, groups);
+            assert!(matches!(adapter, Err(NutsError::InvalidSettings(_))));
+        }
+    }
+
+    fn count_divergences<F: CpuLogpFunc>(logp: F) -> usize {
+        let settings = SamplerArgs {
+            num_tune: 500,
+            ..Default::default()
+        };
+        sample_sequentially(logp, settings, &[0.; 10], 1500, 0, 42)
+            .unwrap()
+            .skip(500)
+            .filter(|draw| draw.as_ref().unwrap().1.divergence_info().is_some())
+            .count()
+    }
+
+    #[test]
+    fn eight_schools_divergences() {
+        let centered = count_divergences(EightSchools {});
+        let adapter = NonCenteredAdapter::new(EightSchools {}, vec![group()]).unwrap();
+        let non_centered = count_divergences(adapter);
+        assert!(centered > 0);
+        assert!(non_centered < centered, "{} {}", non_centered, centered);
+    }
+}
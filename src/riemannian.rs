@@ -0,0 +1,1053 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::adapt_strategy::{DualAverageSettings, DualAverageStats, DualAverageStrategy};
+use crate::cpu_potential::{CpuLogpFunc, DivergenceInfoImpl};
+use crate::cpu_state::{State, StatePool};
+use crate::mass_matrix::LOG_NORM;
+use crate::math::{fill_normal, portable_normal};
+use crate::nuts::{
+    check_dim, check_inverse_temperature, AdaptStrategy, AsSampleStatVec, Collector, Direction,
+    Hamiltonian, HamiltonianSnapshot, LogpError, NutsError, NutsOptions, SampleStatItem, StatField,
+    StepSizeFn,
+};
+use crate::stepsize::AcceptanceRateCollector;
+
+/// A logp function with second and third derivatives, for
+/// [`new_softabs_sampler`](crate::new_softabs_sampler).
+pub trait CpuLogpHessianFunc: CpuLogpFunc {
+    /// Compute the logp and its gradient like [`CpuLogpFunc::logp`], and the
+    /// derivatives of the gradient and of the hessian.
+    ///
+    /// `hessian[i * dim + j]` is the second derivative of the logp with
+    /// respect to positions `i` and `j`, and
+    /// `hessian_grad[(k * dim + i) * dim + j]` is the derivative of
+    /// `hessian[i * dim + j]` with respect to position `k`.
+    fn logp_hessian(
+        &mut self,
+        position: &[f64],
+        grad: &mut [f64],
+        hessian: &mut [f64],
+        hessian_grad: &mut [f64],
+    ) -> Result<f64, Self::Err>;
+}
+
+/// Settings for the Riemannian sampler of
+/// [`new_softabs_sampler`](crate::new_softabs_sampler).
+#[derive(Debug, Clone, Copy)]
+pub struct SoftAbsSettings {
+    /// The SoftAbs metric maps each eigenvalue `l` of the hessian of the
+    /// negative logp to `l / tanh(alpha * l)`. Large eigenvalues keep their
+    /// absolute value, and eigenvalues close to zero are replaced by
+    /// `1 / alpha`, so that the metric is positive definite everywhere.
+    pub alpha: f64,
+    /// The maximum number of fixed point iterations in each implicit update
+    /// of the generalized leapfrog integrator. Leapfrog steps that do not
+    /// converge are counted in the `fixed_point_failures` sample stat.
+    pub max_fixed_point_steps: u64,
+    /// The fixed point iterations stop when no entry changes by more than
+    /// `fixed_point_tol * (1 + |x|)`.
+    pub fixed_point_tol: f64,
+    /// Treat leapfrog steps whose fixed point iterations do not converge as
+    /// divergences. Such steps are not reversible, so without this the
+    /// draws are only approximate posterior samples, even if the energy
+    /// error is small.
+    pub diverge_on_fixed_point_failure: bool,
+}
+
+impl Default for SoftAbsSettings {
+    fn default() -> Self {
+        Self {
+            alpha: 1f64,
+            max_fixed_point_steps: 50,
+            fixed_point_tol: 1e-8,
+            diverge_on_fixed_point_failure: true,
+        }
+    }
+}
+
+/// The number of recently evaluated positions whose geometry is kept
+const CACHE_SIZE: usize = 4;
+
+/// The eigenvalues and eigenvectors of a symmetric `dim x dim` matrix in
+/// row-major order, using cyclic Jacobi rotations.
+///
+/// Eigenvector `i` is column `i` of the returned matrix.
+fn symmetric_eigen(mut matrix: Vec<f64>, dim: usize) -> (Box<[f64]>, Box<[f64]>) {
+    let mut vectors = vec![0f64; dim * dim];
+    (0..dim).for_each(|i| vectors[i * dim + i] = 1f64);
+    let norm: f64 = matrix.iter().map(|val| val * val).sum();
+    for _ in 0..100 {
+        let off_diag: f64 = (0..dim)
+            .flat_map(|i| (0..dim).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| matrix[i * dim + j] * matrix[i * dim + j])
+            .sum();
+        // Also stop for a matrix with nan entries
+        if (off_diag <= 1e-30 * norm) | off_diag.is_nan() {
+            break;
+        }
+        for p in 0..dim {
+            for q in p + 1..dim {
+                let apq = matrix[p * dim + q];
+                if apq == 0f64 {
+                    continue;
+                }
+                let theta = (matrix[q * dim + q] - matrix[p * dim + p]) / (2f64 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1f64).sqrt());
+                let c = 1f64 / (t * t + 1f64).sqrt();
+                let s = t * c;
+                for k in 0..dim {
+                    let (kp, kq) = (matrix[k * dim + p], matrix[k * dim + q]);
+                    matrix[k * dim + p] = c * kp - s * kq;
+                    matrix[k * dim + q] = s * kp + c * kq;
+                }
+                for k in 0..dim {
+                    let (pk, qk) = (matrix[p * dim + k], matrix[q * dim + k]);
+                    matrix[p * dim + k] = c * pk - s * qk;
+                    matrix[q * dim + k] = s * pk + c * qk;
+                }
+                for k in 0..dim {
+                    let (kp, kq) = (vectors[k * dim + p], vectors[k * dim + q]);
+                    vectors[k * dim + p] = c * kp - s * kq;
+                    vectors[k * dim + q] = s * kp + c * kq;
+                }
+            }
+        }
+    }
+    let values = (0..dim).map(|i| matrix[i * dim + i]).collect();
+    (values, vectors.into())
+}
+
+/// Compute `Q X Q^T` for a `dim x dim` matrix `X` and the eigenvectors `Q`.
+fn rotate_back(vectors: &[f64], x: &[f64], dim: usize) -> Vec<f64> {
+    let mut qx = vec![0f64; dim * dim];
+    for a in 0..dim {
+        for j in 0..dim {
+            qx[a * dim + j] = (0..dim)
+                .map(|i| vectors[a * dim + i] * x[i * dim + j])
+                .sum();
+        }
+    }
+    let mut out = vec![0f64; dim * dim];
+    for a in 0..dim {
+        for b in 0..dim {
+            out[a * dim + b] = (0..dim)
+                .map(|j| qx[a * dim + j] * vectors[b * dim + j])
+                .sum();
+        }
+    }
+    out
+}
+
+/// The largest relative change between two iterates, infinite if there is
+/// a nan.
+fn max_change(new: &[f64], old: &[f64]) -> f64 {
+    new.iter().zip(old).fold(0f64, |max, (new, old)| {
+        let change = (new - old).abs() / (1f64 + old.abs());
+        if change.is_nan() {
+            f64::INFINITY
+        } else {
+            max.max(change)
+        }
+    })
+}
+
+/// The SoftAbs map of the eigenvalues of the hessian of the potential to
+/// the eigenvalues of the metric
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SoftAbsMetric {
+    alpha: f64,
+}
+
+impl SoftAbsMetric {
+    pub(crate) fn new(alpha: f64) -> Result<Self, NutsError> {
+        if !(alpha.is_finite() & (alpha > 0f64)) {
+            return Err(NutsError::InvalidSettings(format!(
+                "SoftAbs alpha must be positive and finite, but is {}",
+                alpha
+            )));
+        }
+        Ok(SoftAbsMetric { alpha })
+    }
+
+    /// `l / tanh(alpha * l)`
+    fn map(&self, lambda: f64) -> f64 {
+        let x = self.alpha * lambda;
+        if x.abs() < 1e-3 {
+            // The series of x / tanh(x)
+            (1f64 + x * x / 3f64 - x * x * x * x / 45f64) / self.alpha
+        } else {
+            lambda / x.tanh()
+        }
+    }
+
+    /// The derivative of [`Self::map`]
+    fn map_derivative(&self, lambda: f64) -> f64 {
+        let x = self.alpha * lambda;
+        if x.abs() < 1e-3 {
+            2f64 * x / 3f64 - 4f64 * x * x * x / 45f64
+        } else {
+            let sinh = x.sinh();
+            1f64 / x.tanh() - x / (sinh * sinh)
+        }
+    }
+
+    /// `(map(a) - map(b)) / (a - b)`, the derivative if they are close
+    fn divided_difference(&self, a: f64, b: f64) -> f64 {
+        let scale = a.abs().max(b.abs()).max(1f64 / self.alpha);
+        if (a - b).abs() <= 1e-6 * scale {
+            self.map_derivative((a + b) / 2f64)
+        } else {
+            (self.map(a) - self.map(b)) / (a - b)
+        }
+    }
+
+    /// The metric at `q` from the derivatives of the potential there.
+    fn geometry(
+        &self,
+        q: &[f64],
+        logp: f64,
+        grad: Box<[f64]>,
+        hessian: Vec<f64>,
+        hessian_grad: Box<[f64]>,
+    ) -> Geometry {
+        let dim = q.len();
+        let (lambda, vectors) = symmetric_eigen(hessian, dim);
+        let eigenvalues: Box<[f64]> = lambda.iter().map(|&val| self.map(val)).collect();
+        let divided_diffs: Box<[f64]> = (0..dim)
+            .flat_map(|i| (0..dim).map(move |j| (i, j)))
+            .map(|(i, j)| self.divided_difference(lambda[i], lambda[j]))
+            .collect();
+
+        // tr(G^-1 dG_k) = sum_ab M_ab dH_kab with M = Q diag(J_ii / g_i) Q^T
+        let mut weights = vec![0f64; dim * dim];
+        (0..dim).for_each(|i| weights[i * dim + i] = divided_diffs[i * dim + i] / eigenvalues[i]);
+        let trace_weights = rotate_back(&vectors, &weights, dim);
+        let static_grad = (0..dim)
+            .map(|k| {
+                let dhess = &hessian_grad[k * dim * dim..(k + 1) * dim * dim];
+                let trace: f64 = trace_weights.iter().zip(dhess).map(|(m, d)| m * d).sum();
+                -grad[k] + trace / 2f64
+            })
+            .collect();
+        let log_det = eigenvalues.iter().map(|val| val.ln()).sum();
+
+        Geometry {
+            q: q.into(),
+            logp,
+            grad,
+            vectors,
+            eigenvalues,
+            divided_diffs,
+            hessian_grad,
+            static_grad,
+            log_det,
+        }
+    }
+}
+
+/// The parts of the hamiltonian at a position that do not depend on the
+/// momentum
+///
+/// The metric is `G = Q diag(g) Q^T`, where the columns of `Q` are the
+/// eigenvectors of the hessian `H` of the potential, and `g` are the SoftAbs
+/// eigenvalues. Its derivative is `dG_k = Q (J o (Q^T dH_k Q)) Q^T` with the
+/// divided differences `J` of the SoftAbs map at the eigenvalues of `H`.
+struct Geometry {
+    q: Box<[f64]>,
+    /// The logp, multiplied by the inverse temperature
+    logp: f64,
+    grad: Box<[f64]>,
+    vectors: Box<[f64]>,
+    eigenvalues: Box<[f64]>,
+    divided_diffs: Box<[f64]>,
+    /// The derivatives of the hessian of the potential
+    hessian_grad: Box<[f64]>,
+    /// The part of the derivative of the hamiltonian with respect to the
+    /// position that does not depend on the momentum,
+    /// `-grad + tr(G^-1 dG_k) / 2`
+    static_grad: Box<[f64]>,
+    /// `log det G`
+    log_det: f64,
+}
+
+impl Geometry {
+    fn dim(&self) -> usize {
+        self.q.len()
+    }
+
+    /// `diag(g)^-1 Q^T p`
+    fn scaled_momentum(&self, p: &[f64]) -> Vec<f64> {
+        let dim = self.dim();
+        (0..dim)
+            .map(|i| {
+                let dot: f64 = (0..dim).map(|a| self.vectors[a * dim + i] * p[a]).sum();
+                dot / self.eigenvalues[i]
+            })
+            .collect()
+    }
+
+    /// `G^-1 p`
+    fn velocity(&self, p: &[f64], out: &mut [f64]) {
+        let dim = self.dim();
+        let scaled = self.scaled_momentum(p);
+        out.iter_mut().enumerate().for_each(|(a, out)| {
+            *out = (0..dim)
+                .map(|i| self.vectors[a * dim + i] * scaled[i])
+                .sum();
+        });
+    }
+
+    /// `p^T G^-1 p / 2 + log det G / 2`
+    fn kinetic_energy(&self, p: &[f64]) -> f64 {
+        let quad: f64 = self
+            .scaled_momentum(p)
+            .iter()
+            .zip(self.eigenvalues.iter())
+            .map(|(r, g)| r * r * g)
+            .sum();
+        (quad + self.log_det) / 2f64
+    }
+
+    /// The derivative of the hamiltonian with respect to the position,
+    /// `-grad + tr(G^-1 dG_k) / 2 - p^T G^-1 dG_k G^-1 p / 2`
+    fn hamiltonian_grad(&self, p: &[f64], out: &mut [f64]) {
+        let dim = self.dim();
+        let r = self.scaled_momentum(p);
+        let x: Vec<f64> = (0..dim)
+            .flat_map(|i| (0..dim).map(move |j| (i, j)))
+            .map(|(i, j)| self.divided_diffs[i * dim + j] * r[i] * r[j])
+            .collect();
+        let weights = rotate_back(&self.vectors, &x, dim);
+        out.iter_mut().enumerate().for_each(|(k, out)| {
+            let dhess = &self.hessian_grad[k * dim * dim..(k + 1) * dim * dim];
+            let quad: f64 = weights.iter().zip(dhess).map(|(w, d)| w * d).sum();
+            *out = self.static_grad[k] - quad / 2f64;
+        });
+    }
+
+    /// `Q diag(g)^(1/2) z`, which is distributed as `N(0, G)` for standard
+    /// normal `z`.
+    fn scale_normal(&self, z: &[f64], out: &mut [f64]) {
+        let dim = self.dim();
+        out.iter_mut().enumerate().for_each(|(a, out)| {
+            *out = (0..dim)
+                .map(|i| self.vectors[a * dim + i] * self.eigenvalues[i].sqrt() * z[i])
+                .sum();
+        });
+    }
+
+    /// The diagonal of `G^-1`
+    fn inverse_diag(&self, out: &mut [f64]) {
+        let dim = self.dim();
+        out.iter_mut().enumerate().for_each(|(a, out)| {
+            *out = (0..dim)
+                .map(|i| self.vectors[a * dim + i].powi(2) / self.eigenvalues[i])
+                .sum();
+        });
+    }
+}
+
+/// A hamiltonian with the position dependent SoftAbs metric, integrated with
+/// the implicit generalized leapfrog integrator
+///
+/// See Betancourt, "A General Metric for Riemannian Manifold Hamiltonian
+/// Monte Carlo" (<https://arxiv.org/abs/1212.4693>).
+pub(crate) struct SoftAbsPotential<F: CpuLogpHessianFunc> {
+    logp: RefCell<F>,
+    metric: SoftAbsMetric,
+    settings: SoftAbsSettings,
+    /// The geometry at recently evaluated positions, the latest first. Each
+    /// leapfrog step starts at a position that was evaluated before.
+    cache: RefCell<VecDeque<Rc<Geometry>>>,
+    /// The geometry at the current state of the chain
+    current: Option<Rc<Geometry>>,
+    /// The diagonal of the inverse metric at the current state of the chain
+    inverse_diag: Box<[f64]>,
+    max_energy_error: f64,
+    pub(crate) step_size: f64,
+    inverse_temperature: f64,
+    boundary_hits: u64,
+    fixed_point_failures: u64,
+    pub(crate) store_divergence_states: bool,
+    step_size_fn: Option<StepSizeFn>,
+    last_step_size: f64,
+    dim: usize,
+}
+
+/// The outcome of a generalized leapfrog step
+enum Step {
+    Done(State),
+    /// The fixed point iterations did not converge
+    NotConverged(State),
+    OutsideSupport(State),
+}
+
+impl<F: CpuLogpHessianFunc> SoftAbsPotential<F> {
+    /// Returns [`NutsError::InvalidSettings`] if `settings` are invalid.
+    pub(crate) fn new(
+        logp: F,
+        settings: SoftAbsSettings,
+        max_energy_error: f64,
+    ) -> Result<Self, NutsError> {
+        let valid_tol = settings.fixed_point_tol >= 0f64;
+        if !valid_tol | (settings.max_fixed_point_steps == 0) {
+            return Err(NutsError::InvalidSettings(format!(
+                "SoftAbs fixed point iterations need at least one step and a non-negative \
+                 tolerance, but have {} steps and tolerance {}",
+                settings.max_fixed_point_steps, settings.fixed_point_tol
+            )));
+        }
+        let dim = logp.dim();
+        Ok(SoftAbsPotential {
+            logp: RefCell::new(logp),
+            metric: SoftAbsMetric::new(settings.alpha)?,
+            settings,
+            cache: RefCell::new(VecDeque::with_capacity(CACHE_SIZE)),
+            current: None,
+            inverse_diag: vec![1f64; dim].into(),
+            max_energy_error,
+            step_size: 1f64,
+            last_step_size: 1f64,
+            inverse_temperature: 1f64,
+            boundary_hits: 0,
+            fixed_point_failures: 0,
+            store_divergence_states: true,
+            step_size_fn: None,
+            dim,
+        })
+    }
+
+    /// The geometry at `q`, or `None` if `q` is outside the support.
+    fn geometry(&self, q: &[f64]) -> Result<Option<Rc<Geometry>>, F::Err> {
+        if let Some(geometry) = self.cache.borrow().iter().find(|geom| *geom.q == *q) {
+            return Ok(Some(geometry.clone()));
+        }
+        let dim = self.dim;
+        let mut grad: Box<[f64]> = vec![0f64; dim].into();
+        let mut hessian = vec![0f64; dim * dim];
+        let mut hessian_grad: Box<[f64]> = vec![0f64; dim * dim * dim].into();
+        let logp =
+            self.logp
+                .borrow_mut()
+                .logp_hessian(q, &mut grad, &mut hessian, &mut hessian_grad)?;
+        if logp == f64::NEG_INFINITY {
+            return Ok(None);
+        }
+        // The metric uses the hessian of the potential, the negative logp
+        let beta = self.inverse_temperature;
+        grad.iter_mut().for_each(|val| *val *= beta);
+        hessian.iter_mut().for_each(|val| *val *= -beta);
+        hessian_grad.iter_mut().for_each(|val| *val *= -beta);
+        let geometry = Rc::new(
+            self.metric
+                .geometry(q, beta * logp, grad, hessian, hessian_grad),
+        );
+        let mut cache = self.cache.borrow_mut();
+        cache.truncate(CACHE_SIZE - 1);
+        cache.push_front(geometry.clone());
+        Ok(Some(geometry))
+    }
+
+    /// The geometry at a state that was evaluated before
+    fn state_geometry(&self, state: &State) -> Rc<Geometry> {
+        self.geometry(&state.q)
+            .ok()
+            .flatten()
+            .expect("Logp function failed at a position where it succeeded before")
+    }
+
+    /// Set everything but the momentum of `state` from `geometry`.
+    fn write_state(&self, state: &mut State, geometry: &Geometry) {
+        let inner = state.try_mut_inner().expect("State already in use");
+        inner.q.copy_from_slice(&geometry.q);
+        inner.grad.copy_from_slice(&geometry.grad);
+        inner.potential_energy = -geometry.logp;
+    }
+
+    /// Set the velocity and kinetic energy of `state` from its momentum.
+    fn update_kinetic(&self, state: &mut State, geometry: &Geometry) {
+        let inner = state.try_mut_inner().expect("State already in use");
+        geometry.velocity(&inner.p, &mut inner.v);
+        inner.kinetic_energy = geometry.kinetic_energy(&inner.p);
+    }
+
+    /// Integrate a generalized leapfrog step of size `epsilon` from `start`.
+    fn integrate(
+        &mut self,
+        pool: &mut StatePool,
+        start: &State,
+        epsilon: f64,
+    ) -> Result<Step, F::Err> {
+        let dim = self.dim;
+        let half = epsilon / 2f64;
+        let max_steps = self.settings.max_fixed_point_steps;
+        let tol = self.settings.fixed_point_tol;
+        let start_geometry = self.state_geometry(start);
+
+        // Implicit half step of the momentum
+        let mut p = start.p.to_vec();
+        let mut p_next = vec![0f64; dim];
+        let mut force = vec![0f64; dim];
+        let mut p_converged = false;
+        for _ in 0..max_steps {
+            start_geometry.hamiltonian_grad(&p, &mut force);
+            p_next
+                .iter_mut()
+                .zip(start.p.iter().zip(force.iter()))
+                .for_each(|(next, (p, force))| *next = p - half * force);
+            let change = max_change(&p_next, &p);
+            std::mem::swap(&mut p, &mut p_next);
+            if change <= tol {
+                p_converged = true;
+                break;
+            }
+        }
+
+        // Implicit full step of the position, with the mean of the
+        // velocities at the start and the end
+        let mut start_velocity = vec![0f64; dim];
+        start_geometry.velocity(&p, &mut start_velocity);
+        let mut q: Vec<f64> = start
+            .q
+            .iter()
+            .zip(start_velocity.iter())
+            .map(|(q, v)| q + epsilon * v)
+            .collect();
+        let mut q_next = vec![0f64; dim];
+        let mut velocity = vec![0f64; dim];
+        let mut q_converged = false;
+        for _ in 0..max_steps {
+            let Some(geometry) = self.geometry(&q)? else {
+                break;
+            };
+            geometry.velocity(&p, &mut velocity);
+            q_next
+                .iter_mut()
+                .zip(start.q.iter())
+                .zip(start_velocity.iter().zip(velocity.iter()))
+                .for_each(|((next, q), (v0, v1))| *next = q + half * (v0 + v1));
+            let change = max_change(&q_next, &q);
+            std::mem::swap(&mut q, &mut q_next);
+            if change <= tol {
+                q_converged = true;
+                break;
+            }
+        }
+        let converged = p_converged & q_converged;
+        if !converged {
+            self.fixed_point_failures += 1;
+        }
+
+        let mut out = pool.new_state();
+        let Some(geometry) = self.geometry(&q)? else {
+            let inner = out.try_mut_inner().expect("State already in use");
+            inner.q.copy_from_slice(&q);
+            inner.p.copy_from_slice(&p);
+            inner.grad.fill(0f64);
+            inner.potential_energy = f64::INFINITY;
+            inner.kinetic_energy = 0f64;
+            return Ok(Step::OutsideSupport(out));
+        };
+
+        // Explicit half step of the momentum
+        geometry.hamiltonian_grad(&p, &mut force);
+        p.iter_mut()
+            .zip(force.iter())
+            .for_each(|(p, force)| *p -= half * force);
+
+        self.write_state(&mut out, &geometry);
+        out.try_mut_inner()
+            .expect("State already in use")
+            .p
+            .copy_from_slice(&p);
+        self.update_kinetic(&mut out, &geometry);
+        if converged {
+            Ok(Step::Done(out))
+        } else {
+            Ok(Step::NotConverged(out))
+        }
+    }
+
+    fn divergence_info(
+        &self,
+        logp_function_error: Option<F::Err>,
+        start: &State,
+        end: Option<&State>,
+        energy_error: Option<f64>,
+        outside_support: bool,
+    ) -> DivergenceInfoImpl<F::Err> {
+        DivergenceInfoImpl::new(
+            logp_function_error,
+            start,
+            end,
+            energy_error,
+            outside_support,
+            self.store_divergence_states,
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SoftAbsStats {
+    step_size: f64,
+    inverse_temperature: f64,
+    boundary_hits: u64,
+    fixed_point_failures: u64,
+}
+
+impl AsSampleStatVec for SoftAbsStats {
+    fn add_to_vec(&self, vec: &mut Vec<SampleStatItem>) {
+        vec.push(("step_size", self.step_size.into()));
+        vec.push(("inverse_temperature", self.inverse_temperature.into()));
+        vec.push(("boundary_hits", self.boundary_hits.into()));
+        vec.push(("fixed_point_failures", self.fixed_point_failures.into()));
+    }
+}
+
+impl<F: CpuLogpHessianFunc> Hamiltonian for SoftAbsPotential<F> {
+    type State = State;
+    type DivergenceInfo = DivergenceInfoImpl<F::Err>;
+    type LogpError = F::Err;
+    type Stats = SoftAbsStats;
+
+    fn leapfrog<C: Collector<State = Self::State>>(
+        &mut self,
+        pool: &mut StatePool,
+        start: &Self::State,
+        dir: Direction,
+        initial_energy: f64,
+        collector: &mut C,
+    ) -> Result<Result<Self::State, Self::DivergenceInfo>, NutsError> {
+        let sign = match dir {
+            Direction::Forward => 1,
+            Direction::Backward => -1,
+        };
+
+        let mut epsilon = (sign as f64) * self.step_size;
+        if let Some(func) = self.step_size_fn.as_mut() {
+            let scale = func(&start.q);
+            if !(scale.is_finite() & (scale > 0f64)) {
+                return Err(NutsError::InvalidStepSizeMultiplier(scale));
+            }
+            epsilon *= scale;
+        }
+        self.last_step_size = epsilon.abs();
+
+        let diverge_on_failure = self.settings.diverge_on_fixed_point_failure;
+        let mut out = match self.integrate(pool, start, epsilon) {
+            Ok(Step::Done(out)) => out,
+            Ok(Step::NotConverged(out)) if !diverge_on_failure => out,
+            Ok(Step::NotConverged(out)) => {
+                let energy_error = {
+                    use crate::nuts::State;
+                    out.energy() - initial_energy
+                };
+                let div_info =
+                    self.divergence_info(None, start, Some(&out), Some(energy_error), false);
+                collector.register_leapfrog(start, &out, Some(&div_info));
+                return Ok(Err(div_info));
+            }
+            Ok(Step::OutsideSupport(out)) => {
+                self.boundary_hits += 1;
+                let div_info = self.divergence_info(None, start, Some(&out), None, true);
+                collector.register_leapfrog(start, &out, Some(&div_info));
+                return Ok(Err(div_info));
+            }
+            Err(logp_error) => {
+                if !logp_error.is_recoverable() {
+                    return Err(NutsError::LogpFailure(Box::new(logp_error)));
+                }
+                let div_info = self.divergence_info(Some(logp_error), start, None, None, false);
+                collector.register_leapfrog(start, start, Some(&div_info));
+                return Ok(Err(div_info));
+            }
+        };
+
+        *out.index_in_trajectory_mut() = start.index_in_trajectory() + sign;
+
+        let energy_error = {
+            use crate::nuts::State;
+            out.energy() - initial_energy
+        };
+        if (energy_error > self.max_energy_error) | !energy_error.is_finite() {
+            let divergence_info =
+                self.divergence_info(None, start, Some(&out), Some(energy_error), false);
+            collector.register_leapfrog(start, &out, Some(&divergence_info));
+            return Ok(Err(divergence_info));
+        }
+
+        collector.register_leapfrog(start, &out, None);
+
+        Ok(Ok(out))
+    }
+
+    fn init_state(&mut self, pool: &mut StatePool, init: &[f64]) -> Result<Self::State, NutsError> {
+        check_dim(self.dim(), init.len())?;
+        let geometry = self
+            .geometry(init)
+            .map_err(|err| NutsError::LogpFailure(Box::new(err)))?
+            .ok_or(NutsError::InitOutsideSupport)?;
+        let mut state = pool.new_state();
+        self.write_state(&mut state, &geometry);
+        Ok(state)
+    }
+
+    fn randomize_momentum<R: rand::Rng + ?Sized>(
+        &self,
+        state: &mut Self::State,
+        rng: &mut R,
+        portable: bool,
+    ) {
+        let geometry = self.state_geometry(state);
+        let mut z = vec![0f64; self.dim];
+        if portable {
+            z.iter_mut().for_each(|val| *val = portable_normal(rng));
+        } else {
+            fill_normal(rng, &mut z);
+        }
+        geometry.scale_normal(&z, &mut state.try_mut_inner().unwrap().p);
+        self.update_kinetic(state, &geometry);
+    }
+
+    /// This uses the metric at the current state of the chain, or the
+    /// identity before the position is set.
+    fn sample_momentum<R: rand::Rng + ?Sized>(&self, rng: &mut R, portable: bool, out: &mut [f64]) {
+        let mut z = vec![0f64; self.dim];
+        if portable {
+            z.iter_mut().for_each(|val| *val = portable_normal(rng));
+        } else {
+            fill_normal(rng, &mut z);
+        }
+        match self.current.as_ref() {
+            Some(geometry) => geometry.scale_normal(&z, out),
+            None => out.copy_from_slice(&z),
+        }
+    }
+
+    /// This uses the metric at the current state of the chain, or the
+    /// identity before the position is set.
+    fn logp_momentum(&self, momentum: &[f64]) -> f64 {
+        let norm = self.dim as f64 * LOG_NORM;
+        match self.current.as_ref() {
+            Some(geometry) => norm - geometry.kinetic_energy(momentum),
+            None => norm - momentum.iter().map(|p| p * p).sum::<f64>() / 2f64,
+        }
+    }
+
+    fn set_momentum(&self, state: &mut Self::State, momentum: &[f64]) {
+        let geometry = self.state_geometry(state);
+        state.try_mut_inner().unwrap().p.copy_from_slice(momentum);
+        self.update_kinetic(state, &geometry);
+    }
+
+    fn evaluate(
+        &mut self,
+        pool: &mut StatePool,
+        position: &[f64],
+        momentum: &[f64],
+    ) -> Result<HamiltonianSnapshot, NutsError> {
+        check_dim(self.dim(), momentum.len())?;
+        let mut state = self.init_state(pool, position)?;
+        self.set_momentum(&mut state, momentum);
+        Ok(HamiltonianSnapshot {
+            potential_energy: state.potential_energy,
+            kinetic_energy: state.kinetic_energy,
+            gradient: state.grad.clone(),
+            velocity: state.v.clone(),
+        })
+    }
+
+    fn current_stats(&self) -> Self::Stats {
+        SoftAbsStats {
+            step_size: self.step_size,
+            inverse_temperature: self.inverse_temperature,
+            boundary_hits: self.boundary_hits,
+            fixed_point_failures: self.fixed_point_failures,
+        }
+    }
+
+    fn divergence_stat_schema(&self) -> Vec<StatField> {
+        DivergenceInfoImpl::<F::Err>::stat_schema(self.dim(), self.store_divergence_states)
+    }
+
+    fn set_metric(&mut self, _variance: &[f64]) -> Result<(), NutsError> {
+        Err(NutsError::Unsupported("setting the metric"))
+    }
+
+    fn register_current_state(&mut self, state: &State) {
+        let geometry = self.state_geometry(state);
+        geometry.inverse_diag(&mut self.inverse_diag);
+        self.current = Some(geometry);
+    }
+
+    /// The diagonal of the inverse metric at the current state of the chain
+    fn metric(&self) -> &[f64] {
+        &self.inverse_diag
+    }
+
+    fn set_inverse_temperature(&mut self, inverse_temperature: f64) -> Result<(), NutsError> {
+        check_inverse_temperature(inverse_temperature)?;
+        self.inverse_temperature = inverse_temperature;
+        self.cache.borrow_mut().clear();
+        self.current = None;
+        Ok(())
+    }
+
+    fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    fn set_step_size(&mut self, step_size: f64) {
+        self.step_size = step_size;
+    }
+
+    fn last_step_size(&self) -> f64 {
+        self.last_step_size
+    }
+
+    fn set_step_size_fn(&mut self, func: Option<StepSizeFn>) {
+        self.step_size_fn = func;
+    }
+
+    fn new_empty_state(&mut self, pool: &mut StatePool) -> Self::State {
+        pool.new_state()
+    }
+
+    fn new_pool(&mut self, _capacity: usize) -> StatePool {
+        StatePool::new(self.dim())
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+}
+
+/// Dual averaging step size adaptation for [`SoftAbsPotential`]
+pub(crate) struct SoftAbsStepSizeAdapt<F> {
+    step_size_adapt: DualAverageStrategy<F, SoftAbsMetric>,
+}
+
+impl<F: CpuLogpHessianFunc> AdaptStrategy for SoftAbsStepSizeAdapt<F> {
+    type Potential = SoftAbsPotential<F>;
+    type Collector = AcceptanceRateCollector<State>;
+    type Stats = DualAverageStats;
+    type Options = DualAverageSettings;
+
+    fn new(options: Self::Options, num_tune: u64, _dim: usize) -> Self {
+        SoftAbsStepSizeAdapt {
+            step_size_adapt: DualAverageStrategy::with_options(options, num_tune),
+        }
+    }
+
+    fn init(&mut self, options: &mut NutsOptions, potential: &mut Self::Potential, _state: &State) {
+        self.step_size_adapt
+            .set_portable(options.strict_reproducibility);
+        potential.step_size = self.step_size_adapt.initial_step_size();
+    }
+
+    fn adapt(
+        &mut self,
+        _options: &mut NutsOptions,
+        potential: &mut Self::Potential,
+        draw: u64,
+        collector: &Self::Collector,
+    ) {
+        potential.step_size = self.step_size_adapt.next_step_size(draw, collector);
+    }
+
+    fn new_collector(&self) -> Self::Collector {
+        self.step_size_adapt.new_acceptance_collector()
+    }
+
+    fn current_stats(
+        &self,
+        _options: &NutsOptions,
+        _potential: &Self::Potential,
+        collector: &Self::Collector,
+    ) -> Self::Stats {
+        self.step_size_adapt.stats(collector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_logps::NormalLogpError;
+    use crate::{new_softabs_sampler, Chain, SampleStats, SamplerArgs};
+    use approx::assert_abs_diff_eq;
+
+    /// Neal's funnel with `v = position[0] ~ N(0, 3)` and the other
+    /// positions `x_i ~ N(0, exp(v / 2))`
+    struct Funnel {
+        dim: usize,
+    }
+
+    impl CpuLogpFunc for Funnel {
+        type Err = NormalLogpError;
+
+        fn dim(&self) -> usize {
+            self.dim
+        }
+
+        fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+            let v = position[0];
+            let scale = (-v).exp();
+            let sum_sq: f64 = position[1..].iter().map(|x| x * x).sum();
+            let k = (self.dim - 1) as f64;
+            grad[0] = -v / 9. + sum_sq * scale / 2. - k / 2.;
+            grad[1..]
+                .iter_mut()
+                .zip(position[1..].iter())
+                .for_each(|(grad, x)| *grad = -x * scale);
+            Ok(-v * v / 18. - sum_sq * scale / 2. - k * v / 2.)
+        }
+    }
+
+    impl CpuLogpHessianFunc for Funnel {
+        fn logp_hessian(
+            &mut self,
+            position: &[f64],
+            grad: &mut [f64],
+            hessian: &mut [f64],
+            hessian_grad: &mut [f64],
+        ) -> Result<f64, Self::Err> {
+            let dim = self.dim;
+            let logp = self.logp(position, grad)?;
+            let scale = (-position[0]).exp();
+            let sum_sq: f64 = position[1..].iter().map(|x| x * x).sum();
+            hessian.fill(0.);
+            hessian_grad.fill(0.);
+            let mut set_grad = |k: usize, i: usize, j: usize, val: f64| {
+                hessian_grad[(k * dim + i) * dim + j] = val;
+                hessian_grad[(k * dim + j) * dim + i] = val;
+            };
+            hessian[0] = -1. / 9. - sum_sq * scale / 2.;
+            set_grad(0, 0, 0, sum_sq * scale / 2.);
+            for i in 1..dim {
+                let x = position[i];
+                hessian[i] = x * scale;
+                hessian[i * dim] = x * scale;
+                hessian[i * dim + i] = -scale;
+                set_grad(i, 0, 0, -x * scale);
+                set_grad(0, 0, i, -x * scale);
+                set_grad(i, 0, i, scale);
+                set_grad(0, i, i, scale);
+            }
+            Ok(logp)
+        }
+    }
+
+    #[test]
+    fn eigen_decomposition() {
+        let matrix = vec![4., 1., -2., 1., 2., 0., -2., 0., 3.];
+        let (values, vectors) = symmetric_eigen(matrix.clone(), 3);
+        let mut diag = vec![0f64; 9];
+        (0..3).for_each(|i| diag[i * 3 + i] = values[i]);
+        let rebuilt = rotate_back(&vectors, &diag, 3);
+        for (a, b) in matrix.iter().zip(rebuilt.iter()) {
+            assert_abs_diff_eq!(a, b, epsilon = 1e-12);
+        }
+    }
+
+    #[test]
+    fn hamiltonian_finite_differences() {
+        let settings = SoftAbsSettings {
+            alpha: 2.,
+            ..Default::default()
+        };
+        let mut potential = SoftAbsPotential::new(Funnel { dim: 3 }, settings, 1000.).unwrap();
+        let mut pool = potential.new_pool(4);
+        let position = [0.4, -1., 0.3];
+        let momentum = [1., 0.2, -0.7];
+        let point = potential.evaluate(&mut pool, &position, &momentum).unwrap();
+        let mut hamiltonian_grad = [0f64; 3];
+        potential
+            .geometry(&position)
+            .unwrap()
+            .unwrap()
+            .hamiltonian_grad(&momentum, &mut hamiltonian_grad);
+
+        let h = 1e-5;
+        for i in 0..3 {
+            let mut energy = |delta: f64, shift_position: bool| {
+                let (mut q, mut p) = (position, momentum);
+                if shift_position {
+                    q[i] += delta;
+                } else {
+                    p[i] += delta;
+                }
+                let point = potential.evaluate(&mut pool, &q, &p).unwrap();
+                point.potential_energy + point.kinetic_energy
+            };
+            let velocity = (energy(h, false) - energy(-h, false)) / (2. * h);
+            assert_abs_diff_eq!(point.velocity[i], velocity, epsilon = 1e-6);
+
+            let grad = (energy(h, true) - energy(-h, true)) / (2. * h);
+            assert_abs_diff_eq!(hamiltonian_grad[i], grad, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn funnel() {
+        let run = |softabs: SoftAbsSettings, num_tune: u64, num_draws: usize| {
+            let settings = SamplerArgs {
+                num_tune,
+                step_size_adapt: DualAverageSettings {
+                    target_accept: 0.95,
+                    ..Default::default()
+                },
+                softabs,
+                ..Default::default()
+            };
+            let mut sampler = new_softabs_sampler(Funnel { dim: 3 }, settings, 0, 42).unwrap();
+            sampler.set_position(&[0., 1., -1.]).unwrap();
+            for _ in 0..num_tune {
+                sampler.draw().unwrap();
+            }
+            let mut divergences = 0;
+            let draws: Vec<_> = (0..num_draws)
+                .map(|_| {
+                    let (draw, stats) = sampler.draw().unwrap();
+                    assert!(draw.iter().all(|x| x.is_finite()));
+                    divergences += stats.divergence_info().is_some() as usize;
+                    draw[0]
+                })
+                .collect();
+            (sampler, draws, divergences)
+        };
+
+        let (mut sampler, draws, divergences) = run(SoftAbsSettings::default(), 300, 600);
+        let n = draws.len();
+        // A Euclidean metric misses the neck of the funnel and diverges
+        // there, so that the standard deviation of v is much too small.
+        let mean = draws.iter().sum::<f64>() / n as f64;
+        let var = draws.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n as f64;
+        assert!(mean.abs() < 1., "{}", mean);
+        assert!((var.sqrt() - 3.).abs() < 0.7, "{}", var.sqrt());
+        assert!(divergences < n / 10, "{}", divergences);
+
+        // Steps that do not converge are only divergences by default
+        let settings = SoftAbsSettings {
+            max_fixed_point_steps: 2,
+            fixed_point_tol: 1e-12,
+            ..Default::default()
+        };
+        let (_, _, with) = run(settings, 0, 50);
+        let settings = SoftAbsSettings {
+            diverge_on_fixed_point_failure: false,
+            ..settings
+        };
+        let (_, _, without) = run(settings, 0, 50);
+        assert!(without < with, "{} {}", without, with);
+
+        let stats = sampler.draw().unwrap().1;
+        let names: Vec<_> = stats.to_vec().iter().map(|(name, _)| *name).collect();
+        assert!(names.contains(&"fixed_point_failures"));
+        assert!(matches!(
+            sampler.set_metric(&[1., 1., 1.]),
+            Err(NutsError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::cpu_potential::CpuLogpFunc;
+use crate::nuts::LogpError;
+
+/// A logp function that can update its gradient in place
+///
+/// For models with local structure, like Markov random fields, a gradient
+/// entry only depends on a few coordinates. Such a model can compare the new
+/// position with the previous one and only recompute the entries that are
+/// affected by the coordinates that changed. Use [`SparseGradLogp`] to sample
+/// from it.
+pub trait CpuLogpFuncSparseGrad {
+    type Err: std::fmt::Debug + Send + LogpError + 'static;
+
+    fn dim(&self) -> usize;
+
+    /// Compute the logp at `position`.
+    ///
+    /// `grad` contains the gradient at `previous`, and must be updated to the
+    /// gradient at `position`. The indices of all entries that were written
+    /// must be pushed to `changed`. `previous` is `None` for the first
+    /// evaluation and after an evaluation failed, and then the full gradient
+    /// must be written.
+    fn logp_sparse(
+        &mut self,
+        position: &[f64],
+        previous: Option<&[f64]>,
+        grad: &mut [f64],
+        changed: &mut Vec<usize>,
+    ) -> Result<f64, Self::Err>;
+}
+
+/// Adapt a [`CpuLogpFuncSparseGrad`] to a [`CpuLogpFunc`]
+///
+/// The gradient of the previous evaluation is cached, so the model only has
+/// to update the entries that changed. The leapfrog integrator still updates
+/// all coordinates, because each momentum update needs the complete gradient.
+#[derive(Debug)]
+pub struct SparseGradLogp<F: CpuLogpFuncSparseGrad> {
+    logp: F,
+    previous: Option<Box<[f64]>>,
+    grad: Box<[f64]>,
+    changed: Vec<usize>,
+    num_evals: u64,
+    num_changed: u64,
+}
+
+impl<F: CpuLogpFuncSparseGrad> SparseGradLogp<F> {
+    pub fn new(logp: F) -> Self {
+        let dim = logp.dim();
+        Self {
+            logp,
+            previous: None,
+            grad: vec![0f64; dim].into(),
+            changed: Vec::with_capacity(dim),
+            num_evals: 0,
+            num_changed: 0,
+        }
+    }
+
+    /// The average fraction of gradient entries that were recomputed per
+    /// evaluation.
+    pub fn changed_fraction(&self) -> f64 {
+        if self.num_evals == 0 {
+            return 0f64;
+        }
+        self.num_changed as f64 / (self.num_evals as f64 * self.grad.len() as f64)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.logp
+    }
+}
+
+impl<F: CpuLogpFuncSparseGrad> CpuLogpFunc for SparseGradLogp<F> {
+    type Err = F::Err;
+
+    fn dim(&self) -> usize {
+        self.logp.dim()
+    }
+
+    fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::Err> {
+        self.changed.clear();
+        let result = self.logp.logp_sparse(
+            position,
+            self.previous.as_deref(),
+            &mut self.grad,
+            &mut self.changed,
+        );
+        let logp = match result {
+            Ok(logp) => logp,
+            Err(err) => {
+                // The cached gradient may be partially updated
+                self.previous = None;
+                return Err(err);
+            }
+        };
+        match self.previous.as_mut() {
+            Some(previous) => previous.copy_from_slice(position),
+            None => self.previous = Some(position.into()),
+        }
+        self.num_evals += 1;
+        self.num_changed += self.changed.len() as u64;
+        grad.copy_from_slice(&self.grad);
+        Ok(logp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_sampler, test_logps::sample_moments, SamplerArgs};
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    enum ChainError {}
+
+    impl LogpError for ChainError {
+        fn is_recoverable(&self) -> bool {
+            false
+        }
+    }
+
+    /// A gaussian random walk `x_0 ~ N(0, 1)`, `x_i ~ N(x_{i-1}, 1)`
+    struct RandomWalk {
+        dim: usize,
+    }
+
+    impl RandomWalk {
+        fn grad_entry(&self, x: &[f64], i: usize) -> f64 {
+            let mut grad = if i == 0 { -x[0] } else { -(x[i] - x[i - 1]) };
+            if i + 1 < self.dim {
+                grad += x[i + 1] - x[i];
+            }
+            grad
+        }
+    }
+
+    impl CpuLogpFuncSparseGrad for RandomWalk {
+        type Err = ChainError;
+
+        fn dim(&self) -> usize {
+            self.dim
+        }
+
+        fn logp_sparse(
+            &mut self,
+            position: &[f64],
+            previous: Option<&[f64]>,
+            grad: &mut [f64],
+            changed: &mut Vec<usize>,
+        ) -> Result<f64, Self::Err> {
+            for (i, grad_i) in grad.iter_mut().enumerate() {
+                let affected = match previous {
+                    None => true,
+                    Some(previous) => (i.saturating_sub(1)..(i + 2).min(self.dim))
+                        .any(|j| position[j] != previous[j]),
+                };
+                if affected {
+                    *grad_i = self.grad_entry(position, i);
+                    changed.push(i);
+                }
+            }
+            let logp = -position[0] * position[0] / 2.
+                - position
+                    .windows(2)
+                    .map(|w| (w[1] - w[0]) * (w[1] - w[0]) / 2.)
+                    .sum::<f64>();
+            Ok(logp)
+        }
+    }
+
+    #[test]
+    fn sparse_update() {
+        let dim = 20;
+        let mut func = SparseGradLogp::new(RandomWalk { dim });
+        let mut position: Vec<f64> = (0..dim).map(|i| (i as f64).sin()).collect();
+        let mut grad = vec![0f64; dim];
+        func.logp(&position, &mut grad).unwrap();
+        assert_eq!(func.changed.len(), dim);
+
+        position[7] += 0.5;
+        func.logp(&position, &mut grad).unwrap();
+        assert_eq!(func.changed, vec![6, 7, 8]);
+        let dense = RandomWalk { dim };
+        for (i, &val) in grad.iter().enumerate() {
+            assert_eq!(val, dense.grad_entry(&position, i));
+        }
+        assert!((func.changed_fraction() - (dim + 3) as f64 / (2 * dim) as f64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sample_sparse() {
+        let func = SparseGradLogp::new(RandomWalk { dim: 5 });
+        let settings = SamplerArgs {
+            num_tune: 200,
+            ..Default::default()
+        };
+        let mut sampler = new_sampler(func, settings, 0, 42);
+        let (means, _) = sample_moments(&mut sampler, &[0.; 5], 1000);
+        assert!(means[0].abs() < 0.3, "mean {}", means[0]);
+    }
+}
@@ -1,6 +1,9 @@
 use std::marker::PhantomData;
 
-use crate::nuts::{Collector, NutsOptions, State};
+use crate::{
+    math::{portable_exp, portable_ln, portable_powf},
+    nuts::{Collector, NutsOptions, State},
+};
 
 /// Settings for step size adaptation
 #[derive(Debug, Clone, Copy)]
@@ -22,6 +25,11 @@ impl Default for DualAverageOptions {
     }
 }
 
+/// Dual averaging of the log step size
+///
+/// With [`set_portable`](Self::set_portable), this uses the portable
+/// functions in [`crate::math`], so that step sizes do not depend on the
+/// platform libm.
 #[derive(Clone)]
 pub struct DualAverage {
     log_step: f64,
@@ -29,6 +37,7 @@ pub struct DualAverage {
     hbar: f64,
     mu: f64,
     count: u64,
+    portable: bool,
     settings: DualAverageOptions,
 }
 
@@ -42,33 +51,86 @@ impl DualAverage {
             //mu: (10. * initial_step).ln(),
             mu: (2. * initial_step).ln(),
             count: 1,
+            portable: false,
             settings,
         }
     }
 
+    /// Use the portable functions in [`crate::math`] instead of the platform
+    /// libm, see [`NutsOptions::strict_reproducibility`].
+    ///
+    /// If the dual averaging has not started yet, the initial log step sizes
+    /// are recomputed.
+    pub(crate) fn set_portable(&mut self, portable: bool) {
+        self.portable = portable;
+        if self.count == 1 {
+            let initial_step = self.settings.initial_step;
+            self.log_step = self.ln(initial_step);
+            self.log_step_adapted = self.ln(initial_step);
+            self.mu = self.ln(2. * initial_step);
+        }
+    }
+
+    fn ln(&self, x: f64) -> f64 {
+        if self.portable {
+            portable_ln(x)
+        } else {
+            x.ln()
+        }
+    }
+
+    fn exp(&self, x: f64) -> f64 {
+        if self.portable {
+            portable_exp(x)
+        } else {
+            x.exp()
+        }
+    }
+
     pub fn advance(&mut self, accept_stat: f64, target: f64) {
         let w = 1. / (self.count as f64 + self.settings.t0);
         self.hbar = (1. - w) * self.hbar + w * (target - accept_stat);
         self.log_step = self.mu - self.hbar * (self.count as f64).sqrt() / self.settings.gamma;
-        let mk = (self.count as f64).powf(-self.settings.k);
+        let mk = if self.portable {
+            portable_powf(self.count as f64, -self.settings.k)
+        } else {
+            (self.count as f64).powf(-self.settings.k)
+        };
         self.log_step_adapted = mk * self.log_step + (1. - mk) * self.log_step_adapted;
         self.count += 1;
     }
 
     pub fn current_step_size(&self) -> f64 {
-        self.log_step.exp()
+        self.exp(self.log_step)
     }
 
     pub fn current_step_size_adapted(&self) -> f64 {
-        self.log_step_adapted.exp()
+        self.exp(self.log_step_adapted)
+    }
+
+    /// The averaged statistic, the current and the averaged log step size,
+    /// which chains pool during tuning with
+    /// [`ParallelismSettings::pool_adaptation`](crate::ParallelismSettings::pool_adaptation).
+    pub(crate) fn pool_state(&self) -> [f64; 3] {
+        [self.hbar, self.log_step, self.log_step_adapted]
+    }
+
+    pub(crate) fn set_pool_state(&mut self, [hbar, log_step, log_step_adapted]: [f64; 3]) {
+        self.hbar = hbar;
+        self.log_step = log_step;
+        self.log_step_adapted = log_step_adapted;
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
     }
 
     #[allow(dead_code)]
     pub fn reset(&mut self, initial_step: f64) {
-        self.log_step = initial_step.ln();
-        self.log_step_adapted = initial_step.ln();
+        self.log_step = self.ln(initial_step);
+        self.log_step_adapted = self.ln(initial_step);
         self.hbar = 0f64;
-        self.mu = (10f64 * initial_step).ln();
+        self.mu = self.ln(10f64 * initial_step);
         self.count = 1;
     }
 }
@@ -102,18 +164,68 @@ impl RunningMean {
     }
 }
 
+/// Acceptance statistics of the leapfrog steps of one doubling
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DepthAcceptance {
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) sum: f64,
+    pub(crate) count: u64,
+}
+
+impl DepthAcceptance {
+    fn new() -> Self {
+        Self {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0f64,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub(crate) fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
 pub(crate) struct AcceptanceRateCollector<S: State> {
     initial_energy: f64,
+    portable: bool,
     pub(crate) mean: RunningMean,
+    /// Acceptance statistics for each doubling index, if they are tracked.
+    /// With several trajectories per draw, doublings of the same depth are
+    /// combined.
+    pub(crate) by_depth: Option<Vec<DepthAcceptance>>,
+    depth: usize,
     phantom: PhantomData<S>,
 }
 
 impl<S: State> AcceptanceRateCollector<S> {
-    pub(crate) fn new() -> AcceptanceRateCollector<S> {
+    pub(crate) fn new(track_depth: bool) -> AcceptanceRateCollector<S> {
         AcceptanceRateCollector {
             initial_energy: 0.,
+            portable: false,
             mean: RunningMean::new(),
-            phantom: PhantomData::default(),
+            by_depth: if track_depth { Some(vec![]) } else { None },
+            depth: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    fn add(&mut self, accept: f64) {
+        self.mean.add(accept);
+        if let Some(by_depth) = self.by_depth.as_mut() {
+            if by_depth.len() <= self.depth {
+                by_depth.resize(self.depth + 1, DepthAcceptance::new());
+            }
+            by_depth[self.depth].add(accept);
         }
     }
 }
@@ -128,15 +240,29 @@ impl<S: State> Collector for AcceptanceRateCollector<S> {
         divergence_info: Option<&dyn crate::nuts::DivergenceInfo>,
     ) {
         match divergence_info {
-            Some(_) => self.mean.add(0.),
-            None => self
-                .mean
-                .add(end.log_acceptance_probability(self.initial_energy).exp()),
+            Some(_) => self.add(0.),
+            None => {
+                let log_accept = end.log_acceptance_probability(self.initial_energy);
+                self.add(if self.portable {
+                    portable_exp(log_accept)
+                } else {
+                    log_accept.exp()
+                })
+            }
         }
     }
 
-    fn register_init(&mut self, state: &Self::State, _options: &NutsOptions) {
+    fn register_init(&mut self, state: &Self::State, options: &NutsOptions) {
         self.initial_energy = state.energy();
+        self.portable = options.strict_reproducibility;
         self.mean.reset();
+        self.depth = 0;
+        if let Some(by_depth) = self.by_depth.as_mut() {
+            by_depth.clear();
+        }
+    }
+
+    fn register_doubling(&mut self, depth: u64) {
+        self.depth = depth as usize;
     }
 }
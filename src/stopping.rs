@@ -0,0 +1,552 @@
+use std::{sync::Arc, time::Duration};
+
+use itertools::Itertools;
+
+use crate::nuts::NutsError;
+use crate::{
+    compare::CompareError,
+    diagnostics::{autocorr_time, ess, r_hat},
+};
+#[cfg(feature = "parallel")]
+use {
+    crate::cpu_sampler::{
+        sample_parallel_monitored, CpuLogpFuncMaker, InitPointFunc, ParallelSamplingError,
+        SamplerArgs,
+    },
+    std::time::Instant,
+};
+
+/// The draws of a multi-chain run so far, passed to a [`StoppingRule`].
+#[derive(Debug)]
+pub struct RunState {
+    /// The draws of each chain after tuning. They are shared, so that
+    /// copies of a run state are cheap.
+    pub draws: Vec<Vec<Arc<[f64]>>>,
+    /// The number of parameters
+    pub dim: usize,
+    /// The wall time since sampling started
+    pub elapsed: Duration,
+}
+
+impl RunState {
+    /// The number of draws after tuning that all chains have finished.
+    pub fn num_complete_draws(&self) -> usize {
+        self.draws
+            .iter()
+            .map(|chain| chain.len())
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Apply `func` to the trace of each parameter, using the first
+    /// [`num_complete_draws`](Self::num_complete_draws) of each chain.
+    fn map_params<F: Fn(&[&[f64]]) -> Result<f64, CompareError>>(
+        &self,
+        func: F,
+    ) -> Option<Vec<f64>> {
+        let n = self.num_complete_draws();
+        (0..self.dim)
+            .map(|param| {
+                let traces = self
+                    .draws
+                    .iter()
+                    .map(|chain| chain[..n].iter().map(|draw| draw[param]).collect_vec())
+                    .collect_vec();
+                let traces = traces.iter().map(|trace| &trace[..]).collect_vec();
+                func(&traces)
+            })
+            .collect::<Result<_, _>>()
+            .ok()
+    }
+
+    /// The smallest effective sample size over all parameters, or `None` if
+    /// there are fewer than four draws per chain.
+    pub fn min_ess(&self) -> Option<f64> {
+        if self.num_complete_draws() < 4 {
+            return None;
+        }
+        Some(
+            self.map_params(ess)?
+                .into_iter()
+                .filter(|val| !val.is_nan())
+                .fold(f64::INFINITY, f64::min),
+        )
+    }
+
+    /// The thinning interval after which draws are approximately
+    /// independent, which is the largest integrated autocorrelation time
+    /// over all parameters rounded up. Returns `None` if there are fewer
+    /// than four draws per chain.
+    pub fn recommended_thin(&self) -> Option<usize> {
+        if self.num_complete_draws() < 4 {
+            return None;
+        }
+        let max_time = self
+            .map_params(autocorr_time)?
+            .into_iter()
+            .filter(|val| !val.is_nan())
+            .fold(1f64, f64::max);
+        Some(max_time.ceil() as usize)
+    }
+
+    /// Every `thin`-th draw of each chain, starting at the first one.
+    ///
+    /// Fails with [`NutsError::InvalidSettings`] if `thin` is zero.
+    pub fn thinned(&self, thin: usize) -> Result<Vec<Vec<&[f64]>>, NutsError> {
+        if thin == 0 {
+            return Err(NutsError::InvalidSettings(
+                "thin must be positive".to_string(),
+            ));
+        }
+        Ok(self.every_nth(thin))
+    }
+
+    /// An approximately independent subset of the draws of each chain, using
+    /// the [`recommended_thin`](Self::recommended_thin) interval.
+    pub fn independent_draws(&self) -> Vec<Vec<&[f64]>> {
+        self.every_nth(self.recommended_thin().unwrap_or(1))
+    }
+
+    fn every_nth(&self, thin: usize) -> Vec<Vec<&[f64]>> {
+        self.draws
+            .iter()
+            .map(|chain| chain.iter().step_by(thin).map(|draw| &draw[..]).collect())
+            .collect()
+    }
+
+    /// The largest split R-hat over all parameters, or `None` if there are
+    /// fewer than four draws per chain.
+    pub fn max_r_hat(&self) -> Option<f64> {
+        if self.num_complete_draws() < 4 {
+            return None;
+        }
+        Some(
+            self.map_params(r_hat)?
+                .into_iter()
+                .filter(|val| !val.is_nan())
+                .fold(f64::NEG_INFINITY, f64::max),
+        )
+    }
+}
+
+/// Decide when a multi-chain run has enough draws, see [`sample_until`].
+pub trait StoppingRule {
+    fn should_stop(&mut self, run_state: &RunState) -> bool;
+}
+
+impl<F: FnMut(&RunState) -> bool> StoppingRule for F {
+    fn should_stop(&mut self, run_state: &RunState) -> bool {
+        self(run_state)
+    }
+}
+
+/// Stop when the effective sample size of all parameters reaches a target.
+///
+/// Computing the effective sample size takes time that grows with the
+/// length of the run, so it is only recomputed after the number of complete
+/// draws has grown by a tenth since the last estimate. This can overshoot
+/// the target by up to a tenth of the draws.
+#[derive(Debug, Clone, Copy)]
+pub struct EssTarget {
+    target: f64,
+    next_check: usize,
+}
+
+impl EssTarget {
+    pub fn new(target: f64) -> Self {
+        EssTarget {
+            target,
+            next_check: 0,
+        }
+    }
+
+    pub fn target(&self) -> f64 {
+        self.target
+    }
+}
+
+impl StoppingRule for EssTarget {
+    fn should_stop(&mut self, run_state: &RunState) -> bool {
+        let num_draws = run_state.num_complete_draws();
+        if num_draws < self.next_check {
+            return false;
+        }
+        let Some(min_ess) = run_state.min_ess() else {
+            return false;
+        };
+        self.next_check = num_draws + num_draws.div_ceil(10);
+        min_ess >= self.target
+    }
+}
+
+/// Stop after a fixed wall time.
+#[derive(Debug, Clone, Copy)]
+pub struct WallTime(pub Duration);
+
+impl StoppingRule for WallTime {
+    fn should_stop(&mut self, run_state: &RunState) -> bool {
+        run_state.elapsed >= self.0
+    }
+}
+
+/// Stop when the split R-hat of all parameters is below a threshold, after
+/// at least `min_draws` draws per chain.
+#[derive(Debug, Clone, Copy)]
+pub struct RHatThreshold {
+    pub threshold: f64,
+    pub min_draws: usize,
+}
+
+impl StoppingRule for RHatThreshold {
+    fn should_stop(&mut self, run_state: &RunState) -> bool {
+        if run_state.num_complete_draws() < self.min_draws {
+            return false;
+        }
+        run_state
+            .max_r_hat()
+            .is_some_and(|val| val < self.threshold)
+    }
+}
+
+/// A scalar function of a draw with a range of expected values, see
+/// [`Monitored`]
+pub struct ScalarMonitor {
+    pub name: String,
+    pub lower: f64,
+    pub upper: f64,
+    func: Box<dyn FnMut(&[f64]) -> f64>,
+}
+
+impl ScalarMonitor {
+    pub fn new<F: FnMut(&[f64]) -> f64 + 'static>(
+        name: &str,
+        lower: f64,
+        upper: f64,
+        func: F,
+    ) -> Self {
+        ScalarMonitor {
+            name: name.to_string(),
+            lower,
+            upper,
+            func: Box::new(func),
+        }
+    }
+}
+
+/// A monitored value that was outside of its range, see [`Monitored`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorAlarm {
+    /// The name of the [`ScalarMonitor`]
+    pub name: String,
+    pub chain: usize,
+    /// The index of the draw after tuning
+    pub draw: usize,
+    /// The value of the monitor, which might be `NaN`
+    pub value: f64,
+}
+
+/// Watch scalar functions of the draws while a [`StoppingRule`] decides
+/// when to stop.
+///
+/// Each time the rule is checked, the monitors are evaluated on the draws
+/// since the last check. `on_alarm` is called for every value outside of
+/// the range of its monitor, and the run stops if it returns `true`. This
+/// catches pathologies like an exploding log-likelihood term early in long
+/// runs.
+pub struct Monitored<S> {
+    rule: S,
+    monitors: Vec<ScalarMonitor>,
+    on_alarm: Box<dyn FnMut(&MonitorAlarm) -> bool>,
+    num_checked: Vec<usize>,
+}
+
+impl<S: StoppingRule> Monitored<S> {
+    pub fn new<A: FnMut(&MonitorAlarm) -> bool + 'static>(
+        rule: S,
+        monitors: Vec<ScalarMonitor>,
+        on_alarm: A,
+    ) -> Self {
+        Monitored {
+            rule,
+            monitors,
+            on_alarm: Box::new(on_alarm),
+            num_checked: vec![],
+        }
+    }
+}
+
+impl<S: StoppingRule> StoppingRule for Monitored<S> {
+    fn should_stop(&mut self, run_state: &RunState) -> bool {
+        self.num_checked.resize(run_state.draws.len(), 0);
+        let mut stop = false;
+        for (chain, draws) in run_state.draws.iter().enumerate() {
+            let start = self.num_checked[chain];
+            for (draw, position) in draws.iter().enumerate().skip(start) {
+                for monitor in self.monitors.iter_mut() {
+                    let value = (monitor.func)(position);
+                    if !(monitor.lower..=monitor.upper).contains(&value) {
+                        let alarm = MonitorAlarm {
+                            name: monitor.name.clone(),
+                            chain,
+                            draw,
+                            value,
+                        };
+                        stop |= (self.on_alarm)(&alarm);
+                    }
+                }
+            }
+            self.num_checked[chain] = draws.len();
+        }
+        // Always check the rule, it might keep state between checks
+        self.rule.should_stop(run_state) | stop
+    }
+}
+
+/// Sample chains in parallel until `rule` says to stop or each chain has
+/// `max_draws` draws after tuning.
+///
+/// The rule is evaluated every `check_every` draws after tuning, counted
+/// over all chains, which must be positive. Returns the state of the run
+/// when it stopped.
+///
+/// Rules based on diagnostics only see the draws that all chains have
+/// finished, so all chains should run at the same time, see
+/// [`ParallelismSettings::chain_threads`](crate::ParallelismSettings).
+#[cfg(feature = "parallel")]
+#[allow(clippy::too_many_arguments)]
+pub fn sample_until<F, I, S>(
+    logp_func_maker: F,
+    init_point_func: &mut I,
+    settings: SamplerArgs,
+    n_chains: u64,
+    max_draws: u64,
+    seed: u64,
+    n_try_init: u64,
+    mut rule: S,
+    check_every: u64,
+) -> Result<RunState, ParallelSamplingError>
+where
+    F: CpuLogpFuncMaker + 'static,
+    I: InitPointFunc,
+    S: StoppingRule,
+{
+    if check_every == 0 {
+        return Err(NutsError::InvalidSettings("check_every must be positive".to_string()).into());
+    }
+    let dim = logp_func_maker.dim();
+    let start = Instant::now();
+    let (handle, receiver, monitor) = sample_parallel_monitored(
+        logp_func_maker,
+        init_point_func,
+        settings,
+        n_chains,
+        max_draws,
+        seed,
+        n_try_init,
+    )?;
+
+    let mut state = RunState {
+        draws: (0..n_chains).map(|_| vec![]).collect(),
+        dim,
+        elapsed: Duration::ZERO,
+    };
+    let mut since_check = 0;
+    let mut stopped = false;
+    for (draw, stats) in receiver.iter() {
+        if stopped | (stats.draw() < settings.num_tune) {
+            continue;
+        }
+        state.draws[stats.chain() as usize].push(draw.into());
+        since_check += 1;
+        if since_check >= check_every {
+            since_check = 0;
+            state.elapsed = start.elapsed();
+            if rule.should_stop(&state) {
+                monitor.request_stop();
+                stopped = true;
+            }
+        }
+    }
+    state.elapsed = start.elapsed();
+
+    let results = handle.join().map_err(|_| ParallelSamplingError::Panic)?;
+    results
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| err.source)?;
+    Ok(state)
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use super::*;
+    use crate::{test_logps::NormalLogp, JitterInitFunc, ParallelismSettings};
+
+    fn run<S: StoppingRule>(rule: S) -> RunState {
+        let settings = SamplerArgs {
+            num_tune: 100,
+            parallelism: ParallelismSettings {
+                chain_threads: Some(4),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let maker = crate::test_logps::Maker {
+            logp: NormalLogp::new(3, 0.),
+        };
+        sample_until(
+            maker,
+            &mut JitterInitFunc::new(),
+            settings,
+            4,
+            5000,
+            42,
+            10,
+            rule,
+            40,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn stop_at_ess_target() {
+        let state = run(EssTarget::new(400.));
+        assert!(state.min_ess().unwrap() >= 400.);
+        assert!(state.num_complete_draws() < 5000);
+    }
+
+    #[test]
+    fn ess_target_batches_checks() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut draw = || -> Arc<[f64]> { vec![rng.sample(rand_distr::StandardNormal)].into() };
+        let mut state = RunState {
+            draws: vec![(0..1000).map(|_| draw()).collect_vec()],
+            dim: 1,
+            elapsed: Duration::ZERO,
+        };
+        let mut rule = EssTarget::new(1050.);
+        assert!(!rule.should_stop(&state));
+
+        // The target is reached, but the ESS is only estimated again after
+        // 100 more draws
+        state.draws[0].extend((0..99).map(|_| draw()));
+        assert!(!rule.should_stop(&state));
+        state.draws[0].push(draw());
+        assert!(rule.should_stop(&state));
+
+        let err = sample_until(
+            crate::test_logps::Maker {
+                logp: NormalLogp::new(3, 0.),
+            },
+            &mut JitterInitFunc::new(),
+            SamplerArgs::default(),
+            4,
+            100,
+            42,
+            10,
+            EssTarget::new(400.),
+            0,
+        );
+        assert!(matches!(
+            err,
+            Err(ParallelSamplingError::NutsError {
+                source: NutsError::InvalidSettings(_)
+            })
+        ));
+    }
+
+    #[test]
+    fn custom_and_builtin_rules() {
+        let state = run(|state: &RunState| state.num_complete_draws() >= 50);
+        assert!(state.num_complete_draws() >= 50);
+        assert!(state.draws.iter().all(|chain| chain.len() < 5000));
+
+        let state = run(RHatThreshold {
+            threshold: 1.05,
+            min_draws: 100,
+        });
+        assert!(state.max_r_hat().unwrap() < 1.05);
+
+        let state = run(WallTime(Duration::ZERO));
+        assert!(state.num_complete_draws() < 5000);
+    }
+
+    #[test]
+    fn thinning() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // Independent draws in the first and an AR(1) process with
+        // autocorrelation time 19 in the second parameter
+        let mut rng = StdRng::seed_from_u64(42);
+        let draws = (0..4)
+            .map(|_| {
+                let mut x = 0f64;
+                (0..2000)
+                    .map(|_| {
+                        let noise: f64 = rng.sample(rand_distr::StandardNormal);
+                        x = 0.9 * x + noise;
+                        vec![rng.sample(rand_distr::StandardNormal), x].into()
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+        let state = RunState {
+            draws,
+            dim: 2,
+            elapsed: Duration::ZERO,
+        };
+        let thin = state.recommended_thin().unwrap();
+        assert!((12..30).contains(&thin), "{}", thin);
+
+        let thinned = state.independent_draws();
+        assert_eq!(thinned.len(), 4);
+        assert_eq!(thinned[0].len(), 2000usize.div_ceil(thin));
+        assert_eq!(thinned[0][1], &state.draws[0][thin][..]);
+        assert_eq!(state.thinned(1).unwrap()[3].len(), 2000);
+        assert!(matches!(
+            state.thinned(0),
+            Err(NutsError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn monitored_values() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let alarms = Rc::new(RefCell::new(vec![]));
+        let alarms_inner = alarms.clone();
+        let monitors = vec![
+            ScalarMonitor::new("x0", -3., 3., |draw| draw[0]),
+            ScalarMonitor::new("norm", 0., f64::INFINITY, |draw| {
+                draw.iter().map(|x| x * x).sum::<f64>()
+            }),
+        ];
+        let rule = Monitored::new(
+            |_: &RunState| false,
+            monitors,
+            move |alarm| {
+                alarms_inner.borrow_mut().push(alarm.clone());
+                true
+            },
+        );
+        let state = run(rule);
+        assert!(state.num_complete_draws() < 5000);
+        let alarms = alarms.borrow();
+        assert!(!alarms.is_empty());
+        for alarm in alarms.iter() {
+            assert_eq!(alarm.name, "x0");
+            assert!(alarm.value.abs() > 3.);
+            assert_eq!(state.draws[alarm.chain][alarm.draw][0], alarm.value);
+        }
+
+        // Without alarms, the inner rule decides
+        let monitors = vec![ScalarMonitor::new("x0", -100., 100., |draw| draw[0])];
+        let rule = Monitored::new(EssTarget::new(400.), monitors, |_| {
+            panic!("Unexpected alarm")
+        });
+        let state = run(rule);
+        assert!(state.min_ess().unwrap() >= 400.);
+    }
+}
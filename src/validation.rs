@@ -0,0 +1,306 @@
+//! Statistical tests for validating samplers and custom potentials.
+//!
+//! The tests assume independent draws. Draws from a chain should be thinned
+//! so that the number of draws is not larger than their effective sample
+//! size, see [`ess`](crate::ess), otherwise p-values will be too small.
+
+use crate::nuts::NutsError;
+
+/// The result of a goodness of fit test
+#[derive(Debug, Clone, Copy)]
+pub struct TestResult {
+    /// The value of the test statistic
+    pub statistic: f64,
+    /// The probability of a statistic at least as large as the observed one
+    /// if the null hypothesis is true.
+    pub p_value: f64,
+}
+
+/// The rank of `value` among `draws` for simulation-based calibration.
+///
+/// If `value` was drawn from the prior and `draws` are independent draws from
+/// the posterior given data simulated from `value`, the rank is uniform in
+/// `0..=draws.len()`.
+pub fn sbc_rank(value: f64, draws: &[f64]) -> usize {
+    draws.iter().filter(|&&draw| draw < value).count()
+}
+
+/// Test the uniformity of simulation-based calibration ranks.
+///
+/// `ranks` were computed with [`sbc_rank`] from `num_draws` posterior draws
+/// each. The ranks are grouped into `num_bins` bins of equal width, and the
+/// counts are compared to a uniform distribution with a χ² test. Fails with
+/// [`NutsError::InvalidSettings`] if there are fewer than two bins,
+/// `num_bins` does not divide `num_draws + 1`, or a rank is larger than
+/// `num_draws`.
+pub fn sbc_uniformity_test(
+    ranks: &[usize],
+    num_draws: usize,
+    num_bins: usize,
+) -> Result<TestResult, NutsError> {
+    if num_bins < 2 {
+        return Err(NutsError::InvalidSettings(
+            "Need at least two bins".to_string(),
+        ));
+    }
+    if !(num_draws + 1).is_multiple_of(num_bins) {
+        return Err(NutsError::InvalidSettings(format!(
+            "Number of bins {} does not divide the number of possible ranks {}",
+            num_bins,
+            num_draws + 1
+        )));
+    }
+    let bin_width = (num_draws + 1) / num_bins;
+    let mut counts = vec![0usize; num_bins];
+    for &rank in ranks.iter() {
+        if rank > num_draws {
+            return Err(NutsError::InvalidSettings(format!(
+                "Rank {} out of range",
+                rank
+            )));
+        }
+        counts[rank / bin_width] += 1;
+    }
+
+    let expected = ranks.len() as f64 / num_bins as f64;
+    let statistic = counts
+        .iter()
+        .map(|&count| (count as f64 - expected).powi(2) / expected)
+        .sum::<f64>();
+    Ok(TestResult {
+        statistic,
+        p_value: chi2_sf(statistic, (num_bins - 1) as f64),
+    })
+}
+
+/// Kolmogorov–Smirnov test of `draws` against the distribution function `cdf`.
+///
+/// The p-value uses the asymptotic Kolmogorov distribution with the
+/// small-sample correction of Stephens (1970). Fails with
+/// [`NutsError::InvalidSettings`] if there are no draws or a draw is NaN.
+pub fn ks_test<F: Fn(f64) -> f64>(draws: &[f64], cdf: F) -> Result<TestResult, NutsError> {
+    if draws.is_empty() {
+        return Err(NutsError::InvalidSettings(
+            "Need at least one draw".to_string(),
+        ));
+    }
+    if draws.iter().any(|draw| draw.is_nan()) {
+        return Err(NutsError::InvalidSettings(
+            "Draws must not be nan".to_string(),
+        ));
+    }
+    let mut sorted = draws.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = sorted.len() as f64;
+    let statistic = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let val = cdf(x);
+            (val - i as f64 / n).max((i + 1) as f64 / n - val)
+        })
+        .fold(0f64, f64::max);
+    let sqrt_n = n.sqrt();
+    let lambda = (sqrt_n + 0.12 + 0.11 / sqrt_n) * statistic;
+    Ok(TestResult {
+        statistic,
+        p_value: kolmogorov_sf(lambda),
+    })
+}
+
+/// The distribution function of a normal distribution.
+pub fn normal_cdf(x: f64, mu: f64, sigma: f64) -> f64 {
+    0.5 * erfc(-(x - mu) / (sigma * std::f64::consts::SQRT_2))
+}
+
+/// The complementary error function with a relative error below 1.2e-7.
+///
+/// From Numerical Recipes, section 6.2.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let poly = -z * z - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let val = t * poly.exp();
+    if x >= 0. {
+        val
+    } else {
+        2. - val
+    }
+}
+
+/// The survival function of the Kolmogorov distribution.
+fn kolmogorov_sf(lambda: f64) -> f64 {
+    if lambda < 0.2 {
+        return 1.;
+    }
+    let mut sum = 0f64;
+    let mut sign = 1f64;
+    for k in 1..=100 {
+        let term = (-2. * (k * k) as f64 * lambda * lambda).exp();
+        sum += sign * term;
+        if term < 1e-12 {
+            break;
+        }
+        sign = -sign;
+    }
+    (2. * sum).clamp(0., 1.)
+}
+
+/// The survival function of the χ² distribution with `dof` degrees of freedom.
+fn chi2_sf(x: f64, dof: f64) -> f64 {
+    if x <= 0. {
+        return 1.;
+    }
+    1. - regularized_gamma_p(dof / 2., x / 2.)
+}
+
+/// The regularized lower incomplete gamma function P(a, x).
+///
+/// Uses the series expansion for `x < a + 1` and a continued fraction
+/// otherwise, as in Numerical Recipes, section 6.2.
+fn regularized_gamma_p(a: f64, x: f64) -> f64 {
+    let log_prefactor = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1. {
+        let mut term = 1. / a;
+        let mut sum = term;
+        let mut ap = a;
+        for _ in 0..1000 {
+            ap += 1.;
+            term *= x / ap;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+        (sum.ln() + log_prefactor).exp()
+    } else {
+        let tiny = 1e-300;
+        let mut b = x + 1. - a;
+        let mut c = 1. / tiny;
+        let mut d = 1. / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1. / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.).abs() < 1e-15 {
+                break;
+            }
+        }
+        1. - (h.ln() + log_prefactor).exp()
+    }
+}
+
+/// The log of the gamma function for positive arguments (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+    let mut y = x;
+    for coef in COEFS.iter() {
+        y += 1.;
+        ser += coef / y;
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_sequentially, test_logps::NormalLogp, SamplerArgs};
+    use approx::assert_abs_diff_eq;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rand_distr::StandardNormal;
+
+    #[test]
+    fn special_functions() {
+        assert_abs_diff_eq!(normal_cdf(0., 0., 1.), 0.5, epsilon = 1e-7);
+        assert_abs_diff_eq!(normal_cdf(1.96, 0., 1.), 0.9750021, epsilon = 1e-6);
+        assert_abs_diff_eq!(normal_cdf(3., 1., 2.), 0.8413447, epsilon = 1e-6);
+        // Upper 5% quantiles of the χ² distribution
+        assert_abs_diff_eq!(chi2_sf(3.841459, 1.), 0.05, epsilon = 1e-6);
+        assert_abs_diff_eq!(chi2_sf(18.307038, 10.), 0.05, epsilon = 1e-6);
+        assert_abs_diff_eq!(kolmogorov_sf(1.358099), 0.05, epsilon = 1e-4);
+    }
+
+    #[test]
+    fn sbc_uniform_and_biased() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let num_draws = 99;
+        let mut ranks = vec![];
+        let mut biased_ranks = vec![];
+        for _ in 0..500 {
+            // Prior N(0, 1), one observation with N(mu, 1) noise,
+            // posterior N(y / 2, 1 / 2).
+            let mu: f64 = rng.sample(StandardNormal);
+            let y = mu + rng.sample::<f64, _>(StandardNormal);
+            let draws: Vec<f64> = (0..num_draws)
+                .map(|_| y / 2. + rng.sample::<f64, _>(StandardNormal) / 2f64.sqrt())
+                .collect();
+            ranks.push(sbc_rank(mu, &draws));
+            let too_narrow: Vec<f64> = draws.iter().map(|x| y / 2. + (x - y / 2.) / 2.).collect();
+            biased_ranks.push(sbc_rank(mu, &too_narrow));
+        }
+        assert!(sbc_uniformity_test(&ranks, num_draws, 10).unwrap().p_value > 0.001);
+        assert!(
+            sbc_uniformity_test(&biased_ranks, num_draws, 10)
+                .unwrap()
+                .p_value
+                < 0.001
+        );
+        for (ranks, num_bins) in [(&ranks[..], 1), (&ranks, 7), (&[num_draws + 1][..], 10)] {
+            assert!(matches!(
+                sbc_uniformity_test(ranks, num_draws, num_bins),
+                Err(NutsError::InvalidSettings(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn ks_sampler_draws() {
+        let settings = SamplerArgs {
+            num_tune: 500,
+            ..Default::default()
+        };
+        let chain =
+            sample_sequentially(NormalLogp::new(4, 1.), settings, &[0.; 4], 3500, 0, 42).unwrap();
+        let draws: Vec<f64> = chain
+            .skip(500)
+            .step_by(5)
+            .map(|draw| draw.unwrap().0[0])
+            .collect();
+        assert!(ks_test(&draws, |x| normal_cdf(x, 1., 1.)).unwrap().p_value > 0.001);
+        assert!(ks_test(&draws, |x| normal_cdf(x, 0., 1.)).unwrap().p_value < 0.001);
+        for draws in [&[][..], &[0., f64::NAN]] {
+            assert!(matches!(
+                ks_test(draws, |x| normal_cdf(x, 0., 1.)),
+                Err(NutsError::InvalidSettings(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+#[path = "../examples/eight_schools.rs"]
+mod eight_schools;
+
+use eight_schools::{run, EightSchools};
+use nuts_rs::{leapfrog_n, CpuLogpFunc};
+
+#[test]
+fn gradient_matches_finite_differences() {
+    let mut func = EightSchools::default();
+    let position: Vec<f64> = (0..10).map(|i| 0.3 * i as f64 - 1.2).collect();
+    let mut grad = vec![0f64; 10];
+    let logp = func.logp(&position, &mut grad).unwrap();
+
+    let eps = 1e-6;
+    let mut unused = vec![0f64; 10];
+    for i in 0..10 {
+        let mut shifted = position.clone();
+        shifted[i] += eps;
+        let logp_shifted = func.logp(&shifted, &mut unused).unwrap();
+        let numeric = (logp_shifted - logp) / eps;
+        assert!(
+            (numeric - grad[i]).abs() < 1e-4,
+            "Gradient {} is {}, finite differences give {}",
+            i,
+            grad[i],
+            numeric
+        );
+    }
+
+    // Small leapfrog steps conserve the energy if the gradient is right
+    let path = leapfrog_n(func, &position, &[0.5; 10], None, 0.01, 100).unwrap();
+    let energy_error = path.last().unwrap().energy - path[0].energy;
+    assert!(energy_error.abs() < 1e-3, "{}", energy_error);
+}
+
+#[test]
+fn eight_schools() {
+    let summary = run(4, 1000, 42);
+    assert!(summary.r_hat.iter().all(|&val| val < 1.05), "{:?}", summary);
+    assert!(summary.ess.iter().all(|&val| val > 200.), "{:?}", summary);
+    // Reference values from long runs of Stan and PyMC
+    assert!((3.5..5.5).contains(&summary.mu_mean), "{:?}", summary);
+    assert!((2.5..4.7).contains(&summary.tau_mean), "{:?}", summary);
+    assert!(summary.num_divergences < 40, "{:?}", summary);
+}